@@ -1,194 +1,631 @@
-use crate::util;
+//! Build the project's source distribution and wheel from `pyproject.toml`, without setuptools.
+//! https://packaging.python.org/specifications/core-metadata/
+//! https://packaging.python.org/specifications/binary-distribution-format/
+//! https://packaging.python.org/specifications/source-distribution-format/
+
+use crate::{
+    dep_types::{DependencyError, ReqType},
+    index::normalize_name,
+    install::{self, RecordEntry},
+    markers::{Marker, MarkerOp, MarkerValue},
+    util, Config,
+};
 use crossterm::Color;
-use std::collections::HashMap;
-use std::{env, fs, path::PathBuf, process::Command};
+use flate2::{Compression, GzBuilder};
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::PathBuf,
+};
 
-// https://packaging.python.org/tutorials/packaging-projects/
+/// Timestamp for every file in the archives we build, so building the same project twice
+/// gives identical files: 1980-01-01, the earliest date a zip file can store.
+const BUILD_TIMESTAMP: u64 = 315_532_800;
 
-/// Serialize to a python list of strings.
-fn serialize_py_list(items: &[String]) -> String {
-    let mut result = "[\n".to_string();
-    for item in items.iter() {
-        result.push_str(&format!("    \"{}\",\n", item));
-    }
-    result.push(']');
-    result
+/// A PEP 517 backend, included in source distributions so other tools, eg `pip`, can build
+/// them. It calls this program's `build-backend` command, which prints the filename it built.
+pub const BACKEND_MODULE: &str = r#""""PEP 517 build backend for projects managed by pypackage. Requires the `pypackage`
+executable on the PATH."""
+import shutil
+import subprocess
+
+
+def _build(kind, directory):
+    # pypackage isn't on PyPI, so it can't be listed in `requires`; it must already be installed.
+    executable = shutil.which("pypackage")
+    if executable is None:
+        raise RuntimeError(
+            "Building this project requires pypackage, which isn't on the PATH. Install it "
+            "from https://github.com/David-OConnor/pypackage, then try again."
+        )
+    output = subprocess.check_output([executable, "build-backend", kind, directory])
+    return output.decode().strip().splitlines()[-1]
+
+
+def get_requires_for_build_wheel(config_settings=None):
+    return []
+
+
+def get_requires_for_build_sdist(config_settings=None):
+    return []
+
+
+def build_wheel(wheel_directory, config_settings=None, metadata_directory=None):
+    return _build("wheel", wheel_directory)
+
+
+def build_sdist(sdist_directory, config_settings=None):
+    return _build("sdist", sdist_directory)
+"#;
+
+pub const BACKEND_FILENAME: &str = "pypackage_backend.py";
+
+/// The `[build-system]` table for projects built by the backend above.
+pub const BUILD_SYSTEM: &str = r#"[build-system]
+requires = []
+build-backend = "pypackage_backend"
+backend-path = ["."]
+"#;
+
+/// The name used in wheel and sdist filenames, eg `my_package` for `My-Package`.
+pub(crate) fn dist_name(name: &str) -> String {
+    normalize_name(name).replace('-', "_")
 }
 
-/// Serialize to a Python dics of lists of strings.
-fn _serialize_py_dict(hm: &HashMap<String, Vec<String>>) -> String {
-    let mut result = "{\n".to_string();
-    for (key, val) in hm.iter() {
-        result.push_str(&format!("    \"{}\": {}\n", key, serialize_py_list(val)));
+/// Find the readme, and its content type.
+fn find_readme(cfg: &Config, project_dir: &PathBuf) -> Option<(String, &'static str)> {
+    let candidates = match &cfg.readme_filename {
+        Some(f) => vec![f.clone()],
+        None => vec!["README.md".into(), "README.rst".into(), "README.txt".into()],
+    };
+    let filename = candidates
+        .into_iter()
+        .find(|f| project_dir.join(f).is_file())?;
+
+    let content_type = if filename.ends_with(".md") {
+        "text/markdown"
+    } else if filename.ends_with(".rst") {
+        "text/x-rst"
+    } else {
+        "text/plain"
+    };
+    Some((filename, content_type))
+}
+
+/// License files in the project's top folder, eg `LICENSE` or `COPYING.txt`.
+fn find_license_files(project_dir: &PathBuf) -> Vec<String> {
+    let mut result = vec![];
+    if let Ok(entries) = fs::read_dir(project_dir) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let filename = entry.file_name().to_string_lossy().into_owned();
+            let upper = filename.to_uppercase();
+            if entry.path().is_file()
+                && (upper.starts_with("LICENSE")
+                    || upper.starts_with("LICENCE")
+                    || upper.starts_with("COPYING"))
+            {
+                result.push(filename);
+            }
+        }
     }
-    result.push('}');
+    result.sort();
     result
 }
 
-///// A different format, as used in console_scripts
-//fn serialize_py_dict2(hm: &HashMap<String, String>) -> String {
-//    let mut result = "{\n".to_string();
-//    for (key, val) in hm.iter() {
-//        result.push_str(&format!("    \"{}\": {}\n", key, serialize_py_list(val)));
-//    }
-//    result.push('}');
-//    result
-//}
-
-/// Creates a temporary file which imitates setup.py
-fn create_dummy_setup(cfg: &crate::Config, filename: &str) {
-    let version = match cfg.version {
-        Some(v) => v.to_string(),
-        None => "".into(),
+/// Core metadata, version 2.1, as used for `METADATA` in wheels and `PKG-INFO` in sdists.
+pub fn metadata(cfg: &Config, project_dir: &PathBuf) -> Result<String, DependencyError> {
+    let (name, version) = match (&cfg.name, &cfg.version) {
+        (Some(n), Some(v)) if !n.is_empty() => (n, v),
+        _ => {
+            return Err(DependencyError::new(
+                "`name` and `version` must be specified in `pyproject.toml` to build",
+            ))
+        }
     };
-    let cfg = cfg.clone();
-
-    println!("CFG: {:#?}", &cfg);
-
-    let data = format!(
-        r#"import setuptools
- 
-with open("{}", "r") as fh:
-    long_description = fh.read()
-
-setuptools.setup(
-    name="{}",
-    version="{}",
-    author="{}",
-    author_email="{}",
-    license="{}",
-    description="{}",
-    long_description=long_description,
-    long_description_content_type="text/markdown",
-    url="{}",
-    packages=setuptools.find_packages(),
-
-    classifiers={},
-
-    entry_points={{
-        "console_scripts": {},
-    }},
-)
-"#,
-        cfg.readme_filename.unwrap_or_else(|| "README.md".into()),
-        cfg.name.unwrap_or_else(|| "".into()),
-        version,
-        cfg.author.unwrap_or_else(|| "".into()),
-        cfg.author_email.unwrap_or_else(|| "".into()),
-        cfg.license.unwrap_or_else(|| "".into()),
-        cfg.description.unwrap_or_else(|| "".into()),
-        cfg.homepage.unwrap_or_else(|| "".into()),
-        serialize_py_list(&cfg.classifiers),
-        // todo: For now we only support console_scripts entry points.
-        //        serialize_py_dict(&cfg.entry_points),
-        serialize_py_list(&cfg.console_scripts),
-        // todo:
-        //            extras_require="{}",
-        //        match cfg.extras {
-        //            Some(e) => serialize_py_dict(&e),
-        //            None => "".into(),
-        //        }
+
+    let mut result = format!(
+        "Metadata-Version: 2.1\nName: {}\nVersion: {}\n",
+        name,
+        version.to_string2()
     );
+    let mut add = |field: &str, value: &Option<String>| {
+        if let Some(v) = value {
+            if !v.is_empty() {
+                result.push_str(&format!("{}: {}\n", field, v));
+            }
+        }
+    };
+    add("Summary", &cfg.description);
+    add("Home-page", &cfg.homepage);
+    add("Author", &cfg.author);
+    add("Author-email", &cfg.author_email);
+    add("License", &cfg.license);
+    if !cfg.keywords.is_empty() {
+        add("Keywords", &Some(cfg.keywords.join(",")));
+    }
+    if let Some(repo) = &cfg.repository {
+        add("Project-URL", &Some(format!("Repository, {}", repo)));
+    }
+    for classifier in cfg.classifiers.iter() {
+        add("Classifier", &Some(classifier.clone()));
+    }
+    add("Requires-Python", &cfg.requires_python());
+    for req in cfg.reqs.iter() {
+        add("Requires-Dist", &Some(req.to_pep508_string()));
+    }
+    let mut extras: Vec<&String> = cfg.optional_reqs.keys().collect();
+    extras.sort();
+    for extra in extras.into_iter() {
+        add("Provides-Extra", &Some(extra.clone()));
+        for req in cfg.optional_reqs[extra].iter() {
+            // eg `Requires-Dist: pytest>=5 ; extra == "test"`
+            let in_extra = Marker::Expr(
+                MarkerValue::Var("extra".into()),
+                MarkerOp::Version(ReqType::Exact),
+                MarkerValue::Literal(extra.clone()),
+            );
+            let mut req = req.clone();
+            req.marker = Some(match req.marker {
+                Some(m) => Marker::And(Box::new(m), Box::new(in_extra)),
+                None => in_extra,
+            });
+            add("Requires-Dist", &Some(req.to_pep508_string()));
+        }
+    }
+
+    let readme = find_readme(cfg, project_dir);
+    if let Some((_, content_type)) = &readme {
+        add(
+            "Description-Content-Type",
+            &Some(format!("{}; charset=UTF-8", content_type)),
+        );
+    }
+    // The long description goes in the body, after a blank line.
+    if let Some((filename, _)) = &readme {
+        let text = fs::read_to_string(project_dir.join(filename))
+            .map_err(|_| DependencyError::new(&format!("Problem reading {}", filename)))?;
+        result.push('\n');
+        result.push_str(&text);
+    }
+    Ok(result)
+}
+
+/// The `entry_points.txt` file, if there are any entry points.
+fn entry_points(cfg: &Config) -> Option<String> {
+    let mut groups: Vec<(String, Vec<String>)> = cfg
+        .entry_points
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    if !cfg.console_scripts.is_empty() {
+        match groups.iter_mut().find(|(g, _)| g == "console_scripts") {
+            Some((_, eps)) => eps.extend(cfg.console_scripts.clone()),
+            None => groups.push(("console_scripts".into(), cfg.console_scripts.clone())),
+        }
+    }
+    if groups.is_empty() {
+        return None;
+    }
+    groups.sort();
+
+    let mut result = String::new();
+    for (group, eps) in groups.iter() {
+        result.push_str(&format!("[{}]\n", group));
+        for ep in eps.iter() {
+            result.push_str(&format!("{}\n", ep.trim()));
+        }
+        result.push('\n');
+    }
+    Some(result)
+}
 
-    fs::write(filename, data).expect("Problem writing dummy setup.py");
-    if util::wait_for_dirs(&[env::current_dir()
-        .expect("Problem finding current dir")
-        .join(filename)])
-    .is_err()
-    {
-        util::abort("Problem waiting for setup.py to be created.")
+/// Add all files in a folder to `files`, by path relative to `base`. Skips caches and
+/// compiled files.
+fn add_dir(dir: &PathBuf, base: &PathBuf, files: &mut Vec<(String, PathBuf)>) {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(e) => e.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(_) => return,
     };
+    entries.sort();
+    for path in entries {
+        let filename = path
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if filename == "__pycache__" || filename.starts_with('.') || filename.ends_with(".pyc") {
+            continue;
+        }
+        if path.is_dir() {
+            add_dir(&path, base, files);
+        } else if let Ok(rel) = path.strip_prefix(base) {
+            let rel: Vec<String> = rel
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect();
+            files.push((rel.join("/"), path.clone()));
+        }
+    }
 }
 
-pub(crate) fn build(
-    bin_path: &PathBuf,
-    lib_path: &PathBuf,
-    cfg: &crate::Config,
-    _extras: Vec<String>,
-) {
-    // todo: Check if they exist; only install if they don't.
-    let dummy_setup_fname = "setup_temp_pypackage.py";
-
-    Command::new(bin_path.join("python"))
-        .args(&[
-            "-m", "pip", "install", //            "--upgrade",
-            "twine", "wheel",
-        ])
-        .status()
-        .expect("Problem installing Twine");
-
-    create_dummy_setup(cfg, dummy_setup_fname);
-
-    util::set_pythonpath(lib_path);
-    println!("🛠️️ Building the package...");
-    Command::new(bin_path.join("python"))
-        .args(&[dummy_setup_fname, "sdist", "bdist_wheel"])
-        .status()
-        .expect("Problem building");
+/// Find the files of the package itself: A folder or module named after the project, either at
+/// the top level, or in `src`. Returns paths relative to the project folder, and the prefix to
+/// remove from them for the wheel, ie `src/` or nothing.
+fn find_package_files(
+    name: &str,
+    project_dir: &PathBuf,
+) -> Result<(Vec<(String, PathBuf)>, String), DependencyError> {
+    let import_name = dist_name(name);
+    for prefix in &["", "src/"] {
+        let base = project_dir.join(prefix);
+        let mut files = vec![];
+        if base.join(&import_name).is_dir() {
+            add_dir(&base.join(&import_name), project_dir, &mut files);
+        } else if base.join(format!("{}.py", import_name)).is_file() {
+            files.push((
+                format!("{}{}.py", prefix, import_name),
+                base.join(format!("{}.py", import_name)),
+            ));
+        }
+        if !files.is_empty() {
+            return Ok((files, prefix.to_string()));
+        }
+    }
+    Err(DependencyError::new(&format!(
+        "Can't find the code to package. It should be in a folder named `{0}`, or a file \
+         named `{0}.py`, either in the project folder, or in `src`",
+        import_name
+    )))
+}
 
-    util::print_color("Build complete.", Color::Green);
+fn read_files(files: &[(String, PathBuf)]) -> Result<Vec<(String, Vec<u8>)>, DependencyError> {
+    files
+        .iter()
+        .map(|(rel, path)| {
+            fs::read(path)
+                .map(|data| (rel.clone(), data))
+                .map_err(|_| DependencyError::new(&format!("Problem reading {:?}", path)))
+        })
+        .collect()
+}
 
-    if fs::remove_file(dummy_setup_fname).is_err() {
-        println!("Problem removing temporary setup file while building ")
+/// Build a wheel in `out_dir`, and return its path.
+pub fn build_wheel(
+    cfg: &Config,
+    project_dir: &PathBuf,
+    out_dir: &PathBuf,
+) -> Result<PathBuf, DependencyError> {
+    let metadata = metadata(cfg, project_dir)?;
+    // `metadata` checks these exist.
+    let name = dist_name(cfg.name.as_ref().unwrap());
+    let version = cfg.version.as_ref().unwrap().to_string2();
+
+    let (package_files, prefix) = find_package_files(&name, project_dir)?;
+    let mut files: Vec<(String, Vec<u8>)> = read_files(&package_files)?
+        .into_iter()
+        .map(|(rel, data)| {
+            let rel = rel.strip_prefix(prefix.as_str()).unwrap_or(&rel).to_owned();
+            (rel, data)
+        })
+        .collect();
+
+    let dist_info = format!("{}-{}.dist-info", name, version);
+    for license in find_license_files(project_dir) {
+        let data = fs::read(project_dir.join(&license))
+            .map_err(|_| DependencyError::new(&format!("Problem reading {}", license)))?;
+        files.push((format!("{}/{}", dist_info, license), data));
+    }
+    files.push((format!("{}/METADATA", dist_info), metadata.into_bytes()));
+    files.push((
+        format!("{}/WHEEL", dist_info),
+        format!(
+            "Wheel-Version: 1.0\nGenerator: pypackage {}\nRoot-Is-Purelib: true\nTag: py3-none-any\n",
+            env!("CARGO_PKG_VERSION")
+        )
+        .into_bytes(),
+    ));
+    if let Some(eps) = entry_points(cfg) {
+        files.push((format!("{}/entry_points.txt", dist_info), eps.into_bytes()));
+    }
+
+    let mut record: Vec<RecordEntry> = files
+        .iter()
+        .map(|(path, data)| RecordEntry::new(path, data))
+        .collect();
+    let record_path = format!("{}/RECORD", dist_info);
+    record.push(RecordEntry {
+        path: record_path.clone(),
+        hash: String::new(),
+        size: None,
+    });
+    files.push((record_path, install::serialize_record(&record).into_bytes()));
+
+    fs::create_dir_all(out_dir)
+        .map_err(|_| DependencyError::new("Problem creating the output folder"))?;
+    let path = out_dir.join(format!("{}-{}-py3-none-any.whl", name, version));
+    write_zip(&path, &files)
+        .map_err(|e| DependencyError::new(&format!("Problem writing the wheel: {}", e)))?;
+    Ok(path)
+}
+
+fn write_zip(path: &PathBuf, files: &[(String, Vec<u8>)]) -> zip::result::ZipResult<()> {
+    let mut zip = zip::ZipWriter::new(fs::File::create(path)?);
+    let options = zip::write::FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .last_modified_time(zip::DateTime::from_date_and_time(1980, 1, 1, 0, 0, 0).unwrap())
+        .unix_permissions(0o644);
+    for (name, data) in files.iter() {
+        zip.start_file(name.as_str(), options)?;
+        zip.write_all(data)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Build a source distribution in `out_dir`, and return its path. It includes
+/// `pyproject.toml`, with a `[build-system]` table that uses our backend if it doesn't have one.
+pub fn build_sdist(
+    cfg: &Config,
+    project_dir: &PathBuf,
+    out_dir: &PathBuf,
+) -> Result<PathBuf, DependencyError> {
+    let metadata = metadata(cfg, project_dir)?;
+    let name = dist_name(cfg.name.as_ref().unwrap());
+    let version = cfg.version.as_ref().unwrap().to_string2();
+
+    let (mut paths, _) = find_package_files(&name, project_dir)?;
+    if let Some((readme, _)) = find_readme(cfg, project_dir) {
+        paths.push((readme.clone(), project_dir.join(readme)));
+    }
+    for license in find_license_files(project_dir) {
+        paths.push((license.clone(), project_dir.join(license)));
+    }
+    let mut files = read_files(&paths)?;
+
+    let mut pyproject = fs::read_to_string(project_dir.join("pyproject.toml"))
+        .map_err(|_| DependencyError::new("Problem reading pyproject.toml"))?;
+    let has_build_system = match pyproject.parse::<toml::Value>() {
+        Ok(v) => v.get("build-system").is_some(),
+        Err(_) => false,
     };
+    if !has_build_system {
+        pyproject.push('\n');
+        pyproject.push_str(BUILD_SYSTEM);
+        files.push((BACKEND_FILENAME.into(), BACKEND_MODULE.into()));
+    } else if project_dir.join(BACKEND_FILENAME).is_file() {
+        files.push((BACKEND_FILENAME.into(), BACKEND_MODULE.into()));
+    }
+    files.push(("pyproject.toml".into(), pyproject.into_bytes()));
+    files.push(("PKG-INFO".into(), metadata.into_bytes()));
+    files.sort();
+
+    fs::create_dir_all(out_dir)
+        .map_err(|_| DependencyError::new("Problem creating the output folder"))?;
+    let stem = format!("{}-{}", name, version);
+    let path = out_dir.join(format!("{}.tar.gz", stem));
+    write_tar_gz(&path, &stem, &files)
+        .map_err(|e| DependencyError::new(&format!("Problem writing the sdist: {}", e)))?;
+    Ok(path)
+}
+
+fn write_tar_gz(path: &PathBuf, top_dir: &str, files: &[(String, Vec<u8>)]) -> io::Result<()> {
+    let gz = GzBuilder::new()
+        .mtime(BUILD_TIMESTAMP as u32)
+        .write(fs::File::create(path)?, Compression::default());
+    let mut tar = tar::Builder::new(gz);
+    for (name, data) in files.iter() {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(BUILD_TIMESTAMP);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_cksum();
+        tar.append_data(
+            &mut header,
+            format!("{}/{}", top_dir, name),
+            data.as_slice(),
+        )?;
+    }
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Build the source distribution and wheel into the `dist` folder.
+pub(crate) fn build(cfg: &crate::Config) {
+    let project_dir = env::current_dir().expect("Problem finding current dir");
+    let dist_dir = project_dir.join("dist");
+
+    println!("🛠️️ Building the package...");
+    for result in &[
+        build_sdist(cfg, &project_dir, &dist_dir),
+        build_wheel(cfg, &project_dir, &dist_dir),
+    ] {
+        match result {
+            Ok(path) => println!(
+                "Built {:?}",
+                path.strip_prefix(&project_dir).unwrap_or(path)
+            ),
+            Err(e) => util::abort(&e.details),
+        }
+    }
+    util::print_color("Build complete.", Color::Green);
 }
 
-pub(crate) fn publish(bin_path: &PathBuf, cfg: &crate::Config) {
-    let repo_url = cfg
-        .package_url
-        .clone()
-        .unwrap_or_else(|| "https://test.pypi.org/legacy".to_string());
-
-    println!("Uploading to {}", repo_url);
-    Command::new(bin_path.join("twine"))
-        .args(&[
-            "upload",
-            // todo - test repo / setting repos not working.
-            //            &format!("--repository-url {}/", repo_url),
-            "dist/*",
-        ])
-        .status()
-        .expect("Problem publishing");
+/// Build for a PEP 517 frontend, via `BACKEND_MODULE`: Print only the filename built.
+pub(crate) fn build_backend(cfg: &crate::Config, kind: &str, out_dir: &str) {
+    let project_dir = env::current_dir().expect("Problem finding current dir");
+    let out_dir = PathBuf::from(out_dir);
+    let result = match kind {
+        "wheel" => build_wheel(cfg, &project_dir, &out_dir),
+        "sdist" => build_sdist(cfg, &project_dir, &out_dir),
+        _ => Err(DependencyError::new(
+            "The build backend can build a `wheel` or `sdist`",
+        )),
+    };
+    match result {
+        Ok(path) => println!("{}", path.file_name().unwrap_or_default().to_string_lossy()),
+        Err(e) => util::abort(&e.details),
+    }
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::dep_types::{Constraint, Req, ReqType, Version};
+    use std::io::Read;
+
+    fn test_project(name: &str) -> (PathBuf, Config) {
+        let dir = env::temp_dir().join(name);
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(dir.join("src/my_pkg/sub")).unwrap();
+        fs::create_dir_all(dir.join("src/my_pkg/__pycache__")).unwrap();
+        fs::write(dir.join("src/my_pkg/__init__.py"), "x = 1\n").unwrap();
+        fs::write(dir.join("src/my_pkg/sub/mod.py"), "y = 2\n").unwrap();
+        fs::write(dir.join("src/my_pkg/__pycache__/x.pyc"), "").unwrap();
+        fs::write(dir.join("README.md"), "# My package\n").unwrap();
+        fs::write(dir.join("LICENSE"), "MIT\n").unwrap();
+        fs::write(
+            dir.join("pyproject.toml"),
+            "[tool.pypackage]\nname = \"My-Pkg\"\n",
+        )
+        .unwrap();
+
+        let cfg = Config {
+            name: Some("My-Pkg".into()),
+            version: Some(Version::new(0, 1, 0)),
+            description: Some("A test".into()),
+            py_version: vec![Constraint::new(ReqType::Caret, Version::new_short(3, 7))],
+            reqs: vec![Req::new(
+                "numpy".into(),
+                vec![Constraint::new(ReqType::Caret, Version::new(1, 16, 4))],
+            )],
+            console_scripts: vec!["my-tool = my_pkg:main".into()],
+            ..Default::default()
+        };
+        (dir, cfg)
+    }
+
+    #[test]
+    fn core_metadata() {
+        let (dir, cfg) = test_project("pypackage_build_metadata_test");
+        let md = metadata(&cfg, &dir).unwrap();
+        assert!(md.starts_with("Metadata-Version: 2.1\nName: My-Pkg\nVersion: 0.1.0\n"));
+        assert!(md.contains("Summary: A test\n"));
+        assert!(md.contains("Requires-Python: >=3.7,<4.0.0\n"));
+        assert!(md.contains("Requires-Dist: numpy>=1.16.4,<2.0.0\n"));
+        assert!(md.contains("Description-Content-Type: text/markdown; charset=UTF-8\n"));
+        assert!(md.ends_with("\n\n# My package\n"));
+
+        let parsed = crate::metadata::Metadata::parse(&md);
+        assert_eq!(parsed.name, "My-Pkg");
+        assert_eq!(parsed.requires_dist.len(), 1);
+
+        let mut with_extras = cfg.clone();
+        with_extras.optional_reqs.insert(
+            "test".into(),
+            vec![
+                Req::from_str("pytest>=5.0", true).unwrap(),
+                Req::from_str("pywin32; sys_platform == \"win32\"", true).unwrap(),
+            ],
+        );
+        let md = metadata(&with_extras, &dir).unwrap();
+        assert!(md.contains(
+            "Requires-Dist: numpy>=1.16.4,<2.0.0\nProvides-Extra: test\n\
+             Requires-Dist: pytest>=5.0 ; extra == \"test\"\n\
+             Requires-Dist: pywin32 ; sys_platform == \"win32\" and extra == \"test\"\n"
+        ));
+        let parsed = crate::metadata::Metadata::parse(&md);
+        assert_eq!(parsed.provides_extra, vec!["test"]);
+        assert_eq!(parsed.requires_dist.len(), 3);
+
+        assert!(metadata(&Config::default(), &dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
     #[test]
-    fn py_list() {
-        let expected = r#"[
-    "Programming Language :: Python :: 3",
-    "License :: OSI Approved :: MIT License",
-    "Operating System :: OS Independent",
-]"#;
-
-        let actual = serialize_py_list(&vec![
-            "Programming Language :: Python :: 3".into(),
-            "License :: OSI Approved :: MIT License".into(),
-            "Operating System :: OS Independent".into(),
-        ]);
-
-        assert_eq!(expected, actual);
-    }
-
-    // todo: Re-impl if you end up using this
-    //    #[test]
-    //    fn py_dict() {
-    //        let expected = r#"{
-    //    "PDF": [
-    //        "ReportLab>=1.2",
-    //        "RXP"
-    //    ],
-    //    "reST": [
-    //        "docutils>=0.3"
-    //    ],
-    //    }"#;
-    //
-    //        let mut data = HashMap::new();
-    //        data.insert("PDF".into(), vec!["ReportLab>=1.2".into(), "RXP".into()]);
-    //        data.insert("reST".into(), vec!["docutils>=0.3".into()]);
-    //
-    //        assert_eq!(expected, serialize_py_dict(&data));
-    //    }
+    fn wheel_and_sdist() {
+        let (dir, cfg) = test_project("pypackage_build_test");
+        let out = dir.join("dist");
+
+        let wheel = build_wheel(&cfg, &dir, &out).unwrap();
+        assert_eq!(wheel, out.join("my_pkg-0.1.0-py3-none-any.whl"));
+        let first = fs::read(&wheel).unwrap();
+        // Builds are reproducible.
+        build_wheel(&cfg, &dir, &out).unwrap();
+        assert_eq!(fs::read(&wheel).unwrap(), first);
+
+        let mut archive = zip::ZipArchive::new(fs::File::open(&wheel).unwrap()).unwrap();
+        let mut names = vec![];
+        for i in 0..archive.len() {
+            names.push(archive.by_index(i).unwrap().name().to_owned());
+        }
+        assert_eq!(
+            names,
+            vec![
+                "my_pkg/__init__.py",
+                "my_pkg/sub/mod.py",
+                "my_pkg-0.1.0.dist-info/LICENSE",
+                "my_pkg-0.1.0.dist-info/METADATA",
+                "my_pkg-0.1.0.dist-info/WHEEL",
+                "my_pkg-0.1.0.dist-info/entry_points.txt",
+                "my_pkg-0.1.0.dist-info/RECORD",
+            ]
+        );
+        let mut eps = String::new();
+        archive
+            .by_name("my_pkg-0.1.0.dist-info/entry_points.txt")
+            .unwrap()
+            .read_to_string(&mut eps)
+            .unwrap();
+        assert_eq!(eps, "[console_scripts]\nmy-tool = my_pkg:main\n\n");
+
+        // Our own installer verifies the RECORD.
+        let lib = dir.join("install/lib");
+        fs::create_dir_all(&lib).unwrap();
+        assert!(install::install_wheel(
+            &wheel,
+            "my_pkg",
+            &Version::new(0, 1, 0),
+            &lib,
+            &dir.join("bin"),
+            &None
+        )
+        .is_ok());
+        assert!(lib.join("my_pkg/sub/mod.py").exists());
+
+        let sdist = build_sdist(&cfg, &dir, &out).unwrap();
+        assert_eq!(sdist, out.join("my_pkg-0.1.0.tar.gz"));
+        let first = fs::read(&sdist).unwrap();
+        build_sdist(&cfg, &dir, &out).unwrap();
+        assert_eq!(fs::read(&sdist).unwrap(), first);
+
+        let gz = flate2::read::GzDecoder::new(fs::File::open(&sdist).unwrap());
+        let mut archive = tar::Archive::new(gz);
+        let mut names = vec![];
+        let mut pyproject = String::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            if path.ends_with("pyproject.toml") {
+                entry.read_to_string(&mut pyproject).unwrap();
+            }
+            names.push(path);
+        }
+        assert_eq!(
+            names,
+            vec![
+                "my_pkg-0.1.0/LICENSE",
+                "my_pkg-0.1.0/PKG-INFO",
+                "my_pkg-0.1.0/README.md",
+                "my_pkg-0.1.0/pypackage_backend.py",
+                "my_pkg-0.1.0/pyproject.toml",
+                "my_pkg-0.1.0/src/my_pkg/__init__.py",
+                "my_pkg-0.1.0/src/my_pkg/sub/mod.py",
+            ]
+        );
+        assert!(pyproject.contains("build-backend = \"pypackage_backend\""));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,187 @@
+use crate::dep_types::{DependencyError, Version};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// Set once at startup, from the `--offline` flag.
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, Ordering::Relaxed);
+}
+
+/// If true, we don't make network requests, and rely entirely on the cache and lock file.
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+
+/// The persistent, per-user cache: `~/.cache/pypackage` on Linux (or under `XDG_CACHE_HOME`),
+/// `~/Library/Caches/pypackage` on Mac, and `%LOCALAPPDATA%\pypackage` on Windows.
+/// Override by setting `PYPACKAGE_CACHE_DIR`.
+pub fn cache_dir() -> PathBuf {
+    if let Ok(dir) = env::var("PYPACKAGE_CACHE_DIR") {
+        return PathBuf::from(dir);
+    }
+
+    #[cfg(target_os = "windows")]
+    let base = env::var("LOCALAPPDATA").map(PathBuf::from);
+    #[cfg(target_os = "macos")]
+    let base = env::var("HOME").map(|h| PathBuf::from(h).join("Library/Caches"));
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let base = env::var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|h| PathBuf::from(h).join(".cache")));
+
+    base.unwrap_or_else(|_| env::temp_dir()).join("pypackage")
+}
+
+/// Where a downloaded wheel or sdist is stored, by its filename.
+pub fn artifact_path(filename: &str) -> PathBuf {
+    artifact_path_in(&cache_dir(), filename)
+}
+
+// The functions below ending in `_in` take the cache directory, so tests can use their own.
+
+fn artifact_path_in(dir: &Path, filename: &str) -> PathBuf {
+    dir.join("artifacts").join(filename)
+}
+
+/// Where a page of index data, eg a package's Warehouse JSON, is stored. We key by url, since
+/// different indexes may have different data for the same package.
+fn index_page_path(dir: &Path, url: &str) -> PathBuf {
+    let key: String = url
+        .splitn(2, "://")
+        .last()
+        .unwrap_or(url)
+        .trim_end_matches('/')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    dir.join("index").join(key)
+}
+
+/// Read a cached page of index data, if we have it.
+pub fn read_index_page(url: &str) -> Option<String> {
+    read_index_page_in(&cache_dir(), url)
+}
+
+fn read_index_page_in(dir: &Path, url: &str) -> Option<String> {
+    fs::read_to_string(index_page_path(dir, url)).ok()
+}
+
+/// Cache a page of index data. Failing to write the cache isn't fatal.
+pub fn write_index_page(url: &str, data: &str) {
+    write_index_page_in(&cache_dir(), url, data);
+}
+
+fn write_index_page_in(dir: &Path, url: &str, data: &str) {
+    write(&index_page_path(dir, url), data);
+}
+
+/// Where a package version's core metadata, ie the contents of its `METADATA` file, is stored.
+fn metadata_path(dir: &Path, name: &str, version: &Version) -> PathBuf {
+    dir.join("metadata").join(format!(
+        "{}-{}.METADATA",
+        crate::index::normalize_name(name),
+        version.to_string2()
+    ))
+}
+
+/// Read a package version's cached metadata, if we have it.
+pub fn read_metadata(name: &str, version: &Version) -> Option<String> {
+    read_metadata_in(&cache_dir(), name, version)
+}
+
+fn read_metadata_in(dir: &Path, name: &str, version: &Version) -> Option<String> {
+    fs::read_to_string(metadata_path(dir, name, version)).ok()
+}
+
+/// Cache a package version's metadata. Failing to write the cache isn't fatal.
+pub fn write_metadata(name: &str, version: &Version, data: &str) {
+    write_metadata_in(&cache_dir(), name, version, data);
+}
+
+fn write_metadata_in(dir: &Path, name: &str, version: &Version, data: &str) {
+    write(&metadata_path(dir, name, version), data);
+}
+
+fn write(path: &PathBuf, data: &str) {
+    if let Some(parent) = path.parent() {
+        if fs::create_dir_all(parent).is_ok() {
+            fs::write(path, data).ok();
+        }
+    }
+}
+
+/// Store a downloaded artifact in the cache, from where we install it.
+pub fn store_artifact(filename: &str, data: &[u8]) -> Result<PathBuf, DependencyError> {
+    store_artifact_in(&cache_dir(), filename, data)
+}
+
+fn store_artifact_in(dir: &Path, filename: &str, data: &[u8]) -> Result<PathBuf, DependencyError> {
+    let path = artifact_path_in(dir, filename);
+    let dir = path.parent().expect("Artifact path has no parent");
+    if fs::create_dir_all(dir).is_err() || fs::write(&path, data).is_err() {
+        return Err(DependencyError::new(&format!(
+            "Problem saving {} to the cache at {:?}",
+            filename, dir
+        )));
+    }
+    Ok(path)
+}
+
+/// The error to return when we need the network, but are in offline mode.
+pub fn offline_error(what: &str) -> DependencyError {
+    DependencyError::new(&format!(
+        "{} isn't in the cache, and we can't download it in offline mode",
+        what
+    ))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn index_page_round_trip() {
+        let dir = env::temp_dir().join("pypackage_cache_test");
+        fs::remove_dir_all(&dir).ok();
+
+        let url = "https://pypi.org/pypi/some-package/json";
+        assert_eq!(read_index_page_in(&dir, url), None);
+        write_index_page_in(&dir, url, "{}");
+        assert_eq!(read_index_page_in(&dir, url), Some("{}".to_owned()));
+        assert_eq!(
+            read_index_page_in(&dir, "https://example.com/some-package/json"),
+            None
+        );
+        assert_eq!(
+            index_page_path(&dir, url),
+            dir.join("index/pypi.org_pypi_some-package_json")
+        );
+
+        let version = Version::new(1, 0, 0);
+        assert_eq!(read_metadata_in(&dir, "Some_Package", &version), None);
+        write_metadata_in(&dir, "Some_Package", &version, "Name: some-package");
+        assert_eq!(
+            read_metadata_in(&dir, "some.package", &version),
+            Some("Name: some-package".to_owned())
+        );
+
+        let path = store_artifact_in(&dir, "some_package-1.0-py3-none-any.whl", b"data").unwrap();
+        assert_eq!(
+            path,
+            dir.join("artifacts/some_package-1.0-py3-none-any.whl")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,23 +2,19 @@ use crate::{
     dep_types::{
         self, Constraint, Dependency, DependencyError, Package, Rename, Req, ReqType, Version,
     },
+    index::PackageIndex,
+    markers::{Environment, Marker},
+    metadata,
+    pubgrub::{self, DependencyProvider, ResolveError},
     util,
 };
 
 use crossterm::Color;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use std::cmp::min;
 use std::collections::HashMap;
 use std::str::FromStr;
 
-#[derive(Debug, Deserialize)]
-struct WarehouseInfo {
-    name: String, // Pulling this ensure proper capitalization
-    requires_dist: Option<Vec<String>>,
-    requires_python: Option<String>,
-    version: String,
-}
-
 #[derive(Clone, Debug, Deserialize)]
 pub struct WarehouseDigests {
     pub md5: String,
@@ -36,14 +32,33 @@ pub struct WarehouseRelease {
     pub requires_python: Option<String>,
     pub url: String,
     pub dependencies: Option<Vec<String>>,
+    // https://www.python.org/dev/peps/pep-0592/
+    #[serde(default)]
+    pub yanked: bool,
+    #[serde(default)]
+    pub yanked_reason: Option<String>,
+    /// If true, the index serves this file's metadata at its url, plus `.metadata`.
+    /// https://www.python.org/dev/peps/pep-0658/
+    #[serde(default)]
+    pub core_metadata: bool,
+    /// The metadata file's sha256 hex digest, if the index gives it.
+    #[serde(default)]
+    pub core_metadata_sha256: Option<String>,
 }
 
-/// Only deserialize the info we need to resolve dependencies etc.
-#[derive(Debug, Deserialize)]
-struct WarehouseData {
-    info: WarehouseInfo,
-    releases: HashMap<String, Vec<WarehouseRelease>>,
-    urls: Vec<WarehouseRelease>,
+/// Markers on dependencies, by the standardized names of the package and its dependency.
+/// `None` means the dependency applies in every environment.
+pub type EdgeMarkers = HashMap<(String, String), Option<Marker>>;
+
+/// What the lock file tells us about a previous resolution.
+pub struct Locked<'a> {
+    pub packages: &'a [crate::Package],
+    /// `None` for lock files from before we recorded markers. We then fetch dependencies
+    /// again, to find ones for other platforms, but keep the locked versions.
+    pub markers: Option<&'a EdgeMarkers>,
+    /// Packages to upgrade. If there are any, we keep the others at their locked versions
+    /// where we can, but let them change if the upgraded ones need them to.
+    pub upgrade: &'a [String],
 }
 
 /// Format a name based on how it's listed on PyPi. Ie capitalize or convert - to _'
@@ -55,72 +70,151 @@ fn format_name(name: &str, cache: &HashMap<String, (String, Version, Vec<Version
     }
 }
 
-/// Fetch data about a package from the Pypi Warehouse.
-/// https://warehouse.pypa.io/api-reference/json/
-fn get_warehouse_data(name: &str) -> Result<WarehouseData, reqwest::Error> {
-    let url = format!("https://pypi.org/pypi/{}/json", name);
-    let resp = reqwest::get(&url)?.json()?;
-    Ok(resp)
-}
-
-/// Find the latest version of a package by querying the warehouse.  Also return
+/// Find the latest version of a package by querying the index.  Also return
 /// a vec of the versions found, so we can reuse this later without fetching a second time.
 /// Return name to, so we get correct capitalization.
-pub fn get_version_info(name: &str) -> Result<(String, Version, Vec<Version>), DependencyError> {
+pub fn get_version_info(
+    index: &dyn PackageIndex,
+    name: &str,
+) -> Result<(String, Version, Vec<Version>), DependencyError> {
     //    println!("(dbg) Getting version info for {}", name);
-    let data = get_warehouse_data(name)?;
+    let project = index.project(name)?;
 
-    let all_versions = data
+    let all_versions: Vec<Version> = project.releases.iter().map(|(v, _)| v.clone()).collect();
+    let available: Vec<&Version> = project
         .releases
-        .keys()
-        .filter(|v| Version::from_str(v).is_ok())
-        // todo: way to do this in one step, like filter_map?
-        .map(|v| Version::from_str(v).expect("Trouble parsing version while getting version info"))
+        .iter()
+        // Skip versions that are yanked, or have no files.
+        .filter(|(_, files)| files.iter().any(|f| !f.yanked))
+        .map(|(v, _)| v)
         .collect();
 
-    match Version::from_str(&data.info.version) {
-        Ok(v) => Ok((data.info.name, v, all_versions)),
-        // Unable to parse the version listed in info; iterate through releases.
-        Err(_) => Ok((
-            data.info.name,
-            *all_versions
-                .iter()
-                .max()
-                .unwrap_or_else(|| panic!("Can't find a valid version for {}", name)),
-            all_versions,
-        )),
-    }
+    let latest = match project.latest {
+        Some(v) => v,
+        // The index doesn't tell us the latest; use the newest non-prerelease if there is one.
+        None => match available
+            .iter()
+            .filter(|v| !v.is_prerelease())
+            .max()
+            .or_else(|| available.iter().max())
+        {
+            Some(v) => (*v).clone(),
+            None => {
+                return Err(DependencyError::new(&format!(
+                    "Can't find a valid version for {}",
+                    name
+                )))
+            }
+        },
+    };
+    Ok((project.name, latest, all_versions))
 }
 
-/// Get release data from the warehouse, ie the file url, name, and hash.
+/// Get release data from the index, ie the file url, name, and hash.
 pub fn get_warehouse_release(
+    index: &dyn PackageIndex,
     name: &str,
     version: &Version,
-) -> Result<Vec<WarehouseRelease>, reqwest::Error> {
-    let data = get_warehouse_data(name)?;
+) -> Result<Vec<WarehouseRelease>, DependencyError> {
+    let project = index.project(name)?;
+
+    match project.releases.into_iter().find(|(v, _)| v == version) {
+        Some((_, files)) => Ok(files),
+        None => Err(DependencyError::new(&format!(
+            "Unable to find a release for {} = \"{}\"",
+            name,
+            version.to_string2()
+        ))),
+    }
+}
 
-    // If there are 0s in the version, and unable to find one, try 1 and 2 digit versions on Pypi.
-    let mut release_data = data.releases.get(&version.to_string2());
-    if release_data.is_none() && version.patch == 0 {
-        release_data = data.releases.get(&version.to_string_med());
-        if release_data.is_none() && version.minor == 0 {
-            release_data = data.releases.get(&version.to_string_short());
+/// Whether `requires_python` constraints allow a Python version. A version without a patch, eg
+/// the `3.7` we lock for, is allowed if any of its patch releases are, so `>=3.7.1` allows it.
+pub fn python_allowed(constrs: &[Constraint], py_vers: &Version) -> bool {
+    if py_vers.release.len() > 2 {
+        return constrs.iter().all(|c| c.is_compatible(py_vers));
+    }
+    let (major, minor) = (py_vers.major(), py_vers.minor());
+    // The allowed patches change only at versions the constraints name.
+    let mut patches = vec![Version::new(major, minor, 0)];
+    for constr in constrs {
+        if constr.version.major() == major && constr.version.minor() == minor {
+            let patch = constr.version.patch();
+            patches.push(Version::new(major, minor, patch));
+            patches.push(Version::new(major, minor, patch + 1));
         }
     }
+    patches
+        .iter()
+        .any(|v| constrs.iter().all(|c| c.is_compatible(v)))
+}
 
-    let release_data = release_data
-        .unwrap_or_else(|| panic!("Unable to find a release for {} = \"{}\"", name, version));
+/// Whether a file can be installed with a Python version: Its `requires_python` must allow it,
+/// and wheels must be built for the same major version of Python. We check wheels' full
+/// compatibility with the platform later, when choosing which file to install.
+fn python_compatible(release: &WarehouseRelease, py_vers: &Version) -> bool {
+    if let Some(requires) = &release.requires_python {
+        if let Ok(constrs) = Constraint::from_str_multiple(requires) {
+            if !python_allowed(&constrs, py_vers) {
+                return false;
+            }
+        }
+    }
+    if release.packagetype != "bdist_wheel" {
+        return true;
+    }
+
+    // eg `py3`, `cp37`, or `py2.py3`
+    release.python_version.split('.').any(|tag| {
+        match tag
+            .trim_start_matches(|c: char| c.is_ascii_alphabetic())
+            .chars()
+            .next()
+            .and_then(|c| c.to_digit(10))
+        {
+            Some(major) => major == py_vers.major(),
+            None => true,
+        }
+    })
+}
 
-    Ok(release_data.clone())
+/// Find the versions of a package we can install with every one of some Python versions, ie
+/// ones with a compatible file for each, and which of those are yanked. Return the name as the index formats it
+/// too.
+/// https://www.python.org/dev/peps/pep-0592/
+pub fn get_candidates(
+    index: &dyn PackageIndex,
+    name: &str,
+    py_versions: &[Version],
+) -> Result<(String, Vec<Version>, Vec<Version>), DependencyError> {
+    let project = index.project(name)?;
+
+    let mut versions = vec![];
+    let mut yanked = vec![];
+    for (version, files) in project.releases.iter() {
+        let compatible: Vec<&WarehouseRelease> = files
+            .iter()
+            .filter(|f| py_versions.iter().any(|v| python_compatible(f, v)))
+            .collect();
+        // We lock for every Python version, so each needs a file it can install.
+        let all_covered = py_versions
+            .iter()
+            .all(|v| compatible.iter().any(|f| python_compatible(f, v)));
+        if compatible.is_empty() || !all_covered {
+            continue;
+        }
+        if compatible.iter().all(|f| f.yanked) {
+            yanked.push(version.clone());
+        }
+        versions.push(version.clone());
+    }
+    Ok((project.name, versions, yanked))
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug)]
 struct ReqCache {
-    // Name is present from pydeps if gestruct packagetting deps for multiple package names. Otherwise, we ommit
-    // it since we already know the name when making the request.
     name: Option<String>,
     version: String,
-    requires_python: Option<String>,
     requires_dist: Vec<String>,
 }
 
@@ -136,35 +230,55 @@ impl ReqCache {
     }
 }
 
-#[derive(Debug, Serialize)]
-struct MultipleBody {
-    // name, (version, version). Having trouble implementing Serialize for Version.
-    packages: HashMap<String, Vec<String>>,
-}
-
-/// Fetch items from multiple packages; cuts down on API calls.
+/// Find the dependencies of package versions from their metadata.
 fn get_req_cache_multiple(
+    index: &dyn PackageIndex,
     packages: &HashMap<String, Vec<Version>>,
-) -> Result<Vec<ReqCache>, reqwest::Error> {
-    // input tuple is name, min version, max version.
-    //    println!("(dbg) Getting pydeps data for {:?}", packages);
-    // parse strings here.
-    let mut packages2 = HashMap::new();
+) -> Result<Vec<ReqCache>, DependencyError> {
+    let mut result = vec![];
     for (name, versions) in packages.iter() {
-        let versions = versions.iter().map(|v| v.to_string2()).collect();
-        packages2.insert(name.to_owned(), versions);
+        for version in versions.iter() {
+            let data = metadata::get_metadata(index, name, version)?;
+            result.push(ReqCache {
+                name: Some(name.to_owned()),
+                version: version.to_string2(),
+                requires_dist: data.requires_dist,
+            });
+        }
     }
+    Ok(result)
+}
 
-    let url = "https://pydeps.herokuapp.com/multiple/";
-    //        let url = "http://localhost:8000/multiple/";
+/// The requirements a package version lists in its metadata.
+pub fn get_requirements(
+    index: &dyn PackageIndex,
+    name: &str,
+    version: &Version,
+) -> Result<Vec<Req>, DependencyError> {
+    let mut query = HashMap::new();
+    query.insert(name.to_owned(), vec![version.clone()]);
+    Ok(get_req_cache_multiple(index, &query)?
+        .iter()
+        .flat_map(|r| r.reqs())
+        .collect())
+}
 
-    Ok(reqwest::Client::new()
-        .post(url)
-        .json(&MultipleBody {
-            packages: packages2,
-        })
-        .send()?
-        .json()?)
+/// Like `get_requirements`, but if we can't find them, warn and treat the package as having none.
+pub fn get_requirements_or_warn(
+    index: &dyn PackageIndex,
+    name: &str,
+    version: &Version,
+) -> Vec<Req> {
+    get_requirements(index, name, version).unwrap_or_else(|e| {
+        util::eprint_color(
+            &format!(
+                "Problem getting the requirements of {}: {}",
+                name, e.details
+            ),
+            Color::DarkYellow,
+        );
+        vec![]
+    })
 }
 
 //fn flatten(result: &mut Vec<Dependency>, tree: &Dependency) {
@@ -188,9 +302,9 @@ fn is_compat(constraints: &[Constraint], vers: &Version) -> bool {
     true
 }
 
-/// Pull data on pydeps for a req. Only pull what we need.
-/// todo: Group all reqs and pull with a single call to pydeps to improve speed?
+/// Pull dependency data for a req. Only pull what we need.
 fn fetch_req_data(
+    index: &dyn PackageIndex,
     reqs: &[Req],
     vers_cache: &mut HashMap<String, (String, Version, Vec<Version>)>,
 ) -> Result<Vec<ReqCache>, DependencyError> {
@@ -202,7 +316,7 @@ fn fetch_req_data(
         let (_, latest_version, all_versions) = match vers_cache.get(&req.name) {
             Some(c) => c.clone(),
             None => {
-                match get_version_info(&req.name) {
+                match get_version_info(index, &req.name) {
                     Ok(data) => {
                         vers_cache.insert(req.name.clone(), data.clone());
                         data
@@ -228,12 +342,12 @@ fn fetch_req_data(
             // For Ne, we have two ranges; the second one being ones higher than the version specified.
             // For other types, we only have one item in the compatible range.
             let i = match constr.type_ {
-                ReqType::Ne => 1,
+                ReqType::Ne | ReqType::NeWildcard => 1,
                 _ => 0,
             };
 
             // Ensure we don't query past the latest.
-            max_v_to_query = min(constr.compatible_range()[i].1, max_v_to_query);
+            max_v_to_query = min(constr.compatible_range()[i].1.clone(), max_v_to_query);
         }
 
         // To minimimize request time, only query the latest compatible version.
@@ -252,13 +366,14 @@ fn fetch_req_data(
     if query_data.is_empty() {
         return Ok(vec![]);
     }
-    Ok(get_req_cache_multiple(&query_data)?)
+    Ok(get_req_cache_multiple(index, &query_data)?)
     //    Ok(get_req_cache_single(&req.name, &max_v_to_query)?)
 }
 
 // Build a graph: Start by assuming we can pick the newest compatible dependency at each step.
 // If unable to resolve this way, subsequently run this with additional deconfliction reqs.
 fn guess_graph(
+    index: &dyn PackageIndex,
     parent_id: u32,
     reqs: &[Req],
     locked: &[crate::Package],
@@ -270,28 +385,14 @@ fn guess_graph(
     vers_cache: &mut HashMap<String, (String, Version, Vec<Version>)>,
     reqs_searched: &mut Vec<Req>,
 ) -> Result<(), DependencyError> {
+    let env = Environment::new(os, py_vers).with_extras(extras);
     let reqs: Vec<&Req> = reqs
         .iter()
         // If we've already satisfied this req, don't query it again. Otherwise we'll make extra
         // http calls, and could end up in infinite loops.
         .filter(|r| !reqs_searched.contains(*r))
-        .filter(|r| match &r.extra {
-            Some(ex) => extras.contains(&ex),
-            None => true,
-        })
-        .filter(|r| match r.sys_platform {
-            Some((rt, os_)) => match rt {
-                ReqType::Exact => os_ == os,
-                ReqType::Ne => os_ != os,
-                _ => {
-                    util::abort("Reqtypes for Os must be == or !=");
-                    unreachable!()
-                }
-            },
-            None => true,
-        })
-        .filter(|r| match &r.python_version {
-            Some(v) => v.is_compatible(py_vers),
+        .filter(|r| match &r.marker {
+            Some(m) => m.evaluate(&env),
             None => true,
         })
         .collect();
@@ -300,7 +401,7 @@ fn guess_graph(
     let mut locked_reqs: Vec<Req> = vec![];
 
     // Partition reqs into ones we have lock-file data for, and ones where we need to make
-    // http calls to the pypi warehouse (for versions) and pydeps (for deps).
+    // http calls to the package index (for versions and deps).
     for req in reqs.iter() {
         reqs_searched.push((*req).clone());
 
@@ -324,8 +425,8 @@ fn guess_graph(
     //            println!("NON_LOCKED: {:#?}", &non_locked_reqs);
     //            println!("LOCKED: {:#?}", &locked_reqs);
 
-    // Single http call here to pydeps for all this package's reqs, plus version calls for each req.
-    let mut query_data = match fetch_req_data(&non_locked_reqs, vers_cache) {
+    // Get the metadata for each of this package's reqs, plus version calls for each req.
+    let mut query_data = match fetch_req_data(index, &non_locked_reqs, vers_cache) {
         Ok(d) => d,
         Err(e) => {
             util::abort(&format!("Problem getting dependency data: {:?}", e));
@@ -345,15 +446,14 @@ fn guess_graph(
         let requires_dist = package
             .deps
             .iter()
-            .map(|(_, name, vers)| format!("{} (=={})", name, vers.to_string()))
+            .map(|(_, name, vers)| format!("{} (=={})", name, vers.to_string2()))
             .collect();
 
         // Note that we convert from normal data types to strings here, for the sake of consistency
         // with the http call results.
         query_data.push(ReqCache {
             name: Some(package.name.clone()),
-            version: package.version.to_string(),
-            requires_python: None,
+            version: package.version.to_string2(),
             requires_dist,
         });
     }
@@ -384,7 +484,11 @@ fn guess_graph(
             .collect();
 
         if deps.is_empty() {
-            util::abort(&format!("Can't find a compatible package for {:?}", &req));
+            util::abort(&format!(
+                "Can't find a version of {} that matches {}",
+                &req.name,
+                describe_constraints(&req.constraints)
+            ));
         }
 
         let newest_compat = deps
@@ -395,6 +499,7 @@ fn guess_graph(
         result.push(newest_compat.clone());
 
         if let Err(e) = guess_graph(
+            index,
             newest_compat.id,
             &newest_compat.reqs,
             locked,
@@ -445,9 +550,22 @@ fn find_constraints(
     result
 }
 
+/// Eg `>=1.2, <2.0`, or `any version` if unconstrained.
+fn describe_constraints(constraints: &[Constraint]) -> String {
+    if constraints.is_empty() {
+        return "any version".to_owned();
+    }
+    constraints
+        .iter()
+        .map(|c| c.to_string(false, true))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
 /// We've determined we need to add all the included packages, and renamed all but one.
 fn make_renamed_packs(
     vers_cache: &HashMap<String, (String, Version, Vec<Version>)>,
+    reqs: &[Req],
     deps: &[Dependency],
     all_deps: &[Dependency],
     name: &str,
@@ -463,7 +581,20 @@ fn make_renamed_packs(
         Color::DarkRed,
     );
 
-    println!("DEPS to install all of: {:#?}", &deps);
+    for dep in deps.iter() {
+        let parent = match all_deps.iter().find(|d| d.id == dep.parent) {
+            Some(p) => format!("{} {}", p.name, p.version.to_string2()),
+            None => "your project".to_owned(),
+        };
+        let constraints = find_constraints(reqs, all_deps, std::slice::from_ref(dep));
+        println!(
+            "  {} {}, for {}, which requires {}",
+            dep.name,
+            dep.version.to_string2(),
+            parent,
+            describe_constraints(&constraints)
+        );
+    }
 
     let mut result = vec![];
     // We were unable to resolve using the newest version; add and rename packages.
@@ -479,7 +610,7 @@ fn make_renamed_packs(
             id: dep.id,
             parent: dep.parent,
             name: dep.name.clone(),
-            version: dep.version,
+            version: dep.version.clone(),
             deps: vec![], // to be filled in after resolution
             rename,
         });
@@ -504,21 +635,406 @@ fn assign_subdeps(packages: &mut Vec<Package>, updated_ids: &HashMap<u32, u32>)
                 };
                 parent_id == package.id
             })
-            .map(|child| (child.id, child.name.clone(), child.version))
+            .map(|child| (child.id, child.name.clone(), child.version.clone()))
             .collect();
         package.deps.append(&mut children);
     }
 }
 
-/// Determine which dependencies we need to install, using the newest ones which meet
-/// all constraints. Gets data from a cached repo, and Pypi. Returns name, version, and name/version of its deps.
+/// Supplies the resolver with data from the package index. Unless `use_lock` is false, packages
+/// in the lock file are only offered at their locked version, with their locked dependencies;
+/// this avoids HTTP calls for packages we've already resolved. When upgrading some packages, the
+/// others are offered at any version, but we try their locked one first. We only offer versions
+/// with files compatible with our Python version, and only pick yanked ones if a requirement
+/// pins them.
+struct PypiProvider<'a> {
+    index: &'a dyn PackageIndex,
+    locked: &'a [crate::Package],
+    locked_markers: Option<&'a EdgeMarkers>,
+    use_lock: bool,
+    upgrade: &'a [String],
+    /// We include dependencies needed in any of these environments.
+    targets: &'a [Environment],
+    /// The Python versions of `targets`; versions we pick must work with all of them.
+    py_versions: Vec<Version>,
+    /// Names as the index formats them.
+    names: HashMap<String, String>,
+    /// Available versions, and which of them are yanked.
+    vers_cache: HashMap<String, (Vec<Version>, Vec<Version>)>,
+    /// Versions pinned with `==` by a requirement we've seen.
+    pinned: Vec<(String, Version)>,
+    /// Markers on the dependencies we've found.
+    markers: EdgeMarkers,
+}
+
+impl<'a> PypiProvider<'a> {
+    fn find_locked(&self, name: &str) -> Option<&crate::Package> {
+        if !self.use_lock || !self.upgrade.is_empty() {
+            return None;
+        }
+        self.locked
+            .iter()
+            .find(|p| util::compare_names(&p.name, name))
+    }
+
+    /// The version to try first when upgrading other packages: The locked one.
+    fn preferred(&self, name: &str) -> Option<&Version> {
+        if !self.use_lock
+            || self.upgrade.is_empty()
+            || self.upgrade.iter().any(|u| util::compare_names(u, name))
+        {
+            return None;
+        }
+        self.locked
+            .iter()
+            .find(|p| util::compare_names(&p.name, name))
+            .map(|p| &p.version)
+    }
+
+    fn add_pins(&mut self, reqs: &[Req]) {
+        for req in reqs.iter() {
+            for constr in req.constraints.iter() {
+                if constr.type_ == ReqType::Exact {
+                    self.pinned
+                        .push((util::standardize_name(&req.name), constr.version.clone()));
+                }
+            }
+        }
+    }
+
+    fn add_markers(&mut self, name: &str, reqs: &[Req]) {
+        for req in reqs.iter() {
+            let key = (
+                util::standardize_name(name),
+                util::standardize_name(&req.name),
+            );
+            let marker = req.marker.as_ref().and_then(Marker::without_extras);
+            let combined = match self.markers.remove(&key) {
+                Some(existing) => Marker::or(existing, marker),
+                None => marker,
+            };
+            self.markers.insert(key, combined);
+        }
+    }
+
+    /// We can pick a version if it's not yanked, or if it's pinned or locked.
+    fn can_use(&self, name: &str, version: &Version) -> bool {
+        let yanked = match self.vers_cache.get(name) {
+            Some((_, y)) => y,
+            None => return true,
+        };
+        !yanked.contains(version)
+            || self.find_locked(name).map(|p| &p.version) == Some(version)
+            || self.preferred(name) == Some(version)
+            || self
+                .pinned
+                .iter()
+                .any(|(n, v)| *n == util::standardize_name(name) && v == version)
+    }
+}
+
+impl<'a> DependencyProvider for PypiProvider<'a> {
+    fn versions(&mut self, name: &str) -> Result<Vec<Version>, DependencyError> {
+        if let Some(package) = self.find_locked(name) {
+            return Ok(vec![package.version.clone()]);
+        }
+
+        if let Some((versions, _)) = self.vers_cache.get(name) {
+            return Ok(versions.clone());
+        }
+        match get_candidates(self.index, name, &self.py_versions) {
+            Ok((fmtd_name, versions, yanked)) => {
+                self.names.insert(name.to_owned(), fmtd_name);
+                self.vers_cache
+                    .insert(name.to_owned(), (versions.clone(), yanked));
+                Ok(versions)
+            }
+            Err(e) => Err(DependencyError::new(&format!(
+                "Can't get version info for the dependency `{}`. \
+                 Is it spelled correctly? Is the internet connection ok? ({})",
+                name, e.details
+            ))),
+        }
+    }
+
+    fn dependencies(
+        &mut self,
+        name: &str,
+        extras: &[String],
+        version: &Version,
+    ) -> Result<Vec<Req>, DependencyError> {
+        if let (Some(package), Some(locked_markers)) = (self.find_locked(name), self.locked_markers)
+        {
+            if package.version == *version {
+                let reqs: Vec<Req> = package
+                    .deps
+                    .iter()
+                    .map(|(_, dep_name, vers)| Req {
+                        marker: locked_markers
+                            .get(&(
+                                util::standardize_name(name),
+                                util::standardize_name(dep_name),
+                            ))
+                            .cloned()
+                            .unwrap_or(None),
+                        ..Req::new(
+                            dep_name.clone(),
+                            vec![Constraint::new(ReqType::Exact, vers.clone())],
+                        )
+                    })
+                    .collect();
+                self.add_pins(&reqs);
+                self.add_markers(name, &reqs);
+                return Ok(reqs);
+            }
+        }
+
+        let mut query = HashMap::new();
+        query.insert(name.to_owned(), vec![version.clone()]);
+        let data = match get_req_cache_multiple(self.index, &query) {
+            Ok(d) => d,
+            Err(e) => {
+                return Err(DependencyError::new(&format!(
+                    "Problem getting dependency data for {}: {}",
+                    name, e.details
+                )))
+            }
+        };
+
+        let envs: Vec<Environment> = self
+            .targets
+            .iter()
+            .map(|env| env.with_extras(extras))
+            .collect();
+        let reqs: Vec<Req> = data
+            .iter()
+            .flat_map(|r| r.reqs())
+            .filter(|r| match &r.marker {
+                Some(m) => m.evaluate_any(&envs),
+                None => true,
+            })
+            .collect();
+        self.add_pins(&reqs);
+        self.add_markers(name, &reqs);
+        Ok(reqs)
+    }
+
+    fn choose_version(&mut self, name: &str, candidates: &[Version]) -> Option<Version> {
+        let usable: Vec<&Version> = candidates
+            .iter()
+            .filter(|v| self.can_use(name, v))
+            .collect();
+        if let Some(preferred) = self.preferred(name) {
+            if usable.contains(&preferred) {
+                return Some(preferred.clone());
+            }
+        }
+        usable
+            .iter()
+            .find(|v| !v.is_prerelease())
+            .or_else(|| usable.first())
+            .map(|v| (*v).clone())
+    }
+}
+
+/// The Python versions of the environments we resolve for, or the one we're using if there
+/// aren't any.
+fn environment_python_versions(targets: &[Environment], py_vers: &Version) -> Vec<Version> {
+    let mut result = vec![];
+    for env in targets.iter() {
+        if let Ok(v) = Version::from_str(&env.python_version) {
+            if !result.contains(&v) {
+                result.push(v);
+            }
+        }
+    }
+    if result.is_empty() {
+        result.push(py_vers.clone());
+    }
+    result
+}
+
+/// Determine which dependencies we need to install: A single version of each, found by
+/// backtracking over candidate versions until all constraints are met. We include dependencies
+/// needed in any of the `targets` environments, and return the markers on each dependency.
+/// If there's no solution and `allow_renames` is set, fall back to installing multiple
+/// versions of conflicting packages, renamed, for this `os` only.
 pub fn resolve(
+    index: &dyn PackageIndex,
+    reqs: &[Req],
+    locked: &Locked,
+    targets: &[Environment],
+    os: crate::Os,
+    py_vers: &Version,
+    allow_renames: bool,
+) -> Result<(Vec<crate::Package>, EdgeMarkers), DependencyError> {
+    let reqs: Vec<Req> = reqs
+        .iter()
+        .filter(|r| match &r.marker {
+            Some(m) => m.evaluate_any(targets),
+            None => true,
+        })
+        .cloned()
+        .collect();
+
+    let mut provider = PypiProvider {
+        index,
+        locked: locked.packages,
+        locked_markers: locked.markers,
+        use_lock: true,
+        upgrade: locked.upgrade,
+        targets,
+        py_versions: environment_python_versions(targets, py_vers),
+        names: HashMap::new(),
+        vers_cache: HashMap::new(),
+        pinned: vec![],
+        markers: HashMap::new(),
+    };
+    provider.add_pins(&reqs);
+
+    let mut result = pubgrub::resolve(&reqs, &mut provider);
+    if result.is_err() && !locked.packages.is_empty() {
+        // The lock may pin versions that no longer work with the requirements; start fresh.
+        provider.use_lock = false;
+        provider.markers = HashMap::new();
+        result = pubgrub::resolve(&reqs, &mut provider);
+    }
+
+    let resolved = match result {
+        Ok(r) => r,
+        Err(ResolveError::NoSolution(conflict)) if allow_renames => {
+            util::print_color(
+                &format!(
+                    "Unable to find a single version of each package that satisfies all \
+                     requirements; installing multiple versions of: {}",
+                    conflict.packages().join(", ")
+                ),
+                Color::DarkYellow,
+            );
+            let env = Environment::new(os, py_vers);
+            let reqs: Vec<Req> = reqs
+                .into_iter()
+                .filter(|r| r.marker.as_ref().map(|m| m.evaluate(&env)).unwrap_or(true))
+                .collect();
+            return Ok((
+                resolve_with_renames(index, &reqs, locked.packages, os, py_vers),
+                HashMap::new(),
+            ));
+        }
+        Err(e) => return Err(DependencyError::new(&e.to_string())),
+    };
+
+    // Ids are only used to link packages to their dependencies in the lock file.
+    let packages: Vec<crate::Package> = resolved
+        .iter()
+        .enumerate()
+        .map(|(i, r)| Package {
+            id: i as u32 + 1,
+            parent: 0,
+            name: provider
+                .names
+                .get(&r.name)
+                .cloned()
+                .unwrap_or_else(|| r.name.clone()),
+            version: r.version.clone(),
+            deps: vec![], // filled in below
+            rename: Rename::No,
+        })
+        .collect();
+
+    let mut result = packages.clone();
+    for (package, r) in result.iter_mut().zip(resolved.iter()) {
+        for dep_name in r.deps.iter() {
+            if let Some(dep) = packages
+                .iter()
+                .find(|p| util::compare_names(&p.name, dep_name))
+            {
+                package
+                    .deps
+                    .push((dep.id, dep.name.clone(), dep.version.clone()));
+            }
+        }
+    }
+    Ok((result, provider.markers))
+}
+
+/// Find which environments need each package, by standardized name: The markers on each path
+/// to it from the requirements, combined. `None` means it's needed everywhere.
+pub fn package_markers(
+    reqs: &[Req],
+    packages: &[crate::Package],
+    edges: &EdgeMarkers,
+) -> HashMap<String, Option<Marker>> {
+    // We stop refining a package's marker after this many updates, and say it's needed
+    // everywhere; this can happen with dependency cycles.
+    const MAX_UPDATES: usize = 10;
+
+    let mut result: HashMap<String, Option<Marker>> = HashMap::new();
+    let mut updates: HashMap<String, usize> = HashMap::new();
+    let mut to_visit = vec![];
+
+    let mut add = |name: String,
+                   marker: Option<Marker>,
+                   result: &mut HashMap<String, Option<Marker>>,
+                   to_visit: &mut Vec<String>| {
+        let combined = match result.get(&name) {
+            Some(existing) => Marker::or(existing.clone(), marker),
+            None => marker,
+        };
+        if result.get(&name) == Some(&combined) {
+            return;
+        }
+        let count = updates.entry(name.clone()).or_insert(0);
+        *count += 1;
+        let combined = if *count > MAX_UPDATES { None } else { combined };
+        result.insert(name.clone(), combined);
+        to_visit.push(name);
+    };
+
+    for req in reqs.iter() {
+        let marker = req.marker.as_ref().and_then(Marker::without_extras);
+        add(
+            util::standardize_name(&req.name),
+            marker,
+            &mut result,
+            &mut to_visit,
+        );
+    }
+
+    while let Some(name) = to_visit.pop() {
+        let marker = result.get(&name).cloned().unwrap_or(None);
+        for package in packages
+            .iter()
+            .filter(|p| util::standardize_name(&p.name) == name)
+        {
+            for (_, dep_name, _) in package.deps.iter() {
+                let dep_name = util::standardize_name(dep_name);
+                let edge = edges
+                    .get(&(name.clone(), dep_name.clone()))
+                    .cloned()
+                    .unwrap_or(None);
+                add(
+                    dep_name,
+                    Marker::and(marker.clone(), edge),
+                    &mut result,
+                    &mut to_visit,
+                );
+            }
+        }
+    }
+    result
+}
+
+/// The original resolution approach, used only if renaming's enabled: Greedily pick the newest
+/// compatible version for each requirement, and if that produces conflicts, install multiple
+/// versions of a package, renaming all but one.
+fn resolve_with_renames(
+    index: &dyn PackageIndex,
     reqs: &[Req],
     locked: &[crate::Package],
     os: crate::Os,
     py_vers: &Version,
-    //) -> Result<Vec<(String, Version, Vec<Req>)>, reqwest::Error> {
-) -> Result<Vec<crate::Package>, reqwest::Error> {
+) -> Vec<crate::Package> {
     let mut result = Vec::new();
     let mut cache = HashMap::new();
     let mut reqs_searched = Vec::new();
@@ -526,6 +1042,7 @@ pub fn resolve(
     let mut version_cache = HashMap::new();
 
     if guess_graph(
+        index,
         0,
         &reqs,
         locked,
@@ -575,7 +1092,7 @@ pub fn resolve(
                 id: dep.id,
                 parent: dep.parent,
                 name: fmtd_name,
-                version: dep.version,
+                version: dep.version.clone(),
                 deps: vec![], // to be filled in after resolution
                 rename: Rename::No,
             });
@@ -590,6 +1107,7 @@ pub fn resolve(
             if inter.is_empty() {
                 result_cleaned.append(&mut make_renamed_packs(
                     &version_cache,
+                    reqs,
                     &deps,
                     &result,
                     &fmtd_name,
@@ -626,7 +1144,7 @@ pub fn resolve(
                         id: best.id,
                         parent: best.parent,
                         name: fmtd_name,
-                        version: best.version,
+                        version: best.version.clone(),
                         deps: vec![], // to be filled in after resolution
                         rename: Rename::No,
                     });
@@ -656,6 +1174,7 @@ pub fn resolve(
                     if versions.is_empty() {
                         result_cleaned.append(&mut make_renamed_packs(
                             &version_cache,
+                            reqs,
                             &deps,
                             &result,
                             &fmtd_name,
@@ -670,7 +1189,7 @@ pub fn resolve(
                         .map(|vers| Dependency {
                             id: 0, // placeholder; we'll assign an id to the one we pick.
                             name: fmtd_name.clone(),
-                            version: *vers,
+                            version: vers.clone(),
                             reqs: vec![], // todo
                             parent: 0,    // todo
                         })
@@ -688,7 +1207,7 @@ pub fn resolve(
                         id: newest_unresolved.id,
                         parent: newest_unresolved.parent,
                         name: fmtd_name,
-                        version: newest_unresolved.version,
+                        version: newest_unresolved.version.clone(),
                         deps: vec![], // to be filled in after resolution
                         rename: Rename::No,
                     });
@@ -709,24 +1228,134 @@ pub fn resolve(
     // Now, assign subdeps, so we can store them in the lock.
     assign_subdeps(&mut result_cleaned, &updated_ids);
 
-    let mut a = result.clone();
-    for b in a.iter_mut() {
-        b.reqs = vec![];
-    }
-
-    Ok(result_cleaned)
+    result_cleaned
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::index::{IndexProject, JsonIndex};
+
+    /// Serves a single package, without using the network.
+    pub struct TestIndex(pub IndexProject);
+
+    impl PackageIndex for TestIndex {
+        fn source(&self, name: &str, version: &Version) -> String {
+            format!("test+{}/{}", name, version.to_string2())
+        }
+
+        fn project(&self, _name: &str) -> Result<IndexProject, DependencyError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    pub fn release(
+        python_version: &str,
+        requires_python: Option<&str>,
+        yanked: bool,
+    ) -> WarehouseRelease {
+        let sdist = python_version == "source";
+        WarehouseRelease {
+            filename: "".into(),
+            has_sig: false,
+            digests: WarehouseDigests {
+                md5: "".into(),
+                sha256: "".into(),
+            },
+            packagetype: if sdist { "sdist" } else { "bdist_wheel" }.into(),
+            python_version: python_version.into(),
+            requires_python: requires_python.map(|r| r.to_owned()),
+            url: "".into(),
+            dependencies: None,
+            yanked,
+            yanked_reason: None,
+            core_metadata: false,
+            core_metadata_sha256: None,
+        }
+    }
+
+    #[test]
+    fn candidates_filtered() {
+        let v = |s| Version::from_str(s).unwrap();
+        let index = TestIndex(IndexProject {
+            name: "Saturn".into(),
+            latest: None,
+            releases: vec![
+                (v("1.0"), vec![release("py2", None, false)]),
+                (v("1.1"), vec![release("source", Some(">=3.8"), false)]),
+                (
+                    v("1.2"),
+                    vec![
+                        release("py2", None, false),
+                        release("py2.py3", Some(">=2.7, !=3.0.*"), false),
+                    ],
+                ),
+                (v("1.3"), vec![release("cp37", None, true)]),
+                (v("1.4"), vec![]),
+                (v("1.5"), vec![release("py3", Some(">=3.7.1"), false)]),
+                (v("1.6"), vec![release("py3", Some(">3.7.99, <3.8"), false)]),
+            ],
+        });
+        let py_vers = Version::new_short(3, 7);
+
+        let (name, versions, yanked) =
+            get_candidates(&index, "saturn", std::slice::from_ref(&py_vers)).unwrap();
+        assert_eq!(name, "Saturn");
+        assert_eq!(versions, vec![v("1.2"), v("1.3"), v("1.5"), v("1.6")]);
+        assert_eq!(yanked, vec![v("1.3")]);
+        // The latest ignores yanked versions, and ones without files.
+        assert_eq!(get_version_info(&index, "saturn").unwrap().1, v("1.6"));
+
+        // A full interpreter version must match exactly.
+        let (_, versions, _) = get_candidates(&index, "saturn", &[Version::new(3, 7, 0)]).unwrap();
+        assert_eq!(versions, vec![v("1.2"), v("1.3")]);
+        let (_, versions, _) = get_candidates(&index, "saturn", &[Version::new(3, 7, 2)]).unwrap();
+        assert_eq!(versions, vec![v("1.2"), v("1.3"), v("1.5")]);
+
+        // When locking for several Python versions, each needs a compatible file.
+        let py_versions = [py_vers.clone(), Version::new_short(3, 8)];
+        let (_, versions, _) = get_candidates(&index, "saturn", &py_versions).unwrap();
+        assert_eq!(versions, vec![v("1.2"), v("1.3"), v("1.5")]);
+
+        let targets = Environment::targets(std::slice::from_ref(&py_vers));
+        let mut provider = PypiProvider {
+            index: &index,
+            locked: &[],
+            locked_markers: None,
+            use_lock: true,
+            upgrade: &[],
+            targets: &targets,
+            py_versions: vec![py_vers.clone()],
+            names: HashMap::new(),
+            vers_cache: HashMap::new(),
+            pinned: vec![],
+            markers: HashMap::new(),
+        };
+        provider.versions("saturn").unwrap();
+        let candidates = [v("1.3"), v("1.2")];
+        assert_eq!(
+            provider.choose_version("saturn", &candidates),
+            Some(v("1.2"))
+        );
+        assert_eq!(provider.choose_version("saturn", &candidates[..1]), None);
+
+        // Yanked versions are available if pinned exactly.
+        provider.add_pins(&[Req::from_str("saturn==1.3", true).unwrap()]);
+        assert_eq!(
+            provider.choose_version("saturn", &candidates),
+            Some(v("1.3"))
+        );
+    }
 
     #[test]
     fn warehouse_versions() {
         // Makes API call
         // Assume no new releases since writing this test.
         assert_eq!(
-            get_version_info("scinot").unwrap().2.sort(),
+            get_version_info(&JsonIndex::pypi(), "scinot")
+                .unwrap()
+                .2
+                .sort(),
             vec![
                 Version::new(0, 0, 1),
                 Version::new(0, 0, 2),
@@ -787,4 +1416,104 @@ pub mod tests {
     //    }
 
     // todo: Make dep-resolver tests, including both simple, conflicting/resolvable, and confliction/unresolvable.
+
+    #[test]
+    fn markers_for_packages() {
+        let package = |name: &str, deps: &[&str]| crate::Package {
+            id: 0,
+            parent: 0,
+            name: name.into(),
+            version: Version::new(1, 0, 0),
+            deps: deps
+                .iter()
+                .map(|d| (0, d.to_string(), Version::new(1, 0, 0)))
+                .collect(),
+            rename: crate::dep_types::Rename::No,
+        };
+        let packages = vec![
+            package("pytest", &["colorama", "py"]),
+            package("colorama", &[]),
+            package("py", &[]),
+            package("black", &["colorama"]),
+        ];
+        let m = |s: &str| Some(Marker::from_str(s).unwrap());
+        let mut edges = EdgeMarkers::new();
+        edges.insert(("pytest".into(), "py".into()), None);
+        edges.insert(
+            ("pytest".into(), "colorama".into()),
+            m("sys_platform == 'win32'"),
+        );
+        edges.insert(("black".into(), "colorama".into()), None);
+
+        let mut black = Req::new("black".into(), vec![]);
+        black.marker = Marker::from_str("python_version >= '3.6'").ok();
+        let reqs = vec![Req::new("pytest".into(), vec![]), black.clone()];
+
+        let result = package_markers(&reqs, &packages, &edges);
+        assert_eq!(result["pytest"], None);
+        assert_eq!(result["py"], None);
+        assert_eq!(result["black"], black.marker);
+        assert_eq!(
+            result["colorama"],
+            m("python_version >= '3.6' or sys_platform == 'win32'")
+        );
+
+        // Only `pytest`'s edge needs colorama.
+        let result = package_markers(&reqs[..1], &packages, &edges);
+        assert_eq!(result["colorama"], m("sys_platform == 'win32'"));
+        assert!(!result.contains_key("black"));
+    }
+
+    #[test]
+    fn upgrade_prefers_locked() {
+        let v = |s| Version::from_str(s).unwrap();
+        let index = TestIndex(IndexProject {
+            name: "Saturn".into(),
+            latest: None,
+            releases: vec![
+                (v("1.0"), vec![release("py3", None, false)]),
+                (v("1.1"), vec![release("py3", None, false)]),
+                (v("1.2"), vec![release("py3", None, false)]),
+            ],
+        });
+        let py_vers = Version::new_short(3, 7);
+        let targets = Environment::targets(std::slice::from_ref(&py_vers));
+        let locked = vec![crate::Package {
+            id: 1,
+            parent: 0,
+            name: "saturn".into(),
+            version: v("1.1"),
+            deps: vec![],
+            rename: crate::dep_types::Rename::No,
+        }];
+        let provider = |upgrade| PypiProvider {
+            index: &index,
+            locked: &locked,
+            locked_markers: None,
+            use_lock: true,
+            upgrade,
+            targets: &targets,
+            py_versions: vec![py_vers.clone()],
+            names: HashMap::new(),
+            vers_cache: HashMap::new(),
+            pinned: vec![],
+            markers: HashMap::new(),
+        };
+        let candidates = [v("1.2"), v("1.1"), v("1.0")];
+
+        // Without upgrades, only the locked version is offered.
+        assert_eq!(provider(&[]).versions("saturn").unwrap(), vec![v("1.1")]);
+
+        // Upgrading another package, we try the locked version first, but can move off it.
+        let others = ["jupiter".to_owned()];
+        let mut p = provider(&others);
+        assert_eq!(p.versions("saturn").unwrap().len(), 3);
+        assert_eq!(p.choose_version("saturn", &candidates), Some(v("1.1")));
+        assert_eq!(p.choose_version("saturn", &candidates[2..]), Some(v("1.0")));
+
+        // Upgrading it, we pick the newest.
+        let this = ["Saturn".to_owned()];
+        let mut p = provider(&this);
+        assert_eq!(p.choose_version("saturn", &candidates), Some(v("1.2")));
+    }
 }
@@ -1,9 +1,12 @@
+use crate::index::PackageIndex;
+use crate::markers::Marker;
 use crate::{dep_resolution, util};
 use crossterm::{Color, Colored};
 use regex::{Match, Regex};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::hash::{Hash, Hasher};
 use std::{cmp, fmt, num, str::FromStr};
 
 pub const MAX_VER: u32 = 999_999; // Represents the highest major version we can have
@@ -61,26 +64,23 @@ impl From<reqwest::Error> for DependencyError {
     }
 }
 
-/// Ideally we won't deal with these much, but include for compatibility.
-#[derive(Debug, Clone, Copy, Deserialize, Eq, Hash, PartialEq)]
+/// A pre-release phase, as used in PEP 440 versions. Eg the `rc` in `1.0rc1`.
+#[derive(Debug, Clone, Copy, Deserialize, Eq, Hash, PartialEq, PartialOrd, Ord)]
 pub enum VersionModifier {
     Alpha,
     Beta,
     ReleaseCandidate,
-    Dep, // todo: Not sure what this is, but have found it.
-    // Used to allow comparisons between versions that have and don't have modifiers.
-    Null,
 }
 
 impl FromStr for VersionModifier {
     type Err = DependencyError;
 
+    /// Accepts the alternate spellings PEP 440 normalizes, eg `alpha`, `c`, `pre`.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let result = match s {
-            "a" => Self::Alpha,
-            "b" => Self::Beta,
-            "rc" => Self::ReleaseCandidate,
-            "dep" => Self::Dep,
+        let result = match s.to_lowercase().as_ref() {
+            "a" | "alpha" => Self::Alpha,
+            "b" | "beta" => Self::Beta,
+            "rc" | "c" | "pre" | "preview" => Self::ReleaseCandidate,
             _ => return Err(DependencyError::new("Problem parsing version modifier")),
         };
         Ok(result)
@@ -93,65 +93,70 @@ impl ToString for VersionModifier {
             Self::Alpha => "a".into(),
             Self::Beta => "b".into(),
             Self::ReleaseCandidate => "rc".into(),
-            Self::Dep => "dep".into(),
-            Self::Null => panic!("Can't convert Null to string; misused"),
         }
     }
 }
 
-impl VersionModifier {
-    fn orderval(self) -> u8 {
-        match self {
-            VersionModifier::Null => 4,
-            VersionModifier::ReleaseCandidate => 3,
-            VersionModifier::Beta => 2,
-            VersionModifier::Alpha => 1,
-            VersionModifier::Dep => 0,
-        }
-    }
+/// One dot-separated part of a local version label, eg the `cpu` in `1.4.0+cpu`.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq)]
+pub enum LocalSegment {
+    Num(u32),
+    Str(String),
 }
 
-impl Ord for VersionModifier {
+impl Ord for LocalSegment {
+    /// Numeric segments sort above alphanumeric ones, per PEP 440.
     fn cmp(&self, other: &Self) -> cmp::Ordering {
-        self.orderval().cmp(&other.orderval())
+        match (self, other) {
+            (Self::Num(a), Self::Num(b)) => a.cmp(b),
+            (Self::Str(a), Self::Str(b)) => a.cmp(b),
+            (Self::Num(_), Self::Str(_)) => cmp::Ordering::Greater,
+            (Self::Str(_), Self::Num(_)) => cmp::Ordering::Less,
+        }
     }
 }
 
-impl PartialOrd for VersionModifier {
+impl PartialOrd for LocalSegment {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
-/// An exact, 3-number Semver version. With some possible extras.
-#[derive(Clone, Copy, Default, Deserialize, Eq, Hash, PartialEq)]
+impl ToString for LocalSegment {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Num(n) => n.to_string(),
+            Self::Str(s) => s.clone(),
+        }
+    }
+}
+
+/// A [PEP 440](https://www.python.org/dev/peps/pep-0440/) version, eg `1!2.0.3rc1.post2.dev4+cpu`.
+/// The release segment may have any number of parts; trailing zeros don't affect comparisons,
+/// so `3.7` == `3.7.0`.
+#[derive(Clone, Default, Deserialize)]
 pub struct Version {
-    pub major: u32,
-    pub minor: u32,
-    pub patch: u32,
-    pub extra_num: Option<u32>,                   // eg 4.2.3.1
-    pub modifier: Option<(VersionModifier, u32)>, // eg a1
+    pub epoch: u32,
+    pub release: Vec<u32>,
+    pub pre: Option<(VersionModifier, u32)>, // eg a1
+    pub post: Option<u32>,
+    pub dev: Option<u32>,
+    pub local: Vec<LocalSegment>,
 }
 
 impl Version {
     pub fn new(major: u32, minor: u32, patch: u32) -> Self {
         Self {
-            major,
-            minor,
-            patch,
-            extra_num: None,
-            modifier: None,
+            release: vec![major, minor, patch],
+            ..Default::default()
         }
     }
 
     /// No patch specified.
     pub fn new_short(major: u32, minor: u32) -> Self {
         Self {
-            major,
-            minor,
-            patch: 0,
-            extra_num: None,
-            modifier: None,
+            release: vec![major, minor],
+            ..Default::default()
         }
     }
 
@@ -159,33 +164,156 @@ impl Version {
         Self::new(MAX_VER, 0, 0)
     }
 
-    /// Prevents repetition.
-    fn add_str_mod(&self, s: &mut String) {
-        if let Some(extra_num) = self.extra_num {
-            s.push_str(&format!(".{}", extra_num.to_string()));
+    pub fn major(&self) -> u32 {
+        self.release_part(0)
+    }
+
+    pub fn minor(&self) -> u32 {
+        self.release_part(1)
+    }
+
+    pub fn patch(&self) -> u32 {
+        self.release_part(2)
+    }
+
+    /// Release parts that aren't specified are treated as 0.
+    pub fn release_part(&self, i: usize) -> u32 {
+        *self.release.get(i).unwrap_or(&0)
+    }
+
+    /// The release segment, without trailing zeros. Used for comparisons and hashing.
+    fn release_trimmed(&self) -> &[u32] {
+        let mut end = self.release.len();
+        while end > 0 && self.release[end - 1] == 0 {
+            end -= 1;
         }
-        if let Some((modifier, num)) = self.modifier {
-            s.push_str(&format!("{}{}", modifier.to_string(), num.to_string()));
+        &self.release[..end]
+    }
+
+    pub fn is_prerelease(&self) -> bool {
+        self.pre.is_some() || self.dev.is_some()
+    }
+
+    /// Everything after the release segment, in normalized form.
+    fn suffix(&self) -> String {
+        let mut result = String::new();
+        if let Some((modifier, num)) = self.pre {
+            result.push_str(&format!("{}{}", modifier.to_string(), num));
+        }
+        if let Some(num) = self.post {
+            result.push_str(&format!(".post{}", num));
+        }
+        if let Some(num) = self.dev {
+            result.push_str(&format!(".dev{}", num));
         }
+        if !self.local.is_empty() {
+            let local: Vec<String> = self.local.iter().map(|l| l.to_string()).collect();
+            result.push_str(&format!("+{}", local.join(".")));
+        }
+        result
     }
 
-    pub fn to_string_med(&self) -> String {
-        let mut result = format!("{}.{}", self.major, self.minor);
-        self.add_str_mod(&mut result);
+    /// unlike Display, which overwrites to_string, don't add colors. This is the normalized
+    /// PEP 440 form, and round-trips through `from_str`.
+    pub fn to_string2(&self) -> String {
+        let mut result = String::new();
+        if self.epoch != 0 {
+            result.push_str(&format!("{}!", self.epoch));
+        }
+        let release: Vec<String> = self.release.iter().map(|r| r.to_string()).collect();
+        result.push_str(&release.join("."));
+        result.push_str(&self.suffix());
         result
     }
-    pub fn to_string_short(&self) -> String {
-        let mut result = format!("{}", self.major);
-        self.add_str_mod(&mut result);
+
+    /// The release segment, padded with zeros to at least major, minor, and patch.
+    fn release_padded(&self) -> Vec<u32> {
+        let mut result = self.release.clone();
+        while result.len() < 3 {
+            result.push(0);
+        }
         result
     }
 
-    /// unlike Display, which overwrites to_string, don't add colors.
-    pub fn to_string2(&self) -> String {
-        let mut result = format!("{}.{}.{}", self.major, self.minor, self.patch);
-        self.add_str_mod(&mut result);
+    /// The lowest version we treat as above this one, eg `1.2.4` for `1.2.3`, `1.0rc2` for
+    /// `1.0rc1`, and `1.0.post3` for `1.0.post2`. Bumps the last segment that's present;
+    /// the epoch and any other segments are kept.
+    fn next_above(&self) -> Self {
+        let mut result = Self {
+            local: vec![],
+            ..self.clone()
+        };
+        if let Some(dev) = self.dev {
+            result.dev = Some(dev + 1);
+        } else if let Some(post) = self.post {
+            result.post = Some(post + 1);
+        } else if let Some((modifier, num)) = self.pre {
+            result.pre = Some((modifier, num + 1));
+        } else {
+            result.release = self.release_padded();
+            if let Some(last) = result.release.last_mut() {
+                *last += 1;
+            }
+        }
         result
     }
+
+    /// The highest version we treat as below this one, eg `1.2.2` for `1.2.3`, `2.8.999999` for
+    /// `2.9.0`, and `1.0b999999` for `1.0rc0`.
+    fn prev_below(&self) -> Self {
+        let mut result = Self {
+            local: vec![],
+            ..self.clone()
+        };
+        if !self.local.is_empty() {
+            return result;
+        }
+        match (self.pre, self.post, self.dev) {
+            (_, _, Some(dev)) if dev > 0 => result.dev = Some(dev - 1),
+            (_, _, Some(_)) => {
+                result.dev = None;
+                result = result.prev_below();
+            }
+            (_, Some(post), None) if post > 0 => result.post = Some(post - 1),
+            (_, Some(_), None) => result.post = None,
+            (Some((modifier, num)), None, None) if num > 0 => {
+                result.pre = Some((modifier, num - 1))
+            }
+            (Some((modifier, _)), None, None) => match modifier {
+                VersionModifier::ReleaseCandidate => {
+                    result.pre = Some((VersionModifier::Beta, MAX_VER))
+                }
+                VersionModifier::Beta => result.pre = Some((VersionModifier::Alpha, MAX_VER)),
+                // Below all of this release's pre-releases.
+                VersionModifier::Alpha => {
+                    result.pre = None;
+                    result = result.prev_below();
+                }
+            },
+            (None, None, None) => {
+                // Don't try to make a negative version component, ie for 0.0.0.
+                let mut release = self.release_padded();
+                if let Some(i) = release.iter().rposition(|r| *r > 0) {
+                    release[i] -= 1;
+                    for r in release[i + 1..].iter_mut() {
+                        *r = MAX_VER;
+                    }
+                }
+                result.release = release;
+            }
+        }
+        result
+    }
+
+    /// Used for ordering. Dev releases of a final release sort below its pre-releases,
+    /// and versions without a post release sort below ones with one.
+    fn pre_key(&self) -> (u8, Option<(VersionModifier, u32)>) {
+        match (self.pre, self.post, self.dev) {
+            (None, None, Some(_)) => (0, None),
+            (Some(p), _, _) => (1, Some(p)),
+            (None, _, _) => (2, None),
+        }
+    }
 }
 
 impl FromStr for Version {
@@ -193,75 +321,102 @@ impl FromStr for Version {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         // Treat wildcards as 0.
-        let s = &s.replace("*", "0");
+        let s = &s.trim().replace("*", "0");
+
+        // Adapted from the regex in PEP 440's appendix. Accepts the spellings it normalizes.
+        let re = Regex::new(
+            r"(?ix)^v?
+            (?:(\d+)!)?                                         # epoch
+            (\d+(?:\.\d+)*)                                     # release
+            (?:[-_.]?(a|b|c|rc|alpha|beta|pre|preview)[-_.]?(\d+)?)?  # pre
+            (?:-(\d+)|[-_.]?(post|rev|r)[-_.]?(\d+)?)?          # post
+            (?:[-_.]?(dev)[-_.]?(\d+)?)?                        # dev
+            (?:\+([a-z0-9]+(?:[-_.][a-z0-9]+)*))?               # local
+            $",
+        )
+        .unwrap();
 
-        let re = Regex::new(r"^(\d+)\.?(\d+)?\.?(\d+)?\.?(\d+)?(?:(a|b|rc|dep)(\d+))?$").unwrap();
-        if let Some(caps) = re.captures(s) {
-            return Ok(Self {
-                major: caps.get(1).unwrap().as_str().parse::<u32>()?,
-                minor: match caps.get(2) {
-                    Some(mi) => mi.as_str().parse::<u32>()?,
-                    None => 0,
-                },
-                patch: match caps.get(3) {
-                    Some(p) => p.as_str().parse::<u32>()?,
-                    None => 0,
-                },
-                extra_num: match caps.get(4) {
-                    Some(ex_num) => Some(ex_num.as_str().parse::<u32>()?),
-                    None => None,
-                },
-                modifier: {
-                    match caps.get(5) {
-                        Some(modifier) => {
-                            let m = VersionModifier::from_str(modifier.as_str())?;
-                            let num = match caps.get(6) {
-                                Some(n) => n.as_str().parse::<u32>()?,
-                                // We separate the modifier into two parts for easiser parsing,
-                                // but we shouldn't have one without the other.
-                                None => {
-                                    return Err(DependencyError::new(&format!(
-                                        "Problem parsing version modifier: {}",
-                                        s
-                                    )))
-                                }
-                            };
-                            Some((m, num))
-                        }
-                        None => None,
-                    }
-                },
-            });
+        let caps = match re.captures(s) {
+            Some(c) => c,
+            None => {
+                return Err(DependencyError::new(&format!(
+                    "Problem parsing version: {}",
+                    s
+                )))
+            }
+        };
+
+        let num = |m: Option<Match>| -> Result<Option<u32>, DependencyError> {
+            match m {
+                Some(n) => Ok(Some(n.as_str().parse::<u32>()?)),
+                None => Ok(None),
+            }
+        };
+
+        let mut release = vec![];
+        for part in caps.get(2).unwrap().as_str().split('.') {
+            release.push(part.parse::<u32>()?);
         }
 
-        Err(DependencyError::new(&format!(
-            "Problem parsing version: {}",
-            s
-        )))
+        let pre = match caps.get(3) {
+            Some(m) => Some((
+                VersionModifier::from_str(m.as_str())?,
+                num(caps.get(4))?.unwrap_or(0),
+            )),
+            None => None,
+        };
+
+        // The post-release number has two forms: `1.0-1`, and `1.0.post1`.
+        let post = match caps.get(5) {
+            Some(n) => Some(n.as_str().parse::<u32>()?),
+            None => match caps.get(6) {
+                Some(_) => Some(num(caps.get(7))?.unwrap_or(0)),
+                None => None,
+            },
+        };
+
+        let dev = match caps.get(8) {
+            Some(_) => Some(num(caps.get(9))?.unwrap_or(0)),
+            None => None,
+        };
+
+        let local = match caps.get(10) {
+            Some(l) => l
+                .as_str()
+                .to_lowercase()
+                .split(&['.', '-', '_'][..])
+                .map(|part| match part.parse::<u32>() {
+                    Ok(n) => LocalSegment::Num(n),
+                    Err(_) => LocalSegment::Str(part.to_owned()),
+                })
+                .collect(),
+            None => vec![],
+        };
+
+        Ok(Self {
+            epoch: num(caps.get(1))?.unwrap_or(0),
+            release,
+            pre,
+            post,
+            dev,
+            local,
+        })
     }
 }
 
 impl Ord for Version {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
-        // None version modifiers should rank highest. Ie 17.0 > 17.0rc1
-        let self_mod = self.modifier.unwrap_or((VersionModifier::Null, 0));
-        let other_mod = other.modifier.unwrap_or((VersionModifier::Null, 0));
-
-        if self.major != other.major {
-            self.major.cmp(&other.major)
-        } else if self.minor != other.minor {
-            self.minor.cmp(&other.minor)
-        } else if self.patch != other.patch {
-            self.patch.cmp(&other.patch)
-        } else if self.extra_num != other.extra_num {
-            self.extra_num
-                .unwrap_or(0)
-                .cmp(&other.extra_num.unwrap_or(0))
-        } else if self_mod.0 != other_mod.0 {
-            self_mod.0.cmp(&other_mod.0)
-        } else {
-            self_mod.1.cmp(&other_mod.1)
-        }
+        // Options compare with None lowest, which is what we want for post and local. For dev,
+        // a version without one ranks highest. Ie 17.0 > 17.0.dev1
+        let dev_key = |v: &Self| v.dev.map_or((1, 0), |d| (0, d));
+
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| self.release_trimmed().cmp(other.release_trimmed()))
+            .then_with(|| self.pre_key().cmp(&other.pre_key()))
+            .then_with(|| self.post.cmp(&other.post))
+            .then_with(|| dev_key(self).cmp(&dev_key(other)))
+            .then_with(|| self.local.cmp(&other.local))
     }
 }
 
@@ -271,11 +426,25 @@ impl PartialOrd for Version {
     }
 }
 
-//impl ToString for Version {
-//    fn to_string(&self) -> String {
-//        format!("{}.{}.{}", self.major, self.minor, self.patch)
-//    }
-//}
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl Hash for Version {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Must agree with `Eq`, so ignore trailing zeros.
+        self.epoch.hash(state);
+        self.release_trimmed().hash(state);
+        self.pre.hash(state);
+        self.post.hash(state);
+        self.dev.hash(state);
+        self.local.hash(state);
+    }
+}
 
 impl fmt::Display for Version {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -283,19 +452,24 @@ impl fmt::Display for Version {
         let dot_c = Colored::Fg(Color::DarkYellow);
         let r = Colored::Fg(Color::Reset);
 
-        let mut suffix = "".to_string();
-        if let Some(num) = self.extra_num {
-            suffix.push('.');
-            suffix.push_str(&num.to_string());
-        }
-        if let Some((modifier, num)) = self.modifier {
-            suffix.push_str(&modifier.to_string());
-            suffix.push_str(&num.to_string());
-        }
+        let release: Vec<String> = self
+            .release
+            .iter()
+            .map(|part| format!("{}{}", num_c, part))
+            .collect();
+        let epoch = if self.epoch != 0 {
+            format!("{}{}{}!", num_c, self.epoch, dot_c)
+        } else {
+            "".into()
+        };
+
         write!(
             f,
-            "{}{}{}.{}{}{}.{}{}{}{}",
-            num_c, self.major, dot_c, num_c, self.minor, dot_c, num_c, self.patch, suffix, r
+            "{}{}{}{}",
+            epoch,
+            release.join(&format!("{}.", dot_c)),
+            self.suffix(),
+            r
         )
     }
 }
@@ -317,7 +491,9 @@ pub enum ReqType {
     Lt,
     Caret,
     Tilde,
-    // todo wildcard
+    Compatible, // ~=, per PEP 440
+    Wildcard,   // eg ==3.2.*
+    NeWildcard, // eg !=3.2.*
 }
 
 impl ToString for ReqType {
@@ -332,6 +508,9 @@ impl ToString for ReqType {
             ReqType::Ne => "!=".into(),
             ReqType::Caret => "^".into(),
             ReqType::Tilde => "~".into(),
+            ReqType::Compatible => "~=".into(),
+            ReqType::Wildcard => "==".into(),
+            ReqType::NeWildcard => "!=".into(),
         }
     }
 }
@@ -349,6 +528,9 @@ impl FromStr for ReqType {
             "!=" => Ok(ReqType::Ne),
             "^" => Ok(ReqType::Caret),
             "~" => Ok(ReqType::Tilde),
+            "~=" => Ok(ReqType::Compatible),
+            // Arbitrary equality; we treat it as normal equality.
+            "===" => Ok(ReqType::Exact),
             _ => Err(DependencyError::new("Problem parsing ReqType")),
         }
     }
@@ -370,7 +552,7 @@ impl FromStr for Constraint {
             return Ok(Self::new(ReqType::Gte, Version::new(0, 0, 0)));
         }
 
-        let re = Regex::new(r"^(\^|~|==|<=|>=|<|>|!=)?(.*)$").unwrap();
+        let re = Regex::new(r"^(\^|~=|~|===|==|<=|>=|<|>|!=)?\s*(.*?)(\.\*)?$").unwrap();
 
         let caps = match re.captures(s) {
             Some(c) => c,
@@ -378,11 +560,20 @@ impl FromStr for Constraint {
         };
 
         // Only major is required.
-        let type_ = match caps.get(1) {
+        let mut type_ = match caps.get(1) {
             Some(t) => ReqType::from_str(t.as_str())?,
             None => ReqType::Exact,
         };
 
+        // Trailing wildcards, eg `==3.2.*`, match any version with that prefix.
+        if caps.get(3).is_some() {
+            type_ = match type_ {
+                ReqType::Exact => ReqType::Wildcard,
+                ReqType::Ne => ReqType::NeWildcard,
+                _ => type_, // Treat wildcards as 0 for other types.
+            }
+        }
+
         let version = match caps.get(2) {
             Some(c) => Version::from_str(c.as_str())?,
             None => return Err(DependencyError::new("Problem parsing constraint")),
@@ -410,59 +601,15 @@ impl Constraint {
         Ok(result)
     }
 
-    /// ie cp37, a version from Pypi. Eg: "py3", "cp35.cp36.cp37.cp38", "cp26", "py2.py3",
-    /// "pp36", "any", "2.7",
-    /// Note that we're parsing the `python_version` field, not `requires_python`, since the latter
-    /// May throw false-positives for compatibility.
-    /// Important: The result is intended to be used in an "any" way. Ie "cp35.36" should match
-    /// either Python 3.5 or 3.6.
-    pub fn from_wh_py_vers(s: &str) -> Result<Vec<Self>, DependencyError> {
-        if s == "any" {
-            return Ok(vec![Self::new(ReqType::Gte, Version::new(2, 0, 0))]);
-        }
-
-        if let Ok(parsed) = Version::from_str(s) {
-            return Ok(vec![Self::new(ReqType::Exact, parsed)]);
-        }
-
-        let s_split = s.split('.');
-        let re = Regex::new(r"^(?:cp|py|pp)?([234])(\d)?$").unwrap();
-
-        let mut result = vec![];
-        for part in s_split {
-            if let Some(caps) = re.captures(part) {
-                let major = caps.get(1).unwrap().as_str().parse::<u32>()?;
-                match caps.get(2) {
-                    Some(mi) => {
-                        let minor = mi.as_str().parse::<u32>()?;
-                        result.push(Constraint::new(
-                            ReqType::Exact,
-                            Version::new_short(major, minor),
-                        ));
-                    }
-                    // eg, py2.py3 will add Gte 2.0.0 and Gte 3.0.0
-                    None => {
-                        if major == 2 {
-                            result.push(Constraint::new(ReqType::Lte, Version::new_short(2, 10)));
-                        } else {
-                            result.push(Constraint::new(ReqType::Gte, Version::new_short(3, 0)));
-                        }
-                    }
-                };
-            }
-        }
-
-        Ok(result)
-    }
-
     pub fn to_string(&self, ommit_equals: bool, pip_style: bool) -> String {
         // ommit_equals indicates we dont' want to add any type if it's exact. Eg in config files.
         // pip_style means that ^ is transformed to ^=, and ~ to ~=
-        let mut type_str = if ommit_equals && self.type_ == ReqType::Exact {
-            "".to_string()
-        } else {
-            self.type_.to_string()
-        };
+        let mut type_str =
+            if ommit_equals && (self.type_ == ReqType::Exact || self.type_ == ReqType::Wildcard) {
+                "".to_string()
+            } else {
+                self.type_.to_string()
+            };
         if pip_style {
             match self.type_ {
                 ReqType::Caret => type_str.push_str("="),
@@ -470,101 +617,133 @@ impl Constraint {
                 _ => (),
             }
         }
-        format!("{}{}", type_str, self.version.to_string())
+        format!(
+            "{}{}{}",
+            type_str,
+            self.version.to_string2(),
+            self.wildcard_suffix()
+        )
+    }
+
+    /// A PEP 440 version specifier, eg for `Requires-Dist` in metadata. Caret and tilde
+    /// constraints, which only this tool and Poetry understand, become ranges.
+    /// eg `^1.2.3` becomes `>=1.2.3,<2.0.0`.
+    pub fn to_pep440_string(&self) -> String {
+        let max = match self.type_ {
+            ReqType::Caret => {
+                if self.version.major() > 0 {
+                    Version::new(self.version.major() + 1, 0, 0)
+                } else if self.version.minor() > 0 {
+                    Version::new(0, self.version.minor() + 1, 0)
+                } else {
+                    Version::new(0, 0, self.version.patch() + 2)
+                }
+            }
+            ReqType::Tilde => {
+                if self.version.minor() > 0 {
+                    Version::new(self.version.major(), self.version.minor() + 1, 0)
+                } else {
+                    Version::new(self.version.major() + 1, 0, 0)
+                }
+            }
+            _ => return self.to_string(false, true),
+        };
+        format!(">={},<{}", self.version.to_string2(), max.to_string2())
+    }
+
+    fn wildcard_suffix(&self) -> &str {
+        match self.type_ {
+            ReqType::Wildcard | ReqType::NeWildcard => ".*",
+            _ => "",
+        }
+    }
+
+    /// For wildcards and `~=`: Do the first `len` release parts of `version` match ours?
+    fn prefix_matches(&self, version: &Version, len: usize) -> bool {
+        version.epoch == self.version.epoch
+            && (0..len).all(|i| version.release_part(i) == self.version.release_part(i))
+    }
+
+    /// The lowest version above everything matching our first `len` release parts.
+    /// Eg 1.3 for `==1.2.*`, and 2 for `~=1.2`.
+    fn prefix_upper(&self, len: usize) -> Version {
+        let mut release: Vec<u32> = (0..len).map(|i| self.version.release_part(i)).collect();
+        if let Some(last) = release.last_mut() {
+            *last += 1;
+        }
+        Version {
+            epoch: self.version.epoch,
+            release,
+            ..Default::default()
+        }
+    }
+
+    /// `~=1.4.5` allows anything matching `1.4.*`; `~=2.2` allows `2.*`.
+    fn compatible_len(&self) -> usize {
+        cmp::max(self.version.release.len(), 2) - 1
     }
 
     /// Find the lowest and highest compatible versions. Return a vec, since the != requirement type
     /// has two ranges.
     pub fn compatible_range(&self) -> Vec<(Version, Version)> {
-        let highest = Version::new(MAX_VER, 0, 0);
+        let epoch = self.version.epoch;
+        let highest = Version {
+            epoch,
+            ..Version::_max()
+        };
         let lowest = Version::new(0, 0, 0);
         let max;
 
-        let safely_subtract = |major: u32, minor: u32, patch: u32| {
-            let mut major = major;
-            let mut minor = minor;
-            let mut patch = patch;
-            // Don't try to make a negative version component.
-            // ie 0.0.0. Return max of 0.0.0
-            if major == 0 && minor == 0 && patch == 0 {
-            }
-            // ie 3.0.0. Return max of 2.999999.999999
-            else if minor == 0 && patch == 0 {
-                major -= 1;
-                minor = MAX_VER;
-                patch = MAX_VER;
-            // ie 2.9.0. Return max of 2.8.999999
-            } else if patch == 0 {
-                minor -= 1;
-                patch = MAX_VER
-            } else {
-                patch -= 1;
-            }
-            (major, minor, patch)
-        };
-
         // Note that other than for not-equals, the the resulting Vec has len 1.
         match self.type_ {
-            ReqType::Exact => vec![(self.version, self.version)],
-            ReqType::Gte => vec![(self.version, highest)],
-            ReqType::Lte => vec![(lowest, self.version)],
-            ReqType::Gt => vec![(
-                Version::new(
-                    self.version.major,
-                    self.version.minor,
-                    self.version.patch + 1,
-                ),
-                highest,
-            )],
-            ReqType::Lt => {
-                let (major, minor, patch) =
-                    safely_subtract(self.version.major, self.version.minor, self.version.patch);
-                vec![(lowest, Version::new(major, minor, patch))]
-            }
-            ReqType::Ne => {
-                let (major, minor, patch) =
-                    safely_subtract(self.version.major, self.version.minor, self.version.patch);
-                vec![
-                    (lowest, Version::new(major, minor, patch)),
-                    (
-                        Version::new(
-                            self.version.major,
-                            self.version.minor,
-                            self.version.patch + 1,
-                        ),
-                        highest,
-                    ),
-                ]
-            }
+            ReqType::Exact => vec![(self.version.clone(), self.version.clone())],
+            ReqType::Gte => vec![(self.version.clone(), highest)],
+            ReqType::Lte => vec![(lowest, self.version.clone())],
+            ReqType::Gt => vec![(self.version.next_above(), highest)],
+            ReqType::Lt => vec![(lowest, self.version.prev_below())],
+            ReqType::Ne => vec![
+                (lowest, self.version.prev_below()),
+                (self.version.next_above(), highest),
+            ],
             // This section DRY from `compatible`.
             ReqType::Caret => {
-                if self.version.major > 0 {
-                    max = Version::new(self.version.major + 1, 0, 0);
-                } else if self.version.minor > 0 {
-                    max = Version::new(0, self.version.minor + 1, 0);
+                if self.version.major() > 0 {
+                    max = Version::new(self.version.major() + 1, 0, 0);
+                } else if self.version.minor() > 0 {
+                    max = Version::new(0, self.version.minor() + 1, 0);
                 } else {
-                    max = Version::new(0, 0, self.version.patch + 2);
+                    max = Version::new(0, 0, self.version.patch() + 2);
                 }
                 // We need to use Lt logic for ^ and ~.
-                let (major, minor, patch) = safely_subtract(max.major, max.minor, max.patch);
-                vec![(self.version, Version::new(major, minor, patch))]
+                vec![(self.version.clone(), Version { epoch, ..max }.prev_below())]
             }
             // For tilde, if minor's specified, can only increment patch.
             // If not, can increment minor or patch.
             ReqType::Tilde => {
-                if self.version.minor > 0 {
-                    max = Version::new(self.version.major, self.version.minor + 1, 0);
+                if self.version.minor() > 0 {
+                    max = Version::new(self.version.major(), self.version.minor() + 1, 0);
                 } else {
-                    max = Version::new(self.version.major + 1, 0, 0);
+                    max = Version::new(self.version.major() + 1, 0, 0);
                 }
-                let (major, minor, patch) = safely_subtract(max.major, max.minor, max.patch);
-                vec![(self.version, Version::new(major, minor, patch))]
+                vec![(self.version.clone(), Version { epoch, ..max }.prev_below())]
+            }
+            ReqType::Compatible => {
+                max = self.prefix_upper(self.compatible_len());
+                vec![(self.version.clone(), max.prev_below())]
             }
+            ReqType::Wildcard => {
+                max = self.prefix_upper(self.version.release.len());
+                vec![(self.version.clone(), max.prev_below())]
+            }
+            ReqType::NeWildcard => vec![
+                (lowest, self.version.prev_below()),
+                (self.prefix_upper(self.version.release.len()), highest),
+            ],
         }
     }
 
     pub fn is_compatible(&self, version: &Version) -> bool {
-        let min = self.version;
+        let min = &self.version;
         let max;
 
         match self.type_ {
@@ -575,36 +754,56 @@ impl Constraint {
             ReqType::Lt => self.version > *version,
             ReqType::Ne => self.version != *version,
             ReqType::Caret => {
-                if self.version.major > 0 {
-                    max = Version::new(self.version.major + 1, 0, 0);
-                } else if self.version.minor > 0 {
-                    max = Version::new(0, self.version.minor + 1, 0);
+                if self.version.major() > 0 {
+                    max = Version::new(self.version.major() + 1, 0, 0);
+                } else if self.version.minor() > 0 {
+                    max = Version::new(0, self.version.minor() + 1, 0);
                 } else {
-                    max = Version::new(0, 0, self.version.patch + 2);
+                    max = Version::new(0, 0, self.version.patch() + 2);
                 }
 
-                min <= *version && *version < max
+                *min <= *version && *version < max
             }
             // For tilde, if minor's specified, can only increment patch.
             // If not, can increment minor or patch.
             ReqType::Tilde => {
-                if self.version.minor > 0 {
-                    max = Version::new(self.version.major, self.version.minor + 1, 0);
+                if self.version.minor() > 0 {
+                    max = Version::new(self.version.major(), self.version.minor() + 1, 0);
                 } else {
-                    max = Version::new(self.version.major + 1, 0, 0);
+                    max = Version::new(self.version.major() + 1, 0, 0);
                 }
-                min < *version && *version < max
+                *min < *version && *version < max
+            }
+            ReqType::Compatible => {
+                *min <= *version && self.prefix_matches(version, self.compatible_len())
             }
+            ReqType::Wildcard => self.prefix_matches(version, self.version.release.len()),
+            ReqType::NeWildcard => !self.prefix_matches(version, self.version.release.len()),
         }
     }
 }
 
 impl fmt::Display for Constraint {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}{}", self.type_.to_string(), self.version)
+        write!(
+            f,
+            "{}{}{}",
+            self.type_.to_string(),
+            self.version,
+            self.wildcard_suffix()
+        )
     }
 }
 
+/// A range that includes every version. Its epoch keeps the maximum above versions that have one.
+fn full_range() -> (Version, Version) {
+    let highest = Version {
+        epoch: MAX_VER,
+        ..Version::_max()
+    };
+    (Version::new(0, 0, 0), highest)
+}
+
 pub fn intersection_many(constrs: &[Constraint]) -> Vec<(Version, Version)> {
     // And logic between constraints. We use a range to account for Ne logic, which
     // may result in more than one compatible range.
@@ -619,13 +818,13 @@ pub fn intersection_many(constrs: &[Constraint]) -> Vec<(Version, Version)> {
         let rng = &constr.compatible_range();
         let rng2;
         match constr.type_ {
-            ReqType::Ne => {
-                rng2 = (Version::new(0, 0, 0), Version::_max());
+            ReqType::Ne | ReqType::NeWildcard => {
+                rng2 = full_range();
                 // We'll remove nes at the end.
-                nes.push(constr.version);
+                nes.push(constr.version.clone());
             }
             _ => {
-                rng2 = rng[0]; // If not Ne, there will be exactly 1.
+                rng2 = rng[0].clone(); // If not Ne, there will be exactly 1.
             }
         }
         ranges.push(rng2);
@@ -638,9 +837,9 @@ pub fn intersection_many(constrs: &[Constraint]) -> Vec<(Version, Version)> {
 /// handle 2 at a time.
 fn intersection_many2(reqs: &[(Version, Version)]) -> Vec<(Version, Version)> {
     // todo: Broken for notequals, which involves joining two ranges with OR logic.
-    let init = vec![(Version::new(0, 0, 0), Version::new(MAX_VER, 0, 0))];
+    let init = vec![full_range()];
     reqs.iter().fold(init, |acc, constraint_set| {
-        intersection(&[*constraint_set], &acc)
+        intersection(std::slice::from_ref(constraint_set), &acc)
     })
 }
 
@@ -656,80 +855,24 @@ pub fn intersection(
         for rng2 in ranges2 {
             // 0 is min, 1 is max.
             if rng2.1 >= rng1.0 && rng1.1 >= rng2.0 {
-                result.push((cmp::max(rng2.0, rng1.0), cmp::min(rng1.1, rng2.1)));
+                result.push((
+                    cmp::max(&rng2.0, &rng1.0).clone(),
+                    cmp::min(&rng1.1, &rng2.1).clone(),
+                ));
             }
         }
     }
     result
 }
 
-/// For parsing Req from string.
-fn parse_extras(
-    m: Option<Match>,
-) -> (
-    Option<String>,
-    Option<(ReqType, crate::Os)>,
-    Option<Constraint>,
-) {
-    let mut extra = None;
-    let mut sys_platform = None;
-    let mut python_version = None;
-
-    match m {
-        Some(s) => {
-            let extras = s.as_str();
-            // Now that we've extracted extras etc, parse them with a new re.
-            let ex_re = Regex::new(
-                r#"(extra|sys_platform|python_version)\s*(\^|~|==|<=|>=|<|>|!=)\s*['"](.*?)['"]"#,
-            )
-            .unwrap();
-
-            for caps in ex_re.captures_iter(extras) {
-                let type_ = caps.get(1).unwrap().as_str();
-                let req_type = caps.get(2).unwrap().as_str();
-                let val = caps.get(3).unwrap().as_str();
-
-                match type_ {
-                    "extra" => extra = Some(val.to_owned()),
-                    "sys_platform" => {
-                        sys_platform = Some((
-                            ReqType::from_str(req_type).unwrap_or_else(|_| {
-                                panic!("Problem parsing reqtype: {}", req_type)
-                            }),
-                            crate::Os::from_str(val).unwrap_or_else(|_| {
-                                panic!("Problem parsing Os in extras: {}", val)
-                            }),
-                        ))
-                    }
-                    "python_version" => {
-                        // If we parse reqtype and version separately, version will be forced
-                        // to take 3 digits, even if not all 3 are specified.
-                        python_version = Some(
-                            Constraint::from_str(&(req_type.to_owned() + val)).unwrap_or_else(
-                                |_| panic!("Problem parsing constraint: {} {}", req_type, val),
-                            ),
-                        );
-                    }
-                    _ => println!("Found unexpected extra: {}", type_),
-                }
-            }
-        }
-        None => {
-            extra = None;
-            sys_platform = None;
-            python_version = None;
-        }
-    };
-    (extra, sys_platform, python_version)
-}
-
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Req {
     pub name: String,
     pub constraints: Vec<Constraint>,
-    pub extra: Option<String>,
-    pub sys_platform: Option<(ReqType, crate::Os)>,
-    pub python_version: Option<Constraint>,
+    /// The PEP 508 environment marker, eg `sys_platform == "win32" and extra == "socks"`.
+    pub marker: Option<Marker>,
+    /// A direct reference, eg `pip @ https://github.com/pypa/pip/archive/1.3.1.zip`.
+    pub url: Option<String>,
     pub install_with_extras: Option<Vec<String>>,
 }
 
@@ -738,66 +881,32 @@ impl Req {
         Self {
             name,
             constraints,
-            extra: None,
-            sys_platform: None,
-            python_version: None,
+            marker: None,
+            url: None,
             install_with_extras: None,
         }
     }
 
+    /// Parse a requirement. If `pypi_fmt` is true, this is a
+    /// [PEP 508](https://www.python.org/dev/peps/pep-0508/) string, as found in
+    /// `requires_dist`; otherwise it's a line from `pyproject.toml`.
     pub fn from_str(s: &str, pypi_fmt: bool) -> Result<Self, DependencyError> {
-        let re = if pypi_fmt {
-            // eg saturn (>=0.3.4) or argon2-cffi (>=16.1.0) ; extra == 'argon2'
-            // todo deal with extra etc
-            // Note: We specify what chars are acceptable in a name instead of using
-            // wildcard, so we don't accidentally match a semicolon here if a
-            // set of parens appears later. The non-greedy ? in the version-matching
-            // expression's important as well, in some cases of extras.
-            Regex::new(r#"^([a-zA-Z\-0-9._]+)\s+\((.*?)\)(?:(?:\s*;\s*)(.*))?$"#).unwrap()
-        } else {
-            // eg saturn = ">=0.3.4", as in pyproject.toml
-            // todo extras in this format?
-            Regex::new(r#"^(.*?)\s*=\s*["'](.*)["']$"#).unwrap()
-        };
+        if pypi_fmt {
+            return Self::from_pep508(s);
+        }
 
-        // todo: Excessive nesting
+        // eg saturn = ">=0.3.4", as in pyproject.toml
+        // todo extras in this format?
+        let re = Regex::new(r#"^(.*?)\s*=\s*["'](.*)["']$"#).unwrap();
         if let Some(caps) = re.captures(s) {
             let name = caps.get(1).unwrap().as_str().to_owned();
-            let reqs_m = caps.get(2).unwrap();
-            let constraints = Constraint::from_str_multiple(reqs_m.as_str())?;
-
-            let (extra, sys_platform, python_version) = parse_extras(caps.get(3));
-
-            return Ok(Self {
-                name,
-                constraints,
-                extra,
-                sys_platform,
-                python_version,
-                install_with_extras: None,
-            });
+            let constraints = Constraint::from_str_multiple(caps.get(2).unwrap().as_str())?;
+            return Ok(Self::new(name, constraints));
         };
 
         // Check if no version is specified.
-        let novers_re = if pypi_fmt {
-            Regex::new(r"^([a-zA-Z\-0-9._]+)(?:(?:\s*;\s*)(.*))?$").unwrap()
-        } else {
-            // todo extras
-            Regex::new(r"^([a-zA-Z\-0-9._]+)$").unwrap()
-            //            Regex::new(r"^([a-zA-Z\-0-9._]+)(?:(a|b|rc|dep)(\d+))?$").unwrap()
-        };
-
-        if let Some(caps) = novers_re.captures(s) {
-            let (extra, sys_platform, python_version) = parse_extras(caps.get(2));
-
-            return Ok(Self {
-                name: caps.get(1).unwrap().as_str().to_string(),
-                constraints: vec![],
-                extra,
-                sys_platform,
-                python_version,
-                install_with_extras: None,
-            });
+        if Regex::new(r"^([a-zA-Z\-0-9._]+)$").unwrap().is_match(s) {
+            return Ok(Self::new(s.to_string(), vec![]));
         }
         Err(DependencyError::new(&format!(
             "Problem parsing version requirement: {}",
@@ -805,60 +914,166 @@ impl Req {
         )))
     }
 
+    /// eg `requests [security,tests] >= 2.8.1, == 2.8.* ; python_version < "2.7"`,
+    /// `saturn (>=0.3.4)`, or `pip @ https://github.com/pypa/pip/archive/1.3.1.zip`
+    fn from_pep508(s: &str) -> Result<Self, DependencyError> {
+        let err = || DependencyError::new(&format!("Problem parsing requirement: {}", s));
+
+        // Note: We specify what chars are acceptable in a name instead of using
+        // wildcard, so we don't accidentally match a semicolon or paren.
+        let re =
+            Regex::new(r"^\s*([a-zA-Z0-9][a-zA-Z\-0-9._]*)\s*(?:\[([^\]]*)\])?\s*(.*)$").unwrap();
+        let caps = re.captures(s).ok_or_else(err)?;
+
+        let name = caps.get(1).unwrap().as_str().to_owned();
+        let install_with_extras = match caps.get(2) {
+            Some(ex) => {
+                let extras: Vec<String> = ex
+                    .as_str()
+                    .split(',')
+                    .map(|e| e.trim().to_owned())
+                    .filter(|e| !e.is_empty())
+                    .collect();
+                if extras.is_empty() {
+                    None
+                } else {
+                    Some(extras)
+                }
+            }
+            None => None,
+        };
+        let rest = caps.get(3).unwrap().as_str().trim();
+
+        let mut url = None;
+        let mut constraints = vec![];
+        let marker_str;
+        if rest.starts_with('@') {
+            // A URL may contain semicolons, so the marker must be preceded by whitespace.
+            let rest = rest[1..].trim();
+            let (u, m) = match Regex::new(r"\s+;").unwrap().find(rest) {
+                Some(m) => (&rest[..m.start()], Some(&rest[m.end()..])),
+                None => (rest, None),
+            };
+            if u.is_empty() {
+                return Err(err());
+            }
+            url = Some(u.to_owned());
+            marker_str = m;
+        } else {
+            let (spec, m) = match rest.find(';') {
+                Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+                None => (rest, None),
+            };
+            let mut spec = spec.trim();
+            if spec.starts_with('(') {
+                if !spec.ends_with(')') {
+                    return Err(err());
+                }
+                spec = spec[1..spec.len() - 1].trim();
+            }
+            if !spec.is_empty() {
+                constraints = Constraint::from_str_multiple(spec)?;
+            }
+            marker_str = m;
+        }
+
+        let marker = match marker_str {
+            Some(m) => Some(Marker::from_str(m)?),
+            None => None,
+        };
+
+        Ok(Self {
+            name,
+            constraints,
+            marker,
+            url,
+            install_with_extras,
+        })
+    }
+
     /// We use this for parsing requirements.txt.
     pub fn from_pip_str(s: &str) -> Option<Self> {
-        // todo multiple ie single quotes support?
-        // Check if no version is specified.
-        if Regex::new(r"^([a-zA-Z\-0-9]+)$")
-            .unwrap()
-            .captures(s)
-            .is_some()
-        {
-            return Some(Self::new(s.to_string(), vec![]));
+        // Strip comments; skip blank lines and pip options like `-r` and `-e`.
+        let s = match s.find(" #") {
+            Some(i) => &s[..i],
+            None => s,
         }
+        .trim();
+        if s.is_empty() || s.starts_with('#') || s.starts_with('-') {
+            return None;
+        }
+        Self::from_pep508(s).ok()
+    }
 
-        let re = Regex::new(r"^(.*?)((?:\^|~|==|<=|>=|<|>|!=).*)$").unwrap();
-
-        let caps = match re.captures(s) {
-            Some(c) => c,
-            // todo: Figure out how to return an error
-            None => return None,
+    /// If unconstrained, a copy with a caret constraint for the latest version on the index, and
+    /// the name as the index formats it.
+    pub fn pin_latest(&self, index: &dyn PackageIndex) -> Self {
+        if !self.constraints.is_empty() {
+            return self.clone();
+        }
+        let (name, latest_version) = match dep_resolution::get_version_info(index, &self.name) {
+            Ok((fmtd_name, version, _)) => (fmtd_name, version),
+            Err(_) => {
+                util::abort(&format!("Unable to find version info for {:?}", &self.name));
+                unreachable!()
+            }
         };
+        Self {
+            name,
+            constraints: vec![Constraint::new(ReqType::Caret, latest_version)],
+            ..self.clone()
+        }
+    }
 
-        let name = caps.get(1).unwrap().as_str().to_string();
-        let req = Constraint::from_str(caps.get(2).unwrap().as_str())
-            .expect("Problem parsing requirement");
-
-        Some(Self::new(name, vec![req]))
+    /// The value for this requirement in a `pyproject.toml` dependencies table, eg `"^0.3.1"`
+    /// for `saturn = "^0.3.1"`, or `{version = "^7.7.0", extras = ["qtconsole"]}`. If
+    /// unconstrained, we use a caret constraint for the latest version on the index.
+    pub fn to_cfg_value(&self, index: &dyn PackageIndex) -> String {
+        if self.constraints.is_empty() {
+            return self.pin_latest(index).to_cfg_value(index);
+        }
+        let version = toml::Value::String(
+            self.constraints
+                .iter()
+                .map(|r| r.to_string(true, false))
+                .collect::<Vec<String>>()
+                .join(", "),
+        );
+        match &self.install_with_extras {
+            Some(extras) if !extras.is_empty() => format!(
+                "{{version = {}, extras = {}}}",
+                version,
+                toml::Value::from(extras.clone())
+            ),
+            _ => version.to_string(),
+        }
     }
 
-    /// eg `saturn = "^0.3.1"` or `matplotlib = "3.1.1"`
-    pub fn to_cfg_string(&self) -> String {
-        match self.constraints.len() {
-            0 => {
-                let (name, latest_version) = match dep_resolution::get_version_info(&self.name) {
-                    Ok((fmtd_name, version, _)) => (fmtd_name, version),
-                    Err(_) => {
-                        util::abort(&format!("Unable to find version info for {:?}", &self.name));
-                        unreachable!()
-                    }
-                };
-                format!(
-                    r#"{} = "{}""#,
-                    name,
-                    Constraint::new(ReqType::Caret, latest_version).to_string(true, false)
-                )
+    /// A PEP 508 requirement string, eg `requests[security]>=2.8.1; python_version < "3.8"`,
+    /// as used for `Requires-Dist` in metadata.
+    pub fn to_pep508_string(&self) -> String {
+        let mut result = self.name.clone();
+        if let Some(extras) = &self.install_with_extras {
+            if !extras.is_empty() {
+                result.push_str(&format!("[{}]", extras.join(",")));
             }
-            _ => format!(
-                r#"{} = "{}""#,
-                self.name,
-                self.constraints
+        }
+        match &self.url {
+            Some(url) => result.push_str(&format!(" @ {}", url)),
+            None => result.push_str(
+                &self
+                    .constraints
                     .iter()
-                    .map(|r| r.to_string(true, false))
+                    .map(|c| c.to_pep440_string())
                     .collect::<Vec<String>>()
-                    .join(", ")
+                    .join(","),
             ),
         }
+        if let Some(marker) = &self.marker {
+            // A space before the `;` is required after a url.
+            result.push_str(&format!(" ; {}", marker));
+        }
+        result
     }
     //    /// Return true if other is a subset of self.
     //    fn _fully_contains(&self, other: &Self) -> bool {
@@ -912,7 +1127,7 @@ pub struct Package {
 
 /// Similar to that used by Cargo.lock. Represents an exact package to download. // todo(Although
 /// todo the dependencies field isn't part of that/?)
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct LockPackage {
     // We use Strings here instead of types like Version to make it easier to
     // serialize and deserialize
@@ -923,23 +1138,69 @@ pub struct LockPackage {
     pub source: Option<String>,
     pub dependencies: Option<Vec<String>>,
     pub rename: Option<String>,
+    /// The dependency groups that need this package; `main` for the project's own dependencies.
+    pub groups: Option<Vec<String>>,
+    /// The project's extras that need this package.
+    pub extras: Option<Vec<String>>,
+    /// The environments that need this package, if not all of them.
+    pub marker: Option<String>,
+    /// Every file for this version, so we can install on any platform.
+    pub artifacts: Option<Vec<LockArtifact>>,
 }
 
+/// A wheel or source distribution we can install a locked package from.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LockArtifact {
+    pub filename: String,
+    pub url: String,
+    /// eg `sha256:2e1bfc...`
+    pub hash: String,
+    #[serde(rename = "requires-python")]
+    pub requires_python: Option<String>,
+}
+
+/// The lock file format we write. Lock files without a version are version 1.
+pub const LOCK_VERSION: u32 = 2;
+
 /// Modelled after [Cargo.lock](https://doc.rust-lang.org/cargo/guide/cargo-toml-vs-cargo-lock.html)
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct Lock {
+    #[serde(rename = "lock-version")]
+    pub lock_version: Option<u32>,
+    /// eg the `requirements-hash` of the `pyproject.toml` inputs it was made from.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
     pub package: Option<Vec<LockPackage>>,
-    //    pub metadata: Option<Vec<String>>, // ie checksums
-    pub metadata: HashMap<String, String>, // ie checksums
 }
 
 #[cfg(test)]
 pub mod tests {
+    use crate::index::JsonIndex;
     use ReqType::*;
     use VersionModifier::*;
 
     use super::*;
 
+    /// A locked package with only a name, version, and the names of its dependencies.
+    pub fn lock_pack(name: &str, version: &str, deps: &[&str]) -> LockPackage {
+        LockPackage {
+            id: 0,
+            name: name.into(),
+            version: version.into(),
+            source: None,
+            dependencies: Some(
+                deps.iter()
+                    .map(|d| format!("{} 1.0 test+{}/1.0", d, d))
+                    .collect(),
+            ),
+            rename: None,
+            groups: None,
+            extras: None,
+            marker: None,
+            artifacts: None,
+        }
+    }
+
     #[test]
     fn compat_caret() {
         let req1 = Constraint::new(Caret, Version::new(1, 2, 3));
@@ -995,92 +1256,141 @@ pub mod tests {
         assert_eq!(
             Version::from_str("19.3b0").unwrap(),
             Version {
-                major: 19,
-                minor: 3,
-                patch: 0,
-                extra_num: None,
-                modifier: Some((Beta, 0)),
+                release: vec![19, 3],
+                pre: Some((Beta, 0)),
+                ..Default::default()
             }
         );
 
         assert_eq!(
             Version::from_str("1.3.5rc0").unwrap(),
             Version {
-                major: 1,
-                minor: 3,
-                patch: 5,
-                extra_num: None,
-                modifier: Some((ReleaseCandidate, 0)),
+                release: vec![1, 3, 5],
+                pre: Some((ReleaseCandidate, 0)),
+                ..Default::default()
             }
         );
 
         assert_eq!(
             Version::from_str("1.3.5.11").unwrap(),
             Version {
-                major: 1,
-                minor: 3,
-                patch: 5,
-                extra_num: Some(11),
-                modifier: None,
+                release: vec![1, 3, 5, 11],
+                ..Default::default()
             }
         );
 
         assert_eq!(
             Version::from_str("5.2.5.11b3").unwrap(),
             Version {
-                major: 5,
-                minor: 2,
-                patch: 5,
-                extra_num: Some(11),
-                modifier: Some((Beta, 3)),
+                release: vec![5, 2, 5, 11],
+                pre: Some((Beta, 3)),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn version_parse_pep440() {
+        assert_eq!(
+            Version::from_str("1!2.0").unwrap(),
+            Version {
+                epoch: 1,
+                release: vec![2, 0],
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            Version::from_str("41.0.1.post1").unwrap(),
+            Version {
+                release: vec![41, 0, 1],
+                post: Some(1),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            Version::from_str("1.0.dev3").unwrap(),
+            Version {
+                release: vec![1, 0],
+                dev: Some(3),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            Version::from_str("1.4.0+cpu").unwrap(),
+            Version {
+                release: vec![1, 4, 0],
+                local: vec![LocalSegment::Str("cpu".into())],
+                ..Default::default()
             }
         );
+        assert_eq!(
+            Version::from_str("2019.10.10.1.2.3").unwrap().release,
+            vec![2019, 10, 10, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn version_normalize() {
+        // Alternate spellings, per PEP 440's normalization section.
+        let cases = &[
+            ("1.0alpha1", "1.0a1"),
+            ("1.0-BETA.2", "1.0b2"),
+            ("1.0c1", "1.0rc1"),
+            ("1.0-preview_3", "1.0rc3"),
+            ("1.0-1", "1.0.post1"),
+            ("1.0.rev2", "1.0.post2"),
+            ("1.0.post", "1.0.post0"),
+            ("1.0-dev", "1.0.dev0"),
+            ("v1.0", "1.0"),
+            ("1.0+Ubuntu-1", "1.0+ubuntu.1"),
+            ("1!2.0rc1.post2.dev3+cpu.7", "1!2.0rc1.post2.dev3+cpu.7"),
+        ];
+        for (raw, normalized) in cases {
+            assert_eq!(&Version::from_str(raw).unwrap().to_string2(), normalized);
+        }
+    }
+
+    #[test]
+    fn version_round_trip() {
+        for v in &["3.7", "1.4.0+cpu", "41.0.1.post1", "2!1.0.dev4", "0.9.8rc2"] {
+            assert_eq!(&Version::from_str(v).unwrap().to_string2(), v);
+        }
     }
 
     #[test]
     fn bad_version() {
+        // Note that `3-7` is a valid PEP 440 post release.
         assert_eq!(
-            Version::from_str("3-7"),
+            Version::from_str("3..7"),
             Err(DependencyError {
-                details: "Problem parsing version: 3-7".to_owned()
+                details: "Problem parsing version: 3..7".to_owned()
             })
         );
+        assert!(Version::from_str("1.0+").is_err());
+        assert!(Version::from_str("1.0-foo").is_err());
     }
 
     #[test]
     fn constraint_w_modifier() {
         let a = "!=2.3b3";
         let b = "^1.3.32rc1";
-        let c = "^1.3.32.dep1";
+        let c = "^1.3.32.dev1";
 
-        let req_a = Constraint::new(
-            Ne,
-            Version {
-                major: 2,
-                minor: 3,
-                patch: 0,
-                extra_num: None,
-                modifier: Some((Beta, 3)),
-            },
-        );
+        let req_a = Constraint::new(Ne, Version::from_str("2.3b3").unwrap());
         let req_b = Constraint::new(
             Caret,
             Version {
-                major: 1,
-                minor: 3,
-                patch: 32,
-                extra_num: None,
-                modifier: Some((ReleaseCandidate, 1)),
+                release: vec![1, 3, 32],
+                pre: Some((ReleaseCandidate, 1)),
+                ..Default::default()
             },
         );
         let req_c = Constraint::new(
             Caret,
             Version {
-                major: 1,
-                minor: 3,
-                patch: 32,
-                extra_num: None,
-                modifier: Some((Dep, 1)),
+                release: vec![1, 3, 32],
+                dev: Some(1),
+                ..Default::default()
             },
         );
 
@@ -1139,14 +1449,12 @@ pub mod tests {
 
     #[test]
     fn parse_req_pypi_w_extras() {
-        // tod: Make this handle extras.
         let actual = Req::from_str("pyOpenSSL (>=0.14) ; extra == 'security'", true).unwrap();
         let expected = Req {
             name: "pyOpenSSL".into(),
             constraints: vec![Constraint::new(Gte, Version::new(0, 14, 0))],
-            extra: Some("security".into()),
-            sys_platform: None,
-            python_version: None,
+            marker: Some(Marker::from_str("extra == 'security'").unwrap()),
+            url: None,
             install_with_extras: None,
         };
 
@@ -1159,9 +1467,10 @@ pub mod tests {
         let expected2 = Req {
             name: "pathlib2".into(),
             constraints: vec![],
-            extra: Some("test".into()),
-            sys_platform: None,
-            python_version: Some(Constraint::new(Exact, Version::new(2, 7, 0))),
+            marker: Some(
+                Marker::from_str("extra == \"test\" and python_version == \"2.7\"").unwrap(),
+            ),
+            url: None,
             install_with_extras: None,
         };
 
@@ -1174,9 +1483,10 @@ pub mod tests {
         let expected3 = Req {
             name: "win-unicode-console".into(),
             constraints: vec![Constraint::new(Gte, Version::new(0, 5, 0))],
-            extra: None,
-            sys_platform: Some((Exact, crate::Os::Windows32)),
-            python_version: Some(Constraint::new(Lt, Version::new(3, 6, 0))),
+            marker: Some(
+                Marker::from_str("sys_platform == \"win32\" and python_version < \"3.6\"").unwrap(),
+            ),
+            url: None,
             install_with_extras: None,
         };
 
@@ -1185,6 +1495,76 @@ pub mod tests {
         assert_eq!(actual3, expected3);
     }
 
+    #[test]
+    fn parse_req_pep508() {
+        let actual = Req::from_str(
+            "requests [security, tests] >= 2.8.1, == 2.8.* ; python_version < \"2.7\"",
+            true,
+        )
+        .unwrap();
+        assert_eq!(actual.name, "requests");
+        assert_eq!(
+            actual.install_with_extras,
+            Some(vec!["security".to_string(), "tests".to_string()])
+        );
+        assert_eq!(
+            actual.constraints,
+            vec![
+                Constraint::new(Gte, Version::new(2, 8, 1)),
+                Constraint::new(Wildcard, Version::new_short(2, 8)),
+            ]
+        );
+        assert!(actual.marker.is_some());
+
+        let actual2 = Req::from_str(
+            "pip @ https://github.com/pypa/pip/archive/1.3.1.zip#sha1=da9234ee ; os_name == 'nt'",
+            true,
+        )
+        .unwrap();
+        assert_eq!(actual2.name, "pip");
+        assert_eq!(
+            actual2.url,
+            Some("https://github.com/pypa/pip/archive/1.3.1.zip#sha1=da9234ee".into())
+        );
+        assert!(actual2.constraints.is_empty());
+        assert_eq!(
+            actual2.marker,
+            Some(Marker::from_str("os_name == 'nt'").unwrap())
+        );
+
+        assert!(Req::from_str("attrs (>=19.1", true).is_err());
+        assert!(Req::from_str("attrs; os_name ==", true).is_err());
+    }
+
+    #[test]
+    fn compat_pep440_operators() {
+        let compat = Constraint::from_str("~=1.4.5").unwrap();
+        assert_eq!(compat.type_, Compatible);
+        assert!(compat.is_compatible(&Version::new(1, 4, 5)));
+        assert!(compat.is_compatible(&Version::new(1, 4, 9)));
+        assert!(!compat.is_compatible(&Version::new(1, 5, 0)));
+        assert!(!compat.is_compatible(&Version::new(1, 4, 4)));
+
+        let compat2 = Constraint::from_str("~=2.2").unwrap();
+        assert!(compat2.is_compatible(&Version::new(2, 9, 1)));
+        assert!(!compat2.is_compatible(&Version::new(3, 0, 0)));
+
+        let wc = Constraint::from_str("==3.2.*").unwrap();
+        assert_eq!(wc.type_, Wildcard);
+        assert!(wc.is_compatible(&Version::new(3, 2, 7)));
+        assert!(wc.is_compatible(&Version::new_short(3, 2)));
+        assert!(!wc.is_compatible(&Version::new(3, 3, 0)));
+        assert_eq!(wc.to_string(false, true), "==3.2.*");
+
+        let ne_wc = Constraint::from_str("!=3.2.*").unwrap();
+        assert_eq!(ne_wc.type_, NeWildcard);
+        assert!(!ne_wc.is_compatible(&Version::new(3, 2, 7)));
+        assert!(ne_wc.is_compatible(&Version::new(3, 3, 0)));
+
+        let arb = Constraint::from_str("===1.0").unwrap();
+        assert!(arb.is_compatible(&Version::new(1, 0, 0)));
+    }
+
     #[test]
     fn parse_req_withvers() {
         let p = Req::from_str("bolt = \"3.1.4\"", false).unwrap();
@@ -1286,7 +1666,7 @@ pub mod tests {
         );
 
         //        assert_eq!(a._to_pip_string(), "package==3.3.6".to_string());
-        assert_eq!(a.to_cfg_string(), r#"package = "3.3.6""#.to_string());
+        assert_eq!(a.to_cfg_value(&JsonIndex::pypi()), r#""3.3.6""#.to_string());
     }
 
     #[test]
@@ -1303,8 +1683,20 @@ pub mod tests {
 
         //        assert_eq!(a._to_pip_string(), "'package!=2.7.4,>=3.7'".to_string());
         assert_eq!(
-            a.to_cfg_string(),
-            r#"package = "!=2.7.4, >=3.7.0""#.to_string()
+            a.to_cfg_value(&JsonIndex::pypi()),
+            r#""!=2.7.4, >=3.7.0""#.to_string()
+        );
+
+        let b = Req {
+            install_with_extras: Some(vec!["qtconsole".into()]),
+            ..Req::new(
+                "ipython".into(),
+                vec![Constraint::new(Caret, Version::new(7, 7, 0))],
+            )
+        };
+        assert_eq!(
+            b.to_cfg_value(&JsonIndex::pypi()),
+            r#"{version = "^7.7.0", extras = ["qtconsole"]}"#
         );
     }
 
@@ -1324,48 +1716,65 @@ pub mod tests {
 
     #[test]
     fn version_ordering_modded() {
-        let a = Version {
-            major: 4,
-            minor: 9,
-            patch: 4,
-            extra_num: Some(2),
-            modifier: None,
-        };
+        let a = Version::from_str("4.9.4.2").unwrap();
         let b = Version::new(4, 9, 4);
-
-        let c = Version {
-            major: 4,
-            minor: 9,
-            patch: 4,
-            extra_num: None,
-            modifier: Some((VersionModifier::ReleaseCandidate, 2)),
-        };
-        let d = Version {
-            major: 4,
-            minor: 9,
-            patch: 4,
-            extra_num: None,
-            modifier: Some((VersionModifier::ReleaseCandidate, 1)),
-        };
-        let e = Version {
-            major: 4,
-            minor: 9,
-            patch: 4,
-            extra_num: None,
-            modifier: Some((VersionModifier::Beta, 6)),
-        };
-        let f = Version {
-            major: 4,
-            minor: 9,
-            patch: 4,
-            extra_num: None,
-            modifier: Some((VersionModifier::Alpha, 7)),
-        };
+        let c = Version::from_str("4.9.4rc2").unwrap();
+        let d = Version::from_str("4.9.4rc1").unwrap();
+        let e = Version::from_str("4.9.4b6").unwrap();
+        let f = Version::from_str("4.9.4a7").unwrap();
         let g = Version::new(4, 9, 2);
 
         assert!(a > b && b > c && c > d && d > e && e > f && f > g);
     }
 
+    #[test]
+    fn version_ordering_pep440() {
+        // The example ordering given in PEP 440.
+        let ordered: Vec<Version> = [
+            "1.0.dev456",
+            "1.0a1",
+            "1.0a2.dev456",
+            "1.0a12.dev456",
+            "1.0a12",
+            "1.0b1.dev456",
+            "1.0b2",
+            "1.0b2.post345.dev456",
+            "1.0b2.post345",
+            "1.0rc1.dev456",
+            "1.0rc1",
+            "1.0",
+            "1.0+abc.5",
+            "1.0+abc.7",
+            "1.0+5",
+            "1.0.post456.dev34",
+            "1.0.post456",
+            "1.1.dev1",
+            "1!0.1",
+        ]
+        .iter()
+        .map(|v| Version::from_str(v).unwrap())
+        .collect();
+
+        for pair in ordered.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} < {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn version_eq_trailing_zeros() {
+        let a = Version::from_str("3.7").unwrap();
+        let b = Version::from_str("3.7.0.0").unwrap();
+        assert_eq!(a, b);
+
+        let mut set = std::collections::HashSet::new();
+        set.insert(a);
+        assert!(set.contains(&b));
+        assert_ne!(
+            Version::from_str("1.4.0+cpu").unwrap(),
+            Version::new(1, 4, 0)
+        );
+    }
+
     #[test]
     fn compat_rng() {
         let actual1 = Constraint::new(Gte, Version::new(5, 1, 0)).compatible_range();
@@ -1380,6 +1789,43 @@ pub mod tests {
         assert_eq!(expected2, actual2);
     }
 
+    #[test]
+    fn compat_rng_full_versions() {
+        let v = |s: &str| Version::from_str(s).unwrap();
+        let rng = |s: &str| Constraint::from_str(s).unwrap().compatible_range();
+
+        // Pre-releases, post-releases and dev releases.
+        assert_eq!(rng(">1.0rc1"), vec![(v("1.0rc2"), Version::_max())]);
+        assert_eq!(rng("<1.0rc0"), vec![(v("0"), v("1.0b999999"))]);
+        assert_eq!(rng("<1.0a0"), vec![(v("0"), v("0.999999.999999"))]);
+        assert_eq!(rng(">1.0.post1"), vec![(v("1.0.post2"), Version::_max())]);
+        assert_eq!(rng("<1.0.post0"), vec![(v("0"), v("1.0"))]);
+        assert_eq!(rng("<2.0.dev0"), vec![(v("0"), v("1.999999.999999"))]);
+        assert_eq!(rng("<2.0rc1.dev3"), vec![(v("0"), v("2.0rc1.dev2"))]);
+
+        // Epochs.
+        assert_eq!(
+            rng("!=2!1.0"),
+            vec![
+                (v("0"), v("2!0.999999.999999")),
+                (v("2!1.0.1"), v("2!999999"))
+            ]
+        );
+        assert_eq!(rng("^1!2.3"), vec![(v("1!2.3"), v("1!2.999999.999999"))]);
+        let reqs = vec![
+            Constraint::from_str(">=1!2.0").unwrap(),
+            Constraint::from_str("<1!3").unwrap(),
+        ];
+        assert_eq!(
+            intersection_many(&reqs),
+            vec![(v("1!2.0"), v("1!2.999999.999999"))]
+        );
+
+        // More than 3 release parts.
+        assert_eq!(rng(">1.2.3.4"), vec![(v("1.2.3.5"), Version::_max())]);
+        assert_eq!(rng("<1.2.3.0"), vec![(v("0"), v("1.2.2.999999"))]);
+    }
+
     #[test]
     fn intersections_empty() {
         let reqs1 = vec![
@@ -1490,39 +1936,37 @@ pub mod tests {
     //    }
 
     #[test]
-    fn python_version_from_warehouse() {
-        let a1 = Constraint::from_wh_py_vers("py3").unwrap();
-        let a2 = Constraint::from_wh_py_vers("cp35.cp36.cp37.cp38").unwrap();
-        let a3 = Constraint::from_wh_py_vers("cp26").unwrap();
-        let a4 = Constraint::from_wh_py_vers("py2.py3").unwrap();
-        let a5 = Constraint::from_wh_py_vers("pp36").unwrap();
-        let a6 = Constraint::from_wh_py_vers("any").unwrap();
-        let a7 = Constraint::from_wh_py_vers("2.7").unwrap();
-
-        assert_eq!(a1, vec![Constraint::new(Gte, Version::new(3, 0, 0))]);
+    fn pep508_strings() {
         assert_eq!(
-            a2,
-            vec![
-                //                Constraint::new(Gte, Version::new(3, 5, 0)),
-                //                Constraint::new(Lte, Version::new(3, 8, 0)),
-                Constraint::new(Exact, Version::new(3, 5, 0)),
-                Constraint::new(Exact, Version::new(3, 6, 0)),
-                Constraint::new(Exact, Version::new(3, 7, 0)),
-                Constraint::new(Exact, Version::new(3, 8, 0)),
-            ]
+            Constraint::new(Caret, Version::new(1, 2, 3)).to_pep440_string(),
+            ">=1.2.3,<2.0.0"
         );
-        assert_eq!(a3, vec![Constraint::new(Exact, Version::new(2, 6, 0))]);
-
         assert_eq!(
-            a4,
-            vec![
-                Constraint::new(Lte, Version::new(2, 10, 0)),
-                Constraint::new(Gte, Version::new(3, 0, 0)),
-            ]
+            Constraint::new(Caret, Version::new(0, 3, 1)).to_pep440_string(),
+            ">=0.3.1,<0.4.0"
+        );
+        assert_eq!(
+            Constraint::new(Tilde, Version::new(1, 2, 0)).to_pep440_string(),
+            ">=1.2.0,<1.3.0"
+        );
+        assert_eq!(
+            Constraint::new(Gte, Version::new(2, 0, 0)).to_pep440_string(),
+            ">=2.0.0"
         );
 
-        assert_eq!(a5, vec![Constraint::new(Exact, Version::new(3, 6, 0))]);
-        assert_eq!(a6, vec![Constraint::new(Gte, Version::new(2, 0, 0))]);
-        assert_eq!(a7, vec![Constraint::new(Exact, Version::new(2, 7, 0))]);
+        let req = Req::from_str(
+            r#"requests[security] (>=2.8.1,!=2.9.0) ; python_version < "3.8""#,
+            true,
+        )
+        .unwrap();
+        let pep508 = req.to_pep508_string();
+        assert_eq!(Req::from_str(&pep508, true).unwrap(), req);
+        assert!(pep508.starts_with("requests[security]>=2.8.1,!=2.9.0 ; "));
+
+        let req = Req::new(
+            "numpy".into(),
+            vec![Constraint::new(Caret, Version::new(1, 16, 4))],
+        );
+        assert_eq!(req.to_pep508_string(), "numpy>=1.16.4,<2.0.0");
     }
 }
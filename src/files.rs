@@ -1,5 +1,4 @@
-use crate::dep_types::Constraint;
-use crate::{dep_types::Req, util, Config};
+use crate::{dep_types::Req, index::PackageIndex, toml_doc::Document, util, Config};
 use crossterm::Color;
 use regex::Regex;
 use serde::Deserialize;
@@ -10,10 +9,15 @@ use std::io::{BufRead, BufReader};
 /// This nested structure is required based on how the `toml` crate handles dots.
 #[derive(Debug, Deserialize)]
 pub struct Pyproject {
-    pub tool: Tool,
+    pub project: Option<Project>,
+    /// Named groups of dependencies, eg `dev = ["pytest>=5"]`.
+    /// https://www.python.org/dev/peps/pep-0735/
+    #[serde(rename = "dependency-groups")]
+    pub dependency_groups: Option<HashMap<String, Vec<GroupItem>>>,
+    pub tool: Option<Tool>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct Tool {
     pub pypackage: Option<Pypackage>,
     pub poetry: Option<Poetry>,
@@ -34,6 +38,63 @@ pub enum DepComponentWrapperPoetry {
     B(DepComponentPoetry),
 }
 
+/// The standard `[project]` table.
+/// https://www.python.org/dev/peps/pep-0621/
+#[derive(Debug, Deserialize)]
+pub struct Project {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub description: Option<String>,
+    pub readme: Option<ProjectFile>,
+    #[serde(rename = "requires-python")]
+    pub requires_python: Option<String>,
+    pub license: Option<ProjectFile>,
+    pub authors: Option<Vec<ProjectPerson>>,
+    pub maintainers: Option<Vec<ProjectPerson>>,
+    pub keywords: Option<Vec<String>>,
+    pub classifiers: Option<Vec<String>>,
+    pub urls: Option<HashMap<String, String>>,
+    pub scripts: Option<HashMap<String, String>>,
+    #[serde(rename = "gui-scripts")]
+    pub gui_scripts: Option<HashMap<String, String>>,
+    #[serde(rename = "entry-points")]
+    pub entry_points: Option<HashMap<String, HashMap<String, String>>>,
+    /// PEP 508 strings, eg `"requests[security] >= 2.8.1"`
+    pub dependencies: Option<Vec<String>>,
+    #[serde(rename = "optional-dependencies")]
+    pub optional_dependencies: Option<HashMap<String, Vec<String>>>,
+    /// Fields left out of this table, for another tool to provide.
+    pub dynamic: Option<Vec<String>>,
+}
+
+#[serde(untagged)]
+#[derive(Debug, Deserialize)]
+/// `readme` and `license` may be a string, or a table with a `file` or `text` key.
+pub enum ProjectFile {
+    A(String),
+    B {
+        file: Option<String>,
+        text: Option<String>,
+    },
+}
+
+#[serde(untagged)]
+#[derive(Debug, Deserialize)]
+/// A PEP 508 string, or a table including another group's dependencies.
+pub enum GroupItem {
+    A(String),
+    B {
+        #[serde(rename = "include-group")]
+        include_group: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProjectPerson {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct DepComponent {
     #[serde(rename = "version")]
@@ -73,15 +134,18 @@ pub struct Pypackage {
     pub repository: Option<String>,
     pub repo_url: Option<String>,
     pub package_url: Option<String>,
+    pub repositories: Option<HashMap<String, String>>,
     pub readme_filename: Option<String>,
     pub entry_points: Option<HashMap<String, Vec<String>>>,
     pub console_scripts: Option<Vec<String>>,
+    pub allow_renames: Option<bool>,
+    pub index_url: Option<String>,
+    pub index_type: Option<String>,
 
     pub dependencies: Option<HashMap<String, DepComponentWrapper>>,
-    //    pub dependencies: Option<HashMap<String, String>>,
-    //
-    pub dev_dependencies: Option<HashMap<String, String>>,
-    pub extras: Option<HashMap<String, String>>,
+    pub dev_dependencies: Option<HashMap<String, DepComponentWrapper>>,
+    /// PEP 508 requirements for each extra, eg `test = ["pytest>=5", "nose"]`
+    pub extras: Option<HashMap<String, Vec<String>>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -100,121 +164,176 @@ pub struct Poetry {
     pub packages: Option<Vec<String>>,
     pub include: Option<Vec<String>>,
     pub exclude: Option<Vec<String>>,
-    pub extras: Option<HashMap<String, String>>,
+    /// The names of optional dependencies each extra installs.
+    pub extras: Option<HashMap<String, Vec<String>>>,
 
     pub dependencies: Option<HashMap<String, DepComponentWrapperPoetry>>,
-    // todo: Can't find dets on poetry docs, but apparently exists
-    //    dev_dependencies: Option<HashMap<String, String>>,
+    #[serde(rename = "dev-dependencies")]
+    pub dev_dependencies: Option<HashMap<String, DepComponentWrapperPoetry>>,
+    /// Dependency groups, from Poetry 1.2, eg `[tool.poetry.group.docs.dependencies]`
+    pub group: Option<HashMap<String, PoetryGroup>>,
     // todo: Include these
     //    pub source: Option<HashMap<String, String>>,
     pub scripts: Option<HashMap<String, String>>,
-    //    pub extras: Option<HashMap<String, String>>,
 }
 
-/// Write dependencies to pyproject.toml. If an entry for tha = true;t package already exists, ask if
-/// we should update the version. Assume we've already parsed the config, and are only
-/// adding new reqs, or ones with a changed version.
-pub fn add_reqs_to_cfg(filename: &str, added: &[Req]) {
-    let mut result = String::new();
-    let data = fs::read_to_string(filename)
-        .expect("Unable to read pyproject.toml while attempting to add a dependency");
+#[derive(Debug, Deserialize)]
+pub struct PoetryGroup {
+    pub dependencies: Option<HashMap<String, DepComponentWrapperPoetry>>,
+}
 
-    let mut in_dep = false;
-    let sect_re = Regex::new(r"^\[.*\]$").unwrap();
+/// Where a `pyproject.toml` lists its dependencies.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DepsTable {
+    /// The `dependencies` array in `[project]`, of PEP 508 strings.
+    Project,
+    /// A table of `name = "constraint"` items, eg `tool.pypackage.dependencies`.
+    Keys(&'static str),
+}
 
-    // We collect lines here so we can start the index at a non-0 point.
-    let lines_vec: Vec<&str> = data.lines().collect();
+/// Find where dependencies are listed: In `[project]` if it has that table, and doesn't list
+/// them as `dynamic`, then `[tool.pypackage.dependencies]`, then Poetry's table.
+fn find_deps_table(data: &str) -> DepsTable {
+    let parsed = data.parse::<toml::Value>().ok();
+    let get = |path: &[&str]| -> Option<&toml::Value> {
+        let mut value = parsed.as_ref()?;
+        for part in path.iter() {
+            value = value.get(part)?;
+        }
+        Some(value)
+    };
+
+    let dynamic_deps = get(&["project", "dynamic"])
+        .and_then(|d| d.as_array())
+        .map(|d| d.iter().any(|f| f.as_str() == Some("dependencies")))
+        .unwrap_or(false);
+    if get(&["project"]).is_some() && !dynamic_deps {
+        DepsTable::Project
+    } else if get(&["tool", "pypackage", "dependencies"]).is_none()
+        && get(&["tool", "poetry"]).is_some()
+    {
+        DepsTable::Keys("tool.poetry.dependencies")
+    } else {
+        DepsTable::Keys("tool.pypackage.dependencies")
+    }
+}
 
-    for (i, line) in data.lines().enumerate() {
-        result.push_str(line);
-        result.push_str("\n");
-        if line == "[tool.pypackage.dependencies]" {
-            in_dep = true;
-            continue;
+/// The package names of a `[project]` dependency array's elements, from their TOML text.
+fn project_dep_names(items: &[String]) -> Vec<Option<String>> {
+    items
+        .iter()
+        .map(|item| {
+            let value = toml::from_str::<toml::Value>(&format!("x = {}", item)).ok()?;
+            Req::from_str(value.get("x")?.as_str()?, true)
+                .ok()
+                .map(|r| r.name)
+        })
+        .collect()
+}
+
+fn read_cfg_doc(filename: &str) -> (String, Document) {
+    let data = fs::read_to_string(filename).expect("Unable to read pyproject.toml");
+    match Document::parse(&data) {
+        Ok(doc) => (data, doc),
+        Err(e) => {
+            util::abort(&e.details);
+            unreachable!()
         }
+    }
+}
 
-        if in_dep {
-            let mut ready_to_insert = true;
-            // Check if this is the last non-blank line in the dependencies section.
-            for i2 in i..lines_vec.len() {
-                let line2 = lines_vec[i2];
-                // We've hit the end of the section or file without encountering a non-empty line.
-                if sect_re.is_match(line2) || i2 == lines_vec.len() - 1 {
-                    break;
-                }
-                if !line2.is_empty() {
-                    // We haven't hit the end of the section yet; don't add the new reqs here.
-                    ready_to_insert = false;
-                    break;
+/// Write dependencies to pyproject.toml, where its other dependencies are listed. If an entry
+/// for a package already exists, update its version, and leave other settings like extras.
+/// Comments and formatting are left as they were.
+pub fn add_reqs_to_cfg(filename: &str, added: &[Req], index: &dyn PackageIndex) {
+    let (data, mut doc) = read_cfg_doc(filename);
+
+    match find_deps_table(&data) {
+        DepsTable::Project => {
+            for req in added.iter() {
+                let value =
+                    toml::Value::String(req.pin_latest(index).to_pep508_string()).to_string();
+                let names = project_dep_names(
+                    &doc.array_items("project", "dependencies")
+                        .unwrap_or_default(),
+                );
+                let existing = names.iter().position(|n| {
+                    n.as_ref()
+                        .map(|n| util::compare_names(n, &req.name))
+                        .unwrap_or(false)
+                });
+                match existing {
+                    Some(i) => doc.array_replace("project", "dependencies", i, &value),
+                    None => doc.array_push("project", "dependencies", &value),
                 }
             }
-            if ready_to_insert {
-                for req in added {
-                    result.push_str(&req.to_cfg_string());
-                    result.push_str("\n");
+        }
+        DepsTable::Keys(table) => {
+            for req in added.iter() {
+                let req = req.pin_latest(index);
+                let existing = doc
+                    .keys(table)
+                    .into_iter()
+                    .find(|k| util::compare_names(k, &req.name));
+                let version = toml::Value::String(
+                    req.constraints
+                        .iter()
+                        .map(|c| c.to_string(true, false))
+                        .collect::<Vec<String>>()
+                        .join(", "),
+                )
+                .to_string();
+                let sub_table = format!("{}.{}", table, req.name);
+
+                match existing {
+                    Some(key) => {
+                        if !doc.set_inline(table, &key, "version", &version) {
+                            doc.set(table, &key, &req.to_cfg_value(index));
+                        }
+                    }
+                    // eg `[tool.poetry.dependencies.numpy]`
+                    None if doc.has_table(&sub_table) => doc.set(&sub_table, "version", &version),
+                    None => doc.set(table, &req.name, &req.to_cfg_value(index)),
                 }
             }
         }
     }
 
-    fs::write(filename, result)
-        .expect("Unable to read pyproject.toml while attempting to add a dependency");
+    fs::write(filename, doc.to_string())
+        .expect("Unable to write pyproject.toml while attempting to add a dependency");
 }
 
-/// Remove dependencies from pyproject.toml.
+/// Remove dependencies from pyproject.toml, leaving comments and formatting as they were.
 pub fn remove_reqs_from_cfg(filename: &str, reqs: &[String]) {
-    let mut result = String::new();
-    let data = fs::read_to_string(filename)
-        .expect("Unable to read pyproject.toml while attempting to add a dependency");
-
-    let mut in_dep = false;
-    let sect_re = Regex::new(r"^\[.*\]$").unwrap();
-
-    for line in data.lines() {
-        if line.starts_with('#') || line.is_empty() {
-            // todo handle mid-line comements
-            result.push_str(line);
-            result.push_str("\n");
-            continue;
-        }
-
-        if line == "[tool.pypackage.dependencies]" {
-            in_dep = true;
-            result.push_str(line);
-            result.push_str("\n");
-            continue;
+    let (data, mut doc) = read_cfg_doc(filename);
+    let matches = |name: &str| reqs.iter().any(|r| util::compare_names(r, name));
+
+    match find_deps_table(&data) {
+        DepsTable::Project => {
+            let names = project_dep_names(
+                &doc.array_items("project", "dependencies")
+                    .unwrap_or_default(),
+            );
+            // Remove from the end, so the indexes of the others don't change.
+            for (i, name) in names.iter().enumerate().rev() {
+                if name.as_ref().map(|n| matches(n)).unwrap_or(false) {
+                    doc.array_remove("project", "dependencies", i);
+                }
+            }
         }
-
-        if in_dep {
-            if sect_re.is_match(line) {
-                in_dep = false;
+        DepsTable::Keys(table) => {
+            for key in doc.keys(table).iter().filter(|k| matches(k)) {
+                doc.remove(table, key);
             }
-            // todo: handle comments
-            let req_line = match Req::from_str(line, false) {
-                Ok(r) => r,
-                Err(_) => {
-                    util::abort(&format!(
-                        "Can't parse this line in `pyproject.toml`: {}",
-                        line
-                    ));
-                    Req::new(String::new(), vec![]) // todo temp to allow compiling
-                }
-            };
-
-            if reqs
-                .iter()
-                .map(|r| r.to_lowercase())
-                .any(|x| x == req_line.name.to_lowercase())
-            {
-                continue; // ie don't append this line to result.
+            // Packages specified in their own table, eg `[tool.poetry.dependencies.numpy]`
+            for name in reqs.iter() {
+                doc.remove(table, name);
             }
         }
-        result.push_str(line);
-        result.push_str("\n");
     }
 
-    fs::write(filename, result)
-        .expect("Unable to write to pyproject.toml while attempting to add a dependency");
+    fs::write(filename, doc.to_string())
+        .expect("Unable to write to pyproject.toml while attempting to remove a dependency");
 }
 
 pub fn parse_req_dot_text(cfg: &mut Config) {
@@ -256,6 +375,7 @@ pub fn parse_pipfile(cfg: &mut Config) {
 
     let mut in_metadata = false;
     let mut in_dep = false;
+    let mut in_dev_dep = false;
     let mut _in_extras = false;
 
     let sect_re = Regex::new(r"\[.*\]").unwrap();
@@ -271,21 +391,25 @@ pub fn parse_pipfile(cfg: &mut Config) {
             if &l == "[[source]]" {
                 in_metadata = true;
                 in_dep = false;
+                in_dev_dep = false;
                 _in_extras = false;
                 continue;
             } else if &l == "[packages]" {
                 in_metadata = false;
                 in_dep = true;
+                in_dev_dep = false;
                 _in_extras = false;
                 continue;
             } else if &l == "[dev-packages]" {
                 in_metadata = false;
                 in_dep = false;
-                // todo
+                in_dev_dep = true;
+                _in_extras = false;
                 continue;
             } else if sect_re.is_match(&l) {
                 in_metadata = false;
                 in_dep = false;
+                in_dev_dep = false;
                 _in_extras = false;
                 continue;
             }
@@ -303,11 +427,15 @@ pub fn parse_pipfile(cfg: &mut Config) {
                         cfg.homepage = Some(n.as_str().to_string());
                     }
                 }
-            } else if in_dep && !l.is_empty() {
+            } else if (in_dep || in_dev_dep) && !l.is_empty() {
                 match Req::from_str(&l, false) {
                     Ok(r) => {
-                        cfg.reqs.push(r.clone());
-                        util::print_color(&format!("Added {} from Pipfile", r.name), Color::Green)
+                        util::print_color(&format!("Added {} from Pipfile", r.name), Color::Green);
+                        if in_dev_dep {
+                            cfg.groups.entry("dev".into()).or_default().push(r);
+                        } else {
+                            cfg.reqs.push(r);
+                        }
                     }
                     Err(_) => util::print_color(
                         &format!("Problem parsing {} from Pipfile", l),
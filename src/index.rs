@@ -0,0 +1,835 @@
+//! Package indexes we find and download packages from: The PyPi Warehouse JSON API (the
+//! default), a PEP 503 simple index such as a private mirror, or a local directory of wheels
+//! and sdists, like pip's `--find-links`.
+//! https://www.python.org/dev/peps/pep-0503/
+
+use crate::{
+    cache,
+    dep_resolution::{WarehouseDigests, WarehouseRelease},
+    dep_types::{DependencyError, Version},
+};
+
+use regex::Regex;
+use reqwest::Url;
+use ring::digest;
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::PathBuf, str::FromStr};
+
+pub const PYPI_URL: &str = "https://pypi.org/pypi";
+
+/// What an index knows about a package: Each version, and the files available for it.
+#[derive(Clone, Debug)]
+pub struct IndexProject {
+    /// The name as the index formats it, eg `IPython` instead of `ipython`.
+    pub name: String,
+    /// The version the index considers the latest, if it tells us.
+    pub latest: Option<Version>,
+    pub releases: Vec<(Version, Vec<WarehouseRelease>)>,
+}
+
+pub trait PackageIndex {
+    /// Find the versions and files available for a package.
+    fn project(&self, name: &str) -> Result<IndexProject, DependencyError>;
+
+    /// Where a package version comes from, for the lock file, eg
+    /// `pypi+https://pypi.org/pypi/idna/2.8/json`. It mustn't contain whitespace.
+    fn source(&self, name: &str, version: &Version) -> String;
+
+    /// Download a file this index listed.
+    fn download(&self, release: &WarehouseRelease) -> Result<Vec<u8>, DependencyError> {
+        fetch(&release.url)
+    }
+}
+
+/// Normalize a name as PEP 503 specifies, for use in index URLs. eg `Django_Rest.Framework`
+/// becomes `django-rest-framework`.
+pub fn normalize_name(name: &str) -> String {
+    Regex::new(r"[-_.]+")
+        .unwrap()
+        .replace_all(name, "-")
+        .to_lowercase()
+}
+
+/// Read a file from a `file://` url, or download it.
+pub fn fetch(url: &str) -> Result<Vec<u8>, DependencyError> {
+    if url.starts_with("file://") {
+        let path = match Url::parse(url).ok().and_then(|u| u.to_file_path().ok()) {
+            Some(p) => p,
+            None => return Err(DependencyError::new(&format!("Invalid file url: {}", url))),
+        };
+        return fs::read(&path)
+            .map_err(|e| DependencyError::new(&format!("Problem reading {:?}: {}", path, e)));
+    }
+
+    if cache::is_offline() {
+        return Err(cache::offline_error(url));
+    }
+    let mut resp = reqwest::get(url)?;
+    if !resp.status().is_success() {
+        return Err(DependencyError::new(&format!(
+            "Problem downloading {} ({})",
+            url,
+            resp.status()
+        )));
+    }
+    let mut data = vec![];
+    resp.copy_to(&mut data)?;
+    Ok(data)
+}
+
+/// Request part of a file, eg `bytes=-1000` for the last 1000 bytes. Returns the data, and the
+/// file's total length, or `None` if the server doesn't support range requests.
+pub fn fetch_range(url: &str, range: &str) -> Result<Option<(Vec<u8>, u64)>, DependencyError> {
+    if cache::is_offline() {
+        return Err(cache::offline_error(url));
+    }
+    let mut resp = reqwest::Client::new()
+        .get(url)
+        .header(reqwest::header::RANGE, range)
+        .send()?;
+    if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return Ok(None);
+    }
+
+    // eg `bytes 1000-1999/2000`
+    let total = resp
+        .headers()
+        .get(reqwest::header::CONTENT_RANGE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.rsplit('/').next())
+        .and_then(|t| t.parse::<u64>().ok());
+    let total = match total {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+
+    let mut data = vec![];
+    resp.copy_to(&mut data)?;
+    Ok(Some((data, total)))
+}
+
+/// Fetch a page of index data, storing it in the cache, and reading it from there in offline mode.
+fn fetch_page(url: &str, name: &str, accept: Option<&str>) -> Result<String, DependencyError> {
+    if cache::is_offline() {
+        return match cache::read_index_page(url) {
+            Some(t) => Ok(t),
+            None => Err(cache::offline_error(&format!("Package info for {}", name))),
+        };
+    }
+
+    let mut request = reqwest::Client::new().get(url);
+    if let Some(a) = accept {
+        request = request.header(reqwest::header::ACCEPT, a);
+    }
+    let mut resp = request.send()?;
+    if !resp.status().is_success() {
+        return Err(DependencyError::new(&format!(
+            "Unable to find {} at {} ({})",
+            name,
+            url,
+            resp.status()
+        )));
+    }
+    let text = resp.text()?;
+    cache::write_index_page(url, &text);
+    Ok(text)
+}
+
+/// Parse a wheel or sdist filename into the package name, version, package type, and the Python
+/// version it's for, using the values Warehouse does for the last two. eg
+/// `numpy-1.17.2-cp37-cp37m-manylinux1_x86_64.whl` or `saturn-0.3.1.tar.gz`.
+/// https://www.python.org/dev/peps/pep-0427/#file-name-convention
+pub fn parse_filename(filename: &str) -> Option<(String, Version, String, String)> {
+    if filename.ends_with(".whl") {
+        let parts: Vec<&str> = filename.trim_end_matches(".whl").split('-').collect();
+        if parts.len() != 5 && parts.len() != 6 {
+            return None;
+        }
+        let version = Version::from_str(parts[1]).ok()?;
+        return Some((
+            parts[0].to_owned(),
+            version,
+            "bdist_wheel".to_owned(),
+            parts[parts.len() - 3].to_owned(),
+        ));
+    }
+
+    let stem = [".tar.gz", ".tgz", ".tar.bz2", ".zip"]
+        .iter()
+        .find(|ext| filename.ends_with(*ext))
+        .map(|ext| &filename[..filename.len() - ext.len()])?;
+    // Names may contain dashes, but versions can't.
+    let mut parts = stem.rsplitn(2, '-');
+    let version = Version::from_str(parts.next()?).ok()?;
+    let name = parts.next()?;
+    Some((
+        name.to_owned(),
+        version,
+        "sdist".to_owned(),
+        "source".to_owned(),
+    ))
+}
+
+/// A file listed by a simple index, or found in a local one.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SimpleFile {
+    pub filename: String,
+    pub url: String,
+    /// Hash name, eg `sha256`, to hex digest.
+    pub hashes: HashMap<String, String>,
+    pub requires_python: Option<String>,
+    /// The reason, if yanked. This may be empty.
+    pub yanked: Option<String>,
+    /// If the index serves the file's metadata separately.
+    pub core_metadata: bool,
+    /// Hash name to hex digest, for the metadata file, if the index gives them.
+    pub core_metadata_hashes: HashMap<String, String>,
+}
+
+/// Build a release from a file an index lists, if it's for the package named.
+fn release_from_file(name: &str, file: SimpleFile) -> Option<(Version, WarehouseRelease)> {
+    let (file_name, version, packagetype, python_version) = parse_filename(&file.filename)?;
+    if normalize_name(&file_name) != normalize_name(name) {
+        return None;
+    }
+
+    let hash = |n: &str| file.hashes.get(n).cloned().unwrap_or_default();
+    Some((
+        version,
+        WarehouseRelease {
+            has_sig: false,
+            digests: WarehouseDigests {
+                md5: hash("md5"),
+                sha256: hash("sha256"),
+            },
+            packagetype,
+            python_version,
+            requires_python: file.requires_python.filter(|r| !r.trim().is_empty()),
+            url: file.url,
+            dependencies: None,
+            yanked: file.yanked.is_some(),
+            yanked_reason: file.yanked.filter(|r| !r.is_empty()),
+            core_metadata: file.core_metadata,
+            core_metadata_sha256: file.core_metadata_hashes.get("sha256").cloned(),
+            filename: file.filename,
+        },
+    ))
+}
+
+/// Decode the HTML character references we expect in simple index attributes, eg
+/// `data-requires-python="&gt;=3.6"`.
+fn unescape_html(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#34;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Parse the HTML variant of a simple index's project page: An anchor for each file, with the
+/// hash in the url fragment, and optional `data-requires-python` and `data-yanked` attributes.
+/// https://www.python.org/dev/peps/pep-0503/
+pub fn parse_simple_html(page: &str, page_url: &str) -> Result<Vec<SimpleFile>, DependencyError> {
+    let mut base = Url::parse(page_url)
+        .map_err(|_| DependencyError::new(&format!("Invalid index url: {}", page_url)))?;
+
+    let tag_re = Regex::new(r"(?is)<(a|base)\s([^>]*)>(?:(.*?)</a>)?").unwrap();
+    // Attributes may be quoted with either type of quote, unquoted, or have no value.
+    let attr_re =
+        Regex::new(r#"(?s)([\w-]+)(?:\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+)))?"#).unwrap();
+
+    let mut result = vec![];
+    for caps in tag_re.captures_iter(page) {
+        let mut attrs = HashMap::new();
+        for a in attr_re.captures_iter(caps.get(2).unwrap().as_str()) {
+            let value = a
+                .get(2)
+                .or_else(|| a.get(3))
+                .or_else(|| a.get(4))
+                .map(|v| unescape_html(v.as_str()))
+                .unwrap_or_default();
+            attrs.insert(a.get(1).unwrap().as_str().to_lowercase(), value);
+        }
+        let href = match attrs.get("href") {
+            Some(h) => h,
+            None => continue,
+        };
+
+        // A `base` tag changes what relative links are relative to.
+        if caps.get(1).unwrap().as_str().to_lowercase() == "base" {
+            if let Ok(b) = base.join(href) {
+                base = b;
+            }
+            continue;
+        }
+
+        let mut url = match base.join(href) {
+            Ok(u) => u,
+            Err(_) => continue,
+        };
+        // Indexes may include a hash in the url fragment, eg `#sha256=...`
+        let mut hashes = HashMap::new();
+        if let Some(frag) = url.fragment() {
+            let mut parts = frag.splitn(2, '=');
+            if let (Some(n), Some(h)) = (parts.next(), parts.next()) {
+                hashes.insert(n.to_lowercase(), h.to_owned());
+            }
+        }
+        url.set_fragment(None);
+
+        // The text should be the filename; fall back to the last part of the url.
+        let text = unescape_html(caps.get(3).map(|t| t.as_str().trim()).unwrap_or(""));
+        let filename = if text.is_empty() || text.contains('<') {
+            match url.path_segments().and_then(|mut s| s.next_back()) {
+                Some(f) => f.to_owned(),
+                None => continue,
+            }
+        } else {
+            text
+        };
+
+        // PEP 714 renamed this from `data-dist-info-metadata`. The value may be a hash.
+        let metadata_attr = ["data-core-metadata", "data-dist-info-metadata"]
+            .iter()
+            .filter_map(|a| attrs.get(*a))
+            .find(|v| *v != "false");
+        let mut core_metadata_hashes = HashMap::new();
+        if let Some(v) = metadata_attr {
+            let mut parts = v.splitn(2, '=');
+            if let (Some(n), Some(h)) = (parts.next(), parts.next()) {
+                core_metadata_hashes.insert(n.to_lowercase(), h.to_owned());
+            }
+        }
+
+        result.push(SimpleFile {
+            filename,
+            url: url.to_string(),
+            hashes,
+            requires_python: attrs.get("data-requires-python").cloned(),
+            yanked: attrs.get("data-yanked").cloned(),
+            core_metadata: metadata_attr.is_some(),
+            core_metadata_hashes,
+        });
+    }
+    Ok(result)
+}
+
+#[derive(Debug, Deserialize)]
+struct SimpleJsonMeta {
+    #[serde(rename = "api-version")]
+    api_version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SimpleJsonFile {
+    filename: String,
+    url: String,
+    #[serde(default)]
+    hashes: HashMap<String, String>,
+    #[serde(rename = "requires-python")]
+    requires_python: Option<String>,
+    // Either a bool, or a string with the reason.
+    #[serde(default)]
+    yanked: serde_json::Value,
+    // Either a bool, or the metadata file's hashes.
+    #[serde(default, rename = "core-metadata")]
+    core_metadata: serde_json::Value,
+    #[serde(default, rename = "dist-info-metadata")]
+    dist_info_metadata: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct SimpleJsonProject {
+    meta: SimpleJsonMeta,
+    files: Vec<SimpleJsonFile>,
+}
+
+/// Parse the JSON variant of a simple index's project page.
+/// https://www.python.org/dev/peps/pep-0691/
+pub fn parse_simple_json(page: &str, page_url: &str) -> Result<Vec<SimpleFile>, DependencyError> {
+    let base = Url::parse(page_url)
+        .map_err(|_| DependencyError::new(&format!("Invalid index url: {}", page_url)))?;
+    let data: SimpleJsonProject = serde_json::from_str(page).map_err(|e| {
+        DependencyError::new(&format!(
+            "Problem parsing index data from {}: {}",
+            page_url, e
+        ))
+    })?;
+
+    // Clients must reject major versions they don't understand.
+    if !data.meta.api_version.starts_with("1.") {
+        return Err(DependencyError::new(&format!(
+            "Unsupported simple API version from {}: {}",
+            page_url, data.meta.api_version
+        )));
+    }
+
+    Ok(data
+        .files
+        .into_iter()
+        .filter_map(|f| {
+            let url = base.join(&f.url).ok()?;
+            let metadata = [f.core_metadata, f.dist_info_metadata]
+                .iter()
+                .find(|m| m.is_object() || **m == serde_json::Value::Bool(true))
+                .cloned();
+            let core_metadata_hashes = match metadata {
+                Some(serde_json::Value::Object(ref hashes)) => hashes
+                    .iter()
+                    .filter_map(|(n, h)| Some((n.to_lowercase(), h.as_str()?.to_owned())))
+                    .collect(),
+                _ => HashMap::new(),
+            };
+            Some(SimpleFile {
+                filename: f.filename,
+                url: url.to_string(),
+                hashes: f.hashes,
+                requires_python: f.requires_python,
+                yanked: match f.yanked {
+                    serde_json::Value::Bool(true) => Some(String::new()),
+                    serde_json::Value::String(reason) => Some(reason),
+                    _ => None,
+                },
+                core_metadata: metadata.is_some(),
+                core_metadata_hashes,
+            })
+        })
+        .collect())
+}
+
+/// Group files by version, oldest first.
+fn group_releases(
+    files: Vec<(Version, WarehouseRelease)>,
+) -> Vec<(Version, Vec<WarehouseRelease>)> {
+    let mut result: Vec<(Version, Vec<WarehouseRelease>)> = vec![];
+    for (version, release) in files.into_iter() {
+        match result.iter_mut().find(|(v, _)| *v == version) {
+            Some((_, releases)) => releases.push(release),
+            None => result.push((version, vec![release])),
+        }
+    }
+    result.sort_by(|a, b| a.0.cmp(&b.0));
+    result
+}
+
+#[derive(Debug, Deserialize)]
+struct WarehouseInfo {
+    name: String, // Pulling this ensure proper capitalization
+    version: String,
+}
+
+/// Only deserialize the info we need to resolve dependencies etc.
+#[derive(Debug, Deserialize)]
+struct WarehouseData {
+    info: WarehouseInfo,
+    releases: HashMap<String, Vec<WarehouseRelease>>,
+}
+
+/// The Warehouse JSON API, which PyPi provides.
+/// https://warehouse.pypa.io/api-reference/json/
+pub struct JsonIndex {
+    url: String,
+}
+
+impl JsonIndex {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    pub fn pypi() -> Self {
+        Self::new(PYPI_URL)
+    }
+}
+
+impl PackageIndex for JsonIndex {
+    fn source(&self, name: &str, version: &Version) -> String {
+        format!("pypi+{}/{}/{}/json", self.url, name, version.to_string2())
+    }
+
+    fn project(&self, name: &str) -> Result<IndexProject, DependencyError> {
+        let url = format!("{}/{}/json", self.url, normalize_name(name));
+        let text = fetch_page(&url, name, None)?;
+        let data: WarehouseData = serde_json::from_str(&text).map_err(|e| {
+            DependencyError::new(&format!("Problem parsing package info for {}: {}", name, e))
+        })?;
+
+        // The release keys aren't normalized, eg `1.0` vs `1.0.0`, so we parse them.
+        let mut releases: Vec<(Version, Vec<WarehouseRelease>)> = data
+            .releases
+            .into_iter()
+            .filter_map(|(v, files)| Version::from_str(&v).ok().map(|v| (v, files)))
+            .collect();
+        releases.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(IndexProject {
+            name: data.info.name,
+            latest: Version::from_str(&data.info.version).ok(),
+            releases,
+        })
+    }
+}
+
+/// Ask for the JSON variant of the simple API, but accept HTML from indexes that don't have it.
+const SIMPLE_ACCEPT: &str = "application/vnd.pypi.simple.v1+json, \
+                             application/vnd.pypi.simple.v1+html;q=0.2, text/html;q=0.01";
+
+/// A PEP 503 simple index, eg a private mirror, or devpi, Artifactory, or a static file server.
+pub struct SimpleIndex {
+    url: String,
+}
+
+impl SimpleIndex {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+impl PackageIndex for SimpleIndex {
+    fn source(&self, name: &str, _version: &Version) -> String {
+        format!("simple+{}/{}/", self.url, normalize_name(name))
+    }
+
+    fn project(&self, name: &str) -> Result<IndexProject, DependencyError> {
+        let url = format!("{}/{}/", self.url, normalize_name(name));
+        let page = fetch_page(&url, name, Some(SIMPLE_ACCEPT))?;
+
+        // We check the content instead of the response's type, since we may have read it
+        // from the cache.
+        let files = if page.trim_start().starts_with('{') {
+            parse_simple_json(&page, &url)?
+        } else {
+            parse_simple_html(&page, &url)?
+        };
+
+        Ok(IndexProject {
+            name: name.to_owned(),
+            latest: None,
+            releases: group_releases(
+                files
+                    .into_iter()
+                    .filter_map(|f| release_from_file(name, f))
+                    .collect(),
+            ),
+        })
+    }
+}
+
+/// A directory of wheels and sdists.
+pub struct LocalIndex {
+    dir: PathBuf,
+}
+
+impl LocalIndex {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+impl PackageIndex for LocalIndex {
+    fn source(&self, _name: &str, _version: &Version) -> String {
+        let url = fs::canonicalize(&self.dir)
+            .ok()
+            .and_then(|d| Url::from_file_path(d).ok())
+            .map(|u| u.to_string())
+            .unwrap_or_else(|| self.dir.to_string_lossy().replace(' ', "%20"));
+        format!("local+{}", url)
+    }
+
+    fn project(&self, name: &str) -> Result<IndexProject, DependencyError> {
+        let entries = fs::read_dir(&self.dir).map_err(|e| {
+            DependencyError::new(&format!(
+                "Problem reading the index at {:?}: {}",
+                self.dir, e
+            ))
+        })?;
+        let dir = self.dir.canonicalize().unwrap_or_else(|_| self.dir.clone());
+
+        let mut files = vec![];
+        for entry in entries.filter_map(|e| e.ok()) {
+            let filename = entry.file_name().to_string_lossy().into_owned();
+            // Check the name before reading the file to hash it.
+            match parse_filename(&filename) {
+                Some((n, _, _, _)) if normalize_name(&n) == normalize_name(name) => (),
+                _ => continue,
+            }
+
+            let path = dir.join(&filename);
+            let data = match fs::read(&path) {
+                Ok(d) => d,
+                Err(_) => continue,
+            };
+            let sha256 =
+                data_encoding::HEXLOWER.encode(digest::digest(&digest::SHA256, &data).as_ref());
+            let url = match Url::from_file_path(&path) {
+                Ok(u) => u.to_string(),
+                Err(_) => continue,
+            };
+            let mut hashes = HashMap::new();
+            hashes.insert("sha256".to_owned(), sha256);
+            let file = SimpleFile {
+                filename,
+                url,
+                hashes,
+                ..Default::default()
+            };
+            if let Some(r) = release_from_file(name, file) {
+                files.push(r);
+            }
+        }
+
+        if files.is_empty() {
+            return Err(DependencyError::new(&format!(
+                "Unable to find {} in {:?}",
+                name, self.dir
+            )));
+        }
+
+        Ok(IndexProject {
+            name: name.to_owned(),
+            latest: None,
+            releases: group_releases(files),
+        })
+    }
+}
+
+/// Set up the index specified in `[tool.pypackage]`, or PyPi if none is. `index_url` may be a
+/// Warehouse JSON API (eg `https://pypi.org/pypi`), a simple index (eg `https://host/simple`),
+/// or a local directory, as a path or a `file://` url. Set `index_type` to `json`, `simple`
+/// or `local` if we guess the type wrong.
+pub fn from_config(
+    url: Option<&str>,
+    type_: Option<&str>,
+) -> Result<Box<dyn PackageIndex>, DependencyError> {
+    let url = match url {
+        Some(u) => u,
+        None => return Ok(Box::new(JsonIndex::pypi())),
+    };
+
+    let is_remote = url.starts_with("http://") || url.starts_with("https://");
+    let type_ = match type_ {
+        Some(t) => t.to_owned(),
+        None if !is_remote => "local".to_owned(),
+        None if url.trim_end_matches('/').ends_with("/simple") => "simple".to_owned(),
+        None => "json".to_owned(),
+    };
+
+    Ok(match type_.as_ref() {
+        "json" => Box::new(JsonIndex::new(url)),
+        "simple" => Box::new(SimpleIndex::new(url)),
+        "local" => {
+            let dir = if url.starts_with("file://") {
+                Url::parse(url)
+                    .ok()
+                    .and_then(|u| u.to_file_path().ok())
+                    .ok_or_else(|| DependencyError::new(&format!("Invalid file url: {}", url)))?
+            } else {
+                PathBuf::from(url)
+            };
+            Box::new(LocalIndex::new(dir))
+        }
+        _ => {
+            return Err(DependencyError::new(&format!(
+                "Unknown index_type: {}. Use `json`, `simple`, or `local`",
+                type_
+            )))
+        }
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn parse_filenames() {
+        assert_eq!(
+            parse_filename("numpy-1.17.2-cp37-cp37m-manylinux1_x86_64.whl"),
+            Some((
+                "numpy".to_owned(),
+                Version::new(1, 17, 2),
+                "bdist_wheel".to_owned(),
+                "cp37".to_owned()
+            ))
+        );
+        assert_eq!(
+            parse_filename("django_rest-3.0-1-py2.py3-none-any.whl"),
+            Some((
+                "django_rest".to_owned(),
+                Version::new_short(3, 0),
+                "bdist_wheel".to_owned(),
+                "py2.py3".to_owned()
+            ))
+        );
+        assert_eq!(
+            parse_filename("python-dateutil-2.8.0.tar.gz"),
+            Some((
+                "python-dateutil".to_owned(),
+                Version::new(2, 8, 0),
+                "sdist".to_owned(),
+                "source".to_owned()
+            ))
+        );
+        assert_eq!(parse_filename("README.md"), None);
+        assert_eq!(
+            normalize_name("Django_Rest.Framework"),
+            "django-rest-framework"
+        );
+    }
+
+    #[test]
+    fn simple_html() {
+        let page = r#"<!DOCTYPE html>
+<html><body>
+<h1>Links for some-package</h1>
+<a href="../../packages/ab/some_package-1.0-py3-none-any.whl#sha256=abc123" data-requires-python="&gt;=3.6" data-core-metadata="sha256=fed987">some_package-1.0-py3-none-any.whl</a><br/>
+<a href='https://files.example.com/some-package-1.0.tar.gz#md5=def456' data-yanked="">some-package-1.0.tar.gz</a>
+<A HREF="some-package-1.1.tar.gz" data-yanked="Broken &amp; bad">some-package-1.1.tar.gz</A>
+</body></html>"#;
+        let files =
+            parse_simple_html(page, "https://pypi.example.com/simple/some-package/").unwrap();
+
+        assert_eq!(files.len(), 3);
+        assert_eq!(
+            files[0],
+            SimpleFile {
+                filename: "some_package-1.0-py3-none-any.whl".to_owned(),
+                url: "https://pypi.example.com/packages/ab/some_package-1.0-py3-none-any.whl"
+                    .to_owned(),
+                hashes: vec![("sha256".to_owned(), "abc123".to_owned())]
+                    .into_iter()
+                    .collect(),
+                requires_python: Some(">=3.6".to_owned()),
+                yanked: None,
+                core_metadata: true,
+                core_metadata_hashes: vec![("sha256".to_owned(), "fed987".to_owned())]
+                    .into_iter()
+                    .collect(),
+            }
+        );
+        assert_eq!(files[1].hashes.get("md5"), Some(&"def456".to_owned()));
+        assert_eq!(files[1].yanked, Some("".to_owned()));
+        assert!(!files[1].core_metadata);
+        assert_eq!(
+            files[2].url,
+            "https://pypi.example.com/simple/some-package/some-package-1.1.tar.gz"
+        );
+        assert_eq!(files[2].yanked, Some("Broken & bad".to_owned()));
+
+        let (version, release) = release_from_file("Some.Package", files[0].clone()).unwrap();
+        assert_eq!(version, Version::new_short(1, 0));
+        assert_eq!(release.digests.sha256, "abc123");
+        assert_eq!(release.python_version, "py3");
+        assert_eq!(release.requires_python, Some(">=3.6".to_owned()));
+        assert_eq!(release.core_metadata_sha256, Some("fed987".to_owned()));
+        assert!(!release.yanked);
+
+        let (_, release) = release_from_file("some-package", files[2].clone()).unwrap();
+        assert!(release.yanked);
+        assert_eq!(release.yanked_reason, Some("Broken & bad".to_owned()));
+        assert!(release_from_file("other", files[2].clone()).is_none());
+    }
+
+    #[test]
+    fn simple_json() {
+        let page = r#"{
+  "meta": {"api-version": "1.1"},
+  "name": "some-package",
+  "files": [
+    {
+      "filename": "some_package-1.0-py3-none-any.whl",
+      "url": "/packages/some_package-1.0-py3-none-any.whl",
+      "hashes": {"sha256": "abc123"},
+      "requires-python": ">=3.6",
+      "yanked": false,
+      "core-metadata": {"sha256": "fed987"}
+    },
+    {
+      "filename": "some-package-1.1.tar.gz",
+      "url": "https://files.example.com/some-package-1.1.tar.gz",
+      "hashes": {},
+      "yanked": "Security issue"
+    }
+  ]
+}"#;
+        let files =
+            parse_simple_json(page, "https://pypi.example.com/simple/some-package/").unwrap();
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            files[0].url,
+            "https://pypi.example.com/packages/some_package-1.0-py3-none-any.whl"
+        );
+        assert_eq!(files[0].hashes.get("sha256"), Some(&"abc123".to_owned()));
+        assert_eq!(files[0].requires_python, Some(">=3.6".to_owned()));
+        assert_eq!(files[0].yanked, None);
+        assert!(files[0].core_metadata);
+        assert_eq!(
+            files[0].core_metadata_hashes.get("sha256"),
+            Some(&"fed987".to_owned())
+        );
+        assert_eq!(files[1].yanked, Some("Security issue".to_owned()));
+        assert!(!files[1].core_metadata);
+
+        let unsupported = page.replace("1.1", "2.0");
+        assert!(parse_simple_json(&unsupported, "https://pypi.example.com/simple/x/").is_err());
+    }
+
+    #[test]
+    fn local_index() {
+        let dir = env::temp_dir().join("pypackage_local_index_test");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Some_Package-1.0-py3-none-any.whl"), b"wheel").unwrap();
+        fs::write(dir.join("some-package-1.0.tar.gz"), b"sdist").unwrap();
+        fs::write(dir.join("some_package-1.1.tar.gz"), b"sdist").unwrap();
+        fs::write(dir.join("other-2.0.tar.gz"), b"other").unwrap();
+
+        let index = from_config(Some(dir.to_str().unwrap()), None).unwrap();
+        let project = index.project("some.package").unwrap();
+
+        let versions: Vec<Version> = project.releases.iter().map(|r| r.0.clone()).collect();
+        assert_eq!(
+            versions,
+            vec![Version::new_short(1, 0), Version::new_short(1, 1)]
+        );
+        assert_eq!(project.releases[0].1.len(), 2);
+
+        let wheel = project.releases[0]
+            .1
+            .iter()
+            .find(|r| r.packagetype == "bdist_wheel")
+            .unwrap();
+        assert_eq!(wheel.python_version, "py3");
+        assert_eq!(
+            wheel.digests.sha256,
+            data_encoding::HEXLOWER.encode(digest::digest(&digest::SHA256, b"wheel").as_ref())
+        );
+        assert_eq!(index.download(wheel).unwrap(), b"wheel".to_vec());
+        assert!(index
+            .source("some-package", &Version::new_short(1, 0))
+            .starts_with("local+file:///"));
+
+        assert!(index.project("missing").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sources() {
+        let version = Version::new_short(2, 8);
+        assert_eq!(
+            JsonIndex::pypi().source("idna", &version),
+            "pypi+https://pypi.org/pypi/idna/2.8/json"
+        );
+        assert_eq!(
+            SimpleIndex::new("https://pypi.example.com/simple/").source("Some_Package", &version),
+            "simple+https://pypi.example.com/simple/some-package/"
+        );
+    }
+}
@@ -1,11 +1,13 @@
-use crate::dep_types::Version;
+use crate::cache;
+use crate::dep_resolution::WarehouseRelease;
+use crate::dep_types::{DependencyError, Version};
+use crate::index::PackageIndex;
+use crate::pep517;
 use crate::util;
 use crossterm::{Color, Colored};
-use flate2::read::GzDecoder;
 use regex::Regex;
 use ring::digest;
-use std::{fs, io, io::BufRead, path::PathBuf, process::Command};
-use tar::Archive;
+use std::{env, fs, io, io::BufRead, path::PathBuf};
 
 #[derive(Copy, Clone, Debug)]
 pub enum PackageType {
@@ -13,34 +15,13 @@ pub enum PackageType {
     Source,
 }
 
-/// Extract the wheel. (It's like a zip)
-fn extract_wheel(file: &fs::File, lib_path: &PathBuf, rename: &Option<(String, String)>) {
-    // Separate function, since we use it twice.
+/// Extract a zip file, eg a source distribution that isn't a `tar.gz`.
+pub fn extract_zip(file: &fs::File, dest: &PathBuf) {
     let mut archive = zip::ZipArchive::new(file).unwrap();
 
     for i in 0..archive.len() {
         let mut file = archive.by_index(i).unwrap();
-        // Change name here instead of after in case we've already installed a non-renamed version.
-        // (which would be overwritten by this one.)
-        let file_str2 = file.sanitized_name();
-        let file_str = file_str2.to_str().expect("Problem converting path to str");
-
-        let extracted_file = if !file_str.contains("dist-info") && !file_str.contains("egg-info") {
-            match rename {
-                Some((old, new)) => file
-                    .sanitized_name()
-                    .to_str()
-                    .unwrap()
-                    .to_owned()
-                    .replace(old, new)
-                    .into(),
-                None => file.sanitized_name(),
-            }
-        } else {
-            file.sanitized_name()
-        };
-
-        let outpath = lib_path.join(extracted_file);
+        let outpath = dest.join(file.sanitized_name());
 
         if (&*file.name()).ends_with('/') {
             fs::create_dir_all(&outpath).unwrap();
@@ -56,9 +37,342 @@ fn extract_wheel(file: &fs::File, lib_path: &PathBuf, rename: &Option<(String, S
     }
 }
 
+/// A line from a `RECORD` file: A path relative to the lib folder, its hash, eg
+/// `sha256=<urlsafe base64, no padding>`, and its size in bytes. `RECORD` itself has no hash.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordEntry {
+    pub path: String,
+    pub hash: String,
+    pub size: Option<u64>,
+}
+
+impl RecordEntry {
+    pub fn new(path: &str, data: &[u8]) -> Self {
+        Self {
+            path: path.to_owned(),
+            hash: record_hash(data),
+            size: Some(data.len() as u64),
+        }
+    }
+}
+
+/// The hash format used in `RECORD` files.
+fn record_hash(data: &[u8]) -> String {
+    let digest = digest::digest(&digest::SHA256, data);
+    format!(
+        "sha256={}",
+        data_encoding::BASE64URL_NOPAD.encode(digest.as_ref())
+    )
+}
+
+/// The algorithm a `RECORD` hash uses, eg `sha256=<digest>`, and its digest, without padding.
+/// Wheels may use any algorithm at least as strong as sha256; we reject weaker ones.
+fn record_algorithm(hash: &str) -> Option<(&'static digest::Algorithm, &str)> {
+    let mut parts = hash.splitn(2, '=');
+    let algorithm = match parts.next()? {
+        "sha256" => &digest::SHA256,
+        "sha384" => &digest::SHA384,
+        "sha512" => &digest::SHA512,
+        _ => return None,
+    };
+    Some((algorithm, parts.next()?.trim_end_matches('=')))
+}
+
+/// Split a line of CSV into fields, handling quoted fields, eg ones containing commas.
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                fields.push(field.clone());
+                field.clear();
+            }
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Parse the contents of a `RECORD` file.
+pub fn parse_record(text: &str) -> Vec<RecordEntry> {
+    text.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            let fields = split_csv_line(l.trim_end_matches('\r'));
+            RecordEntry {
+                path: fields[0].clone(),
+                hash: fields.get(1).cloned().unwrap_or_default(),
+                size: fields.get(2).and_then(|s| s.parse().ok()),
+            }
+        })
+        .collect()
+}
+
+pub fn serialize_record(entries: &[RecordEntry]) -> String {
+    let mut result = String::new();
+    for entry in entries.iter() {
+        let size = match entry.size {
+            Some(s) => s.to_string(),
+            None => String::new(),
+        };
+        result.push_str(&format!(
+            "{},{},{}\n",
+            csv_field(&entry.path),
+            entry.hash,
+            size
+        ));
+    }
+    result
+}
+
+/// Check that a path from inside a wheel stays inside the folder we install it to.
+fn is_safe_path(path: &str) -> bool {
+    !path.starts_with('/')
+        && !path.starts_with('\\')
+        && !path.contains(':')
+        && !path.split(&['/', '\\'][..]).any(|part| part == "..")
+}
+
+/// Find where a file from a wheel goes, relative to the lib folder. Files in
+/// `{name}-{version}.data/{scheme}/` go to that scheme's path under `__pypackages__/X.Y/`;
+/// everything else to the lib folder, since `purelib` and `platlib` are the same for us,
+/// regardless of `Root-Is-Purelib`.
+fn install_path(path: &str, data_dir: &str, name: &str) -> Result<String, DependencyError> {
+    if !path.starts_with(&format!("{}/", data_dir)) {
+        return Ok(path.to_owned());
+    }
+    let mut parts = path[data_dir.len() + 1..].splitn(2, '/');
+    let scheme = parts.next().unwrap_or("");
+    let rest = parts.next().unwrap_or("");
+
+    Ok(match scheme {
+        "purelib" | "platlib" => rest.to_owned(),
+        "scripts" => format!("../bin/{}", rest),
+        "headers" => format!("../include/{}/{}", name, rest),
+        "data" => format!("../{}", rest),
+        _ => {
+            return Err(DependencyError::new(&format!(
+                "Unknown data directory in wheel: {}",
+                path
+            )))
+        }
+    })
+}
+
+/// Point scripts with a `#!python` shebang at our Python.
+fn fix_shebang(data: Vec<u8>, bin_path: &PathBuf) -> Vec<u8> {
+    for placeholder in &[&b"#!pythonw"[..], &b"#!python"[..]] {
+        if data.starts_with(placeholder)
+            && data[placeholder.len()..]
+                .first()
+                .map(|c| c.is_ascii_whitespace())
+                == Some(true)
+        {
+            let mut result =
+                format!("#!{}", bin_path.join("python").to_string_lossy()).into_bytes();
+            result.extend_from_slice(&data[placeholder.len()..]);
+            return result;
+        }
+    }
+    data
+}
+
+#[cfg(unix)]
+fn make_executable(path: &PathBuf) {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).ok();
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &PathBuf) {}
+
+/// Install a wheel, following the binary distribution format spec:
+/// https://packaging.python.org/specifications/binary-distribution-format/
+/// We verify every file against the wheel's `RECORD` before extracting anything, place
+/// `.data` directories in their scheme paths, set up entry points, and write `INSTALLER`, and a
+/// `RECORD` listing what we installed.
+pub fn install_wheel(
+    archive_path: &PathBuf,
+    name: &str,
+    version: &Version,
+    lib_path: &PathBuf,
+    bin_path: &PathBuf,
+    rename: &Option<(String, String)>,
+) -> Result<(), DependencyError> {
+    let wheel_err = |msg: &str| {
+        DependencyError::new(&format!("Problem installing {:?}: {}", archive_path, msg))
+    };
+    let file = fs::File::open(archive_path).map_err(|_| wheel_err("Can't open the wheel"))?;
+    let mut archive =
+        zip::ZipArchive::new(file).map_err(|_| wheel_err("The wheel isn't a valid zip file"))?;
+
+    let mut names = vec![];
+    for i in 0..archive.len() {
+        let file = archive
+            .by_index(i)
+            .map_err(|_| wheel_err("Problem reading the wheel"))?;
+        if !is_safe_path(file.name()) {
+            return Err(wheel_err(&format!(
+                "It contains a path outside the install folder: {}",
+                file.name()
+            )));
+        }
+        names.push(file.name().to_owned());
+    }
+
+    let dist_info = names
+        .iter()
+        .filter_map(|n| {
+            let mut parts = n.splitn(2, '/');
+            let dir = parts.next()?;
+            if dir.ends_with(".dist-info") && parts.next() == Some("WHEEL") {
+                Some(dir.to_owned())
+            } else {
+                None
+            }
+        })
+        .next()
+        .ok_or_else(|| wheel_err("It has no .dist-info/WHEEL file"))?;
+    let data_dir = format!("{}.data", dist_info.trim_end_matches(".dist-info"));
+    let record_path = format!("{}/RECORD", dist_info);
+
+    let mut read_text = |path: &str| -> Result<String, DependencyError> {
+        let mut file = archive
+            .by_name(path)
+            .map_err(|_| wheel_err(&format!("It has no {}", path)))?;
+        let mut text = String::new();
+        io::Read::read_to_string(&mut file, &mut text)
+            .map_err(|_| wheel_err(&format!("Problem reading {}", path)))?;
+        Ok(text)
+    };
+    let wheel_info = read_text(&format!("{}/WHEEL", dist_info))?;
+    let record = parse_record(&read_text(&record_path)?);
+
+    let wheel_version_re = Regex::new(r"(?m)^Wheel-Version:\s*(\d+)").unwrap();
+    if let Some(caps) = wheel_version_re.captures(&wheel_info) {
+        if caps.get(1).unwrap().as_str() != "1" {
+            return Err(wheel_err("Unsupported Wheel-Version"));
+        }
+    }
+
+    // Verify everything first, so we don't leave a partial install of a bad wheel.
+    let unhashed = [
+        record_path.clone(),
+        format!("{}.jws", record_path),
+        format!("{}.p7s", record_path),
+    ];
+    for path in names.iter() {
+        if path.ends_with('/') || unhashed.contains(path) {
+            continue;
+        }
+        let entry = match record.iter().find(|e| &e.path == path) {
+            Some(e) => e,
+            None => return Err(wheel_err(&format!("{} isn't listed in RECORD", path))),
+        };
+        let file = archive
+            .by_name(path)
+            .map_err(|_| wheel_err(&format!("Problem reading {}", path)))?;
+        let (algorithm, expected) = match record_algorithm(&entry.hash) {
+            Some(a) => a,
+            None => {
+                return Err(wheel_err(&format!(
+                    "Unsupported hash in RECORD for {}: {}",
+                    path, entry.hash
+                )))
+            }
+        };
+        let digest = file_digest(file, algorithm)
+            .map_err(|_| wheel_err(&format!("Problem reading {}", path)))?;
+        if data_encoding::BASE64URL_NOPAD.encode(digest.as_ref()) != expected {
+            return Err(wheel_err(&format!("Hash failed for {}", path)));
+        }
+    }
+
+    let mut installed = vec![];
+    for path in names.iter() {
+        if path.ends_with('/') || unhashed.contains(path) {
+            continue;
+        }
+        let mut dest = install_path(path, &data_dir, name)?;
+        // Change name here instead of after in case we've already installed a non-renamed
+        // version. (which would be overwritten by this one.)
+        if let Some((old, new)) = rename {
+            if !path.starts_with(&dist_info) && !path.starts_with(&data_dir) {
+                dest = dest.replace(old, new);
+            }
+        }
+
+        let mut file = archive
+            .by_name(path)
+            .map_err(|_| wheel_err(&format!("Problem reading {}", path)))?;
+        let mut data = vec![];
+        io::Read::read_to_end(&mut file, &mut data)
+            .map_err(|_| wheel_err(&format!("Problem reading {}", path)))?;
+
+        let is_script = dest.starts_with("../bin/");
+        if is_script {
+            data = fix_shebang(data, bin_path);
+        }
+
+        let outpath = lib_path.join(&dest);
+        if let Some(p) = outpath.parent() {
+            fs::create_dir_all(p).map_err(|_| wheel_err("Problem creating a folder"))?;
+        }
+        fs::write(&outpath, &data)
+            .map_err(|_| wheel_err(&format!("Problem writing {:?}", outpath)))?;
+        if is_script {
+            make_executable(&outpath);
+        }
+        installed.push(RecordEntry::new(&dest, &data));
+    }
+
+    for script in setup_scripts(name, version, lib_path) {
+        let path = format!("../bin/{}", script);
+        if let Ok(data) = fs::read(lib_path.join(&path)) {
+            installed.push(RecordEntry::new(&path, &data));
+        }
+    }
+
+    let installer = b"pypackage\n";
+    let installer_path = format!("{}/INSTALLER", dist_info);
+    fs::write(lib_path.join(&installer_path), installer)
+        .map_err(|_| wheel_err("Problem writing INSTALLER"))?;
+    installed.push(RecordEntry::new(&installer_path, installer));
+    installed.push(RecordEntry {
+        path: record_path.clone(),
+        hash: String::new(),
+        size: None,
+    });
+
+    fs::write(lib_path.join(&record_path), serialize_record(&installed))
+        .map_err(|_| wheel_err("Problem writing RECORD"))?;
+    Ok(())
+}
+
 /// https://rust-lang-nursery.github.io/rust-cookbook/cryptography/hashing.html
-fn sha256_digest<R: io::Read>(mut reader: R) -> Result<digest::Digest, std::io::Error> {
-    let mut context = digest::Context::new(&digest::SHA256);
+fn file_digest<R: io::Read>(
+    mut reader: R,
+    algorithm: &'static digest::Algorithm,
+) -> Result<digest::Digest, std::io::Error> {
+    let mut context = digest::Context::new(algorithm);
     let mut buffer = [0; 1024];
 
     loop {
@@ -72,21 +386,6 @@ fn sha256_digest<R: io::Read>(mut reader: R) -> Result<digest::Digest, std::io::
     Ok(context.finish())
 }
 
-/// If the setup.py file uses `distutils.core`, replace with `setuptools`. This is required to build
-/// a wheel. Eg, replace `from distutils.core import setup` with `from setuptools import setup`.
-fn replace_distutils(setup_path: &PathBuf) {
-    let setup_text =
-        fs::read_to_string(setup_path).expect("Can't find setup.py on a source distribution.");
-
-    let re = Regex::new(r"distutils.core").unwrap();
-    let new_text = re.replace_all(&setup_text, "setuptools");
-
-    if new_text != setup_text {
-        fs::write(setup_path, new_text.to_string())
-            .expect("Problem replacing `distutils.core` with `setuptools` in `setup.py`");
-    }
-}
-
 /// Remove scripts. Used when uninstalling.
 fn remove_scripts(scripts: Vec<String>, scripts_path: &PathBuf) {
     // todo: Likely not a great approach. QC.
@@ -124,20 +423,11 @@ if __name__ == '__main__':
 
 /// Set up entry points (ie scripts like `ipython`, `black` etc) in a single file.
 /// Alternatively, we could just parse all `dist-info` folders every run; this should
-/// be faster.
-fn setup_scripts(name: &str, version: &Version, lib_path: &PathBuf) {
+/// be faster. Returns the names of the scripts created.
+fn setup_scripts(name: &str, version: &Version, lib_path: &PathBuf) -> Vec<String> {
     let mut scripts = vec![];
-    // todo: Sep fn for dist_info path, to avoid repetition between here and uninstall?
-    let mut dist_info_path = lib_path.join(format!("{}-{}.dist-info", name, version.to_string()));
-    // If we can't find the dist_info path, it may be due to it not using a full 3-digit semvar format.
-    // todo: Dry from dep_resolution, release check.
-    if !dist_info_path.exists() && version.patch == 0 {
-        dist_info_path = lib_path.join(format!("{}-{}.dist-info", name, version.to_string_med()));
-        if !dist_info_path.exists() && version.minor == 0 {
-            dist_info_path =
-                lib_path.join(format!("{}-{}.dist-info", name, version.to_string_short()));
-        }
-    }
+    let dist_info_path = util::find_dist_info(name, version, lib_path)
+        .unwrap_or_else(|| lib_path.join(format!("{}-{}.dist-info", name, version.to_string2())));
 
     if let Ok(ep_file) = fs::File::open(&dist_info_path.join("entry_points.txt")) {
         let mut in_scripts_section = false;
@@ -174,6 +464,7 @@ fn setup_scripts(name: &str, version: &Version, lib_path: &PathBuf) {
         util::abort("Problem creating script path")
     }
 
+    let mut created = vec![];
     for new_script in scripts {
         let re = Regex::new(r"^(.*?)\s*=\s*(.*?):(.*)$").unwrap();
         if let Some(caps) = re.captures(&new_script) {
@@ -186,49 +477,60 @@ fn setup_scripts(name: &str, version: &Version, lib_path: &PathBuf) {
                 &format!("Added a command-line script: {}", name),
                 Color::Green,
             );
+            created.push(name.to_owned());
         }
     }
 
     //    fs::write(scripts_file, existing_scripts).expect("Unable to write to the console_scripts file");
+    created
 }
 
 /// Download and install a package. For wheels, we can just extract the contents into
 /// the lib folder.  For source dists, make a wheel first.
 pub fn download_and_install_package(
+    index: &dyn PackageIndex,
     name: &str,
     version: &Version,
-    url: &str,
-    filename: &str,
-    expected_digest: &str,
+    release: &WarehouseRelease,
     lib_path: &PathBuf,
     bin_path: &PathBuf,
     package_type: PackageType,
     rename: &Option<(u32, String)>,
-) -> Result<(), reqwest::Error> {
+) -> Result<(), DependencyError> {
     if !lib_path.exists() {
         fs::create_dir(lib_path).expect("Problem creating lib directory");
     }
+    let filename = &release.filename;
     let archive_path = lib_path.join(filename);
 
     // If the archive is already in the lib folder, don't re-download it. Note that this
     // isn't the usual flow, but may have some uses.
     if !archive_path.exists() {
-        // Save the file
-        let mut resp = reqwest::get(url)?; // Download the file
-        let mut out =
-            fs::File::create(&archive_path).expect("Failed to save downloaded package file");
-        io::copy(&mut resp, &mut out).expect("failed to copy content");
+        // Download into the cache if we haven't already, then install from there.
+        let cached_path = cache::artifact_path(filename);
+        if !cached_path.exists() {
+            if cache::is_offline() {
+                return Err(cache::offline_error(filename));
+            }
+            let data = index.download(release)?;
+            cache::store_artifact(filename, &data)?;
+        }
+        fs::copy(&cached_path, &archive_path).expect("Failed to copy package file from the cache");
     }
 
     let file = fs::File::open(&archive_path).unwrap();
 
     // https://rust-lang-nursery.github.io/rust-cookbook/cryptography/hashing.html
     let reader = io::BufReader::new(&file);
-    let file_digest =
-        sha256_digest(reader).unwrap_or_else(|_| panic!("Problem reading hash for {}", filename));
+    let file_digest = file_digest(reader, &digest::SHA256)
+        .unwrap_or_else(|_| panic!("Problem reading hash for {}", filename));
 
+    // Some simple indexes don't provide hashes; there's nothing to check against.
+    let expected_digest = &release.digests.sha256;
     let file_digest_str = data_encoding::HEXUPPER.encode(file_digest.as_ref());
-    if file_digest_str.to_lowercase() != expected_digest.to_lowercase() {
+    if !expected_digest.is_empty()
+        && file_digest_str.to_lowercase() != expected_digest.to_lowercase()
+    {
         util::print_color(&format!("Hash failed for {}. Expected: {}, Actual: {}. Continue with installation anyway? (yes / no)", filename, expected_digest.to_lowercase(), file_digest_str.to_lowercase()), Color::Red);
 
         let mut input = String::new();
@@ -249,11 +551,6 @@ pub fn download_and_install_package(
         }
     }
 
-    // We must re-open the file after computing the hash.
-    let archive_file = fs::File::open(&archive_path).unwrap();
-
-    // todo: Setup executable scripts.
-
     let rename = match rename.as_ref() {
         Some((_, new)) => Some((name.to_owned(), new.to_owned())),
         None => None,
@@ -261,105 +558,19 @@ pub fn download_and_install_package(
 
     match package_type {
         PackageType::Wheel => {
-            extract_wheel(&archive_file, lib_path, &rename);
+            install_wheel(&archive_path, name, version, lib_path, bin_path, &rename)?;
         }
         PackageType::Source => {
-            // Extract the tar.gz source code.
-            let tar = GzDecoder::new(&archive_file);
-            let mut archive = Archive::new(tar);
-
-            if archive.unpack(lib_path).is_err() {
-                // The extract_wheel function just extracts a zip file, so it's appropriate here.
-                // We'll then continue with this leg, and build/move/cleanup.
-                extract_wheel(&archive_file, lib_path, &None);
-                // Check if we have a zip file instead.
-            }
-
-            // The archive is now unpacked into a parent folder from the `tar.gz`. Place
-            // its sub-folders directly in the lib folder, and delete the parent.
-            let re = Regex::new(r"^(.*?)(?:\.tar\.gz|\.zip)$").unwrap();
-            let folder_name = re
-                .captures(&filename)
-                .expect("Problem matching extracted folder name")
-                .get(1)
-                .unwrap_or_else(|| panic!("Unable to find extracted folder name: {}", filename))
-                .as_str();
-
-            // todo: This fs_extras move does a full copy. Normal fs lib doesn't include
-            // todo moves, only copies. Figure out how to do a normal move,
-            // todo, to speed this up.
-
-            let extracted_parent = lib_path.join(folder_name);
-
-            replace_distutils(&extracted_parent.join("setup.py"));
-
-            // Build a wheel from source.
-            Command::new(bin_path.join("python"))
-                .current_dir(&extracted_parent)
-                .args(&["setup.py", "bdist_wheel"])
-                .output()
-                .expect("Problem running setup.py bdist_wheel");
-
-            // todo: Clippy flags this for not iterating, but I can't get a better way working, ie
-            //              let built_wheel_filename = &dist_files.get(0)
-            //                .expect("Dist file directory is empty")
-            //                .unwrap()
-            //                .path()
-            //                .file_name()
-            //                .expect("Unable to find built wheel filename")
-            //                .to_str()
-            //                .unwrap()
-            //                .to_owned();
-            let mut built_wheel_filename = String::new();
-            for entry in
-                fs::read_dir(extracted_parent.join("dist")).expect("Problem reading dist directory")
-            {
-                let entry = entry.unwrap();
-                built_wheel_filename = entry
-                    .path()
-                    .file_name()
-                    .expect("Unable to find built wheel filename")
-                    .to_str()
-                    .unwrap()
-                    .to_owned();
-                break;
-            }
-
-            let built_wheel_filename = &built_wheel_filename;
-            if built_wheel_filename.is_empty() {
-                util::abort("Problem finding built wheel")
-            }
-
-            // todo: Again, try to move vice copy.
-            let options = fs_extra::file::CopyOptions::new();
-            fs_extra::file::move_file(
-                extracted_parent.join("dist").join(built_wheel_filename),
-                lib_path.join(built_wheel_filename),
-                &options,
-            )
-            .expect("Problem copying wheel built from source");
-
-            let file_created = fs::File::open(&lib_path.join(built_wheel_filename))
-                .expect("Can't find created wheel.");
-            extract_wheel(&file_created, lib_path, &rename);
-
-            // Remove the created and moved wheel
-            if fs::remove_file(&lib_path.join(built_wheel_filename)).is_err() {
-                util::abort(&format!(
-                    "Problem removing this downloaded package: {:?}",
-                    &built_wheel_filename
-                ));
-            }
-            // Remove the source directeory extracted from the tar.gz file.
-            if fs::remove_dir_all(&extracted_parent).is_err() {
-                util::abort(&format!(
-                    "Problem removing parent folder of this downloaded package: {:?}",
-                    &extracted_parent
-                ));
-            }
+            let build_dir =
+                env::temp_dir().join(format!("pypackage-build-{}-{}", name, version.to_string2()));
+            let installed =
+                pep517::build_wheel(&archive_path, &build_dir, bin_path).and_then(|wheel| {
+                    install_wheel(&wheel, name, version, lib_path, bin_path, &rename)
+                });
+            fs::remove_dir_all(&build_dir).ok();
+            installed?;
         }
     }
-    setup_scripts(name, version, lib_path);
 
     // Remove the archive
     //    if fs::remove_file(&archive_path).is_err() {
@@ -372,7 +583,130 @@ pub fn download_and_install_package(
     Ok(())
 }
 
-pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &PathBuf) {
+/// Resolve a path from `RECORD`, relative to the lib folder. Paths may reach into sibling
+/// folders, eg `../bin`, but not outside `__pypackages__/X.Y/`.
+fn resolve_record_path(lib_path: &PathBuf, path: &str) -> Option<PathBuf> {
+    if path.starts_with('/') || path.starts_with('\\') || path.contains(':') {
+        return None;
+    }
+    let top = lib_path.parent()?;
+    let mut parts = vec![lib_path.file_name()?.to_string_lossy().into_owned()];
+    for part in path.split(&['/', '\\'][..]) {
+        match part {
+            "" | "." => (),
+            ".." => {
+                parts.pop()?;
+            }
+            _ => parts.push(part.to_owned()),
+        }
+    }
+    if parts.len() < 2 {
+        return None;
+    }
+    Some(parts.iter().fold(top.to_path_buf(), |p, part| p.join(part)))
+}
+
+/// Remove a file's compiled bytecode, eg `__pycache__/mod.cpython-37.pyc` for `mod.py`.
+fn remove_bytecode(path: &PathBuf) {
+    let (dir, stem) = match (path.parent(), path.file_stem()) {
+        (Some(d), Some(s)) => (d.join("__pycache__"), s.to_string_lossy().into_owned()),
+        _ => return,
+    };
+    if let Ok(entries) = fs::read_dir(&dir) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let filename = entry.file_name().to_string_lossy().into_owned();
+            if filename.starts_with(&format!("{}.", stem)) && filename.ends_with(".pyc") {
+                fs::remove_file(entry.path()).ok();
+            }
+        }
+    }
+}
+
+/// Remove folders left empty after uninstalling, leaving `__pypackages__/X.Y/` and its
+/// immediate subfolders, eg `lib` and `bin`.
+fn remove_empty_dirs(path: &PathBuf, top: &PathBuf) {
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if !d.starts_with(top) || d.parent() == Some(top.as_path()) || d == top.as_path() {
+            break;
+        }
+        // This only succeeds if they're empty.
+        fs::remove_dir(d.join("__pycache__")).ok();
+        if fs::remove_dir(d).is_err() {
+            break; // Not empty.
+        }
+        dir = d.parent();
+    }
+}
+
+/// Uninstall a package, removing exactly the files listed in its `RECORD`. If `dry_run` is
+/// true, list what we'd remove instead. Falls back to guessing from the package and
+/// `top_level.txt` names if there's no `RECORD`.
+pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &PathBuf, dry_run: bool) {
+    let record = util::find_dist_info(name_ins, vers_ins, lib_path).and_then(|d| {
+        fs::read_to_string(d.join("RECORD"))
+            .ok()
+            .map(|r| parse_record(&r))
+    });
+    let record = match record {
+        Some(r) => r,
+        None => {
+            if dry_run {
+                println!(
+                    "Would uninstall {} {}; it has no RECORD file, so we can't list its files",
+                    name_ins, vers_ins
+                );
+            } else {
+                uninstall_legacy(name_ins, vers_ins, lib_path);
+            }
+            return;
+        }
+    };
+
+    let top = lib_path
+        .parent()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| lib_path.clone());
+    let mut files = vec![];
+    let mut modified = vec![];
+    for entry in record.iter() {
+        let path = match resolve_record_path(lib_path, &entry.path) {
+            Some(p) => p,
+            None => {
+                util::print_color(
+                    &format!("Skipping a path outside the install folder: {}", entry.path),
+                    Color::DarkYellow,
+                );
+                continue;
+            }
+        };
+        if !path.is_file() {
+            continue;
+        }
+        if !entry.hash.is_empty() {
+            if let Ok(data) = fs::read(&path) {
+                if record_hash(&data) != entry.hash {
+                    modified.push(entry.path.clone());
+                }
+            }
+        }
+        files.push((entry.path.clone(), path));
+    }
+
+    for path in modified.iter() {
+        util::print_color(
+            &format!("{} has been modified since it was installed", path),
+            Color::DarkYellow,
+        );
+    }
+
+    if dry_run {
+        println!("Would uninstall {} {}:", name_ins, vers_ins);
+        for (rel_path, _) in files.iter() {
+            println!("  {}", rel_path);
+        }
+        return;
+    }
     #[cfg(target_os = "windows")]
     println!("Uninstalling {}: {}...", name_ins, vers_ins.to_string());
     #[cfg(target_os = "linux")]
@@ -388,28 +722,30 @@ pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &PathBuf) {
         vers_ins.to_string()
     );
 
+    for (_, path) in files.iter() {
+        if fs::remove_file(path).is_err() {
+            println!("{}Problem removing {:?}", Colored::Fg(Color::DarkRed), path);
+            continue;
+        }
+        if path.extension().map(|e| e == "py") == Some(true) {
+            remove_bytecode(path);
+        }
+    }
+    for (_, path) in files.iter() {
+        remove_empty_dirs(path, &top);
+    }
+}
+
+/// Uninstall a package installed without a `RECORD`, eg by an older version of this tool.
+fn uninstall_legacy(name_ins: &str, vers_ins: &Version, lib_path: &PathBuf) {
     // Uninstall the package
     // package folders appear to be lowercase, while metadata keeps the package title's casing.
 
-    let mut dist_info_path =
-        lib_path.join(format!("{}-{}.dist-info", name_ins, vers_ins.to_string()));
-    // todo: DRY
-    if !dist_info_path.exists() && vers_ins.patch == 0 {
-        dist_info_path = lib_path.join(format!(
-            "{}-{}.dist-info",
-            name_ins,
-            vers_ins.to_string_med()
-        ));
-        if !dist_info_path.exists() && vers_ins.minor == 0 {
-            dist_info_path = lib_path.join(format!(
-                "{}-{}.dist-info",
-                name_ins,
-                vers_ins.to_string_short()
-            ));
-        }
-    }
+    let dist_info_path = util::find_dist_info(name_ins, vers_ins, lib_path).unwrap_or_else(|| {
+        lib_path.join(format!("{}-{}.dist-info", name_ins, vers_ins.to_string2()))
+    });
 
-    let egg_info_path = lib_path.join(format!("{}-{}.egg-info", name_ins, vers_ins.to_string()));
+    let egg_info_path = lib_path.join(format!("{}-{}.egg-info", name_ins, vers_ins.to_string2()));
 
     // todo: could top_level.txt be in egg-info too?
     // Sometimes the folder unpacked to isn't the same name as on pypi. Check for `top_level.txt`.
@@ -459,7 +795,7 @@ pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &PathBuf) {
     }
 
     // Remove the data directory, if it exists.
-    fs::remove_dir_all(lib_path.join(format!("{}-{}.data", name_ins, vers_ins.to_string())))
+    fs::remove_dir_all(lib_path.join(format!("{}-{}.data", name_ins, vers_ins.to_string2())))
         .unwrap_or_else(|_| ());
 
     // Remove console scripts.
@@ -517,3 +853,290 @@ pub fn rename_metadata(path: &PathBuf, _old: &str, new: &str) {
 
     // todo: Modify other files like entry_points.txt, perhaps.
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::{env, io::Write};
+
+    /// Build a wheel from (path, contents) pairs, with a `RECORD` listing them.
+    fn make_wheel(path: &PathBuf, files: &[(&str, &str)], record_extra: &[RecordEntry]) {
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        let options = zip::write::FileOptions::default();
+        let mut record = vec![];
+        for (name, data) in files.iter() {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+            record.push(RecordEntry::new(name, data.as_bytes()));
+        }
+        record.extend_from_slice(record_extra);
+        zip.start_file("pkg-1.0.dist-info/RECORD", options).unwrap();
+        zip.write_all(serialize_record(&record).as_bytes()).unwrap();
+        zip.finish().unwrap();
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(name);
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        dir
+    }
+
+    const WHEEL: (&str, &str) = (
+        "pkg-1.0.dist-info/WHEEL",
+        "Wheel-Version: 1.0\nRoot-Is-Purelib: true\n",
+    );
+
+    #[test]
+    fn record() {
+        let text = "pkg/__init__.py,sha256=abc,12\n\"pkg/a,b.py\",sha256=def,3\npkg-1.0.dist-info/RECORD,,\n";
+        let parsed = parse_record(text);
+        assert_eq!(
+            parsed,
+            vec![
+                RecordEntry {
+                    path: "pkg/__init__.py".into(),
+                    hash: "sha256=abc".into(),
+                    size: Some(12)
+                },
+                RecordEntry {
+                    path: "pkg/a,b.py".into(),
+                    hash: "sha256=def".into(),
+                    size: Some(3)
+                },
+                RecordEntry {
+                    path: "pkg-1.0.dist-info/RECORD".into(),
+                    hash: "".into(),
+                    size: None
+                },
+            ]
+        );
+        assert_eq!(serialize_record(&parsed), text);
+        assert_eq!(
+            record_hash(b""),
+            "sha256=47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU"
+        );
+    }
+
+    #[test]
+    fn install_wheel_with_data() {
+        let dir = test_dir("pypackage_install_test");
+        let (lib, bin) = (dir.join("lib"), dir.join(".venv/bin"));
+        let wheel = dir.join("pkg-1.0-py3-none-any.whl");
+        make_wheel(
+            &wheel,
+            &[
+                ("pkg/__init__.py", "x = 1\n"),
+                ("pkg-1.0.data/scripts/pkg-tool", "#!python\nprint('hi')\n"),
+                ("pkg-1.0.data/headers/pkg.h", "int x;\n"),
+                ("pkg-1.0.data/data/share/pkg/readme.txt", "docs\n"),
+                ("pkg-1.0.data/platlib/pkg_ext.so", "binary"),
+                ("pkg-1.0.dist-info/METADATA", "Name: pkg\nVersion: 1.0\n"),
+                WHEEL,
+            ],
+            &[],
+        );
+
+        install_wheel(&wheel, "pkg", &Version::new(1, 0, 0), &lib, &bin, &None).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(lib.join("pkg/__init__.py")).unwrap(),
+            "x = 1\n"
+        );
+        assert!(lib.join("pkg_ext.so").exists());
+        assert!(dir.join("include/pkg/pkg.h").exists());
+        assert!(dir.join("share/pkg/readme.txt").exists());
+        assert!(fs::read_to_string(dir.join("bin/pkg-tool"))
+            .unwrap()
+            .starts_with(&format!("#!{}\n", bin.join("python").to_string_lossy())));
+        assert!(!lib.join("pkg-1.0.data").exists());
+        assert_eq!(
+            fs::read_to_string(lib.join("pkg-1.0.dist-info/INSTALLER")).unwrap(),
+            "pypackage\n"
+        );
+
+        let record =
+            parse_record(&fs::read_to_string(lib.join("pkg-1.0.dist-info/RECORD")).unwrap());
+        let paths: Vec<&str> = record.iter().map(|e| e.path.as_ref()).collect();
+        assert!(paths.contains(&"pkg/__init__.py"));
+        assert!(paths.contains(&"../bin/pkg-tool"));
+        assert!(paths.contains(&"../include/pkg/pkg.h"));
+        assert!(paths.contains(&"../share/pkg/readme.txt"));
+        assert!(paths.contains(&"pkg-1.0.dist-info/INSTALLER"));
+        assert_eq!(paths.last(), Some(&"pkg-1.0.dist-info/RECORD"));
+        for entry in record.iter().filter(|e| !e.hash.is_empty()) {
+            let data = fs::read(lib.join(&entry.path)).unwrap();
+            assert_eq!(entry.hash, record_hash(&data));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reject_bad_wheels() {
+        let dir = test_dir("pypackage_install_bad_test");
+        let (lib, bin) = (dir.join("lib"), dir.join("bin"));
+        let version = Version::new(1, 0, 0);
+
+        // A file that doesn't match its hash in RECORD.
+        let wheel = dir.join("bad_hash.whl");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&wheel).unwrap());
+        let options = zip::write::FileOptions::default();
+        zip.start_file(WHEEL.0, options).unwrap();
+        zip.write_all(WHEEL.1.as_bytes()).unwrap();
+        zip.start_file("pkg/__init__.py", options).unwrap();
+        zip.write_all(b"import os; os.system('bad')").unwrap();
+        zip.start_file("pkg-1.0.dist-info/RECORD", options).unwrap();
+        let record = vec![
+            RecordEntry::new(WHEEL.0, WHEEL.1.as_bytes()),
+            RecordEntry::new("pkg/__init__.py", b"x = 1\n"),
+        ];
+        zip.write_all(serialize_record(&record).as_bytes()).unwrap();
+        zip.finish().unwrap();
+
+        assert!(install_wheel(&wheel, "pkg", &version, &lib, &bin, &None).is_err());
+        assert!(!lib.join("pkg").exists());
+
+        // A file not listed in RECORD.
+        let wheel = dir.join("unlisted.whl");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&wheel).unwrap());
+        zip.start_file(WHEEL.0, options).unwrap();
+        zip.write_all(WHEEL.1.as_bytes()).unwrap();
+        zip.start_file("pkg/__init__.py", options).unwrap();
+        zip.write_all(b"x = 1\n").unwrap();
+        zip.start_file("pkg-1.0.dist-info/RECORD", options).unwrap();
+        zip.write_all(serialize_record(&record[..1]).as_bytes())
+            .unwrap();
+        zip.finish().unwrap();
+
+        assert!(install_wheel(&wheel, "pkg", &version, &lib, &bin, &None).is_err());
+
+        // Path traversal.
+        let wheel = dir.join("traversal.whl");
+        make_wheel(&wheel, &[WHEEL, ("../../evil.py", "x = 1\n")], &[]);
+        assert!(install_wheel(&wheel, "pkg", &version, &lib, &bin, &None).is_err());
+        assert!(!dir.join("evil.py").exists());
+        assert!(!env::temp_dir().join("evil.py").exists());
+
+        let wheel = dir.join("traversal_data.whl");
+        make_wheel(
+            &wheel,
+            &[WHEEL, ("pkg-1.0.data/data/../../../evil.py", "x = 1\n")],
+            &[],
+        );
+        assert!(install_wheel(&wheel, "pkg", &version, &lib, &bin, &None).is_err());
+
+        assert!(!is_safe_path("/etc/passwd"));
+        assert!(!is_safe_path("C:\\evil.py"));
+        assert!(is_safe_path("pkg/sub/mod.py"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn record_hash_algorithms() {
+        let dir = test_dir("pypackage_install_algorithms_test");
+        let (lib, bin) = (dir.join("lib"), dir.join("bin"));
+        let version = Version::new(1, 0, 0);
+
+        // Build a wheel whose RECORD uses a given algorithm.
+        let make = |filename: &str, name: &str, algorithm: &'static digest::Algorithm| {
+            let wheel = dir.join(filename);
+            let mut zip = zip::ZipWriter::new(fs::File::create(&wheel).unwrap());
+            let options = zip::write::FileOptions::default();
+            let mut record = vec![];
+            for (path, data) in [WHEEL, ("pkg/__init__.py", "x = 1\n")].iter() {
+                zip.start_file(*path, options).unwrap();
+                zip.write_all(data.as_bytes()).unwrap();
+                let digest = digest::digest(algorithm, data.as_bytes());
+                record.push(RecordEntry {
+                    path: (*path).to_owned(),
+                    hash: format!(
+                        "{}={}",
+                        name,
+                        data_encoding::BASE64URL_NOPAD.encode(digest.as_ref())
+                    ),
+                    size: Some(data.len() as u64),
+                });
+            }
+            zip.start_file("pkg-1.0.dist-info/RECORD", options).unwrap();
+            zip.write_all(serialize_record(&record).as_bytes()).unwrap();
+            zip.finish().unwrap();
+            wheel
+        };
+
+        let wheel = make("sha512.whl", "sha512", &digest::SHA512);
+        assert!(install_wheel(&wheel, "pkg", &version, &lib, &bin, &None).is_ok());
+        assert!(lib.join("pkg/__init__.py").exists());
+
+        // Weaker algorithms aren't accepted, even if the hash matches.
+        fs::remove_dir_all(&lib).unwrap();
+        let wheel = make("sha1.whl", "sha1", &digest::SHA1_FOR_LEGACY_USE_ONLY);
+        assert!(install_wheel(&wheel, "pkg", &version, &lib, &bin, &None).is_err());
+        assert!(!lib.join("pkg").exists());
+
+        assert_eq!(record_algorithm("sha384=abc").map(|(_, h)| h), Some("abc"));
+        assert!(record_algorithm("md5=abc").is_none());
+        assert!(record_algorithm("sha256").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn record_paths() {
+        let lib = PathBuf::from("/proj/__pypackages__/3.7/lib");
+        let top = PathBuf::from("/proj/__pypackages__/3.7");
+        assert_eq!(
+            resolve_record_path(&lib, "pkg/__init__.py"),
+            Some(top.join("lib/pkg/__init__.py"))
+        );
+        assert_eq!(
+            resolve_record_path(&lib, "../bin/pkg-tool"),
+            Some(top.join("bin/pkg-tool"))
+        );
+        assert_eq!(resolve_record_path(&lib, "../../evil.py"), None);
+        assert_eq!(resolve_record_path(&lib, "../"), None);
+        assert_eq!(resolve_record_path(&lib, "/etc/passwd"), None);
+    }
+
+    #[test]
+    fn uninstall_from_record() {
+        let dir = test_dir("pypackage_uninstall_test");
+        let (lib, bin) = (dir.join("lib"), dir.join(".venv/bin"));
+        fs::write(lib.join("other.py"), "").unwrap();
+        let wheel = dir.join("pkg-1.0-py3-none-any.whl");
+        make_wheel(
+            &wheel,
+            &[
+                ("pkg/__init__.py", "x = 1\n"),
+                ("pkg/sub/mod.py", "y = 2\n"),
+                ("pkg_ext.so", "binary"),
+                ("pkg-1.0.data/scripts/pkg-tool", "#!python\n"),
+                ("pkg-1.0.data/headers/pkg.h", "int x;\n"),
+                ("pkg-1.0.dist-info/METADATA", "Name: pkg\nVersion: 1.0\n"),
+                WHEEL,
+            ],
+            &[],
+        );
+        let version = Version::new(1, 0, 0);
+        install_wheel(&wheel, "pkg", &version, &lib, &bin, &None).unwrap();
+        fs::create_dir_all(lib.join("pkg/__pycache__")).unwrap();
+        fs::write(lib.join("pkg/__pycache__/__init__.cpython-37.pyc"), "").unwrap();
+        fs::write(lib.join("pkg/sub/mod.py"), "y = 3\n").unwrap();
+
+        uninstall("pkg", &version, &lib, true);
+        assert!(lib.join("pkg/__init__.py").exists());
+        assert!(lib.join("pkg-1.0.dist-info/RECORD").exists());
+
+        uninstall("pkg", &version, &lib, false);
+        assert!(!lib.join("pkg").exists());
+        assert!(!lib.join("pkg_ext.so").exists());
+        assert!(!lib.join("pkg-1.0.dist-info").exists());
+        assert!(!dir.join("bin/pkg-tool").exists());
+        assert!(!dir.join("include/pkg").exists());
+        assert!(lib.join("other.py").exists());
+        assert!(dir.join("bin").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
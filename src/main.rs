@@ -1,26 +1,45 @@
 use crate::dep_types::{
-    Constraint, DependencyError, Lock, LockPackage, Package, Rename, Req, ReqType, Version,
+    Constraint, DependencyError, Lock, LockArtifact, LockPackage, Package, Rename, Req, ReqType,
+    Version, LOCK_VERSION,
 };
+use crate::markers::Marker;
 use crate::util::abort;
 use crossterm::{Color, Colored};
 use install::PackageType::{Source, Wheel};
 use regex::Regex;
 use serde::Deserialize;
 use std::{
-    collections::HashMap, env, error::Error, fmt, fs, io, path::PathBuf, process::Command,
+    collections::{BTreeMap, HashMap},
+    env,
+    error::Error,
+    fmt, fs, io,
+    path::PathBuf,
+    process::Command,
     str::FromStr,
 };
 
 use crate::dep_resolution::WarehouseRelease;
+use crate::index::PackageIndex;
 use crate::install::PackageType;
 use structopt::StructOpt;
 
 mod build;
+mod cache;
 mod commands;
 mod dep_resolution;
 mod dep_types;
 mod files;
+mod index;
 mod install;
+mod markers;
+mod metadata;
+mod outdated;
+mod pep517;
+mod pubgrub;
+mod tags;
+mod toml_doc;
+mod tree;
+mod upload;
 mod util;
 
 type PackToInstall = ((String, Version), Option<(u32, String)>); // ((Name, Version), (parent id, rename name))
@@ -92,17 +111,135 @@ Install packages from `pyproject.toml`, `pypackage.lock`, or speficied ones. Exa
 
 `pypackage install`: sync your installation with `pyproject.toml`, or `pypackage.lock` if it exists.
 `pypackage install numpy scipy`: install `numpy` and `scipy`.
+`pypackage install --group docs`: install the `docs` dependency group too.
+`pypackage install --no-dev`: install without the `dev` dependency group.
+`pypackage install --extras security,socks`: install the project's `security` and `socks` extras.
+`pypackage install --frozen`: install exactly what's in `pypackage.lock`, without resolving.
+`pypackage install --locked`: install from `pypackage.lock`, failing if it's out of date.
 "
     )]
     Install {
         #[structopt(name = "packages")]
         packages: Vec<String>,
+        /// Don't use the network; install from the lock file and the package cache only
+        #[structopt(long = "offline")]
+        offline: bool,
+        /// Also install a dependency group, eg `docs` or `test`. May be used more than once
+        #[structopt(short = "g", long = "group")]
+        group: Vec<String>,
+        /// Don't install the `dev` dependency group
+        #[structopt(long = "no-dev")]
+        no_dev: bool,
+        /// Install the project's extras, eg `--extras security,socks`
+        #[structopt(short = "E", long = "extras", raw(use_delimiter = "true"))]
+        extras: Vec<String>,
+        /// Install exactly what's in the lock file, without resolving or checking `pyproject.toml`
+        #[structopt(
+            long = "frozen",
+            raw(conflicts_with_all = r#"&["locked", "packages"]"#)
+        )]
+        frozen: bool,
+        /// Install from the lock file, and fail if it's out of date with `pyproject.toml`
+        #[structopt(long = "locked", raw(conflicts_with = r#""packages""#))]
+        locked: bool,
+    },
+    /// Upgrade locked packages to their newest compatible versions
+    #[structopt(
+        name = "update",
+        help = "
+Upgrade locked packages to the newest versions `pyproject.toml` allows. Example:
+
+`pypackage update requests`: upgrade `requests`, and only what it needs changed.
+`pypackage update --all`: upgrade everything.
+`pypackage update --all --dry-run`: show what would change.
+`pypackage update --all --bump-pyproject`: also raise caret constraints, eg `^2.22` to `^2.23`.
+"
+    )]
+    Update {
+        #[structopt(name = "packages")]
+        packages: Vec<String>,
+        /// Upgrade every package
+        #[structopt(long = "all", raw(conflicts_with = r#""packages""#))]
+        all: bool,
+        /// Show the version changes, without changing anything
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+        /// Raise caret constraints in `pyproject.toml` to the new versions
+        #[structopt(long = "bump-pyproject")]
+        bump_pyproject: bool,
+    },
+    /// Show locked packages with newer versions, and what's keeping them back
+    #[structopt(name = "outdated")]
+    Outdated {
+        /// `text`, or `json`
+        #[structopt(
+            long = "format",
+            default_value = "text",
+            raw(possible_values = r#"&["text", "json"]"#)
+        )]
+        format: String,
+    },
+    /// Show the locked dependencies as a tree
+    #[structopt(
+        name = "tree",
+        help = "
+Show the locked dependencies as a tree, with the requirement behind each. Example:
+
+`pypackage tree`: show everything the project requires.
+`pypackage tree --invert urllib3`: show what requires `urllib3`.
+`pypackage tree --depth 1`: show only the project's own requirements.
+`pypackage tree --format dot | dot -Tsvg > deps.svg`: draw the graph with Graphviz.
+"
+    )]
+    Tree {
+        /// Show what requires this package, instead of what the project requires
+        #[structopt(short = "i", long = "invert")]
+        invert: Option<String>,
+        /// How many levels of dependencies to show
+        #[structopt(short = "d", long = "depth")]
+        depth: Option<usize>,
+        /// `text`, `dot`, or `json`
+        #[structopt(
+            long = "format",
+            default_value = "text",
+            raw(possible_values = r#"&["text", "dot", "json"]"#)
+        )]
+        format: String,
+    },
+    /// Explain why a package is installed at its locked version
+    #[structopt(name = "why", raw(alias = r#""explain""#))]
+    Why {
+        #[structopt(name = "package")]
+        package: String,
+    },
+    /// Resolve dependencies and write the lock file, without installing anything
+    #[structopt(name = "lock")]
+    Lock {
+        /// Don't use the network; resolve from the lock file and the package cache only
+        #[structopt(long = "offline")]
+        offline: bool,
     },
     /// Uninstall all packages, or ones specified
     #[structopt(name = "uninstall")]
     Uninstall {
         #[structopt(name = "packages")]
         packages: Vec<String>,
+        /// Don't use the network; re-lock the remaining packages from the lock file and the
+        /// package cache only
+        #[structopt(long = "offline")]
+        offline: bool,
+        /// List the files that would be removed, without changing anything
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+        /// Keep a dependency group installed, eg `docs` or `test`. May be used more than once
+        #[structopt(short = "g", long = "group")]
+        group: Vec<String>,
+        /// Remove the `dev` dependency group
+        #[structopt(long = "no-dev")]
+        no_dev: bool,
+        /// Keep the project's extras installed, eg `--extras security,socks`
+        #[structopt(short = "E", long = "extras", raw(use_delimiter = "true"))]
+        extras: Vec<String>,
     },
     /// Run python
     #[structopt(name = "python")]
@@ -115,13 +252,29 @@ Install packages from `pyproject.toml`, `pypackage.lock`, or speficied ones. Exa
     List,
     /// Build the package - source and wheel
     #[structopt(name = "package")]
-    Package {
-        #[structopt(name = "extras")]
-        extras: Vec<String>, // todo: rename features?
-    },
-    /// Publish to `pypi`
+    Package,
+    /// Upload the package to PyPi, or another repository
     #[structopt(name = "publish")]
-    Publish,
+    Publish {
+        /// A repository name, from `pyproject.toml` or `~/.pypirc`, or an upload url
+        #[structopt(short = "r", long = "repository")]
+        repository: Option<String>,
+        /// Check the distributions, and show what would be uploaded, without uploading
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Build a wheel or sdist for a PEP 517 frontend, and print its filename. Used by
+    /// `pypackage_backend.py`.
+    #[structopt(
+        name = "build-backend",
+        raw(setting = "structopt::clap::AppSettings::Hidden")
+    )]
+    BuildBackend {
+        #[structopt(name = "kind")]
+        kind: String,
+        #[structopt(name = "directory")]
+        directory: String,
+    },
     /// Create a `pyproject.toml` from requirements.txt, pipfile etc, setup.py etc
     #[structopt(name = "init")]
     Init,
@@ -141,14 +294,14 @@ Install packages from `pyproject.toml`, `pypackage.lock`, or speficied ones. Exa
 #[derive(Clone, Debug, Default, Deserialize)]
 // todo: Auto-desr some of these
 pub struct Config {
-    py_version: Option<Constraint>,
+    /// The `requires-python` constraints, all of which must match.
+    py_version: Vec<Constraint>,
     reqs: Vec<Req>, // name, requirements.
     name: Option<String>,
     version: Option<Version>,
     author: Option<String>,
     author_email: Option<String>,
     license: Option<String>,
-    extras: HashMap<String, String>,
     description: Option<String>,
     classifiers: Vec<String>, // https://pypi.org/classifiers/
     keywords: Vec<String>,    // todo: Options for classifiers and keywords?
@@ -160,6 +313,114 @@ pub struct Config {
     entry_points: HashMap<String, Vec<String>>, // todo option?
     //    console_scripts: HashMap<String, String>,   // todo option?
     console_scripts: Vec<String>, // We don't parse these; pass them to `setup.py` as-entered.
+    allow_renames: bool, // Install multiple renamed versions of a package if resolution fails.
+    index_url: Option<String>, // PyPi if not specified.
+    index_type: Option<String>, // Guessed from the url if not specified.
+    repositories: HashMap<String, String>, // Upload urls for `publish`, by name.
+    optional_reqs: HashMap<String, Vec<Req>>, // From `[project.optional-dependencies]`.
+    groups: HashMap<String, Vec<Req>>, // Dependency groups, eg `dev`, by name.
+}
+
+/// Parse PEP 508 requirements, eg `"requests[security] >= 2.8.1"`.
+fn parse_reqs(reqs: &[String]) -> Vec<Req> {
+    reqs.iter()
+        .map(|r| match Req::from_str(r, true) {
+            Ok(req) => req,
+            Err(e) => {
+                abort(&format!(
+                    "Problem parsing a dependency in `pyproject.toml`: {}",
+                    e.details
+                ));
+                unreachable!()
+            }
+        })
+        .collect()
+}
+
+/// Parse a requirement in the style of `[tool.pypackage.dependencies]`, and Poetry: Its name
+/// is the key, and its value's either a constraint, or a table with `version` and other fields.
+fn req_from_table(
+    name: String,
+    constrs: &str,
+    extras: Option<Vec<String>>,
+    python: Option<String>,
+) -> Req {
+    let constraints = Constraint::from_str_multiple(constrs)
+        .expect("Problem parsing constraints in `pyproject.toml`.");
+    let marker = python.and_then(|v| {
+        markers::Marker::from_python_constraints(
+            &Constraint::from_str_multiple(&v)
+                .expect("Problem parsing python version in dependency"),
+        )
+    });
+    // todo repository etc
+    Req {
+        name,
+        constraints,
+        marker,
+        url: None,
+        install_with_extras: extras,
+    }
+}
+
+fn reqs_from_table(deps: HashMap<String, files::DepComponentWrapper>) -> Vec<Req> {
+    deps.into_iter()
+        .map(|(name, data)| match data {
+            files::DepComponentWrapper::A(constrs) => req_from_table(name, &constrs, None, None),
+            files::DepComponentWrapper::B(d) => {
+                req_from_table(name, &d.constrs, d.extras, d.python)
+            }
+        })
+        .collect()
+}
+
+/// Poetry's dependencies, leaving out the Python version.
+fn reqs_from_poetry(deps: HashMap<String, files::DepComponentWrapperPoetry>) -> Vec<Req> {
+    deps.into_iter()
+        .filter(|(name, _)| name.to_lowercase() != "python")
+        .map(|(name, data)| match data {
+            files::DepComponentWrapperPoetry::A(constrs) => {
+                req_from_table(name, &constrs, None, None)
+            }
+            files::DepComponentWrapperPoetry::B(d) => {
+                req_from_table(name, &d.constrs, d.extras, d.python)
+            }
+        })
+        .collect()
+}
+
+/// The requirements in a `[dependency-groups]` group, including those of groups it includes.
+fn group_reqs(
+    name: &str,
+    groups: &HashMap<String, Vec<files::GroupItem>>,
+    parents: &mut Vec<String>,
+) -> Vec<Req> {
+    if parents.iter().any(|p| util::compare_names(p, name)) {
+        abort(&format!("Dependency group `{}` includes itself", name));
+    }
+    let items = match groups.iter().find(|(g, _)| util::compare_names(g, name)) {
+        Some((_, items)) => items,
+        None => {
+            abort(&format!(
+                "Can't find the included dependency group `{}`",
+                name
+            ));
+            unreachable!()
+        }
+    };
+
+    parents.push(name.to_owned());
+    let mut result = vec![];
+    for item in items.iter() {
+        match item {
+            files::GroupItem::A(r) => result.append(&mut parse_reqs(std::slice::from_ref(r))),
+            files::GroupItem::B { include_group } => {
+                result.append(&mut group_reqs(include_group, groups, parents))
+            }
+        }
+    }
+    parents.pop();
+    result
 }
 
 impl Config {
@@ -181,9 +442,11 @@ impl Config {
             }
         };
         let mut result = Self::default();
+        let tool = decoded.tool.unwrap_or_default();
 
-        // Parse Poetry first, since we'll use PyPackage if there's a conflict.
-        if let Some(po) = decoded.tool.poetry {
+        // Parse Poetry first, then PyPackage, then `[project]`, since we use the last one
+        // parsed if there's a conflict.
+        if let Some(po) = tool.poetry {
             if let Some(v) = po.name {
                 result.name = Some(v);
             }
@@ -203,6 +466,9 @@ impl Config {
             if let Some(v) = po.repository {
                 result.repository = Some(v);
             }
+            if let Some(v) = po.readme {
+                result.readme_filename = Some(v);
+            }
 
             // todo: Process entry pts, classifiers etc?
             if let Some(v) = po.classifiers {
@@ -218,65 +484,80 @@ impl Config {
             //            if let Some(v) = po.scripts {
             //                result.console_scripts = v;
             //            }
-            if let Some(v) = po.extras {
-                result.extras = v;
-            }
-
             if let Some(v) = po.version {
                 result.version = Some(
                     Version::from_str(&v).expect("Problem parsing version in `pyproject.toml`"),
                 )
             }
 
-            // todo: DRY (c+p) from pypackage dependency parsing, other than parsing python version here,
-            // todo which only poetry does.
             if let Some(deps) = po.dependencies {
-                for (name, data) in deps {
-                    let constraints;
-                    let mut extras = None;
-                    let mut python_version = None;
-                    match data {
-                        files::DepComponentWrapperPoetry::A(constrs) => {
-                            constraints = Constraint::from_str_multiple(&constrs)
-                                .expect("Problem parsing constraints in `pyproject.toml`.");
-                        }
-                        files::DepComponentWrapperPoetry::B(subdata) => {
-                            constraints = Constraint::from_str_multiple(&subdata.constrs)
-                                .expect("Problem parsing constraints in `pyproject.toml`.");
-                            if let Some(ex) = subdata.extras {
-                                extras = Some(ex);
-                            }
-                            if let Some(v) = subdata.python {
-                                python_version = Some(
-                                    Constraint::from_str(&v)
-                                        .expect("Problem parsing python version in dependency"),
-                                );
-                            }
-                            // todo repository etc
-                        }
-                    }
-                    if name.to_lowercase() == "python" {
-                        result.py_version = Some(
-                            constraints
-                                .get(0)
-                                .unwrap_or(&Constraint::new(ReqType::Tilde, Version::new(3, 4, 0)))
-                                .clone(),
-                        );
-                    } else {
-                        result.reqs.push(Req {
-                            name,
-                            constraints,
-                            extra: None,
-                            sys_platform: None,
-                            python_version,
-                            install_with_extras: extras,
-                        });
+                // Poetry lists the Python version along with dependencies.
+                let python = deps.iter().find(|(n, _)| n.to_lowercase() == "python");
+                if let Some((_, data)) = python {
+                    let constrs = match data {
+                        files::DepComponentWrapperPoetry::A(constrs) => constrs,
+                        files::DepComponentWrapperPoetry::B(subdata) => &subdata.constrs,
+                    };
+                    result.py_version = Constraint::from_str_multiple(constrs)
+                        .expect("Problem parsing constraints in `pyproject.toml`.");
+                    if result.py_version.is_empty() {
+                        result.py_version =
+                            vec![Constraint::new(ReqType::Tilde, Version::new(3, 4, 0))];
                     }
                 }
+                // Optional dependencies are only installed with the extras that list them.
+                let optional: Vec<String> = deps
+                    .iter()
+                    .filter_map(|(name, data)| match data {
+                        files::DepComponentWrapperPoetry::B(d) if d.optional == Some(true) => {
+                            Some(name.clone())
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                let (optional, reqs): (Vec<Req>, Vec<Req>) = reqs_from_poetry(deps)
+                    .into_iter()
+                    .partition(|r| optional.contains(&r.name));
+                result.reqs.extend(reqs);
+
+                for (extra, names) in po.extras.unwrap_or_default().into_iter() {
+                    let reqs = names
+                        .iter()
+                        .map(
+                            |n| match optional.iter().find(|r| util::compare_names(&r.name, n)) {
+                                Some(r) => r.clone(),
+                                None => {
+                                    abort(&format!(
+                                        "Extra `{}` lists `{}`, which isn't an optional dependency",
+                                        extra, n
+                                    ));
+                                    unreachable!()
+                                }
+                            },
+                        )
+                        .collect();
+                    result.optional_reqs.insert(extra, reqs);
+                }
+            }
+            if let Some(deps) = po.dev_dependencies {
+                result
+                    .groups
+                    .entry("dev".into())
+                    .or_default()
+                    .extend(reqs_from_poetry(deps));
+            }
+            for (name, group) in po.group.unwrap_or_default().into_iter() {
+                if let Some(deps) = group.dependencies {
+                    result
+                        .groups
+                        .entry(name)
+                        .or_default()
+                        .extend(reqs_from_poetry(deps));
+                }
             }
         }
 
-        if let Some(pp) = decoded.tool.pypackage {
+        if let Some(pp) = tool.pypackage {
             if let Some(v) = pp.name {
                 result.name = Some(v);
             }
@@ -298,6 +579,15 @@ impl Config {
             if let Some(v) = pp.repository {
                 result.repository = Some(v);
             }
+            if let Some(v) = pp.readme_filename {
+                result.readme_filename = Some(v);
+            }
+            if let Some(v) = pp.package_url {
+                result.package_url = Some(v);
+            }
+            if let Some(v) = pp.repositories {
+                result.repositories = v;
+            }
 
             // todo: Process entry pts, classifiers etc?
             if let Some(v) = pp.classifiers {
@@ -312,6 +602,15 @@ impl Config {
             if let Some(v) = pp.console_scripts {
                 result.console_scripts = v;
             }
+            if let Some(v) = pp.allow_renames {
+                result.allow_renames = v;
+            }
+            if let Some(v) = pp.index_url {
+                result.index_url = Some(v);
+            }
+            if let Some(v) = pp.index_type {
+                result.index_type = Some(v);
+            }
 
             if let Some(v) = pp.version {
                 result.version = Some(
@@ -320,100 +619,361 @@ impl Config {
             }
 
             if let Some(v) = pp.py_version {
-                result.py_version = Some(
-                    Constraint::from_str(&v)
-                        .expect("Problem parsing python version in `pyproject.toml`"),
-                )
+                result.py_version = vec![Constraint::from_str(&v)
+                    .expect("Problem parsing python version in `pyproject.toml`")];
             }
 
             if let Some(deps) = pp.dependencies {
-                for (name, data) in deps {
-                    let constraints;
-                    let mut extras = None;
-                    let mut python_version = None;
-                    match data {
-                        files::DepComponentWrapper::A(constrs) => {
-                            constraints = Constraint::from_str_multiple(&constrs)
-                                .expect("Problem parsing constraints in `pyproject.toml`.");
-                        }
-                        files::DepComponentWrapper::B(subdata) => {
-                            constraints = Constraint::from_str_multiple(&subdata.constrs)
-                                .expect("Problem parsing constraints in `pyproject.toml`.");
-                            if let Some(ex) = subdata.extras {
-                                extras = Some(ex);
-                            }
-                            if let Some(v) = subdata.python {
-                                python_version = Some(
-                                    Constraint::from_str(&v)
-                                        .expect("Problem parsing python version in dependency"),
-                                );
-                            }
-                            // todo repository etc
-                        }
+                result.reqs.extend(reqs_from_table(deps));
+            }
+            if let Some(extras) = pp.extras {
+                for (extra, reqs) in extras.into_iter() {
+                    result.optional_reqs.insert(extra, parse_reqs(&reqs));
+                }
+            }
+            if let Some(deps) = pp.dev_dependencies {
+                result
+                    .groups
+                    .entry("dev".into())
+                    .or_default()
+                    .extend(reqs_from_table(deps));
+            }
+        }
+
+        if let Some(pr) = decoded.project {
+            result.apply_project(pr, &toml_str);
+        }
+        // Standard dependency groups replace tool-specific ones with the same name.
+        if let Some(groups) = decoded.dependency_groups {
+            for name in groups.keys() {
+                let reqs = group_reqs(name, &groups, &mut vec![]);
+                result.groups.insert(name.clone(), reqs);
+            }
+        }
+
+        Some(result)
+    }
+
+    /// Use values from the standard `[project]` table, overriding the tool-specific ones.
+    /// Fields it lists as `dynamic` are left to the tool tables.
+    fn apply_project(&mut self, pr: files::Project, toml_str: &str) {
+        let dynamic = pr.dynamic.unwrap_or_default();
+        // PEP 621 forbids listing a field as dynamic, and also specifying it.
+        if let Ok(toml::Value::Table(t)) = toml_str.parse::<toml::Value>() {
+            if let Some(toml::Value::Table(project)) = t.get("project") {
+                for field in dynamic.iter() {
+                    if project.contains_key(field) {
+                        abort(&format!(
+                            "`{}` is listed in `dynamic` in `[project]`, but is also specified there",
+                            field
+                        ));
                     }
-                    //                    let
+                }
+            }
+        }
 
-                    result.reqs.push(Req {
-                        name,
-                        constraints,
-                        extra: None,
-                        sys_platform: None,
-                        python_version,
-                        install_with_extras: extras,
-                    });
+        if let Some(v) = pr.name {
+            self.name = Some(v);
+        }
+        if let Some(v) = pr.version {
+            self.version =
+                Some(Version::from_str(&v).expect("Problem parsing version in `pyproject.toml`"))
+        }
+        if let Some(v) = pr.description {
+            self.description = Some(v);
+        }
+        match pr.readme {
+            Some(files::ProjectFile::A(v)) => self.readme_filename = Some(v),
+            Some(files::ProjectFile::B { file: Some(v), .. }) => self.readme_filename = Some(v),
+            _ => (),
+        }
+        if let Some(v) = pr.requires_python {
+            self.py_version = Constraint::from_str_multiple(&v)
+                .expect("Problem parsing `requires-python` in `pyproject.toml`");
+        }
+        match pr.license {
+            Some(files::ProjectFile::A(v)) => self.license = Some(v),
+            Some(files::ProjectFile::B { text: Some(v), .. }) => self.license = Some(v),
+            _ => (),
+        }
+        if let Some(people) = pr.authors.or(pr.maintainers) {
+            let names: Vec<String> = people.iter().filter_map(|p| p.name.clone()).collect();
+            let emails: Vec<String> = people.iter().filter_map(|p| p.email.clone()).collect();
+            if !names.is_empty() {
+                self.author = Some(names.join(", "));
+            }
+            if !emails.is_empty() {
+                self.author_email = Some(emails.join(", "));
+            }
+        }
+        if let Some(v) = pr.keywords {
+            self.keywords = v;
+        }
+        if let Some(v) = pr.classifiers {
+            self.classifiers = v;
+        }
+        if let Some(urls) = pr.urls {
+            for (label, url) in urls.into_iter() {
+                // Labels are free-form, eg `Homepage` or `Source Code`.
+                let label: String = label
+                    .to_lowercase()
+                    .chars()
+                    .filter(char::is_ascii_alphanumeric)
+                    .collect();
+                match label.as_ref() {
+                    "homepage" => self.homepage = Some(url),
+                    "repository" | "source" | "sourcecode" => self.repository = Some(url),
+                    _ => (),
                 }
             }
         }
 
-        Some(result)
+        let sorted_eps = |eps: HashMap<String, String>| {
+            let mut result: Vec<String> =
+                eps.iter().map(|(k, v)| format!("{} = {}", k, v)).collect();
+            result.sort();
+            result
+        };
+        if let Some(v) = pr.scripts {
+            self.console_scripts = sorted_eps(v);
+        }
+        if let Some(v) = pr.gui_scripts {
+            self.entry_points
+                .insert("gui_scripts".to_owned(), sorted_eps(v));
+        }
+        if let Some(groups) = pr.entry_points {
+            for (group, eps) in groups.into_iter() {
+                self.entry_points.insert(group, sorted_eps(eps));
+            }
+        }
+
+        if let Some(v) = pr.dependencies {
+            self.reqs = parse_reqs(&v);
+        }
+        if let Some(v) = pr.optional_dependencies {
+            self.optional_reqs = v
+                .iter()
+                .map(|(extra, reqs)| (extra.clone(), parse_reqs(reqs)))
+                .collect();
+        }
+    }
+
+    /// The requirements an extra adds. Requirements on this project itself, eg `my-pkg[test]`
+    /// in an `all` extra, are replaced by those extras' requirements.
+    fn extra_reqs(&self, extra: &str) -> Vec<Req> {
+        let mut result = vec![];
+        let mut to_visit = vec![extra.to_owned()];
+        let mut visited: Vec<String> = vec![];
+        while let Some(extra) = to_visit.pop() {
+            if visited.iter().any(|v| util::compare_names(v, &extra)) {
+                continue;
+            }
+            let reqs = self
+                .optional_reqs
+                .iter()
+                .find(|(e, _)| util::compare_names(e, &extra))
+                .map(|(_, reqs)| reqs.clone())
+                .unwrap_or_default();
+            for req in reqs.into_iter() {
+                let this_project = match &self.name {
+                    Some(name) => util::compare_names(name, &req.name),
+                    None => false,
+                };
+                if this_project {
+                    to_visit.extend(req.install_with_extras.unwrap_or_default());
+                } else if !result.contains(&req) {
+                    result.push(req);
+                }
+            }
+            visited.push(extra);
+        }
+        result
+    }
+
+    /// The main requirements, and those of every group and extra.
+    fn all_reqs(&self) -> Vec<Req> {
+        let mut result = self.reqs.clone();
+        for reqs in self.groups.values().chain(self.optional_reqs.values()) {
+            result.extend(reqs.iter().cloned());
+        }
+        result
+    }
+
+    /// The `requires-python` constraints, eg `>=3.7, <4`, if there are any.
+    fn requires_python(&self) -> Option<String> {
+        if self.py_version.is_empty() {
+            return None;
+        }
+        let constrs: Vec<String> = self
+            .py_version
+            .iter()
+            .map(|c| c.to_pep440_string())
+            .collect();
+        Some(constrs.join(", "))
+    }
+
+    /// Whether `requires-python` allows a Python version.
+    fn python_compatible(&self, version: &Version) -> bool {
+        self.py_version.iter().all(|c| c.is_compatible(version))
     }
 
-    /// Create a new `pyproject.toml` file.
-    fn write_file(&self, filename: &str) {
+    /// Create a new `pyproject.toml` file, using the standard `[project]` table.
+    fn write_file(&self, filename: &str, index: &dyn PackageIndex) {
         let file = PathBuf::from(filename);
         if file.exists() {
             abort("`pyproject.toml` already exists")
         }
+        // A TOML string, with quotes and escapes.
+        let quote = |v: &str| toml::Value::String(v.to_owned()).to_string();
+        let quote_list = |vs: &[String]| {
+            vs.iter()
+                .map(|v| quote(v))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        let entry_point = |ep: &str| -> Option<String> {
+            let mut parts = ep.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) => Some(format!("{} = {}\n", k.trim(), quote(v.trim()))),
+                _ => None,
+            }
+        };
 
         let mut result =
-            "# See PEP 518: https://www.python.org/dev/peps/pep-0518/ for info on this \
+            "# See PEP 621: https://www.python.org/dev/peps/pep-0621/ for info on this \
              file's structure.\n"
                 .to_string();
 
-        result.push_str("\n[tool.pypackage]\n");
-        if let Some(name) = &self.name {
-            result.push_str(&("name = \"".to_owned() + name + "\"\n"));
-        } else {
-            // Give name, and a few other fields default values.
-            result.push_str(&("name = \"\"".to_owned() + "\n"));
+        result.push_str("\n[project]\n");
+        result.push_str(&format!(
+            "name = {}\n",
+            quote(&self.name.clone().unwrap_or_default())
+        ));
+        if let Some(vers) = &self.version {
+            result.push_str(&format!("version = {}\n", quote(&vers.to_string2())));
         }
-        if let Some(py_v) = &self.py_version {
-            result
-                .push_str(&("py_version = \"".to_owned() + &py_v.to_string(false, false) + "\"\n"));
-        } else {
-            result.push_str(&("py_version = \"^3.4\"".to_owned() + "\n"));
+        if let Some(v) = &self.description {
+            result.push_str(&format!("description = {}\n", quote(v)));
         }
-        if let Some(vers) = self.version {
-            result.push_str(&(format!("version = \"{}\"", vers.to_string2()) + "\n"));
+        if let Some(v) = &self.readme_filename {
+            result.push_str(&format!("readme = {}\n", quote(v)));
         }
-        if let Some(author) = &self.author {
-            result.push_str(&(format!("author = \"{}\"", author) + "\n"));
+        let py_version = self.requires_python().unwrap_or_else(|| ">=3.4".to_owned());
+        result.push_str(&format!("requires-python = {}\n", quote(&py_version)));
+        if let Some(v) = &self.license {
+            result.push_str(&format!("license = {{text = {}}}\n", quote(v)));
         }
-        if let Some(v) = &self.author_email {
-            result.push_str(&(format!("author_email = \"{}\"", v) + "\n"));
+        if self.author.is_some() || self.author_email.is_some() {
+            let mut person = vec![];
+            if let Some(v) = &self.author {
+                person.push(format!("name = {}", quote(v)));
+            }
+            if let Some(v) = &self.author_email {
+                person.push(format!("email = {}", quote(v)));
+            }
+            result.push_str(&format!("authors = [{{{}}}]\n", person.join(", ")));
         }
-        if let Some(v) = &self.description {
-            result.push_str(&(format!("description = \"{}\"", v) + "\n"));
+        if !self.keywords.is_empty() {
+            result.push_str(&format!("keywords = [{}]\n", quote_list(&self.keywords)));
         }
-        if let Some(v) = &self.homepage {
-            result.push_str(&(format!("homepage = \"{}\"", v) + "\n"));
+        if !self.classifiers.is_empty() {
+            result.push_str("classifiers = [\n");
+            for classifier in self.classifiers.iter() {
+                result.push_str(&format!("    {},\n", quote(classifier)));
+            }
+            result.push_str("]\n");
         }
-        // todo: more fields.
 
-        result.push_str("\n\n");
-        result.push_str("[tool.pypackage.dependencies]\n\n");
+        result.push_str("dependencies = [\n");
         for dep in self.reqs.iter() {
-            result.push_str(&(dep.to_cfg_string() + "\n"));
+            result.push_str(&format!(
+                "    {},\n",
+                quote(&dep.pin_latest(index).to_pep508_string())
+            ));
+        }
+        result.push_str("]\n");
+
+        if !self.optional_reqs.is_empty() {
+            result.push_str("\n[project.optional-dependencies]\n");
+            let mut extras: Vec<&String> = self.optional_reqs.keys().collect();
+            extras.sort();
+            for extra in extras.into_iter() {
+                let reqs: Vec<String> = self.optional_reqs[extra]
+                    .iter()
+                    .map(|r| r.pin_latest(index).to_pep508_string())
+                    .collect();
+                result.push_str(&format!("{} = [{}]\n", extra, quote_list(&reqs)));
+            }
+        }
+
+        if self.homepage.is_some() || self.repository.is_some() {
+            result.push_str("\n[project.urls]\n");
+            if let Some(v) = &self.homepage {
+                result.push_str(&format!("Homepage = {}\n", quote(v)));
+            }
+            if let Some(v) = &self.repository {
+                result.push_str(&format!("Repository = {}\n", quote(v)));
+            }
+        }
+
+        let mut scripts = self.console_scripts.clone();
+        if let Some(eps) = self.entry_points.get("console_scripts") {
+            scripts.extend(eps.clone());
+        }
+        if !scripts.is_empty() {
+            result.push_str("\n[project.scripts]\n");
+            for ep in scripts.iter() {
+                result.push_str(&entry_point(ep).unwrap_or_default());
+            }
+        }
+        let mut groups: Vec<&String> = self
+            .entry_points
+            .keys()
+            .filter(|g| *g != "console_scripts")
+            .collect();
+        groups.sort();
+        for group in groups.into_iter() {
+            result.push_str(&match group.as_ref() {
+                "gui_scripts" => "\n[project.gui-scripts]\n".to_owned(),
+                _ => format!("\n[project.entry-points.{}]\n", quote(group)),
+            });
+            for ep in self.entry_points[group].iter() {
+                result.push_str(&entry_point(ep).unwrap_or_default());
+            }
+        }
+
+        if !self.groups.is_empty() {
+            result.push_str("\n[dependency-groups]\n");
+            let mut groups: Vec<&String> = self.groups.keys().collect();
+            groups.sort();
+            for group in groups.into_iter() {
+                result.push_str(&format!("{} = [\n", group));
+                for dep in self.groups[group].iter() {
+                    result.push_str(&format!(
+                        "    {},\n",
+                        quote(&dep.pin_latest(index).to_pep508_string())
+                    ));
+                }
+                result.push_str("]\n");
+            }
+        }
+
+        // Settings only this tool uses.
+        let mut tool = String::new();
+        if let Some(v) = &self.package_url {
+            tool.push_str(&format!("package_url = {}\n", quote(v)));
+        }
+        if let Some(v) = &self.index_url {
+            tool.push_str(&format!("index_url = {}\n", quote(v)));
+        }
+        if let Some(v) = &self.index_type {
+            tool.push_str(&format!("index_type = {}\n", quote(v)));
+        }
+        if self.allow_renames {
+            tool.push_str("allow_renames = true\n");
+        }
+        if !tool.is_empty() {
+            result.push_str(&format!("\n[tool.pypackage]\n{}", tool));
         }
 
         match fs::write(file, result) {
@@ -449,23 +1009,26 @@ __pypackages__/
 "##;
 
     let pyproject_init = &format!(
-        r##"#See PEP 518: https://www.python.org/dev/peps/pep-0518/ for info on this file's structure.
+        r##"# See PEP 621: https://www.python.org/dev/peps/pep-0621/ for info on this file's structure.
 
-[tool.pypackage]
+[project]
 name = "{}"
-py_version = "^3.7"
 version = "0.1.0"
 description = ""
-author = ""
-
-pyackage_url = "https://test.pypi.org"
-# pyackage_url = "https://pypi.org"
+readme = "README.md"
+requires-python = ">=3.7"
+authors = []
+dependencies = [
+]
 
+[tool.pypackage]
+# The repository `pypackage publish` uploads to: `pypi`, `testpypi`, or an upload url.
+package_url = "testpypi"
 
-[tool.pypackage.dependencies]
 
-"##,
-        name
+{}"##,
+        name,
+        build::BUILD_SYSTEM
     );
 
     // todo: flesh readme out
@@ -474,6 +1037,10 @@ pyackage_url = "https://test.pypi.org"
     fs::write(&format!("{}/.gitignore", name), gitignore_init)?;
     fs::write(&format!("{}/pyproject.toml", name), pyproject_init)?;
     fs::write(&format!("{}/README.md", name), readme_init)?;
+    fs::write(
+        &format!("{}/{}", name, build::BACKEND_FILENAME),
+        build::BACKEND_MODULE,
+    )?;
 
     Ok(())
 }
@@ -511,7 +1078,7 @@ fn prompt_alias(aliases: &[(String, Version)]) -> (String, Version) {
         .expect(
             "Can't find the Python alias associated with that number. Is it in the list above?",
         );
-    (alias.to_string(), *version)
+    (alias.to_string(), version.clone())
 }
 
 #[derive(Debug)]
@@ -583,28 +1150,8 @@ fn write_lock(filename: &str, data: &Lock) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-/// Find the operating system from a wheel filename. This doesn't appear to be available
-/// anywhere else on the Pypi Warehouse.
-fn os_from_wheel_fname(filename: &str) -> Result<(Os), dep_types::DependencyError> {
-    // Format is "name-version-pythonversion-mobileversion?-os.whl"
-    // Also works with formats like this:
-    // `PyQt5-5.13.0-5.13.0-cp35.cp36.cp37.cp38-none-win32.whl` too.
-    // The point is, pull the last part before ".whl".
-    let re = Regex::new(r"^(?:.*?-)+(.*).whl$").unwrap();
-    if let Some(caps) = re.captures(filename) {
-        let parsed = caps.get(1).unwrap().as_str();
-        return Ok(
-            Os::from_str(parsed).unwrap_or_else(|_| panic!("Problem parsing Os: {}", parsed))
-        );
-    }
-
-    Err(dep_types::DependencyError::new(
-        "Problem parsing os from wheel name",
-    ))
-}
-
 /// Create a new virtual environment, and install Wheel.
-fn create_venv(cfg_v: Option<&Constraint>, pyypackages_dir: &PathBuf) -> Version {
+fn create_venv(cfg_v: &[Constraint], pyypackages_dir: &PathBuf) -> Version {
     // We only use the alias for creating the virtual environment. After that,
     // we call our venv's executable directly.
 
@@ -620,7 +1167,8 @@ fn create_venv(cfg_v: Option<&Constraint>, pyypackages_dir: &PathBuf) -> Version
 
     let vers_path = pyypackages_dir.join(format!(
         "{}.{}",
-        py_ver_from_alias.major, py_ver_from_alias.minor
+        py_ver_from_alias.major(),
+        py_ver_from_alias.minor()
     ));
 
     let lib_path = vers_path.join("lib");
@@ -629,14 +1177,13 @@ fn create_venv(cfg_v: Option<&Constraint>, pyypackages_dir: &PathBuf) -> Version
         fs::create_dir_all(&lib_path).expect("Problem creating __pypackages__ directory");
     }
 
-    if let Some(c_v) = cfg_v {
-        // We don't expect the config version to specify a patch, but if it does, take it
-        // into account.
-        if !c_v.is_compatible(&py_ver_from_alias) {
-            abort(&format!("The Python version you selected ({}) doesn't match the one specified in `pyprojecttoml` ({})",
-                           py_ver_from_alias.to_string(), c_v.to_string(false, false))
-            );
-        }
+    // We don't expect the config version to specify a patch, but if it does, take it
+    // into account.
+    if !cfg_v.iter().all(|c| c.is_compatible(&py_ver_from_alias)) {
+        let constrs: Vec<String> = cfg_v.iter().map(|c| c.to_string(false, false)).collect();
+        abort(&format!("The Python version you selected ({}) doesn't match the one specified in `pyprojecttoml` ({})",
+                       py_ver_from_alias, constrs.join(", "))
+        );
     }
 
     println!("Setting up Python environment...");
@@ -690,11 +1237,12 @@ fn parse_lockpack_rename(rename: &str) -> (u32, String) {
 }
 
 /// Find the most appropriate release to download. Ie Windows vs Linux, wheel vs source.
+/// Wheels are ranked by their most-preferred compatibility tag, from `supported`.
 fn find_best_release(
     data: &[WarehouseRelease],
     name: &str,
     version: &Version,
-    os: Os,
+    supported: &[tags::Tag],
     python_vers: &Version,
 ) -> (WarehouseRelease, PackageType) {
     // Find which release we should download. Preferably wheels, and if so, for the right OS and
@@ -704,55 +1252,31 @@ fn find_best_release(
     let mut source_releases = vec![];
 
     for rel in data.iter() {
-        let mut compatible = true;
         match rel.packagetype.as_ref() {
             "bdist_wheel" => {
                 if let Some(py_ver) = &rel.requires_python {
                     // If a version constraint exists, make sure it's compatible.
-                    let py_constrs = Constraint::from_str_multiple(&py_ver)
-                        .expect("Problem parsing constraint from requires_python");
-
-                    for constr in py_constrs.iter() {
-                        if !constr.is_compatible(&python_vers) {
-                            compatible = false;
+                    if let Ok(py_constrs) = Constraint::from_str_multiple(py_ver) {
+                        // Agree with the resolver, eg that `>=3.7.1` allows `3.7`.
+                        if !dep_resolution::python_allowed(&py_constrs, python_vers) {
+                            continue;
                         }
                     }
                 }
 
-                let wheel_os =
-                    os_from_wheel_fname(&rel.filename).expect("Problem getting os from wheel name");
-                if wheel_os != os && wheel_os != Os::Any {
-                    compatible = false;
-                }
-
                 // Packages that use C code(eg numpy) may fail to load C extensions if installing
-                // for the wrong version of python (eg  cp35 when python 3.7 is installed), even
-                // if `requires_python` doesn't indicate an incompatibility. Check `python_version`
-                // instead of `requires_python`.
-                // Note that the result of this parse is an any match.
-                match Constraint::from_wh_py_vers(&rel.python_version) {
-                    Ok(constrs) => {
-                        let mut compat_py_v = false;
-                        for constr in constrs.iter() {
-                            if constr.is_compatible(python_vers) {
-                                compat_py_v = true;
-                            }
+                // for the wrong version of python (eg  cp35 when python 3.7 is installed), or
+                // the wrong platform, even if `requires_python` doesn't indicate an
+                // incompatibility. The tags in the filename tell us what the wheel was built for.
+                match tags::rank(&rel.filename, supported) {
+                    // Prefer files that aren't yanked, then the most specific tag.
+                    Some(rank) => compatible_releases.push(((rel.yanked, rank), rel.clone())),
+                    None => {
+                        if tags::wheel_tags(&rel.filename).is_none() {
+                            println!("Unable to parse tags from wheel name: {}", &rel.filename)
                         }
-                        if !compat_py_v {
-                            compatible = false;
-                        }
-                    }
-                    Err(_) => {
-                        (println!(
-                            "Unable to match python version from python_version: {}",
-                            &rel.python_version
-                        ))
                     }
                 }
-
-                if compatible {
-                    compatible_releases.push(rel.clone());
-                }
             }
             "sdist" => source_releases.push(rel.clone()),
             // todo: handle dist_egg and bdist_wininst?
@@ -766,9 +1290,6 @@ fn find_best_release(
         }
     }
 
-    let best_release;
-    let package_type;
-    // todo: Sort further / try to match exact python_version if able.
     if compatible_releases.is_empty() {
         if source_releases.is_empty() {
             abort(&format!(
@@ -777,26 +1298,26 @@ fn find_best_release(
                 version.to_string()
             ));
             unreachable!()
-        } else {
-            best_release = source_releases[0].clone();
-            package_type = Source;
         }
+        source_releases.sort_by_key(|rel| rel.yanked);
+        (source_releases[0].clone(), Source)
     } else {
-        best_release = compatible_releases[0].clone();
-        package_type = Wheel;
+        compatible_releases.sort_by_key(|(key, _)| *key);
+        (compatible_releases.remove(0).1, Wheel)
     }
-
-    (best_release, package_type)
 }
 
 /// Install/uninstall deps as required from the passed list, and re-write the lock file.
+/// If `dry_run` is true, only list what we'd uninstall.
 fn sync_deps(
+    index: &dyn PackageIndex,
     bin_path: &PathBuf,
     lib_path: &PathBuf,
     lock_packs: &[LockPackage],
     installed: &[(String, Version, Vec<String>)],
     os: Os,
     python_vers: &Version,
+    dry_run: bool,
 ) {
     let packages: Vec<PackToInstall> = lock_packs
         .iter()
@@ -818,7 +1339,7 @@ fn sync_deps(
     // todo shim. Use top-level A/R. We discard it temporarily while working other issues.
     let installed: Vec<(String, Version)> = installed
         .iter()
-        .map(|t| (util::standardize_name(&t.0), t.1))
+        .map(|t| (util::standardize_name(&t.0), t.1.clone()))
         .collect();
 
     // Filter by not-already-installed.
@@ -832,23 +1353,73 @@ fn sync_deps(
     let to_uninstall: Vec<&(String, Version)> = installed
         .iter()
         .filter(|inst| {
-            let inst = (util::standardize_name(&inst.0), inst.1);
+            let inst = (util::standardize_name(&inst.0), inst.1.clone());
             !packages_only.contains(&&inst)
         })
         .collect();
 
     for (name, version) in to_uninstall.iter() {
         // todo: Deal with renamed. Currently won't work correctly with them.
-        install::uninstall(name, version, lib_path)
+        install::uninstall(name, version, lib_path, dry_run)
+    }
+    if dry_run {
+        return;
     }
 
-    for ((name, version), rename) in to_install.iter() {
-        let data = dep_resolution::get_warehouse_release(&name, &version)
-            .expect("Problem getting warehouse data");
+    let supported = tags::supported_tags(python_vers, &tags::Platform::detect(os));
 
-        let (best_release, package_type) =
-            find_best_release(&data, &name, &version, os, python_vers);
+    // Find what to download for each package up front, so that in offline mode we can report
+    // everything missing from the cache at once.
+    let mut releases = vec![];
+    let mut missing = vec![];
+    for ((name, version), _) in to_install.iter() {
+        let artifacts = lock_packs
+            .iter()
+            .find(|lp| {
+                util::standardize_name(&lp.name) == *name
+                    && Version::from_str(&lp.version).ok().as_ref() == Some(version)
+            })
+            .and_then(|lp| lp.artifacts.as_ref());
+        let data = match artifacts {
+            Some(a) => Ok(artifact_releases(a)),
+            None => dep_resolution::get_warehouse_release(index, name, version),
+        };
+        match data {
+            Ok(data) => {
+                let release = find_best_release(&data, &name, &version, &supported, python_vers);
+                if cache::is_offline()
+                    && !release.0.url.starts_with("file://")
+                    && !cache::artifact_path(&release.0.filename).exists()
+                    && !lib_path.join(&release.0.filename).exists()
+                {
+                    missing.push(release.0.filename.clone());
+                }
+                releases.push(release);
+            }
+            Err(e) => {
+                if cache::is_offline() {
+                    missing.push(format!(
+                        "Package info for {} {}",
+                        name,
+                        version.to_string2()
+                    ));
+                } else {
+                    abort(&format!("Problem getting warehouse data: {}", e.details));
+                }
+            }
+        }
+    }
+    if !missing.is_empty() {
+        abort(&format!(
+            "Unable to install in offline mode; these aren't in the cache ({:?}):\n{}",
+            cache::cache_dir(),
+            missing.join("\n")
+        ));
+    }
 
+    for (((name, version), rename), (best_release, package_type)) in
+        to_install.iter().zip(releases.into_iter())
+    {
         // Powershell  doesn't like emojis // todo format literal issues
         //        #[cfg(target_os = "windows")]
         //            let text = "Installing {}{}{} {} ...";
@@ -864,20 +1435,18 @@ fn sync_deps(
             Colored::Fg(Color::Reset),
             &version
         );
-        if install::download_and_install_package(
+        match install::download_and_install_package(
+            index,
             &name,
             &version,
-            &best_release.url,
-            &best_release.filename,
-            &best_release.digests.sha256,
+            &best_release,
             lib_path,
             bin_path,
             package_type,
             rename,
-        )
-        .is_err()
-        {
-            abort("Problem downloading packages");
+        ) {
+            Err(e) => abort(&format!("Problem downloading packages: {}", e.details)),
+            Ok(()) => (),
         }
 
         if let Some((id, new)) = rename {
@@ -901,149 +1470,766 @@ fn sync_deps(
     }
 }
 
-fn already_locked(locked: &[Package], name: &str, constraints: &[Constraint]) -> bool {
-    let mut result = true;
-    for constr in constraints.iter() {
-        if !locked
-            .iter()
-            .any(|p| util::compare_names(&p.name, name) && constr.is_compatible(&p.version))
-        {
-            result = false;
-            break;
+/// The group name for the project's own dependencies, in the lock file.
+const MAIN_GROUP: &str = "main";
+
+/// Find which roots need each locked package, by following dependencies from each root's
+/// requirements. Roots are dependency groups, or extras.
+fn membership(lock_packs: &[LockPackage], roots: &[(String, Vec<Req>)]) -> Vec<Vec<String>> {
+    let mut result = vec![vec![]; lock_packs.len()];
+
+    for (root, reqs) in roots.iter() {
+        let mut to_visit: Vec<String> = reqs.iter().map(|r| r.name.clone()).collect();
+        let mut visited: Vec<String> = vec![];
+        while let Some(name) = to_visit.pop() {
+            if visited.iter().any(|v| util::compare_names(v, &name)) {
+                continue;
+            }
+            for (i, lp) in lock_packs.iter().enumerate() {
+                if !util::compare_names(&lp.name, &name) {
+                    continue;
+                }
+                if !result[i].contains(root) {
+                    result[i].push(root.clone());
+                }
+                // Lock dependencies are in the format `name version source`.
+                for dep in lp.dependencies.as_ref().unwrap_or(&vec![]) {
+                    if let Some(dep_name) = dep.split_whitespace().next() {
+                        to_visit.push(dep_name.to_owned());
+                    }
+                }
+            }
+            visited.push(name);
         }
     }
     result
 }
 
-/// Function used by `Install` and `Uninstall` subcommands to syn dependencies with
-/// the config and lock files.
-fn sync(
-    bin_path: &PathBuf,
-    lib_path: &PathBuf,
-    lockpacks: &[LockPackage],
-    reqs: &[Req],
-    os: Os,
-    py_vers: &Version,
-    lock_filename: &str,
-) {
-    let installed = util::find_installed(&lib_path);
-    // We control the lock format, so this regex will always match
-    let dep_re = Regex::new(r"^(.*?)\s(.*)\s.*$").unwrap();
+/// The dependency groups and extras to install, besides the main dependencies.
+#[derive(Clone, Debug, Default)]
+struct Selection {
+    groups: Vec<String>,
+    extras: Vec<String>,
+}
 
-    // We don't need to resolve reqs that are already locked.
-    let locked: Vec<Package> = lockpacks
-        .iter()
-        .map(|lp| {
-            let mut deps = vec![];
-            for dep in lp.dependencies.as_ref().unwrap_or(&vec![]) {
-                let caps = dep_re
-                    .captures(&dep)
-                    .expect("Problem reading lock file dependencies");
-                let name = caps.get(1).unwrap().as_str().to_owned();
-                let vers = Version::from_str(caps.get(2).unwrap().as_str())
-                    .expect("Problem parsing version from lock");
-                deps.push((999, name, vers)); // dummy id
+impl Selection {
+    /// Select groups and extras specified, and `dev` unless `no_dev` is set.
+    fn new(cfg: &Config, groups: &[String], no_dev: bool, extras: &[String]) -> Self {
+        for group in groups.iter() {
+            if !cfg.groups.keys().any(|g| util::compare_names(g, group)) {
+                abort(&format!(
+                    "Can't find the dependency group `{}` in `pyproject.toml`",
+                    group
+                ));
             }
-
-            Package {
-                id: lp.id, // todo
-                parent: 0, // todo
-                name: lp.name.clone(),
-                version: Version::from_str(&lp.version).expect("Problem parsing lock version"),
-                deps,
-                rename: Rename::No, // todo
+        }
+        for extra in extras.iter() {
+            if !cfg
+                .optional_reqs
+                .keys()
+                .any(|e| util::compare_names(e, extra))
+            {
+                abort(&format!(
+                    "Can't find the extra `{}` in `pyproject.toml`",
+                    extra
+                ));
             }
-        })
-        .collect();
-
-    // Powershell  doesn't like emojis
-    #[cfg(target_os = "windows")]
-    println!("Resolving dependencies...");
-    #[cfg(target_os = "linux")]
-    println!("🔍 Resolving dependencies...");
-    #[cfg(target_os = "macos")]
-    println!("🔍 Resolving dependencies...");
+        }
 
-    let resolved = match dep_resolution::resolve(&reqs, &locked, os, &py_vers) {
-        Ok(r) => r,
-        Err(_) => {
-            abort("Problem resolving dependencies");
+        let mut groups = groups.to_vec();
+        if !no_dev {
+            groups.push("dev".into());
+        }
+        Self {
+            groups,
+            extras: extras.to_vec(),
+        }
+    }
+
+    /// Should we install this locked package?
+    fn includes(&self, lock_pack: &LockPackage) -> bool {
+        let selected = |names: &[String], options: &Option<Vec<String>>| {
+            options
+                .as_ref()
+                .unwrap_or(&vec![])
+                .iter()
+                .any(|o| names.iter().any(|n| util::compare_names(n, o)))
+        };
+        selected(&[MAIN_GROUP.to_owned()], &lock_pack.groups)
+            || selected(&self.groups, &lock_pack.groups)
+            || selected(&self.extras, &lock_pack.extras)
+    }
+}
+
+/// Parse a dependency from the lock file: `name version source`, with ` ; marker` after it if
+/// it's only needed in some environments.
+fn parse_lock_dep(dep: &str) -> (String, Version, Option<Marker>) {
+    let mut parts = dep.splitn(2, " ; ");
+    let mut fields = parts.next().unwrap_or_default().split_whitespace();
+    let (name, version) = match (fields.next(), fields.next()) {
+        (Some(n), Some(v)) => (n, v),
+        _ => {
+            abort(&format!("Problem reading lock file dependency: {}", dep));
+            unreachable!()
+        }
+    };
+    let version = Version::from_str(version).expect("Problem parsing version from lock");
+    let marker = parts.next().map(|m| {
+        Marker::from_str(m).unwrap_or_else(|e| {
+            abort(&format!("Problem reading lock file marker: {}", e.details));
+            unreachable!()
+        })
+    });
+    (name.to_owned(), version, marker)
+}
+
+fn lock_dep_string(
+    index: &dyn PackageIndex,
+    name: &str,
+    version: &Version,
+    marker: &Option<Marker>,
+) -> String {
+    let result = format!(
+        "{} {} {}",
+        name,
+        version.to_string2(),
+        index.source(name, version)
+    );
+    match marker {
+        Some(m) => format!("{} ; {}", result, m),
+        None => result,
+    }
+}
+
+/// Every file the index has for a package version, for the lock file. We skip yanked files,
+/// unless the whole version's yanked, ie it was pinned exactly.
+fn find_artifacts(index: &dyn PackageIndex, name: &str, version: &Version) -> Vec<LockArtifact> {
+    match dep_resolution::get_warehouse_release(index, name, version) {
+        Ok(releases) => {
+            let all_yanked = releases.iter().all(|r| r.yanked);
+            releases
+                .into_iter()
+                .filter(|r| all_yanked || !r.yanked)
+                .map(|r| LockArtifact {
+                    filename: r.filename,
+                    url: r.url,
+                    hash: format!("sha256:{}", r.digests.sha256),
+                    requires_python: r.requires_python,
+                })
+                .collect()
+        }
+        Err(e) => {
+            abort(&format!(
+                "Problem getting the files for {} {}: {}",
+                name,
+                version.to_string2(),
+                e.details
+            ));
             unreachable!()
         }
+    }
+}
+
+/// Convert locked artifacts to the index's format, to pick which to install.
+fn artifact_releases(artifacts: &[LockArtifact]) -> Vec<WarehouseRelease> {
+    artifacts
+        .iter()
+        .map(|a| {
+            let (packagetype, python_version) = if a.filename.ends_with(".whl") {
+                // eg `numpy-1.17.4-cp37-cp37m-win_amd64.whl`
+                let python = a.filename.trim_end_matches(".whl").rsplit('-').nth(2);
+                ("bdist_wheel", python.unwrap_or_default())
+            } else {
+                ("sdist", "source")
+            };
+            WarehouseRelease {
+                filename: a.filename.clone(),
+                has_sig: false,
+                digests: dep_resolution::WarehouseDigests {
+                    md5: String::new(),
+                    sha256: a.hash.trim_start_matches("sha256:").to_owned(),
+                },
+                packagetype: packagetype.to_owned(),
+                python_version: python_version.to_owned(),
+                requires_python: a.requires_python.clone(),
+                url: a.url.clone(),
+                dependencies: None,
+                yanked: false,
+                yanked_reason: None,
+                core_metadata: false,
+                core_metadata_sha256: None,
+            }
+        })
+        .collect()
+}
+
+/// The Python versions we lock for: The one we're using, and each other minor version
+/// `requires-python` allows, from the lowest it names to the highest it names, or the one we're
+/// using, if that's higher.
+fn target_python_versions(cfg_version: &[Constraint], py_vers: &Version) -> Vec<Version> {
+    let current = Version::new_short(py_vers.major(), py_vers.minor());
+    let mut result = vec![current.clone()];
+
+    let named: Vec<u32> = cfg_version
+        .iter()
+        .filter(|c| c.version.major() == current.major())
+        .map(|c| c.version.minor())
+        .collect();
+    let lowest = named.iter().cloned().min().unwrap_or(current.minor());
+    let highest = named.iter().cloned().max().unwrap_or(current.minor());
+    for minor in lowest.min(current.minor())..=highest.max(current.minor()) {
+        let version = Version::new_short(current.major(), minor);
+        if version != current && dep_resolution::python_allowed(cfg_version, &version) {
+            result.push(version);
+        }
+    }
+    result
+}
+
+/// A hash of the inputs from `pyproject.toml` a lock file was made from, so we can tell if
+/// they've changed since.
+fn requirements_hash(reqs: &[Req], cfg: &Config) -> String {
+    let mut lines = vec![];
+    if let Some(v) = cfg.requires_python() {
+        lines.push(format!("requires-python {}", v));
+    }
+    if let Some(v) = &cfg.index_url {
+        lines.push(format!("index {}", v));
+    }
+    for req in reqs.iter() {
+        lines.push(format!("main {}", req.to_pep508_string()));
+    }
+    for (group, reqs) in cfg.groups.iter() {
+        for req in reqs.iter() {
+            lines.push(format!("group {} {}", group, req.to_pep508_string()));
+        }
+    }
+    for (extra, reqs) in cfg.optional_reqs.iter() {
+        for req in reqs.iter() {
+            lines.push(format!("extra {} {}", extra, req.to_pep508_string()));
+        }
+    }
+    lines.sort();
+
+    let hash = ring::digest::digest(&ring::digest::SHA256, lines.join("\n").as_bytes());
+    format!("sha256:{}", data_encoding::HEXLOWER.encode(hash.as_ref()))
+}
+
+/// Resolve the requirements, using versions from `lock` where we can, and write the result to
+/// the lock file, unless `dry_run` is true. We lock the main dependencies, and all groups and
+/// extras together, for every platform. Packages in `upgrade` get their newest compatible
+/// versions, changing others only where they need to.
+fn lock_reqs(
+    index: &dyn PackageIndex,
+    lock: &Lock,
+    reqs: &[Req],
+    cfg: &Config,
+    upgrade: &[String],
+    os: Os,
+    py_vers: &Version,
+    lock_filename: &str,
+    dry_run: bool,
+) -> Lock {
+    let lockpacks = lock.package.clone().unwrap_or_default();
+
+    // We don't need to resolve reqs that are already locked.
+    let mut locked_markers = HashMap::new();
+    let locked: Vec<Package> = lockpacks
+        .iter()
+        .map(|lp| {
+            let mut deps = vec![];
+            for dep in lp.dependencies.as_ref().unwrap_or(&vec![]) {
+                let (name, vers, marker) = parse_lock_dep(dep);
+                locked_markers.insert(
+                    (
+                        util::standardize_name(&lp.name),
+                        util::standardize_name(&name),
+                    ),
+                    marker,
+                );
+                deps.push((999, name, vers)); // dummy id
+            }
+
+            Package {
+                id: lp.id, // todo
+                parent: 0, // todo
+                name: lp.name.clone(),
+                version: Version::from_str(&lp.version).expect("Problem parsing lock version"),
+                deps,
+                rename: Rename::No, // todo
+            }
+        })
+        .collect();
+    let locked = dep_resolution::Locked {
+        packages: &locked,
+        // Version 1 lock files only have dependencies for the platform they were made on.
+        markers: if lock.lock_version.is_some() {
+            Some(&locked_markers)
+        } else {
+            None
+        },
+        upgrade,
     };
 
-    //    println!("RESOLVED: {:#?}", &resolved);
+    // Powershell  doesn't like emojis
+    #[cfg(target_os = "windows")]
+    println!("Resolving dependencies...");
+    #[cfg(target_os = "linux")]
+    println!("🔍 Resolving dependencies...");
+    #[cfg(target_os = "macos")]
+    println!("🔍 Resolving dependencies...");
+
+    // The main dependencies first, then groups, sorted by name.
+    let mut group_names: Vec<&String> = cfg.groups.keys().collect();
+    group_names.sort();
+    let mut all_groups = vec![(MAIN_GROUP.to_owned(), reqs.to_vec())];
+    for name in group_names.into_iter() {
+        all_groups.push((name.clone(), cfg.groups[name].clone()));
+    }
+    let mut extra_names: Vec<&String> = cfg.optional_reqs.keys().collect();
+    extra_names.sort();
+    let all_extras: Vec<(String, Vec<Req>)> = extra_names
+        .into_iter()
+        .map(|name| (name.clone(), cfg.extra_reqs(name)))
+        .collect();
+    let all_reqs: Vec<Req> = all_groups
+        .iter()
+        .chain(all_extras.iter())
+        .flat_map(|(_, reqs)| reqs.iter().cloned())
+        .collect();
+
+    let targets = markers::Environment::targets(&target_python_versions(&cfg.py_version, py_vers));
+    let (resolved, edges) = match dep_resolution::resolve(
+        index,
+        &all_reqs,
+        &locked,
+        &targets,
+        os,
+        py_vers,
+        cfg.allow_renames,
+    ) {
+        Ok(r) => r,
+        Err(e) => {
+            abort(&format!("Problem resolving dependencies:\n{}", e.details));
+            unreachable!()
+        }
+    };
+    let package_markers = dep_resolution::package_markers(&all_reqs, &resolved, &edges);
 
     // Now merge the existing lock packages with new ones from resolved packages.
     // We have a collection of requirements; attempt to merge them with the already-locked ones.
     let mut updated_lock_packs = vec![];
 
     for package in resolved.iter() {
-        let dummy_constraints = vec![Constraint::new(ReqType::Exact, package.version)];
-        if already_locked(&locked, &package.name, &dummy_constraints) {
-            let existing: Vec<&LockPackage> = lockpacks
-                .iter()
-                .filter(|lp| util::compare_names(&lp.name, &package.name))
-                .collect();
-            let existing2 = existing[0];
-
-            updated_lock_packs.push(existing2.clone());
-            continue;
-        }
-
+        let std_name = util::standardize_name(&package.name);
         let deps = package
             .deps
             .iter()
             .map(|(_, name, version)| {
-                format!(
-                    "{} {} pypi+https://pypi.org/pypi/{}/{}/json",
-                    name,
-                    version.to_string2(),
-                    name,
-                    version.to_string2(),
-                )
+                let marker = edges
+                    .get(&(std_name.clone(), util::standardize_name(name)))
+                    .cloned()
+                    .unwrap_or(None);
+                lock_dep_string(index, name, version, &marker)
             })
             .collect();
 
-        updated_lock_packs.push(LockPackage {
-            id: package.id,
-            name: package.name.clone(),
-            version: package.version.to_string(),
-            source: Some(format!(
-                "pypi+https://pypi.org/pypi/{}/{}/json",
-                package.name,
-                package.version.to_string()
-            )),
-            dependencies: Some(deps),
-            rename: match &package.rename {
-                Rename::Yes(parent_id, _, name) => Some(format!("{} {}", parent_id, name)),
-                Rename::No => None,
-            },
+        let existing = lockpacks.iter().find(|lp| {
+            util::compare_names(&lp.name, &package.name)
+                && Version::from_str(&lp.version).ok().as_ref() == Some(&package.version)
         });
+        let mut lock_pack = match existing {
+            Some(lp) => lp.clone(),
+            None => LockPackage {
+                id: package.id,
+                name: package.name.clone(),
+                version: package.version.to_string2(),
+                source: Some(index.source(&package.name, &package.version)),
+                dependencies: None,
+                rename: match &package.rename {
+                    Rename::Yes(parent_id, _, name) => Some(format!("{} {}", parent_id, name)),
+                    Rename::No => None,
+                },
+                groups: None,
+                extras: None,
+                marker: None,
+                artifacts: None,
+            },
+        };
+        lock_pack.dependencies = Some(deps);
+        lock_pack.marker = package_markers
+            .get(&std_name)
+            .cloned()
+            .unwrap_or(None)
+            .map(|m| m.to_string());
+        if lock_pack.artifacts.is_none() {
+            lock_pack.artifacts = Some(find_artifacts(index, &package.name, &package.version));
+        }
+        updated_lock_packs.push(lock_pack);
     }
 
+    let groups = membership(&updated_lock_packs, &all_groups);
+    let extras = membership(&updated_lock_packs, &all_extras);
+    for (lp, (groups, extras)) in updated_lock_packs
+        .iter_mut()
+        .zip(groups.into_iter().zip(extras))
+    {
+        lp.groups = Some(groups);
+        lp.extras = if extras.is_empty() {
+            None
+        } else {
+            Some(extras)
+        };
+    }
+
+    let mut metadata = BTreeMap::new();
+    metadata.insert("requirements-hash".to_owned(), requirements_hash(reqs, cfg));
     let updated_lock = Lock {
-        //        metadata: Some(lock_metadata),
-        metadata: HashMap::new(), // todo: Problem with toml conversion.
-        package: Some(updated_lock_packs.clone()),
+        lock_version: Some(LOCK_VERSION),
+        metadata,
+        package: Some(updated_lock_packs),
     };
-    if write_lock(lock_filename, &updated_lock).is_err() {
-        abort("Problem writing lock file");
+    if !dry_run {
+        if lock.lock_version.is_none() && !lockpacks.is_empty() {
+            println!(
+                "Upgrading `{}` to lock version {}",
+                lock_filename, LOCK_VERSION
+            );
+        }
+        if write_lock(lock_filename, &updated_lock).is_err() {
+            abort("Problem writing lock file");
+        }
     }
+    updated_lock
+}
 
-    // Now that we've confirmed or modified the lock file, we're ready to sync installed
-    // depenencies with it.
+/// Sync installed dependencies with the lock file, for this environment: We install the main
+/// dependencies, and those `selected`, and remove the rest. If `dry_run` is true, we only list
+/// what we'd uninstall.
+fn install_locked(
+    index: &dyn PackageIndex,
+    bin_path: &PathBuf,
+    lib_path: &PathBuf,
+    lock_packs: &[LockPackage],
+    selected: &Selection,
+    os: Os,
+    py_vers: &Version,
+    dry_run: bool,
+) {
+    let installed = util::find_installed(lib_path);
+    let env = markers::Environment::new(os, py_vers);
+    let selected_packs: Vec<LockPackage> = lock_packs
+        .iter()
+        .filter(|lp| {
+            let needed_here = match &lp.marker {
+                Some(m) => Marker::from_str(m)
+                    .map(|m| m.evaluate(&env))
+                    .unwrap_or(true),
+                None => true,
+            };
+            selected.includes(lp) && needed_here
+        })
+        .cloned()
+        .collect();
     sync_deps(
         //                &bin_path, &lib_path, &packages, &installed, &os, &py_vers, &resolved,
+        index,
         &bin_path,
         &lib_path,
-        &updated_lock_packs,
+        &selected_packs,
         &installed,
         os,
         &py_vers,
+        dry_run,
+    );
+}
+
+/// Function used by `Install` and `Uninstall` subcommands to syn dependencies with
+/// the config and lock files. If `dry_run` is true, we don't modify the lock file or install
+/// anything, and only list what we'd uninstall.
+fn sync(
+    index: &dyn PackageIndex,
+    bin_path: &PathBuf,
+    lib_path: &PathBuf,
+    lock: &Lock,
+    reqs: &[Req],
+    cfg: &Config,
+    selected: &Selection,
+    os: Os,
+    py_vers: &Version,
+    lock_filename: &str,
+    dry_run: bool,
+) {
+    let updated_lock = lock_reqs(
+        index,
+        lock,
+        reqs,
+        cfg,
+        &[],
+        os,
+        py_vers,
+        lock_filename,
+        dry_run,
+    );
+
+    // Now that we've confirmed or modified the lock file, we're ready to sync installed
+    // depenencies with it.
+    install_locked(
+        index,
+        bin_path,
+        lib_path,
+        updated_lock.package.as_ref().unwrap_or(&vec![]),
+        selected,
+        os,
+        py_vers,
+        dry_run,
     );
 }
 
+/// Packages whose versions differ between two lock files: Name, old version, and new version,
+/// with `None` for ones added or removed.
+fn lock_diff(
+    old: &[LockPackage],
+    new: &[LockPackage],
+) -> Vec<(String, Option<String>, Option<String>)> {
+    let find = |packs: &[LockPackage], name: &str| {
+        packs
+            .iter()
+            .find(|lp| util::compare_names(&lp.name, name))
+            .map(|lp| lp.version.clone())
+    };
+    let mut result = vec![];
+    for lp in old.iter() {
+        let new_version = find(new, &lp.name);
+        if new_version.as_ref() != Some(&lp.version) {
+            result.push((lp.name.clone(), Some(lp.version.clone()), new_version));
+        }
+    }
+    for lp in new.iter() {
+        if find(old, &lp.name).is_none() {
+            result.push((lp.name.clone(), None, Some(lp.version.clone())));
+        }
+    }
+    result.sort_by_key(|(name, _, _)| util::standardize_name(name));
+    result
+}
+
+fn print_lock_diff(diff: &[(String, Option<String>, Option<String>)]) {
+    if diff.is_empty() {
+        println!("Everything is up to date");
+        return;
+    }
+    let show = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_owned());
+    let mut rows = vec![("Package".to_owned(), "Old".to_owned(), "New".to_owned())];
+    for (name, old, new) in diff.iter() {
+        rows.push((name.clone(), show(old), show(new)));
+    }
+    let name_width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0);
+    let old_width = rows.iter().map(|r| r.1.len()).max().unwrap_or(0);
+    for (name, old, new) in rows.iter() {
+        println!(
+            "{:name_width$}  {:old_width$}  {}",
+            name,
+            old,
+            new,
+            name_width = name_width,
+            old_width = old_width
+        );
+    }
+}
+
+/// Requirements with caret constraints, eg `^2.22`, raised to the versions now locked.
+fn bumped_reqs(reqs: &[Req], lock_packs: &[LockPackage]) -> Vec<Req> {
+    let mut result = vec![];
+    for req in reqs.iter() {
+        let locked = lock_packs
+            .iter()
+            .find(|lp| util::compare_names(&lp.name, &req.name))
+            .and_then(|lp| Version::from_str(&lp.version).ok());
+        let locked = match locked {
+            Some(v) => v,
+            None => continue,
+        };
+        if req.constraints.len() != 1 {
+            continue;
+        }
+        let constr = &req.constraints[0];
+        if constr.type_ == ReqType::Caret && constr.version < locked {
+            result.push(Req {
+                constraints: vec![Constraint::new(ReqType::Caret, locked)],
+                ..req.clone()
+            });
+        }
+    }
+    result
+}
+
+/// Re-lock with some packages upgraded, and print what changes, without writing anything.
+/// Returns the new lock, and the requirements `--bump-pyproject` would change.
+fn plan_update(
+    index: &dyn PackageIndex,
+    lock: &Lock,
+    cfg: &Config,
+    packages: Vec<String>,
+    all: bool,
+    bump_pyproject: bool,
+    os: Os,
+    py_vers: &Version,
+    lock_filename: &str,
+) -> (Lock, Vec<Req>) {
+    if packages.is_empty() && !all {
+        abort("Specify the packages to update, or update all of them with `--all`");
+    }
+    let lock_packs = lock.package.clone().unwrap_or_default();
+    let upgrade: Vec<String> = if all {
+        lock_packs.iter().map(|lp| lp.name.clone()).collect()
+    } else {
+        packages
+    };
+    for name in upgrade.iter() {
+        if !lock_packs
+            .iter()
+            .any(|lp| util::compare_names(&lp.name, name))
+        {
+            abort(&format!("`{}` isn't in `{}`", name, lock_filename));
+        }
+    }
+
+    let updated_lock = lock_reqs(
+        index,
+        lock,
+        &cfg.reqs,
+        cfg,
+        &upgrade,
+        os,
+        py_vers,
+        lock_filename,
+        true,
+    );
+    let updated_packs = updated_lock.package.clone().unwrap_or_default();
+    print_lock_diff(&lock_diff(&lock_packs, &updated_packs));
+
+    let bumped = if bump_pyproject {
+        bumped_reqs(&cfg.reqs, &updated_packs)
+    } else {
+        vec![]
+    };
+    for req in bumped.iter() {
+        println!("`pyproject.toml`: {}", req.to_pep508_string());
+    }
+    (updated_lock, bumped)
+}
+
+/// The index set in `pyproject.toml`, or PyPi.
+fn package_index(cfg: &Config) -> Box<dyn PackageIndex> {
+    match index::from_config(
+        cfg.index_url.as_ref().map(String::as_str),
+        cfg.index_type.as_ref().map(String::as_str),
+    ) {
+        Ok(i) => i,
+        Err(e) => {
+            abort(&format!(
+                "Problem setting up the package index: {}",
+                e.details
+            ));
+            unreachable!()
+        }
+    }
+}
+
+/// The Python version to lock for without setting up an environment: That of an existing one
+/// compatible with `pyproject.toml`, or the one on the path.
+fn lock_py_version(cfg: &Config, pypackages_dir: &PathBuf) -> Version {
+    let venv = util::find_venvs(pypackages_dir)
+        .into_iter()
+        .map(|(major, minor)| Version::new_short(major, minor))
+        .find(|v| cfg.python_compatible(v));
+    if let Some(v) = venv {
+        return v;
+    }
+    match find_py_alias() {
+        Ok((_, v)) => Version::new_short(v.major(), v.minor()),
+        Err(_) => {
+            abort("Unable to find a Python version on the path");
+            unreachable!()
+        }
+    }
+}
+
+fn abort_newer_lock(lock_filename: &str, lock: &Lock) {
+    abort(&format!(
+        "`{}` is lock version {}, which is newer than this version of pypackage supports. \
+         Please upgrade pypackage.",
+        lock_filename,
+        lock.lock_version.unwrap_or_default()
+    ));
+}
+
+fn abort_no_lock(lock_filename: &str) {
+    abort(&format!(
+        "Can't find `{}`; run `pypackage lock` to create it",
+        lock_filename
+    ));
+}
+
+/// Read the lock file for commands that need one, eg `tree`.
+fn read_lock_existing(lock_filename: &str) -> Lock {
+    let lock = match read_lock(lock_filename) {
+        Ok(l) => l,
+        Err(_) => {
+            abort_no_lock(lock_filename);
+            unreachable!()
+        }
+    };
+    if lock.lock_version > Some(LOCK_VERSION) {
+        abort_newer_lock(lock_filename, &lock);
+    }
+    lock
+}
+
+/// Check we can install from a lock without resolving: It must list artifacts, and if
+/// `check_hash` is set, be up to date with `pyproject.toml`. Returns an error message if not.
+fn check_lock_strict(
+    lock_filename: &str,
+    lock: &Lock,
+    cfg: &Config,
+    check_hash: bool,
+) -> Result<(), String> {
+    // Version 1 locks predate the version field.
+    if lock.lock_version.is_none() {
+        return Err(format!(
+            "`{}` is lock version 1, which has no artifacts; run `pypackage lock` to update it",
+            lock_filename
+        ));
+    }
+    let up_to_date = match lock.metadata.get("requirements-hash") {
+        Some(hash) => *hash == requirements_hash(&cfg.reqs, cfg),
+        None => false,
+    };
+    if check_hash && !up_to_date {
+        return Err(format!(
+            "`{}` is out of date with `pyproject.toml`; run `pypackage lock` to update it",
+            lock_filename
+        ));
+    }
+    Ok(())
+}
+
+/// Read the lock file for `--frozen` or `--locked`, where we install from it without resolving.
+fn read_lock_strict(lock_filename: &str, cfg: &Config, check_hash: bool) -> Lock {
+    let lock = match read_lock(lock_filename) {
+        Ok(l) => l,
+        Err(_) => {
+            abort_no_lock(lock_filename);
+            unreachable!()
+        }
+    };
+    if let Err(e) = check_lock_strict(lock_filename, &lock, cfg, check_hash) {
+        abort(&e);
+    }
+    lock
+}
+
 fn main() {
     // todo perhaps much of this setup code should only be in certain match branches.
     let cfg_filename = "pyproject.toml";
@@ -1057,6 +2243,17 @@ fn main() {
         None => SubCommand::Run { args: opt.script },
     };
 
+    #[cfg(target_os = "windows")]
+    let os = Os::Windows;
+    #[cfg(target_os = "linux")]
+    let os = Os::Linux;
+    #[cfg(target_os = "macos")]
+    let os = Os::Mac;
+
+    let pypackages_dir = env::current_dir()
+        .expect("Can't find current path")
+        .join("__pypackages__");
+
     // New doesn't execute any other logic. Init must execute befor the rest of the logic,
     // since it sets up a new (or modified) `pyproject.toml`. The rest of the commands rely
     // on the virtualenv and `pyproject.toml`, so make sure those are set up before processing them.
@@ -1076,35 +2273,164 @@ fn main() {
             if PathBuf::from(cfg_filename).exists() {
                 abort("pyproject.toml already exists - not overwriting.")
             }
-            cfg.write_file(cfg_filename);
+            cfg.write_file(cfg_filename, package_index(&cfg).as_ref());
+        }
+        // Building and publishing don't use the environment, so do them before setting that up.
+        SubCommand::Package => {
+            build::build(&cfg);
+            return;
+        }
+        SubCommand::BuildBackend { kind, directory } => {
+            build::build_backend(&cfg, &kind, &directory);
+            return;
+        }
+        SubCommand::Publish {
+            repository,
+            dry_run,
+        } => {
+            upload::publish(&cfg, repository, dry_run);
+            return;
+        }
+        // Locking doesn't install anything, so it doesn't need an environment either.
+        SubCommand::Lock { offline } => {
+            cache::set_offline(offline);
+            let lock = read_lock(lock_filename).unwrap_or_default();
+            if lock.lock_version > Some(LOCK_VERSION) {
+                abort_newer_lock(lock_filename, &lock);
+            }
+            let py_vers = lock_py_version(&cfg, &pypackages_dir);
+            lock_reqs(
+                package_index(&cfg).as_ref(),
+                &lock,
+                &cfg.reqs,
+                &cfg,
+                &[],
+                os,
+                &py_vers,
+                lock_filename,
+                false,
+            );
+            util::print_color(&format!("Wrote `{}`", lock_filename), Color::Green);
+            return;
+        }
+        // These only read the lock file, and don't install anything.
+        SubCommand::Why { package } => {
+            let lock = read_lock_existing(lock_filename);
+            let graph = tree::Graph::new(
+                package_index(&cfg).as_ref(),
+                &cfg.all_reqs(),
+                lock.package.as_ref().unwrap_or(&vec![]),
+            );
+            let id = util::standardize_name(&package);
+            if graph.node(&id).is_none() {
+                abort(&format!("`{}` isn't in `{}`", package, lock_filename));
+            }
+            for line in graph.explain(&id).iter() {
+                println!("{}", line);
+            }
+            return;
+        }
+        SubCommand::Tree {
+            invert,
+            depth,
+            format,
+        } => {
+            let lock = read_lock_existing(lock_filename);
+            let graph = tree::Graph::new(
+                package_index(&cfg).as_ref(),
+                &cfg.all_reqs(),
+                lock.package.as_ref().unwrap_or(&vec![]),
+            );
+            let start = match &invert {
+                Some(name) => {
+                    let id = util::standardize_name(name);
+                    if graph.node(&id).is_none() {
+                        abort(&format!("`{}` isn't in `{}`", name, lock_filename));
+                    }
+                    id
+                }
+                None => tree::ROOT.to_owned(),
+            };
+            let (lines, shown) = graph.render(&start, invert.is_some(), depth);
+            match format.as_ref() {
+                "dot" => print!("{}", graph.subgraph(&start, &shown).to_dot()),
+                "json" => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&graph.subgraph(&start, &shown))
+                        .expect("Problem serializing to JSON")
+                ),
+                _ => {
+                    for line in lines.iter() {
+                        println!("{}", line);
+                    }
+                }
+            }
+            return;
+        }
+        SubCommand::Outdated { format } => {
+            let lock = read_lock_existing(lock_filename);
+            let result = outdated::find(
+                package_index(&cfg).as_ref(),
+                &cfg.all_reqs(),
+                lock.package.as_ref().unwrap_or(&vec![]),
+                &lock_py_version(&cfg, &pypackages_dir),
+            );
+            if format == "json" {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&result).expect("Problem serializing to JSON")
+                );
+            } else {
+                outdated::print(&result);
+            }
+            return;
+        }
+        SubCommand::Update {
+            packages,
+            all,
+            dry_run: true,
+            bump_pyproject,
+        } => {
+            let lock = read_lock(lock_filename).unwrap_or_default();
+            if lock.lock_version > Some(LOCK_VERSION) {
+                abort_newer_lock(lock_filename, &lock);
+            }
+            plan_update(
+                package_index(&cfg).as_ref(),
+                &lock,
+                &cfg,
+                packages,
+                all,
+                bump_pyproject,
+                os,
+                &lock_py_version(&cfg, &pypackages_dir),
+                lock_filename,
+            );
+            return;
         }
         _ => (),
     }
 
-    let pypackages_dir = env::current_dir()
-        .expect("Can't find current path")
-        .join("__pypackages__");
-
     // Check for environments. Create one if none exist. Set `vers_path`.
     let mut vers_path = PathBuf::new();
     let mut py_vers = Version::new(0, 0, 0);
     let venvs = util::find_venvs(&pypackages_dir);
 
-    match &cfg.py_version {
+    match cfg.requires_python() {
         // The version's explicitly specified; check if an environment for that versionc
         // exists. If not, create one, and make sure it's the right version.
-        Some(cfg_constr) => {
+        Some(_) => {
             // The version's explicitly specified; check if an environment for that version
             let compatible_venvs: Vec<&(u32, u32)> = venvs
                 .iter()
-                .filter(|(ma, mi)| cfg_constr.is_compatible(&Version::new_short(*ma, *mi)))
+                .filter(|(ma, mi)| cfg.python_compatible(&Version::new_short(*ma, *mi)))
                 .collect();
 
             match compatible_venvs.len() {
                 0 => {
-                    let vers = create_venv(Some(cfg_constr), &pypackages_dir);
-                    vers_path = pypackages_dir.join(&format!("{}.{}", vers.major, vers.minor));
-                    py_vers = Version::new_short(vers.major, vers.minor); // Don't include patch.
+                    let vers = create_venv(&cfg.py_version, &pypackages_dir);
+                    vers_path = pypackages_dir.join(&format!("{}.{}", vers.major(), vers.minor()));
+                    py_vers = Version::new_short(vers.major(), vers.minor()); // Don't include patch.
                 }
                 1 => {
                     vers_path = pypackages_dir.join(&format!(
@@ -1123,8 +2449,8 @@ fn main() {
         // one if we can't find any.
         None => match venvs.len() {
             0 => {
-                let vers = create_venv(None, &pypackages_dir);
-                vers_path = pypackages_dir.join(&format!("{}.{}", vers.major, vers.minor));
+                let vers = create_venv(&[], &pypackages_dir);
+                vers_path = pypackages_dir.join(&format!("{}.{}", vers.major(), vers.minor()));
                 py_vers = vers;
             }
             1 => {
@@ -1134,8 +2460,8 @@ fn main() {
             _ => abort(
                 "Multiple Python environments found
                 for this project; specify the desired one in `pyproject.toml`. Example:
-[tool.pypackage]
-py_version = \"^3.7\"",
+[project]
+requires-python = \">=3.7\"",
             ),
         },
     };
@@ -1152,15 +2478,11 @@ py_version = \"^3.7\"",
         Err(_) => Lock::default(),
     };
 
-    #[cfg(target_os = "windows")]
-    let os = Os::Windows;
-    #[cfg(target_os = "linux")]
-    let os = Os::Linux;
-    #[cfg(target_os = "macos")]
-    let os = Os::Mac;
+    if lock.lock_version > Some(LOCK_VERSION) {
+        abort_newer_lock(lock_filename, &lock);
+    }
 
-    let lockpacks = lock.package.unwrap_or_else(|| vec![]);
-    //    let extras = cfg.extras;
+    let index = package_index(&cfg);
 
     match subcmd {
         // Add pacakge names to `pyproject.toml` if needed. Then sync installed packages
@@ -1168,30 +2490,68 @@ py_version = \"^3.7\"",
         // We use data from three sources: `pyproject.toml`, `pypackage.lock`, and
         // the currently-installed packages, found by crawling metadata in the `lib` path.
         // See the readme section `How installation and locking work` for details.
-        SubCommand::Install { packages } => {
+        SubCommand::Install {
+            packages,
+            offline,
+            group,
+            no_dev,
+            extras,
+            frozen,
+            locked,
+        } => {
+            cache::set_offline(offline);
+            let selected = Selection::new(&cfg, &group, no_dev, &extras);
+            if frozen || locked {
+                let lock = read_lock_strict(lock_filename, &cfg, locked);
+                install_locked(
+                    index.as_ref(),
+                    &bin_path,
+                    &lib_path,
+                    lock.package.as_ref().unwrap_or(&vec![]),
+                    &selected,
+                    os,
+                    &py_vers,
+                    false,
+                );
+                util::print_color("Installation complete", Color::Green);
+                return;
+            }
+
             if !PathBuf::from(cfg_filename).exists() {
-                cfg.write_file(cfg_filename);
+                cfg.write_file(cfg_filename, index.as_ref());
             }
 
             if found_lock {
                 println!("Found lockfile");
             }
             // Merge reqs added via cli with those in `pyproject.toml`.
-            let updated_reqs = util::merge_reqs(&packages, &cfg, cfg_filename);
+            let updated_reqs = util::merge_reqs(&packages, &cfg, cfg_filename, index.as_ref());
 
             sync(
+                index.as_ref(),
                 &bin_path,
                 &lib_path,
-                &lockpacks,
+                &lock,
                 &updated_reqs,
+                &cfg,
+                &selected,
                 os,
                 &py_vers,
                 &lock_filename,
+                false,
             );
             util::print_color("Installation complete", Color::Green);
         }
 
-        SubCommand::Uninstall { packages } => {
+        SubCommand::Uninstall {
+            packages,
+            offline,
+            dry_run,
+            group,
+            no_dev,
+            extras,
+        } => {
+            cache::set_offline(offline);
             // Remove dependencies specified in the CLI from the config, then lock and sync.
 
             let removed_reqs: Vec<String> = packages
@@ -1202,27 +2562,35 @@ py_version = \"^3.7\"",
                         .name
                 })
                 .collect();
-            println!("(dbg) to remove {:#?}", &removed_reqs);
 
-            files::remove_reqs_from_cfg(cfg_filename, &removed_reqs);
+            if !dry_run {
+                files::remove_reqs_from_cfg(cfg_filename, &removed_reqs);
+            }
 
             // Filter reqs here instead of re-reading the config from file.
             let updated_reqs: Vec<Req> = cfg
                 .reqs
-                .into_iter()
+                .iter()
                 .filter(|req| !removed_reqs.contains(&req.name))
+                .cloned()
                 .collect();
 
             sync(
+                index.as_ref(),
                 &bin_path,
                 &lib_path,
-                &lockpacks,
+                &lock,
                 &updated_reqs,
+                &cfg,
+                &Selection::new(&cfg, &group, no_dev, &extras),
                 os,
                 &py_vers,
                 &lock_filename,
+                dry_run,
             );
-            util::print_color("Uninstall complete", Color::Green);
+            if !dry_run {
+                util::print_color("Uninstall complete", Color::Green);
+            }
         }
 
         SubCommand::Python { args } => {
@@ -1230,8 +2598,6 @@ py_version = \"^3.7\"",
                 abort("Problem running Python");
             }
         }
-        SubCommand::Package { extras } => build::build(&bin_path, &lib_path, &cfg, extras),
-        SubCommand::Publish {} => build::publish(&bin_path, &cfg),
         SubCommand::Reset {} => {
             if fs::remove_dir_all(&pypackages_dir).is_err() {
                 abort("Problem removing `__pypackages__` directory")
@@ -1278,8 +2644,813 @@ py_version = \"^3.7\"",
         // We already handled init and new
         SubCommand::Init {} => (),
         SubCommand::New { .. } => (),
+        SubCommand::Package => (),
+        SubCommand::BuildBackend { .. } => (),
+        SubCommand::Publish { .. } => (),
+        SubCommand::Lock { .. } => (),
+        SubCommand::Why { .. } => (),
+        SubCommand::Tree { .. } => (),
+        SubCommand::Outdated { .. } => (),
+        SubCommand::Update {
+            packages,
+            all,
+            bump_pyproject,
+            ..
+        } => {
+            let (mut updated_lock, bumped) = plan_update(
+                index.as_ref(),
+                &lock,
+                &cfg,
+                packages,
+                all,
+                bump_pyproject,
+                os,
+                &py_vers,
+                lock_filename,
+            );
+            let updated_packs = updated_lock.package.clone().unwrap_or_default();
+
+            if !bumped.is_empty() {
+                files::add_reqs_to_cfg(cfg_filename, &bumped, index.as_ref());
+                // Keep the lock file up to date with the new constraints.
+                let reqs: Vec<Req> = cfg
+                    .reqs
+                    .iter()
+                    .map(|r| {
+                        bumped
+                            .iter()
+                            .find(|b| b.name == r.name)
+                            .unwrap_or(r)
+                            .clone()
+                    })
+                    .collect();
+                updated_lock.metadata.insert(
+                    "requirements-hash".to_owned(),
+                    requirements_hash(&reqs, &cfg),
+                );
+            }
+            if write_lock(lock_filename, &updated_lock).is_err() {
+                abort("Problem writing lock file");
+            }
+            install_locked(
+                index.as_ref(),
+                &bin_path,
+                &lib_path,
+                &updated_packs,
+                &Selection::new(&cfg, &[], false, &[]),
+                os,
+                &py_vers,
+                false,
+            );
+            util::print_color("Update complete", Color::Green);
+        }
     }
 }
 
 #[cfg(test)]
-pub mod tests {}
+pub mod tests {
+    use super::*;
+
+    /// Requirements in tests are constrained, so this isn't queried.
+    fn pypi() -> index::JsonIndex {
+        index::JsonIndex::pypi()
+    }
+
+    /// Parse a command line, including the program name.
+    fn parse_args(args: &[&str]) -> Result<Opt, structopt::clap::Error> {
+        Opt::from_iter_safe(args.iter())
+    }
+
+    fn temp_pyproject(name: &str, text: &str) -> String {
+        let dir = env::temp_dir().join(name);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pyproject.toml");
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn project_table() {
+        let filename = temp_pyproject(
+            "pypackage_project_table_test",
+            r#"[project]
+name = "my-pkg"
+dynamic = ["version"]
+requires-python = ">=3.7, <4"
+authors = [{name = "Fraa Erasmas", email = "raz@edhar.math"}]
+license = {text = "MIT"}
+readme = {file = "README.rst", content-type = "text/x-rst"}
+dependencies = [
+    "requests[security] >= 2.8.1",
+    'pywin32 >= 1.0; sys_platform == "win32"',
+]
+scripts = {my-tool = "my_pkg:main"}
+
+[project.optional-dependencies]
+test = ["pytest >= 5"]
+
+[project.urls]
+Homepage = "https://example.com"
+"Source Code" = "https://github.com/raz/my-pkg"
+
+[project.entry-points."my.plugins"]
+a = "my_pkg.plugins:a"
+
+[tool.pypackage]
+name = "ignored"
+version = "0.2.0"
+allow_renames = true
+
+[tool.pypackage.dependencies]
+numpy = "^1.16.4"
+"#,
+        );
+        let cfg = Config::from_file(&filename).unwrap();
+
+        // `[project]` takes precedence; dynamic fields come from `[tool.pypackage]`.
+        assert_eq!(cfg.name, Some("my-pkg".into()));
+        assert_eq!(cfg.version, Some(Version::new(0, 2, 0)));
+        assert!(cfg.allow_renames);
+        assert_eq!(
+            cfg.py_version,
+            vec![
+                Constraint::new(ReqType::Gte, Version::new_short(3, 7)),
+                Constraint::new(ReqType::Lt, Version::new_short(4, 0)),
+            ]
+        );
+        assert_eq!(cfg.requires_python(), Some(">=3.7, <4".to_owned()));
+        assert_eq!(cfg.author, Some("Fraa Erasmas".into()));
+        assert_eq!(cfg.author_email, Some("raz@edhar.math".into()));
+        assert_eq!(cfg.license, Some("MIT".into()));
+        assert_eq!(cfg.readme_filename, Some("README.rst".into()));
+        assert_eq!(cfg.homepage, Some("https://example.com".into()));
+        assert_eq!(cfg.repository, Some("https://github.com/raz/my-pkg".into()));
+        assert_eq!(cfg.console_scripts, vec!["my-tool = my_pkg:main"]);
+        assert_eq!(cfg.entry_points["my.plugins"], vec!["a = my_pkg.plugins:a"]);
+
+        let names: Vec<&str> = cfg.reqs.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["requests", "pywin32"]);
+        assert_eq!(
+            cfg.reqs[0].install_with_extras,
+            Some(vec!["security".into()])
+        );
+        assert!(cfg.reqs[1].marker.is_some());
+        assert_eq!(cfg.optional_reqs["test"][0].name, "pytest");
+    }
+
+    #[test]
+    fn write_and_edit_project() {
+        let filename = temp_pyproject("pypackage_write_project_test", "");
+        fs::remove_file(&filename).unwrap();
+
+        let cfg = Config {
+            name: Some("my-pkg".into()),
+            version: Some(Version::new(0, 1, 0)),
+            py_version: vec![Constraint::new(ReqType::Caret, Version::new_short(3, 7))],
+            author: Some("Fraa \"Raz\" Erasmas".into()),
+            reqs: vec![Req::new(
+                "numpy".into(),
+                vec![Constraint::new(ReqType::Caret, Version::new(1, 16, 4))],
+            )],
+            console_scripts: vec!["my-tool = my_pkg:main".into()],
+            allow_renames: true,
+            ..Default::default()
+        };
+        cfg.write_file(&filename, &pypi());
+        let text = fs::read_to_string(&filename).unwrap();
+        assert!(text.contains("[project]\nname = \"my-pkg\"\n"));
+        assert!(text.contains("requires-python = \">=3.7,<4.0.0\"\n"));
+        assert!(text.contains("dependencies = [\n    \"numpy>=1.16.4,<2.0.0\",\n]\n"));
+        assert!(text.contains("[project.scripts]\nmy-tool = \"my_pkg:main\"\n"));
+        assert!(text.contains("[tool.pypackage]\nallow_renames = true\n"));
+
+        let read = Config::from_file(&filename).unwrap();
+        assert_eq!(read.name, cfg.name);
+        assert_eq!(read.author, cfg.author);
+        assert_eq!(read.reqs[0].name, "numpy");
+        assert_eq!(read.console_scripts, cfg.console_scripts);
+
+        let requests = Req::new(
+            "requests".into(),
+            vec![Constraint::new(ReqType::Gte, Version::new(2, 22, 0))],
+        );
+        files::add_reqs_to_cfg(&filename, &[requests], &pypi());
+        let names = |f: &str| -> Vec<String> {
+            Config::from_file(f)
+                .unwrap()
+                .reqs
+                .into_iter()
+                .map(|r| r.name)
+                .collect()
+        };
+        assert_eq!(names(&filename), vec!["numpy", "requests"]);
+
+        files::remove_reqs_from_cfg(&filename, &["NumPy".into()]);
+        assert_eq!(names(&filename), vec!["requests"]);
+        let text = fs::read_to_string(&filename).unwrap();
+        assert!(text.contains("dependencies = [\n    \"requests>=2.22.0\",\n]\n"));
+        assert!(text.contains("[project.scripts]"));
+    }
+
+    #[test]
+    fn edit_keeps_formatting() {
+        let gte = |name: &str, major| {
+            Req::new(
+                name.into(),
+                vec![Constraint::new(ReqType::Gte, Version::new(major, 0, 0))],
+            )
+        };
+
+        let filename = temp_pyproject(
+            "pypackage_edit_poetry_test",
+            r#"[tool.poetry]
+name = "my-pkg"
+
+[tool.poetry.dependencies]
+# Runtime deps.
+python = "^3.7"
+Django = { version = "^2.2", extras = ["bcrypt"] }  # The web framework.
+numpy = "^1.16.4"
+
+[tool.poetry.dependencies.scipy]
+version = "^1.3"
+optional = true
+"#,
+        );
+        files::add_reqs_to_cfg(
+            &filename,
+            &[gte("django", 3), gte("scipy", 1), gte("requests", 2)],
+            &pypi(),
+        );
+        files::remove_reqs_from_cfg(&filename, &["numpy".into()]);
+        assert_eq!(
+            fs::read_to_string(&filename).unwrap(),
+            r#"[tool.poetry]
+name = "my-pkg"
+
+[tool.poetry.dependencies]
+# Runtime deps.
+python = "^3.7"
+Django = { version = ">=3.0.0", extras = ["bcrypt"] }  # The web framework.
+requests = ">=2.0.0"
+
+[tool.poetry.dependencies.scipy]
+version = ">=1.0.0"
+optional = true
+"#
+        );
+
+        let filename = temp_pyproject(
+            "pypackage_edit_project_test",
+            r#"[project]
+name = "my-pkg"
+dependencies = [
+    "numpy>=1.16",  # For arrays.
+    'Django[bcrypt] >= 2.2; python_version >= "3.6"',
+]
+"#,
+        );
+        files::add_reqs_to_cfg(&filename, &[gte("scipy", 1), gte("numpy", 1)], &pypi());
+        files::remove_reqs_from_cfg(&filename, &["django".into()]);
+        assert_eq!(
+            fs::read_to_string(&filename).unwrap(),
+            r#"[project]
+name = "my-pkg"
+dependencies = [
+    "numpy>=1.0.0",  # For arrays.
+    "scipy>=1.0.0",
+]
+"#
+        );
+    }
+
+    #[test]
+    fn dependency_groups() {
+        let names = |reqs: &[Req]| -> Vec<String> {
+            let mut result: Vec<String> = reqs.iter().map(|r| r.name.clone()).collect();
+            result.sort();
+            result
+        };
+
+        let filename = temp_pyproject(
+            "pypackage_poetry_groups_test",
+            r#"[tool.poetry]
+name = "my-pkg"
+
+[tool.poetry.dependencies]
+python = "^3.7"
+numpy = "^1.16.4"
+
+[tool.poetry.dev-dependencies]
+pytest = "^5.2"
+
+[tool.poetry.group.dev.dependencies]
+black = "^19.10b0"
+
+[tool.poetry.group.docs.dependencies]
+sphinx = { version = "^2.2", python = ">=3.6" }
+"#,
+        );
+        let cfg = Config::from_file(&filename).unwrap();
+        assert_eq!(names(&cfg.reqs), vec!["numpy"]);
+        assert_eq!(names(&cfg.groups["dev"]), vec!["black", "pytest"]);
+        assert_eq!(names(&cfg.groups["docs"]), vec!["sphinx"]);
+        assert!(cfg.groups["docs"][0].marker.is_some());
+
+        let filename = temp_pyproject(
+            "pypackage_dependency_groups_test",
+            r#"[project]
+name = "my-pkg"
+dependencies = ["numpy>=1.16"]
+
+[dependency-groups]
+test = ["pytest>=5", "hypothesis>=4.0"]
+lint = ["black==19.10b0"]
+dev = [{include-group = "test"}, {include-group = "lint"}]
+
+[tool.pypackage.dev_dependencies]
+mypy = "^0.740"
+"#,
+        );
+        let cfg = Config::from_file(&filename).unwrap();
+        assert_eq!(names(&cfg.groups["test"]), vec!["hypothesis", "pytest"]);
+        assert_eq!(
+            names(&cfg.groups["dev"]),
+            vec!["black", "hypothesis", "pytest"]
+        );
+        assert_eq!(
+            Selection::new(&cfg, &["test".into()], true, &[]).groups,
+            vec!["test".to_string()]
+        );
+        assert_eq!(
+            Selection::new(&cfg, &[], false, &[]).groups,
+            vec!["dev".to_string()]
+        );
+
+        fs::remove_file(&filename).unwrap();
+        let cfg = Config {
+            name: Some("my-pkg".into()),
+            groups: cfg.groups,
+            ..Default::default()
+        };
+        cfg.write_file(&filename, &pypi());
+        let text = fs::read_to_string(&filename).unwrap();
+        assert!(text.contains("\n[dependency-groups]\ndev = [\n    \"pytest>=5\",\n"));
+        assert!(text.contains("lint = [\n    \"black==19.10b0\",\n]\n"));
+    }
+
+    #[test]
+    fn lock_groups() {
+        let lock_pack = |name: &str, deps: &[&str]| dep_types::tests::lock_pack(name, "1.0", deps);
+        let mut packs = vec![
+            lock_pack("requests", &["urllib3", "idna"]),
+            lock_pack("urllib3", &[]),
+            lock_pack("idna", &[]),
+            lock_pack("pytest", &["py", "Urllib3"]),
+            lock_pack("py", &[]),
+            lock_pack("unused", &[]),
+        ];
+        let req = |name: &str| Req::new(name.into(), vec![]);
+        let groups = membership(
+            &packs,
+            &[
+                ("main".into(), vec![req("Requests")]),
+                ("test".into(), vec![req("pytest")]),
+            ],
+        );
+
+        let main = || vec!["main".to_string()];
+        let test = || vec!["test".to_string()];
+        assert_eq!(
+            groups,
+            vec![
+                main(),
+                vec!["main".to_string(), "test".to_string()],
+                main(),
+                test(),
+                test(),
+                vec![]
+            ]
+        );
+
+        for (lp, groups) in packs.iter_mut().zip(groups) {
+            lp.groups = Some(groups);
+        }
+        packs[5].extras = Some(vec!["socks".into()]);
+        let cfg = Config {
+            groups: vec![("test".to_string(), vec![])].into_iter().collect(),
+            optional_reqs: vec![("socks".to_string(), vec![])].into_iter().collect(),
+            ..Default::default()
+        };
+        let installed = |selection: Selection| -> Vec<String> {
+            packs
+                .iter()
+                .filter(|lp| selection.includes(lp))
+                .map(|lp| lp.name.clone())
+                .collect()
+        };
+        assert_eq!(
+            installed(Selection::new(&cfg, &[], true, &[])),
+            vec!["requests", "urllib3", "idna"]
+        );
+        assert_eq!(
+            installed(Selection::new(
+                &cfg,
+                &["test".into()],
+                true,
+                &["socks".into()]
+            )),
+            vec!["requests", "urllib3", "idna", "pytest", "py", "unused"]
+        );
+    }
+
+    #[test]
+    fn extras() {
+        let names = |reqs: Vec<Req>| -> Vec<String> {
+            let mut result: Vec<String> = reqs.into_iter().map(|r| r.name).collect();
+            result.sort();
+            result
+        };
+
+        let filename = temp_pyproject(
+            "pypackage_poetry_extras_test",
+            r#"[tool.poetry]
+name = "my-pkg"
+
+[tool.poetry.dependencies]
+requests = "^2.22"
+pysocks = { version = "^1.7", optional = true }
+mysqlclient = { version = "^1.3", optional = true }
+
+[tool.poetry.extras]
+socks = ["PySocks"]
+mysql = ["mysqlclient"]
+"#,
+        );
+        let cfg = Config::from_file(&filename).unwrap();
+        assert_eq!(names(cfg.reqs.clone()), vec!["requests"]);
+        assert_eq!(names(cfg.extra_reqs("socks")), vec!["pysocks"]);
+        assert_eq!(names(cfg.extra_reqs("MySQL")), vec!["mysqlclient"]);
+
+        let filename = temp_pyproject(
+            "pypackage_project_extras_test",
+            r#"[project]
+name = "my-pkg"
+
+[project.optional-dependencies]
+test = ["pytest>=5"]
+docs = ["sphinx>=2.2", "pytest>=5"]
+all = ["My_Pkg[test,docs]", "black==19.10b0"]
+
+[tool.pypackage.extras]
+test = ["nose>=1.3"]
+"#,
+        );
+        let cfg = Config::from_file(&filename).unwrap();
+        // `[project]` takes precedence.
+        assert_eq!(names(cfg.extra_reqs("test")), vec!["pytest"]);
+        assert_eq!(
+            names(cfg.extra_reqs("all")),
+            vec!["black", "pytest", "sphinx"]
+        );
+        assert!(cfg.extra_reqs("missing").is_empty());
+    }
+
+    #[test]
+    fn lock_dep_strings() {
+        let marker = Marker::from_str("sys_platform == \"win32\"").unwrap();
+        let version = Version::new(0, 4, 3);
+        let dep = lock_dep_string(&pypi(), "colorama", &version, &Some(marker.clone()));
+        assert_eq!(
+            dep,
+            "colorama 0.4.3 pypi+https://pypi.org/pypi/colorama/0.4.3/json ; sys_platform == \"win32\""
+        );
+        assert_eq!(
+            parse_lock_dep(&dep),
+            ("colorama".to_owned(), Version::new(0, 4, 3), Some(marker))
+        );
+        assert_eq!(
+            parse_lock_dep("idna 2.8 pypi+https://pypi.org/pypi/idna/2.8/json"),
+            ("idna".to_owned(), Version::new(2, 8, 0), None)
+        );
+    }
+
+    #[test]
+    fn lock_versions() {
+        let v1 = r#"
+[[package]]
+id = 1
+name = "idna"
+version = "2.8"
+source = "pypi+https://pypi.org/pypi/idna/2.8/json"
+dependencies = []
+"#;
+        let lock: Lock = toml::from_str(v1).unwrap();
+        assert_eq!(lock.lock_version, None);
+        assert!(lock.metadata.is_empty());
+        assert_eq!(lock.package.unwrap()[0].artifacts, None);
+
+        let mut metadata = BTreeMap::new();
+        metadata.insert("requirements-hash".to_owned(), "sha256:abc".to_owned());
+        let lock = Lock {
+            lock_version: Some(LOCK_VERSION),
+            metadata,
+            package: Some(vec![LockPackage {
+                id: 1,
+                name: "colorama".into(),
+                version: "0.4.3".into(),
+                source: None,
+                dependencies: Some(vec![]),
+                rename: None,
+                groups: Some(vec!["main".into()]),
+                extras: None,
+                marker: Some("sys_platform == \"win32\"".into()),
+                artifacts: Some(vec![LockArtifact {
+                    filename: "colorama-0.4.3-py2.py3-none-any.whl".into(),
+                    url: "https://files.pythonhosted.org/colorama-0.4.3-py2.py3-none-any.whl"
+                        .into(),
+                    hash: "sha256:7d73d2a99753107a36ac6b455ee49046802e59d9d076ef8e47b61499fa29afff"
+                        .into(),
+                    requires_python: Some(
+                        ">=2.7, !=3.0.*, !=3.1.*, !=3.2.*, !=3.3.*, !=3.4.*".into(),
+                    ),
+                }]),
+            }]),
+        };
+        let text = toml::to_string(&lock).unwrap();
+        assert!(text.starts_with("lock-version = 2\n"));
+        assert_eq!(toml::from_str::<Lock>(&text).unwrap(), lock);
+
+        let releases = artifact_releases(lock.package.unwrap()[0].artifacts.as_ref().unwrap());
+        assert_eq!(releases[0].packagetype, "bdist_wheel");
+        assert_eq!(releases[0].python_version, "py2.py3");
+        assert_eq!(
+            releases[0].digests.sha256,
+            "7d73d2a99753107a36ac6b455ee49046802e59d9d076ef8e47b61499fa29afff"
+        );
+    }
+
+    #[test]
+    fn best_release_patch_requires_python() {
+        let release = |filename: &str, python_version, requires_python| WarehouseRelease {
+            filename: filename.into(),
+            ..dep_resolution::tests::release(python_version, requires_python, false)
+        };
+        let data = vec![
+            release("saturn-1.0.tar.gz", "source", None),
+            release("saturn-1.0-py3-none-any.whl", "py3", Some(">=3.7.1")),
+        ];
+        let py_vers = Version::new_short(3, 7);
+        let supported = tags::supported_tags(&py_vers, &tags::Platform::detect(Os::Linux));
+        let (best, package_type) = find_best_release(
+            &data,
+            "saturn",
+            &Version::new_short(1, 0),
+            &supported,
+            &py_vers,
+        );
+        assert_eq!(best.filename, "saturn-1.0-py3-none-any.whl");
+        if let Source = package_type {
+            panic!("Expected a wheel");
+        }
+    }
+
+    #[test]
+    fn yanked_artifacts() {
+        let release = |filename: &str, yanked| WarehouseRelease {
+            filename: filename.into(),
+            ..dep_resolution::tests::release("py3", None, yanked)
+        };
+        let index = dep_resolution::tests::TestIndex(index::IndexProject {
+            name: "saturn".into(),
+            latest: None,
+            releases: vec![
+                (
+                    Version::new_short(1, 0),
+                    vec![release("a.whl", false), release("b.whl", true)],
+                ),
+                (
+                    Version::new_short(1, 1),
+                    vec![release("c.whl", true), release("d.whl", true)],
+                ),
+            ],
+        });
+        let filenames = |version| -> Vec<String> {
+            find_artifacts(&index, "saturn", &version)
+                .into_iter()
+                .map(|a| a.filename)
+                .collect()
+        };
+        assert_eq!(filenames(Version::new_short(1, 0)), vec!["a.whl"]);
+        // A yanked version we locked anyway keeps its files.
+        assert_eq!(filenames(Version::new_short(1, 1)), vec!["c.whl", "d.whl"]);
+    }
+
+    #[test]
+    fn strict_locks() {
+        let cfg = Config {
+            reqs: vec![Req::new(
+                "numpy".into(),
+                vec![Constraint::new(ReqType::Caret, Version::new(1, 16, 4))],
+            )],
+            ..Config::default()
+        };
+        let mut lock = Lock {
+            lock_version: Some(LOCK_VERSION),
+            ..Lock::default()
+        };
+        let check =
+            |lock: &Lock, check_hash| check_lock_strict("pypackage.lock", lock, &cfg, check_hash);
+
+        // A missing hash only matters with `--locked`.
+        assert!(check(&lock, false).is_ok());
+        assert!(check(&lock, true).unwrap_err().contains("out of date"));
+
+        lock.metadata.insert(
+            "requirements-hash".into(),
+            requirements_hash(&cfg.reqs, &cfg),
+        );
+        assert!(check(&lock, true).is_ok());
+
+        lock.metadata
+            .insert("requirements-hash".into(), "sha256:abc".into());
+        assert!(check(&lock, false).is_ok());
+        assert!(check(&lock, true).unwrap_err().contains("out of date"));
+
+        lock.lock_version = None;
+        assert_eq!(
+            check(&lock, false).unwrap_err(),
+            "`pypackage.lock` is lock version 1, which has no artifacts; \
+             run `pypackage lock` to update it"
+        );
+    }
+
+    #[test]
+    fn lock_targets() {
+        let constraint = Constraint::new(ReqType::Gte, Version::new_short(3, 11));
+        assert_eq!(
+            target_python_versions(&[constraint], &Version::new(3, 12, 1)),
+            vec![Version::new_short(3, 12), Version::new_short(3, 11)]
+        );
+        // The range comes from the constraints, including a patch-level lower bound.
+        let constraints = Constraint::from_str_multiple(">=3.7.1, <3.10").unwrap();
+        assert_eq!(
+            target_python_versions(&constraints, &Version::new(3, 8, 2)),
+            vec![
+                Version::new_short(3, 8),
+                Version::new_short(3, 7),
+                Version::new_short(3, 9)
+            ]
+        );
+        assert_eq!(
+            target_python_versions(&[], &Version::new(3, 7, 4)),
+            vec![Version::new_short(3, 7)]
+        );
+    }
+
+    #[test]
+    fn requirements_hash_changes() {
+        let req = |name: &str| {
+            Req::new(
+                name.into(),
+                vec![Constraint::new(ReqType::Gte, Version::new(1, 0, 0))],
+            )
+        };
+        let mut cfg = Config::default();
+        cfg.groups.insert("dev".into(), vec![req("pytest")]);
+        let hash = requirements_hash(&[req("requests"), req("numpy")], &cfg);
+        assert!(hash.starts_with("sha256:"));
+        // Order doesn't matter, but contents do.
+        assert_eq!(
+            hash,
+            requirements_hash(&[req("numpy"), req("requests")], &cfg)
+        );
+        assert_ne!(hash, requirements_hash(&[req("numpy")], &cfg));
+        cfg.groups.insert("dev".into(), vec![req("black")]);
+        assert_ne!(
+            hash,
+            requirements_hash(&[req("numpy"), req("requests")], &cfg)
+        );
+    }
+
+    #[test]
+    fn install_modes() {
+        match parse_args(&["pypackage", "install", "--frozen"])
+            .unwrap()
+            .subcmds
+        {
+            Some(SubCommand::Install { frozen, locked, .. }) => assert!(frozen && !locked),
+            _ => panic!("Expected an install command"),
+        }
+        assert!(parse_args(&["pypackage", "install", "--locked", "--no-dev"]).is_ok());
+        assert!(parse_args(&["pypackage", "install", "--frozen", "--locked"]).is_err());
+        assert!(parse_args(&["pypackage", "install", "--frozen", "numpy"]).is_err());
+        assert!(parse_args(&["pypackage", "install", "--locked", "numpy"]).is_err());
+        match parse_args(&[
+            "pypackage",
+            "uninstall",
+            "numpy",
+            "-g",
+            "docs",
+            "--extras",
+            "a,b",
+        ])
+        .unwrap()
+        .subcmds
+        {
+            Some(SubCommand::Uninstall {
+                packages,
+                group,
+                no_dev,
+                extras,
+                ..
+            }) => {
+                assert_eq!(packages, vec!["numpy"]);
+                assert_eq!(group, vec!["docs"]);
+                assert!(!no_dev);
+                assert_eq!(extras, vec!["a", "b"]);
+            }
+            _ => panic!("Expected an uninstall command"),
+        }
+        match parse_args(&["pypackage", "lock", "--offline"])
+            .unwrap()
+            .subcmds
+        {
+            Some(SubCommand::Lock { offline }) => assert!(offline),
+            _ => panic!("Expected a lock command"),
+        }
+    }
+
+    #[test]
+    fn update_diff() {
+        let lock_pack = |name: &str, version: &str| dep_types::tests::lock_pack(name, version, &[]);
+        let old = vec![
+            lock_pack("requests", "2.22.0"),
+            lock_pack("idna", "2.8"),
+            lock_pack("chardet", "3.0.4"),
+        ];
+        let new = vec![
+            lock_pack("requests", "2.23.0"),
+            lock_pack("idna", "2.8"),
+            lock_pack("Certifi", "2019.11.28"),
+        ];
+        let s = |v: &str| Some(v.to_owned());
+        assert_eq!(
+            lock_diff(&old, &new),
+            vec![
+                ("Certifi".to_owned(), None, s("2019.11.28")),
+                ("chardet".to_owned(), s("3.0.4"), None),
+                ("requests".to_owned(), s("2.22.0"), s("2.23.0")),
+            ]
+        );
+        assert!(lock_diff(&old, &old).is_empty());
+
+        let reqs = vec![
+            Req::new(
+                "Requests".into(),
+                vec![Constraint::new(ReqType::Caret, Version::new(2, 22, 0))],
+            ),
+            Req::new(
+                "idna".into(),
+                vec![Constraint::new(ReqType::Gte, Version::new(2, 0, 0))],
+            ),
+        ];
+        assert_eq!(
+            bumped_reqs(&reqs, &new),
+            vec![Req::new(
+                "Requests".into(),
+                vec![Constraint::new(ReqType::Caret, Version::new(2, 23, 0))],
+            )]
+        );
+        assert!(bumped_reqs(&reqs, &old).is_empty());
+    }
+
+    #[test]
+    fn update_args() {
+        match parse_args(&["pypackage", "update", "requests", "--dry-run"])
+            .unwrap()
+            .subcmds
+        {
+            Some(SubCommand::Update {
+                packages, dry_run, ..
+            }) => {
+                assert_eq!(packages, vec!["requests".to_owned()]);
+                assert!(dry_run);
+            }
+            _ => panic!("Expected an update command"),
+        }
+        assert!(parse_args(&["pypackage", "update", "--all", "--bump-pyproject"]).is_ok());
+        assert!(parse_args(&["pypackage", "update", "--all", "requests"]).is_err());
+    }
+
+    #[test]
+    fn why_alias() {
+        match parse_args(&["pypackage", "explain", "urllib3"])
+            .unwrap()
+            .subcmds
+        {
+            Some(SubCommand::Why { package }) => assert_eq!(package, "urllib3"),
+            _ => panic!("Expected a why command"),
+        }
+    }
+}
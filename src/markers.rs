@@ -0,0 +1,691 @@
+//! Environment markers, as described in [PEP 508](https://www.python.org/dev/peps/pep-0508/).
+//! Eg the `sys_platform == "win32" and python_version < "3.8"` in
+//! `pywin32 ; sys_platform == "win32" and python_version < "3.8"`.
+
+use crate::dep_types::{Constraint, DependencyError, ReqType, Version};
+use crate::{util, Os};
+use serde::Deserialize;
+use std::{env, fmt, str::FromStr};
+
+/// Marker variables whose values are compared as versions when able.
+const VERSION_VARS: &[&str] = &[
+    "python_version",
+    "python_full_version",
+    "implementation_version",
+];
+
+const ENV_VARS: &[&str] = &[
+    "os_name",
+    "sys_platform",
+    "platform_machine",
+    "platform_python_implementation",
+    "platform_release",
+    "platform_system",
+    "platform_version",
+    "python_version",
+    "python_full_version",
+    "implementation_name",
+    "implementation_version",
+    "extra",
+];
+
+/// Describes the environment we're resolving for. Used to evaluate markers.
+#[derive(Clone, Debug)]
+pub struct Environment {
+    pub os_name: String,
+    pub sys_platform: String,
+    pub platform_machine: String,
+    pub platform_python_implementation: String,
+    pub platform_release: String,
+    pub platform_system: String,
+    pub platform_version: String,
+    pub python_version: String,
+    pub python_full_version: String,
+    pub implementation_name: String,
+    pub implementation_version: String,
+    pub extras: Vec<String>,
+}
+
+impl Environment {
+    /// Build an environment for a target OS and Python version. We assume CPython, and
+    /// the machine architecture we're running on.
+    pub fn new(os: Os, py_vers: &Version) -> Self {
+        let (os_name, sys_platform, platform_system) = match os {
+            Os::Windows | Os::Windows32 => ("nt", "win32", "Windows"),
+            Os::Mac => ("posix", "darwin", "Darwin"),
+            Os::Linux | Os::Linux32 | Os::Any => ("posix", "linux", "Linux"),
+        };
+
+        let platform_machine = match (os, env::consts::ARCH) {
+            (Os::Linux32, _) => "i686",
+            (Os::Windows32, _) => "x86",
+            (Os::Windows, "aarch64") => "ARM64",
+            (Os::Windows, _) => "AMD64",
+            (Os::Mac, "aarch64") => "arm64",
+            (_, arch) => arch,
+        };
+
+        let full_version = format!(
+            "{}.{}.{}",
+            py_vers.major(),
+            py_vers.minor(),
+            py_vers.patch()
+        );
+
+        Self {
+            os_name: os_name.into(),
+            sys_platform: sys_platform.into(),
+            platform_machine: platform_machine.into(),
+            platform_python_implementation: "CPython".into(),
+            platform_release: "".into(),
+            platform_system: platform_system.into(),
+            platform_version: "".into(),
+            python_version: format!("{}.{}", py_vers.major(), py_vers.minor()),
+            python_full_version: full_version.clone(),
+            implementation_name: "cpython".into(),
+            implementation_version: full_version,
+            extras: vec![],
+        }
+    }
+
+    /// The environments we lock for: Linux, Mac, and Windows, with each Python version.
+    pub fn targets(py_versions: &[Version]) -> Vec<Self> {
+        let mut result = vec![];
+        for os in [Os::Linux, Os::Mac, Os::Windows].iter() {
+            for py_vers in py_versions.iter() {
+                result.push(Self::new(*os, py_vers));
+            }
+        }
+        result
+    }
+
+    /// The same environment, with a set of extras enabled.
+    pub fn with_extras(&self, extras: &[String]) -> Self {
+        Self {
+            extras: extras.to_vec(),
+            ..self.clone()
+        }
+    }
+
+    fn get(&self, var: &str) -> &str {
+        match var {
+            "os_name" => &self.os_name,
+            "sys_platform" => &self.sys_platform,
+            "platform_machine" => &self.platform_machine,
+            "platform_python_implementation" => &self.platform_python_implementation,
+            "platform_release" => &self.platform_release,
+            "platform_system" => &self.platform_system,
+            "platform_version" => &self.platform_version,
+            "python_version" => &self.python_version,
+            "python_full_version" => &self.python_full_version,
+            "implementation_name" => &self.implementation_name,
+            "implementation_version" => &self.implementation_version,
+            _ => "",
+        }
+    }
+}
+
+/// Either side of a marker comparison.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub enum MarkerValue {
+    Var(String),
+    Literal(String),
+}
+
+impl fmt::Display for MarkerValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Var(v) => write!(f, "{}", v),
+            Self::Literal(l) => write!(f, "\"{}\"", l),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum MarkerOp {
+    Version(ReqType),
+    /// `===`; compares strings exactly.
+    Arbitrary,
+    In,
+    NotIn,
+}
+
+impl fmt::Display for MarkerOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Version(ReqType::Compatible) => write!(f, "~="),
+            Self::Version(t) => write!(f, "{}", t.to_string()),
+            Self::Arbitrary => write!(f, "==="),
+            Self::In => write!(f, "in"),
+            Self::NotIn => write!(f, "not in"),
+        }
+    }
+}
+
+/// A parsed marker expression.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub enum Marker {
+    And(Box<Marker>, Box<Marker>),
+    Or(Box<Marker>, Box<Marker>),
+    Expr(MarkerValue, MarkerOp, MarkerValue),
+}
+
+impl Marker {
+    /// Build a marker from a Python version requirement, as specified in `pyproject.toml`,
+    /// eg `ipython = {version = "^7.7.0", python = ">=3.6"}`.
+    pub fn from_python_constraints(constraints: &[Constraint]) -> Option<Self> {
+        constraints
+            .iter()
+            .map(|c| {
+                Self::Expr(
+                    MarkerValue::Var("python_version".into()),
+                    MarkerOp::Version(c.type_),
+                    MarkerValue::Literal(c.version.to_string2()),
+                )
+            })
+            .fold(None, |acc, m| match acc {
+                Some(a) => Some(Self::And(Box::new(a), Box::new(m))),
+                None => Some(m),
+            })
+    }
+
+    /// Evaluate the marker for a target environment.
+    pub fn evaluate(&self, env: &Environment) -> bool {
+        match self {
+            Self::And(a, b) => a.evaluate(env) && b.evaluate(env),
+            Self::Or(a, b) => a.evaluate(env) || b.evaluate(env),
+            Self::Expr(lhs, op, rhs) => {
+                // `extra` is special: it's true if any of the extras we're installing match.
+                if let MarkerValue::Var(v) = lhs {
+                    if v == "extra" {
+                        return eval_extra(env, *op, rhs);
+                    }
+                }
+                if let MarkerValue::Var(v) = rhs {
+                    if v == "extra" {
+                        return eval_extra(env, *op, lhs);
+                    }
+                }
+
+                let is_version_var = |v: &MarkerValue| match v {
+                    MarkerValue::Var(name) => VERSION_VARS.contains(&name.as_ref()),
+                    MarkerValue::Literal(_) => false,
+                };
+                let resolve = |v: &MarkerValue| -> String {
+                    match v {
+                        MarkerValue::Var(name) => env.get(name).to_owned(),
+                        MarkerValue::Literal(l) => l.clone(),
+                    }
+                };
+
+                compare(
+                    &resolve(lhs),
+                    *op,
+                    &resolve(rhs),
+                    is_version_var(lhs) || is_version_var(rhs),
+                )
+            }
+        }
+    }
+
+    /// Is this marker true in any of these environments?
+    pub fn evaluate_any(&self, envs: &[Environment]) -> bool {
+        envs.iter().any(|env| self.evaluate(env))
+    }
+
+    /// This marker, with `extra` comparisons removed, for recording which environments need
+    /// a dependency once we've chosen extras. `None` means it applies everywhere.
+    pub fn without_extras(&self) -> Option<Self> {
+        match self {
+            Self::And(a, b) => Self::and(a.without_extras(), b.without_extras()),
+            Self::Or(a, b) => Self::or(a.without_extras(), b.without_extras()),
+            Self::Expr(_, _, _) if self.has_extra() => None,
+            Self::Expr(_, _, _) => Some(self.clone()),
+        }
+    }
+
+    /// Both markers, where `None` means always true.
+    pub fn and(a: Option<Self>, b: Option<Self>) -> Option<Self> {
+        match (a, b) {
+            (Some(a), Some(b)) => {
+                if a.implies(&b) {
+                    Some(a)
+                } else if b.implies(&a) {
+                    Some(b)
+                } else {
+                    Some(Self::And(Box::new(a), Box::new(b)))
+                }
+            }
+            (Some(m), None) | (None, Some(m)) => Some(m),
+            (None, None) => None,
+        }
+    }
+
+    /// Either marker, where `None` means always true.
+    pub fn or(a: Option<Self>, b: Option<Self>) -> Option<Self> {
+        match (a, b) {
+            (Some(a), Some(b)) => {
+                if a.implies(&b) {
+                    Some(b)
+                } else if b.implies(&a) {
+                    Some(a)
+                } else {
+                    Some(Self::Or(Box::new(a), Box::new(b)))
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether this marker being true means `other` is too, from their structure alone; eg
+    /// `a and b` implies `a`. This keeps markers we combine from growing.
+    fn implies(&self, other: &Self) -> bool {
+        if self == other {
+            return true;
+        }
+        match (self, other) {
+            (Self::And(a, b), _) => a.implies(other) || b.implies(other),
+            (Self::Or(a, b), _) => a.implies(other) && b.implies(other),
+            (_, Self::Or(a, b)) => self.implies(a) || self.implies(b),
+            (_, Self::And(a, b)) => self.implies(a) && self.implies(b),
+            _ => false,
+        }
+    }
+
+    /// Does this marker reference the `extra` variable?
+    pub fn has_extra(&self) -> bool {
+        match self {
+            Self::And(a, b) | Self::Or(a, b) => a.has_extra() || b.has_extra(),
+            Self::Expr(lhs, _, rhs) => {
+                lhs == &MarkerValue::Var("extra".into()) || rhs == &MarkerValue::Var("extra".into())
+            }
+        }
+    }
+}
+
+fn eval_extra(env: &Environment, op: MarkerOp, other: &MarkerValue) -> bool {
+    let name = match other {
+        MarkerValue::Literal(l) => l,
+        MarkerValue::Var(_) => return false,
+    };
+    let present = env.extras.iter().any(|e| util::compare_names(e, name));
+    match op {
+        MarkerOp::Version(ReqType::Exact) | MarkerOp::Arbitrary => present,
+        MarkerOp::Version(ReqType::Ne) => !present,
+        _ => false,
+    }
+}
+
+/// Compare two marker values. Per PEP 508, use version comparison if both sides parse as
+/// versions, and fall back to string comparison otherwise.
+fn compare(lhs: &str, op: MarkerOp, rhs: &str, version_var: bool) -> bool {
+    match op {
+        MarkerOp::In => rhs.contains(lhs),
+        MarkerOp::NotIn => !rhs.contains(lhs),
+        MarkerOp::Arbitrary => lhs == rhs,
+        MarkerOp::Version(type_) => {
+            if version_var {
+                // The rhs may have a wildcard, eg `python_version == "2.*"`.
+                if let Ok(constr) = Constraint::from_str(&format!("{}{}", op, rhs)) {
+                    if let Ok(vers) = Version::from_str(lhs) {
+                        return constr.is_compatible(&vers);
+                    }
+                }
+            } else if let (Ok(l), Ok(r)) = (Version::from_str(lhs), Version::from_str(rhs)) {
+                return Constraint::new(type_, r).is_compatible(&l);
+            }
+
+            match type_ {
+                ReqType::Exact => lhs == rhs,
+                ReqType::Ne => lhs != rhs,
+                ReqType::Lt => lhs < rhs,
+                ReqType::Lte => lhs <= rhs,
+                ReqType::Gt => lhs > rhs,
+                ReqType::Gte => lhs >= rhs,
+                _ => false,
+            }
+        }
+    }
+}
+
+impl fmt::Display for Marker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::And(a, b) => {
+                // `and` binds tighter than `or`, so we only need parens around `or`s here.
+                let wrap = |m: &Marker| match m {
+                    Self::Or(_, _) => format!("({})", m),
+                    _ => m.to_string(),
+                };
+                write!(f, "{} and {}", wrap(a), wrap(b))
+            }
+            Self::Or(a, b) => write!(f, "{} or {}", a, b),
+            Self::Expr(lhs, op, rhs) => write!(f, "{} {} {}", lhs, op, rhs),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Str(String),
+    Ident(String),
+    Op(String),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, DependencyError> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut result = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ' ' | '\t' => i += 1,
+            '(' => {
+                result.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                result.push(Token::RParen);
+                i += 1;
+            }
+            '\'' | '"' => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|ch| *ch == c)
+                    .ok_or_else(|| {
+                        DependencyError::new(&format!("Unterminated string in marker: {}", s))
+                    })?;
+                result.push(Token::Str(chars[i + 1..i + 1 + end].iter().collect()));
+                i += end + 2;
+            }
+            '<' | '>' | '=' | '!' | '~' => {
+                let op: String = chars[i..]
+                    .iter()
+                    .take_while(|ch| "<>=!~".contains(**ch))
+                    .collect();
+                if !["<", "<=", ">", ">=", "==", "!=", "~=", "==="].contains(&op.as_ref()) {
+                    return Err(DependencyError::new(&format!(
+                        "Invalid operator in marker: {}",
+                        op
+                    )));
+                }
+                i += op.len();
+                result.push(Token::Op(op));
+            }
+            _ if c.is_ascii_alphanumeric() || c == '_' || c == '.' => {
+                let ident: String = chars[i..]
+                    .iter()
+                    .take_while(|ch| ch.is_ascii_alphanumeric() || **ch == '_' || **ch == '.')
+                    .collect();
+                i += ident.len();
+                result.push(Token::Ident(ident));
+            }
+            _ => {
+                return Err(DependencyError::new(&format!(
+                    "Unexpected character in marker: {}",
+                    s
+                )))
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// A recursive-descent parser for the marker grammar:
+/// `or_expr := and_expr ('or' and_expr)*`, `and_expr := expr ('and' expr)*`,
+/// `expr := '(' or_expr ')' | value op value`.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let result = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        result
+    }
+
+    fn parse_or(&mut self) -> Result<Marker, DependencyError> {
+        let mut result = self.parse_and()?;
+        while self.peek() == Some(&Token::Ident("or".into())) {
+            self.pos += 1;
+            result = Marker::Or(Box::new(result), Box::new(self.parse_and()?));
+        }
+        Ok(result)
+    }
+
+    fn parse_and(&mut self) -> Result<Marker, DependencyError> {
+        let mut result = self.parse_expr()?;
+        while self.peek() == Some(&Token::Ident("and".into())) {
+            self.pos += 1;
+            result = Marker::And(Box::new(result), Box::new(self.parse_expr()?));
+        }
+        Ok(result)
+    }
+
+    fn parse_expr(&mut self) -> Result<Marker, DependencyError> {
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let result = self.parse_or()?;
+            if self.next() != Some(Token::RParen) {
+                return Err(DependencyError::new("Missing closing paren in marker"));
+            }
+            return Ok(result);
+        }
+
+        let lhs = self.parse_value()?;
+        let op = match self.next() {
+            Some(Token::Op(o)) => match o.as_ref() {
+                "===" => MarkerOp::Arbitrary,
+                "~=" => MarkerOp::Version(ReqType::Compatible),
+                _ => MarkerOp::Version(ReqType::from_str(&o)?),
+            },
+            Some(Token::Ident(ref i)) if i == "in" => MarkerOp::In,
+            Some(Token::Ident(ref i)) if i == "not" => match self.next() {
+                Some(Token::Ident(ref i2)) if i2 == "in" => MarkerOp::NotIn,
+                _ => return Err(DependencyError::new("Expected `in` after `not` in marker")),
+            },
+            t => {
+                return Err(DependencyError::new(&format!(
+                    "Expected a marker operator; found {:?}",
+                    t
+                )))
+            }
+        };
+        let rhs = self.parse_value()?;
+        Ok(Marker::Expr(lhs, op, rhs))
+    }
+
+    fn parse_value(&mut self) -> Result<MarkerValue, DependencyError> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(MarkerValue::Literal(s)),
+            Some(Token::Ident(i)) => {
+                // Some old packages use the pre-PEP 508, dotted variable names.
+                let name = match i.as_ref() {
+                    "os.name" => "os_name".to_owned(),
+                    "sys.platform" => "sys_platform".to_owned(),
+                    "platform.version" => "platform_version".to_owned(),
+                    "platform.machine" => "platform_machine".to_owned(),
+                    "platform.python_implementation" => "platform_python_implementation".to_owned(),
+                    "python_implementation" => "platform_python_implementation".to_owned(),
+                    _ => i,
+                };
+                if ENV_VARS.contains(&name.as_ref()) {
+                    Ok(MarkerValue::Var(name))
+                } else {
+                    Err(DependencyError::new(&format!(
+                        "Unknown marker variable: {}",
+                        name
+                    )))
+                }
+            }
+            t => Err(DependencyError::new(&format!(
+                "Expected a marker value; found {:?}",
+                t
+            ))),
+        }
+    }
+}
+
+impl FromStr for Marker {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let result = parser.parse_or()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(DependencyError::new(&format!(
+                "Unexpected trailing input in marker: {}",
+                s
+            )));
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn linux_37() -> Environment {
+        Environment::new(Os::Linux, &Version::new_short(3, 7))
+    }
+
+    fn eval(marker: &str, env: &Environment) -> bool {
+        Marker::from_str(marker).unwrap().evaluate(env)
+    }
+
+    #[test]
+    fn parse_simple() {
+        assert_eq!(
+            Marker::from_str("python_version < '3.8'").unwrap(),
+            Marker::Expr(
+                MarkerValue::Var("python_version".into()),
+                MarkerOp::Version(ReqType::Lt),
+                MarkerValue::Literal("3.8".into())
+            )
+        );
+    }
+
+    #[test]
+    fn parse_precedence() {
+        // `and` binds tighter than `or`.
+        let m =
+            Marker::from_str("os_name == 'nt' or os_name == 'posix' and extra == 'test'").unwrap();
+        match m {
+            Marker::Or(_, b) => match *b {
+                Marker::And(_, _) => (),
+                _ => panic!("Expected an and expression"),
+            },
+            _ => panic!("Expected an or expression"),
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Marker::from_str("python_version <").is_err());
+        assert!(Marker::from_str("(python_version < '3'").is_err());
+        assert!(Marker::from_str("foo == 'bar'").is_err());
+        assert!(Marker::from_str("python_version < '3' and").is_err());
+    }
+
+    #[test]
+    fn evaluate_platform() {
+        let env = linux_37();
+        let win = Environment::new(Os::Windows, &Version::new_short(3, 7));
+        let m = r#"sys_platform == "win32" and python_version < "3.8""#;
+
+        assert!(!eval(m, &env));
+        assert!(eval(m, &win));
+        assert!(!eval(
+            m,
+            &Environment::new(Os::Windows, &Version::new_short(3, 8))
+        ));
+        assert!(eval("platform_system != 'Windows'", &env));
+        assert!(eval("os_name == 'posix' and (implementation_name == 'cpython' or implementation_name == 'pypy')", &env));
+        assert!(eval("'linux' in sys_platform", &env));
+        assert!(eval("'win' not in sys_platform", &env));
+    }
+
+    #[test]
+    fn evaluate_versions() {
+        let env = linux_37();
+        // Compare as versions, not strings: "3.10" > "3.7".
+        assert!(eval("python_version < '3.10'", &env));
+        assert!(eval("python_version == '3.*'", &env));
+        assert!(!eval("python_version == '2.*'", &env));
+        assert!(eval("python_full_version >= '3.6.1'", &env));
+        assert!(eval("python_version ~= '3.5'", &env));
+    }
+
+    #[test]
+    fn evaluate_extras() {
+        let env = linux_37();
+        let m = "extra == 'security'";
+
+        assert!(!eval(m, &env));
+        assert!(eval(m, &env.with_extras(&["security".into()])));
+        assert!(eval(
+            m,
+            &env.with_extras(&["Security".into(), "socks".into()])
+        ));
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let m =
+            "(os_name == \"nt\" or os_name == \"posix\") and python_version not in \"2.6, 2.7\"";
+        assert_eq!(Marker::from_str(m).unwrap().to_string(), m);
+    }
+
+    #[test]
+    fn combine() {
+        let m = |s: &str| Some(Marker::from_str(s).unwrap());
+        let win = m("sys_platform == 'win32'");
+        let old = m("python_version < '3.8'");
+
+        assert_eq!(Marker::and(win.clone(), None), win);
+        assert_eq!(Marker::or(win.clone(), None), None);
+        assert_eq!(Marker::or(win.clone(), win.clone()), win);
+        let both = Marker::and(win.clone(), old.clone());
+        assert_eq!(
+            both,
+            m("sys_platform == 'win32' and python_version < '3.8'")
+        );
+        // `a and b` implies `a`, so we don't need the longer marker.
+        assert_eq!(Marker::or(both.clone(), win.clone()), win);
+        assert_eq!(Marker::and(both.clone(), win), both);
+
+        let targets = Environment::targets(&[Version::new_short(3, 7), Version::new_short(3, 8)]);
+        assert_eq!(targets.len(), 6);
+        assert!(both.unwrap().evaluate_any(&targets));
+        assert!(!Marker::from_str("python_version < '3'")
+            .unwrap()
+            .evaluate_any(&targets));
+    }
+
+    #[test]
+    fn remove_extras() {
+        let m = |s: &str| Marker::from_str(s).unwrap();
+        assert_eq!(m("extra == 'socks'").without_extras(), None);
+        assert_eq!(
+            m("sys_platform == 'win32' and extra == 'socks'").without_extras(),
+            Some(m("sys_platform == 'win32'"))
+        );
+        assert_eq!(
+            m("sys_platform == 'win32' or extra == 'socks'").without_extras(),
+            None
+        );
+    }
+}
@@ -0,0 +1,558 @@
+//! Core metadata, which lists a package version's dependencies.
+//! https://packaging.python.org/specifications/core-metadata/
+//! We find it, in order of preference, in the cache, in an artifact we've already downloaded,
+//! in a `.metadata` file the index serves alongside a wheel (PEP 658), in a remote wheel's
+//! `METADATA` file, read with HTTP range requests, or by downloading a wheel or sdist.
+
+use crate::{
+    cache,
+    dep_resolution::{self, WarehouseRelease},
+    dep_types::{DependencyError, Version},
+    index::{self, PackageIndex},
+};
+
+use flate2::read::{DeflateDecoder, GzDecoder};
+use std::{
+    fs,
+    io::{Cursor, Read},
+};
+use tar::Archive;
+
+const EOCD_SIG: u64 = 0x0605_4b50;
+const CENTRAL_SIG: u64 = 0x0201_4b50;
+const LOCAL_SIG: u64 = 0x0403_4b50;
+
+/// How much of the end of a remote wheel to request first. This usually includes the whole
+/// central directory.
+const TAIL_LEN: u64 = 65_536;
+
+/// The core metadata fields we use.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub name: String,
+    pub version: String,
+    pub requires_python: Option<String>,
+    /// PEP 508 requirements, eg `requests (>=2.0); extra == "security"`
+    pub requires_dist: Vec<String>,
+    pub provides_extra: Vec<String>,
+}
+
+/// The header fields of the email-header format `METADATA` and `PKG-INFO` use, with lowercase
+/// names, in order, and the description that may follow them after a blank line.
+pub fn parse_fields(text: &str) -> (Vec<(String, String)>, String) {
+    let mut headers: Vec<(String, String)> = vec![];
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        if line.trim().is_empty() {
+            break;
+        }
+        // A continuation of the previous header's value.
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some(i) = line.find(':') {
+            headers.push((
+                line[..i].trim().to_lowercase(),
+                line[i + 1..].trim().to_owned(),
+            ));
+        }
+    }
+    let body: Vec<&str> = lines.collect();
+    (headers, body.join("\n"))
+}
+
+impl Metadata {
+    /// Parse the email-header format `METADATA` and `PKG-INFO` use. The headers may be followed
+    /// by a blank line, and the description.
+    pub fn parse(text: &str) -> Self {
+        let mut result = Self::default();
+        for (key, value) in parse_fields(text).0.into_iter() {
+            match key.as_ref() {
+                "name" => result.name = value,
+                "version" => result.version = value,
+                "requires-python" => result.requires_python = Some(value),
+                "requires-dist" => result.requires_dist.push(value),
+                "provides-extra" => result.provides_extra.push(value),
+                _ => (),
+            }
+        }
+        result
+    }
+}
+
+/// Convert setuptools' `requires.txt`, which older sdists include instead of listing
+/// `Requires-Dist` in `PKG-INFO`, to `Requires-Dist` values. Sections are an extra, a marker,
+/// or both, eg `[socks]`, `[:python_version < "3"]`, or `[socks:sys_platform == "win32"]`.
+fn parse_requires_txt(text: &str) -> Vec<String> {
+    let mut result = vec![];
+    let mut marker: Option<String> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            let mut parts = line[1..line.len() - 1].splitn(2, ':');
+            let extra = parts.next().unwrap_or("").trim();
+            let env = parts.next().map(str::trim).filter(|m| !m.is_empty());
+            marker = match (extra.is_empty(), env) {
+                (true, None) => None,
+                (true, Some(m)) => Some(m.to_owned()),
+                (false, None) => Some(format!("extra == \"{}\"", extra)),
+                (false, Some(m)) => Some(format!("({}) and extra == \"{}\"", m, extra)),
+            };
+            continue;
+        }
+        result.push(match &marker {
+            Some(m) => format!("{}; {}", line, m),
+            None => line.to_owned(),
+        });
+    }
+    result
+}
+
+/// Is this a wheel's metadata file, ie `{name}-{version}.dist-info/METADATA`?
+fn is_wheel_metadata(path: &str) -> bool {
+    let parts: Vec<&str> = path.split('/').collect();
+    parts.len() == 2 && parts[0].ends_with(".dist-info") && parts[1] == "METADATA"
+}
+
+fn le_u16(data: &[u8], i: usize) -> Option<u64> {
+    data.get(i..i + 2)
+        .map(|b| u64::from(b[0]) | u64::from(b[1]) << 8)
+}
+
+fn le_u32(data: &[u8], i: usize) -> Option<u64> {
+    data.get(i..i + 4)
+        .map(|b| b.iter().rev().fold(0, |acc, x| acc << 8 | u64::from(*x)))
+}
+
+/// Read a file from a zip archive we can only access in pieces, eg remotely. `tail` is the end
+/// of the archive, which must include the end of central directory record. `read` fetches the
+/// bytes from a start position, up to an end position. Returns `None` if the file isn't found,
+/// or if the archive uses features we don't handle here, like Zip64.
+/// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+fn read_zip_file<F>(
+    tail: &[u8],
+    len: u64,
+    path_matches: fn(&str) -> bool,
+    mut read: F,
+) -> Result<Option<Vec<u8>>, DependencyError>
+where
+    F: FnMut(u64, u64) -> Result<Vec<u8>, DependencyError>,
+{
+    let tail_start = len.saturating_sub(tail.len() as u64);
+    // The end of central directory record is 22 bytes, plus a comment.
+    let eocd = match (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|i| le_u32(tail, *i) == Some(EOCD_SIG))
+    {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    let (cd_len, cd_offset) = match (le_u32(tail, eocd + 12), le_u32(tail, eocd + 16)) {
+        (Some(l), Some(o)) => (l, o),
+        _ => return Ok(None),
+    };
+    // Zip64 archives mark these fields as 0xFFFFFFFF.
+    if cd_offset == 0xFFFF_FFFF || cd_offset + cd_len > len {
+        return Ok(None);
+    }
+
+    let cd = if cd_offset >= tail_start {
+        let start = (cd_offset - tail_start) as usize;
+        tail[start..tail.len().min(start + cd_len as usize)].to_vec()
+    } else {
+        read(cd_offset, cd_offset + cd_len)?
+    };
+
+    let mut i = 0;
+    while le_u32(&cd, i) == Some(CENTRAL_SIG) {
+        let fields = (
+            le_u16(&cd, i + 10),
+            le_u32(&cd, i + 20),
+            le_u16(&cd, i + 28),
+            le_u16(&cd, i + 30),
+            le_u16(&cd, i + 32),
+            le_u32(&cd, i + 42),
+        );
+        let (method, compressed_len, name_len, extra_len, comment_len, offset) = match fields {
+            (Some(m), Some(c), Some(n), Some(e), Some(cm), Some(o)) => (m, c, n, e, cm, o),
+            _ => return Ok(None),
+        };
+        let name_start = i + 46;
+        let name = match cd.get(name_start..name_start + name_len as usize) {
+            Some(n) => String::from_utf8_lossy(n).into_owned(),
+            None => return Ok(None),
+        };
+
+        if path_matches(&name) {
+            if compressed_len == 0xFFFF_FFFF || offset == 0xFFFF_FFFF {
+                return Ok(None);
+            }
+            // The local header's extra field may be longer than the central directory's;
+            // leave room, so we usually only need one request.
+            let end = len.min(offset + 30 + name_len + extra_len + compressed_len + 1024);
+            let local = read(offset, end)?;
+            if le_u32(&local, 0) != Some(LOCAL_SIG) {
+                return Ok(None);
+            }
+            let data_start = match (le_u16(&local, 26), le_u16(&local, 28)) {
+                (Some(n), Some(e)) => 30 + n + e,
+                _ => return Ok(None),
+            };
+            let data = match local.get(data_start as usize..(data_start + compressed_len) as usize)
+            {
+                Some(d) => d.to_vec(),
+                None => read(offset + data_start, offset + data_start + compressed_len)?,
+            };
+
+            return match method {
+                0 => Ok(Some(data)),
+                8 => {
+                    let mut result = vec![];
+                    match DeflateDecoder::new(&data[..]).read_to_end(&mut result) {
+                        Ok(_) => Ok(Some(result)),
+                        Err(_) => Ok(None),
+                    }
+                }
+                _ => Ok(None),
+            };
+        }
+        i = name_start + (name_len + extra_len + comment_len) as usize;
+    }
+    Ok(None)
+}
+
+/// Read a remote wheel's metadata without downloading the whole wheel, using range requests.
+/// Returns `None` if the server doesn't support them.
+fn metadata_from_remote_wheel(url: &str) -> Result<Option<String>, DependencyError> {
+    let (tail, len) = match index::fetch_range(url, &format!("bytes=-{}", TAIL_LEN))? {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+
+    let read = |start: u64, end: u64| match index::fetch_range(
+        url,
+        &format!("bytes={}-{}", start, end - 1),
+    )? {
+        Some((data, _)) => Ok(data),
+        None => Err(DependencyError::new(&format!(
+            "Problem reading part of {}",
+            url
+        ))),
+    };
+
+    Ok(read_zip_file(&tail, len, is_wheel_metadata, read)?
+        .map(|d| String::from_utf8_lossy(&d).into_owned()))
+}
+
+/// Read the metadata from a downloaded wheel.
+pub fn metadata_from_wheel(data: &[u8]) -> Option<String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).ok()?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).ok()?;
+        if is_wheel_metadata(file.name()) {
+            let mut text = String::new();
+            file.read_to_string(&mut text).ok()?;
+            return Some(text);
+        }
+    }
+    None
+}
+
+/// Read the metadata from a downloaded sdist: Its `PKG-INFO`, plus its `requires.txt` if
+/// `PKG-INFO` doesn't list dependencies, which is common for sdists built by setuptools.
+pub fn metadata_from_sdist(data: &[u8], filename: &str) -> Option<String> {
+    let wanted = |p: &str| p.ends_with("PKG-INFO") || p.ends_with(".egg-info/requires.txt");
+    let mut files: Vec<(String, String)> = vec![];
+
+    if filename.ends_with(".zip") {
+        let mut archive = zip::ZipArchive::new(Cursor::new(data)).ok()?;
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).ok()?;
+            let path = file.name().to_owned();
+            let mut text = String::new();
+            if wanted(&path) && file.read_to_string(&mut text).is_ok() {
+                files.push((path, text));
+            }
+        }
+    } else {
+        let mut archive = Archive::new(GzDecoder::new(data));
+        for entry in archive.entries().ok()? {
+            let mut entry = entry.ok()?;
+            let path = entry.path().ok()?.to_string_lossy().into_owned();
+            let mut text = String::new();
+            if wanted(&path) && entry.read_to_string(&mut text).is_ok() {
+                files.push((path, text));
+            }
+        }
+    }
+
+    // Prefer the top-level files, eg `saturn-0.3.1/PKG-INFO` over
+    // `saturn-0.3.1/saturn.egg-info/PKG-INFO`.
+    files.sort_by_key(|(p, _)| p.matches('/').count());
+    let mut text = files
+        .iter()
+        .find(|(p, _)| p.ends_with("PKG-INFO"))
+        .map(|(_, t)| t.clone())?;
+
+    if Metadata::parse(&text).requires_dist.is_empty() {
+        if let Some((_, requires)) = files.iter().find(|(p, _)| p.ends_with("requires.txt")) {
+            let headers: String = parse_requires_txt(requires)
+                .iter()
+                .map(|r| format!("Requires-Dist: {}\n", r))
+                .collect();
+            text = headers + &text;
+        }
+    }
+    Some(text)
+}
+
+fn metadata_from_artifact(release: &WarehouseRelease, data: &[u8]) -> Option<String> {
+    match release.packagetype.as_ref() {
+        "bdist_wheel" => metadata_from_wheel(data),
+        "sdist" => metadata_from_sdist(data, &release.filename),
+        _ => None,
+    }
+}
+
+/// Find the metadata from the files available for a version, trying the cheapest sources first.
+fn find_metadata(
+    index: &dyn PackageIndex,
+    releases: &[WarehouseRelease],
+) -> Result<Option<String>, DependencyError> {
+    // We may have already downloaded one of the files.
+    for release in releases.iter() {
+        if let Ok(data) = fs::read(cache::artifact_path(&release.filename)) {
+            if let Some(text) = metadata_from_artifact(release, &data) {
+                return Ok(Some(text));
+            }
+        }
+    }
+    if cache::is_offline() {
+        return Ok(None);
+    }
+
+    for release in releases.iter().filter(|r| r.core_metadata) {
+        if let Ok(data) = index::fetch(&format!("{}.metadata", release.url)) {
+            if core_metadata_matches(release, &data) {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    // If range requests don't work for one wheel, they probably won't for the others.
+    if let Some(wheel) = releases
+        .iter()
+        .find(|r| r.packagetype == "bdist_wheel" && r.url.starts_with("http"))
+    {
+        if let Ok(Some(text)) = metadata_from_remote_wheel(&wheel.url) {
+            return Ok(Some(text));
+        }
+    }
+
+    // Download the file; we'll probably install it, so keep it in the cache. If a download
+    // fails, try the other files, and only report an error if they all fail.
+    let mut error = None;
+    let mut downloaded = false;
+    for release in releases.iter() {
+        let data = match index.download(release) {
+            Ok(d) => d,
+            Err(e) => {
+                error = Some(e);
+                continue;
+            }
+        };
+        cache::store_artifact(&release.filename, &data)?;
+        if let Some(text) = metadata_from_artifact(release, &data) {
+            return Ok(Some(text));
+        }
+        downloaded = true;
+    }
+    match error {
+        Some(e) if !downloaded => Err(e),
+        _ => Ok(None),
+    }
+}
+
+/// Check a metadata file an index serves against the hash it lists for it, if any.
+fn core_metadata_matches(release: &WarehouseRelease, data: &[u8]) -> bool {
+    match &release.core_metadata_sha256 {
+        Some(expected) => {
+            let digest = ring::digest::digest(&ring::digest::SHA256, data);
+            data_encoding::HEXLOWER.encode(digest.as_ref()) == expected.to_lowercase()
+        }
+        None => true,
+    }
+}
+
+/// Find a package version's metadata, and cache it.
+pub fn get_metadata(
+    index: &dyn PackageIndex,
+    name: &str,
+    version: &Version,
+) -> Result<Metadata, DependencyError> {
+    if let Some(text) = cache::read_metadata(name, version) {
+        return Ok(Metadata::parse(&text));
+    }
+
+    let mut releases = dep_resolution::get_warehouse_release(index, name, version)?;
+    // Wheel metadata is reliable, and quicker to get than an sdist's. Prefer wheels whose
+    // metadata the index serves separately.
+    releases.sort_by_key(|r| (r.packagetype != "bdist_wheel", !r.core_metadata));
+
+    match find_metadata(index, &releases)? {
+        Some(text) => {
+            cache::write_metadata(name, version, &text);
+            Ok(Metadata::parse(&text))
+        }
+        None if cache::is_offline() => Err(cache::offline_error(&format!(
+            "Dependency data for {} {}",
+            name,
+            version.to_string2()
+        ))),
+        None => Err(DependencyError::new(&format!(
+            "Unable to find metadata for {} {}",
+            name,
+            version.to_string2()
+        ))),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const METADATA: &str = "Metadata-Version: 2.1
+Name: requests
+Version: 2.22.0
+Summary: Python HTTP for Humans.
+Requires-Python: >=2.7, !=3.0.*
+Requires-Dist: chardet (<3.1.0,>=3.0.2)
+Requires-Dist: idna (<2.9,>=2.5)
+Provides-Extra: security
+Requires-Dist: pyOpenSSL (>=0.14) ; extra == 'security'
+
+Requests
+========
+Requires-Dist: not-a-header
+";
+
+    fn make_zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (name, contents) in files.iter() {
+            writer
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn parse_metadata() {
+        let metadata = Metadata::parse(METADATA);
+        assert_eq!(metadata.name, "requests");
+        assert_eq!(metadata.version, "2.22.0");
+        assert_eq!(metadata.requires_python, Some(">=2.7, !=3.0.*".to_owned()));
+        assert_eq!(
+            metadata.requires_dist,
+            vec![
+                "chardet (<3.1.0,>=3.0.2)",
+                "idna (<2.9,>=2.5)",
+                "pyOpenSSL (>=0.14) ; extra == 'security'"
+            ]
+        );
+        assert_eq!(metadata.provides_extra, vec!["security"]);
+    }
+
+    #[test]
+    fn requires_txt() {
+        let text = "six>=1.0\n\n[socks]\nPySocks>=1.5.6\n\n[:python_version < \"3\"]\n\
+                    futures\n\n[win:sys_platform == \"win32\"]\nwin_inet_pton\n";
+        assert_eq!(
+            parse_requires_txt(text),
+            vec![
+                "six>=1.0",
+                "PySocks>=1.5.6; extra == \"socks\"",
+                "futures; python_version < \"3\"",
+                "win_inet_pton; (sys_platform == \"win32\") and extra == \"win\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn wheel_metadata() {
+        let wheel = make_zip(&[
+            ("requests/__init__.py", "print('hi')\n"),
+            ("requests-2.22.0.dist-info/WHEEL", "Wheel-Version: 1.0\n"),
+            ("requests-2.22.0.dist-info/METADATA", METADATA),
+            ("requests-2.22.0.dist-info/RECORD", ""),
+        ]);
+        assert_eq!(metadata_from_wheel(&wheel), Some(METADATA.to_owned()));
+
+        // Reading in pieces, as with range requests, gives the same result, whether or not the
+        // tail includes the central directory.
+        for tail_len in [wheel.len(), 100, 30].iter() {
+            let tail = &wheel[wheel.len() - tail_len..];
+            let mut reads = 0;
+            let read = |start: u64, end: u64| {
+                reads += 1;
+                Ok(wheel[start as usize..end as usize].to_vec())
+            };
+            let result = read_zip_file(tail, wheel.len() as u64, is_wheel_metadata, read).unwrap();
+            assert_eq!(result, Some(METADATA.as_bytes().to_vec()));
+            assert!(reads <= 2);
+        }
+
+        let no_metadata = make_zip(&[("requests/__init__.py", "")]);
+        let read = |start: u64, end: u64| Ok(no_metadata[start as usize..end as usize].to_vec());
+        assert_eq!(
+            read_zip_file(
+                &no_metadata,
+                no_metadata.len() as u64,
+                is_wheel_metadata,
+                read
+            )
+            .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn sdist_metadata() {
+        let pkg_info = "Metadata-Version: 1.1\nName: saturn\nVersion: 0.3.1\n";
+        let sdist = make_zip(&[
+            ("saturn-0.3.1/PKG-INFO", pkg_info),
+            ("saturn-0.3.1/saturn.egg-info/PKG-INFO", "Name: wrong\n"),
+            (
+                "saturn-0.3.1/saturn.egg-info/requires.txt",
+                "pytz\n[dev]\npytest\n",
+            ),
+        ]);
+        let text = metadata_from_sdist(&sdist, "saturn-0.3.1.zip").unwrap();
+        let metadata = Metadata::parse(&text);
+        assert_eq!(metadata.name, "saturn");
+        assert_eq!(
+            metadata.requires_dist,
+            vec!["pytz", "pytest; extra == \"dev\""]
+        );
+    }
+
+    #[test]
+    fn core_metadata_hash() {
+        let mut release = dep_resolution::tests::release("py3", None, false);
+        assert!(core_metadata_matches(&release, METADATA.as_bytes()));
+
+        let digest = ring::digest::digest(&ring::digest::SHA256, METADATA.as_bytes());
+        release.core_metadata_sha256 = Some(data_encoding::HEXUPPER.encode(digest.as_ref()));
+        assert!(core_metadata_matches(&release, METADATA.as_bytes()));
+        assert!(!core_metadata_matches(&release, b"Name: other\n"));
+    }
+}
@@ -0,0 +1,322 @@
+//! Find locked packages with newer versions on the index, and what's keeping them back.
+
+use crate::{
+    dep_resolution,
+    dep_types::{Constraint, LockPackage, Req, Version},
+    index::PackageIndex,
+    util,
+};
+
+use crossterm::Color;
+use serde::Serialize;
+use std::{collections::HashMap, str::FromStr};
+
+/// Used in `blocked_by` when our own requirements keep a package back.
+pub const PROJECT: &str = "pyproject.toml";
+
+/// A locked package that isn't the latest version.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Outdated {
+    pub name: String,
+    pub current: String,
+    /// The newest version all requirements on this package allow.
+    pub compatible: String,
+    pub latest: String,
+    /// Whether `pyproject.toml` requires it directly.
+    #[serde(rename = "top-level")]
+    pub top_level: bool,
+    /// What stops us using the latest version: `pyproject.toml`, or the packages that
+    /// require older versions.
+    #[serde(rename = "blocked-by")]
+    pub blocked_by: Vec<String>,
+}
+
+/// Compare a package's locked version with those available. `constraints` are the requirements
+/// on it, by what requires it: `pyproject.toml`, or a package name.
+fn classify(
+    name: &str,
+    current: &Version,
+    candidates: &[Version],
+    latest: &Version,
+    constraints: &[(String, Vec<Constraint>)],
+    top_level: bool,
+) -> Option<Outdated> {
+    if latest <= current {
+        return None;
+    }
+    let allowed = |version: &Version| {
+        constraints
+            .iter()
+            .all(|(_, constrs)| constrs.iter().all(|c| c.is_compatible(version)))
+    };
+    let compatible = candidates
+        .iter()
+        .filter(|v| !v.is_prerelease() || current.is_prerelease())
+        .filter(|v| allowed(v))
+        .max()
+        .unwrap_or(current)
+        .max(current);
+
+    let mut blocked_by: Vec<String> = constraints
+        .iter()
+        .filter(|(_, constrs)| !constrs.iter().all(|c| c.is_compatible(latest)))
+        .map(|(source, _)| source.clone())
+        .collect();
+    blocked_by.sort();
+    blocked_by.dedup();
+
+    Some(Outdated {
+        name: name.to_owned(),
+        current: current.to_string2(),
+        compatible: compatible.to_string2(),
+        latest: latest.to_string2(),
+        top_level,
+        blocked_by,
+    })
+}
+
+/// Check each locked package against the index. `reqs` are the project's requirements,
+/// including groups and extras.
+pub fn find(
+    index: &dyn PackageIndex,
+    reqs: &[Req],
+    lock_packs: &[LockPackage],
+    py_vers: &Version,
+) -> Vec<Outdated> {
+    // The packages that require each one, by standardized name.
+    let mut parents: HashMap<String, Vec<&LockPackage>> = HashMap::new();
+    for lp in lock_packs.iter() {
+        for dep in lp.dependencies.as_ref().unwrap_or(&vec![]) {
+            let (name, _, _) = crate::parse_lock_dep(dep);
+            parents
+                .entry(util::standardize_name(&name))
+                .or_default()
+                .push(lp);
+        }
+    }
+
+    // Cache each parent's requirements, since they may have many dependencies.
+    let mut parent_reqs: HashMap<(String, String), Vec<Req>> = HashMap::new();
+    let mut result = vec![];
+    for lp in lock_packs.iter() {
+        let std_name = util::standardize_name(&lp.name);
+        let current = match Version::from_str(&lp.version) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+
+        let mut constraints = vec![];
+        let direct: Vec<&Req> = reqs
+            .iter()
+            .filter(|r| util::compare_names(&r.name, &lp.name))
+            .collect();
+        for req in direct.iter() {
+            constraints.push((PROJECT.to_owned(), req.constraints.clone()));
+        }
+        for parent in parents.get(&std_name).unwrap_or(&vec![]).iter() {
+            let key = (parent.name.clone(), parent.version.clone());
+            if !parent_reqs.contains_key(&key) {
+                let version = Version::from_str(&parent.version).unwrap_or_default();
+                let parent_deps =
+                    dep_resolution::get_requirements_or_warn(index, &parent.name, &version);
+                parent_reqs.insert(key.clone(), parent_deps);
+            }
+            for req in parent_reqs[&key].iter() {
+                if util::compare_names(&req.name, &lp.name) {
+                    constraints.push((parent.name.clone(), req.constraints.clone()));
+                }
+            }
+        }
+
+        let latest = match dep_resolution::get_version_info(index, &lp.name) {
+            Ok((_, latest, _)) => latest,
+            Err(e) => {
+                util::eprint_color(
+                    &format!("Problem getting versions of {}: {}", lp.name, e.details),
+                    Color::DarkYellow,
+                );
+                continue;
+            }
+        };
+        let candidates =
+            match dep_resolution::get_candidates(index, &lp.name, std::slice::from_ref(py_vers)) {
+                Ok((_, versions, yanked)) => versions
+                    .into_iter()
+                    .filter(|v| !yanked.contains(v))
+                    .collect(),
+                Err(_) => vec![],
+            };
+
+        if let Some(o) = classify(
+            &lp.name,
+            &current,
+            &candidates,
+            &latest,
+            &constraints,
+            !direct.is_empty(),
+        ) {
+            result.push(o);
+        }
+    }
+    result.sort_by_key(|o| util::standardize_name(&o.name));
+    result
+}
+
+fn print_table(title: &str, packages: &[&Outdated], show_blockers: bool) {
+    if packages.is_empty() {
+        return;
+    }
+    util::print_color(title, Color::Cyan);
+    let mut rows = vec![vec![
+        "Package".to_owned(),
+        "Current".to_owned(),
+        "Compatible".to_owned(),
+        "Latest".to_owned(),
+    ]];
+    if show_blockers {
+        rows[0].push("Blocked by".to_owned());
+    }
+    for o in packages.iter() {
+        let mut row = vec![
+            o.name.clone(),
+            o.current.clone(),
+            o.compatible.clone(),
+            o.latest.clone(),
+        ];
+        if show_blockers {
+            row.push(o.blocked_by.join(", "));
+        }
+        rows.push(row);
+    }
+
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|i| rows.iter().map(|r| r[i].len()).max().unwrap_or(0))
+        .collect();
+    for row in rows.iter() {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+    println!();
+}
+
+/// Show outdated packages, grouped by whether `pypackage update` can upgrade them to the
+/// latest version, or if our requirements or another package's keep them back.
+pub fn print(outdated: &[Outdated]) {
+    if outdated.is_empty() {
+        util::print_color("All packages are up to date", Color::Green);
+        return;
+    }
+    let by_project: Vec<&Outdated> = outdated
+        .iter()
+        .filter(|o| o.blocked_by.iter().any(|b| b == PROJECT))
+        .collect();
+    let by_packages: Vec<&Outdated> = outdated
+        .iter()
+        .filter(|o| !o.blocked_by.is_empty() && !o.blocked_by.iter().any(|b| b == PROJECT))
+        .collect();
+    let free: Vec<&Outdated> = outdated
+        .iter()
+        .filter(|o| o.blocked_by.is_empty())
+        .collect();
+
+    print_table("Can be updated:", &free, false);
+    print_table("Blocked by `pyproject.toml`:", &by_project, true);
+    print_table("Blocked by other packages:", &by_packages, true);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::dep_types::ReqType;
+
+    #[test]
+    fn outdated_classified() {
+        let v = |s| Version::from_str(s).unwrap();
+        let candidates = vec![v("1.24.3"), v("1.25.7"), v("1.26.0"), v("2.0.0a1")];
+        let below = |major, minor| Constraint::new(ReqType::Lt, Version::new(major, minor, 0));
+
+        // Nothing holds it back.
+        assert_eq!(
+            classify(
+                "urllib3",
+                &v("1.24.3"),
+                &candidates,
+                &v("1.26.0"),
+                &[],
+                false
+            ),
+            Some(Outdated {
+                name: "urllib3".into(),
+                current: "1.24.3".into(),
+                compatible: "1.26.0".into(),
+                latest: "1.26.0".into(),
+                top_level: false,
+                blocked_by: vec![],
+            })
+        );
+
+        // Another package requires an older version.
+        let constraints = vec![
+            ("requests".to_owned(), vec![below(1, 25)]),
+            ("botocore".to_owned(), vec![below(2, 0)]),
+        ];
+        let o = classify(
+            "urllib3",
+            &v("1.24.3"),
+            &candidates,
+            &v("1.26.0"),
+            &constraints,
+            false,
+        )
+        .unwrap();
+        assert_eq!(o.compatible, "1.24.3");
+        assert_eq!(o.blocked_by, vec!["requests".to_owned()]);
+
+        // Our own requirements do.
+        let constraints = vec![(PROJECT.to_owned(), vec![below(1, 26)])];
+        let o = classify(
+            "urllib3",
+            &v("1.24.3"),
+            &candidates,
+            &v("1.26.0"),
+            &constraints,
+            true,
+        )
+        .unwrap();
+        assert_eq!(o.compatible, "1.25.7");
+        assert_eq!(o.blocked_by, vec![PROJECT.to_owned()]);
+
+        // Up to date.
+        assert_eq!(
+            classify(
+                "urllib3",
+                &v("1.26.0"),
+                &candidates,
+                &v("1.26.0"),
+                &[],
+                false
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn outdated_json() {
+        let o = Outdated {
+            name: "idna".into(),
+            current: "2.8".into(),
+            compatible: "2.8".into(),
+            latest: "2.9".into(),
+            top_level: false,
+            blocked_by: vec!["requests".into()],
+        };
+        assert_eq!(
+            serde_json::to_string(&o).unwrap(),
+            r#"{"name":"idna","current":"2.8","compatible":"2.8","latest":"2.9","top-level":false,"blocked-by":["requests"]}"#
+        );
+    }
+}
@@ -0,0 +1,348 @@
+//! A PEP 517 build frontend, for building wheels from source distributions.
+//! https://www.python.org/dev/peps/pep-0517/
+//! https://www.python.org/dev/peps/pep-0518/
+
+use crate::{cache, dep_types::DependencyError, install, util};
+
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use std::{fs, path::PathBuf, process::Command};
+use tar::Archive;
+
+/// Calls a hook on the build backend, in the build environment. Arguments are passed, and the
+/// result returned, as JSON files, so the backend's own output doesn't interfere.
+/// Must run on any Python 3 we support, so no f-strings.
+const HOOK_RUNNER: &str = r#"import importlib
+import json
+import os
+import sys
+
+
+def main(input_path, output_path):
+    with open(input_path) as f:
+        data = json.load(f)
+
+    for path in reversed(data["backend_path"]):
+        sys.path.insert(0, os.path.abspath(path))
+
+    module_name, _, obj_path = data["backend"].partition(":")
+    backend = importlib.import_module(module_name)
+    for attr in filter(None, obj_path.split(".")):
+        backend = getattr(backend, attr)
+
+    hook = getattr(backend, data["hook"], None)
+    # `get_requires_for_build_wheel` is optional; PEP 517 defines its default.
+    result = [] if hook is None else hook(**data["kwargs"])
+
+    with open(output_path, "w") as f:
+        json.dump({"result": result}, f)
+
+
+if __name__ == "__main__":
+    main(sys.argv[1], sys.argv[2])
+"#;
+
+/// The `[build-system]` table from a source distribution's `pyproject.toml`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct BuildSystem {
+    pub requires: Vec<String>,
+    #[serde(rename = "build-backend")]
+    pub build_backend: Option<String>,
+    #[serde(rename = "backend-path")]
+    pub backend_path: Option<Vec<String>>,
+}
+
+impl Default for BuildSystem {
+    /// Used for projects without a `pyproject.toml`, or without a `build-backend`: Run
+    /// `setup.py` with setuptools, which also handles ones that import `distutils`.
+    fn default() -> Self {
+        Self {
+            requires: vec!["setuptools>=40.8.0".into(), "wheel".into()],
+            build_backend: Some("setuptools.build_meta:__legacy__".into()),
+            backend_path: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SdistPyproject {
+    #[serde(rename = "build-system")]
+    build_system: Option<BuildSystem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HookOutput {
+    result: serde_json::Value,
+}
+
+impl BuildSystem {
+    /// Read the build system from a source tree's `pyproject.toml`.
+    pub fn from_source(src_path: &PathBuf) -> Result<Self, DependencyError> {
+        let text = match fs::read_to_string(src_path.join("pyproject.toml")) {
+            Ok(t) => t,
+            Err(_) => return Ok(Self::default()),
+        };
+        let pyproject: SdistPyproject = toml::from_str(&text)
+            .map_err(|e| DependencyError::new(&format!("Problem parsing pyproject.toml: {}", e)))?;
+
+        Ok(match pyproject.build_system {
+            Some(bs) => {
+                if bs.build_backend.is_some() {
+                    bs
+                } else {
+                    Self {
+                        requires: bs.requires,
+                        ..Self::default()
+                    }
+                }
+            }
+            None => Self::default(),
+        })
+    }
+}
+
+/// Run a command, adding its output to the build log. If it fails, return the log as an error.
+fn run(cmd: &mut Command, what: &str, log: &mut String) -> Result<(), DependencyError> {
+    let output = cmd
+        .output()
+        .map_err(|e| DependencyError::new(&format!("Problem running {}: {}", what, e)))?;
+    log.push_str(&String::from_utf8_lossy(&output.stdout));
+    log.push_str(&String::from_utf8_lossy(&output.stderr));
+
+    if !output.status.success() {
+        return Err(DependencyError::new(&format!(
+            "Problem {}. Build log:\n{}",
+            what, log
+        )));
+    }
+    Ok(())
+}
+
+/// Install packages into the build environment with `pip`. In offline mode, only use packages
+/// from the cache.
+fn pip_install(python: &PathBuf, reqs: &[String], log: &mut String) -> Result<(), DependencyError> {
+    if reqs.is_empty() {
+        return Ok(());
+    }
+    let mut cmd = Command::new(python);
+    cmd.args(&["-m", "pip", "install", "--disable-pip-version-check"]);
+    if cache::is_offline() {
+        cmd.arg("--no-index")
+            .arg("--find-links")
+            .arg(cache::cache_dir().join("artifacts"));
+    }
+    cmd.args(reqs);
+    run(
+        &mut cmd,
+        &format!("installing build requirements: {}", reqs.join(", ")),
+        log,
+    )
+}
+
+/// Call a build backend hook, and return its result.
+fn call_hook(
+    python: &PathBuf,
+    build_dir: &PathBuf,
+    src_path: &PathBuf,
+    build_system: &BuildSystem,
+    hook: &str,
+    kwargs: serde_json::Value,
+    log: &mut String,
+) -> Result<serde_json::Value, DependencyError> {
+    let input_path = build_dir.join("hook_input.json");
+    let output_path = build_dir.join("hook_output.json");
+    let input = serde_json::json!({
+        "backend": build_system.build_backend,
+        "backend_path": build_system.backend_path.clone().unwrap_or_default(),
+        "hook": hook,
+        "kwargs": kwargs,
+    });
+    fs::write(&input_path, input.to_string())
+        .map_err(|_| DependencyError::new("Problem writing build hook input"))?;
+    fs::remove_file(&output_path).ok();
+
+    run(
+        Command::new(python)
+            .arg(build_dir.join("hook_runner.py"))
+            .arg(&input_path)
+            .arg(&output_path)
+            .current_dir(src_path),
+        &format!("running the build backend's {} hook", hook),
+        log,
+    )?;
+
+    let output = fs::read_to_string(&output_path)
+        .map_err(|_| DependencyError::new(&format!("The {} hook returned nothing", hook)))?;
+    let output: HookOutput = serde_json::from_str(&output)
+        .map_err(|_| DependencyError::new(&format!("Problem reading the {} hook result", hook)))?;
+    Ok(output.result)
+}
+
+/// Extract a source distribution, and return the path of its top-level folder.
+fn extract_sdist(sdist_path: &PathBuf, dest: &PathBuf) -> Result<PathBuf, DependencyError> {
+    let filename = sdist_path
+        .file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let file = fs::File::open(sdist_path)
+        .map_err(|_| DependencyError::new(&format!("Problem opening {}", filename)))?;
+
+    if filename.ends_with(".zip") {
+        install::extract_zip(&file, dest);
+    } else if filename.ends_with(".tar.gz") || filename.ends_with(".tgz") {
+        Archive::new(GzDecoder::new(file))
+            .unpack(dest)
+            .map_err(|_| DependencyError::new(&format!("Problem extracting {}", filename)))?;
+    } else {
+        return Err(DependencyError::new(&format!(
+            "Unsupported source distribution format: {}",
+            filename
+        )));
+    }
+
+    // Source distributions contain a single folder, eg `name-version`.
+    let mut dirs = vec![];
+    for entry in fs::read_dir(dest).map_err(|_| DependencyError::new("Problem reading sdist"))? {
+        if let Ok(entry) = entry {
+            if entry.path().is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+    }
+    if dirs.len() == 1 {
+        Ok(dirs.remove(0))
+    } else {
+        Ok(dest.clone())
+    }
+}
+
+/// Build a wheel from a source distribution, in an isolated environment created with the
+/// Python at `bin_path`. Everything happens in `build_dir`, which the caller removes afterwards.
+/// Returns the path of the built wheel.
+pub fn build_wheel(
+    sdist_path: &PathBuf,
+    build_dir: &PathBuf,
+    bin_path: &PathBuf,
+) -> Result<PathBuf, DependencyError> {
+    let (src_dir, wheel_dir) = (build_dir.join("src"), build_dir.join("dist"));
+    fs::remove_dir_all(build_dir).ok();
+    for dir in &[&src_dir, &wheel_dir] {
+        fs::create_dir_all(dir)
+            .map_err(|_| DependencyError::new("Problem creating the build directory"))?;
+    }
+    fs::write(build_dir.join("hook_runner.py"), HOOK_RUNNER)
+        .map_err(|_| DependencyError::new("Problem writing the build hook runner"))?;
+
+    let src_path = extract_sdist(sdist_path, &src_dir)?;
+    let build_system = BuildSystem::from_source(&src_path)?;
+    let mut log = String::new();
+
+    // Build requirements go in their own environment, so they don't conflict with the
+    // project's dependencies.
+    run(
+        Command::new(bin_path.join("python"))
+            .args(&["-m", "venv", ".venv"])
+            .current_dir(build_dir),
+        "creating the build environment",
+        &mut log,
+    )?;
+    let python = util::find_bin_path(build_dir).join("python");
+    pip_install(&python, &build_system.requires, &mut log)?;
+
+    let hook_args = |b: &BuildSystem, hook: &str, kwargs, log: &mut String| {
+        call_hook(&python, build_dir, &src_path, b, hook, kwargs, log)
+    };
+
+    let extra_reqs = hook_args(
+        &build_system,
+        "get_requires_for_build_wheel",
+        serde_json::json!({ "config_settings": {} }),
+        &mut log,
+    )?;
+    let extra_reqs: Vec<String> = serde_json::from_value(extra_reqs).map_err(|_| {
+        DependencyError::new("get_requires_for_build_wheel didn't return a list of strings")
+    })?;
+    pip_install(&python, &extra_reqs, &mut log)?;
+
+    println!(
+        "🛠️ Building a wheel from {:?}...",
+        sdist_path.file_name().unwrap_or_default()
+    );
+    let wheel_name = hook_args(
+        &build_system,
+        "build_wheel",
+        serde_json::json!({
+            "wheel_directory": wheel_dir.to_string_lossy(),
+            "config_settings": {},
+        }),
+        &mut log,
+    )?;
+    match wheel_name.as_str() {
+        Some(name) if wheel_dir.join(name).exists() => Ok(wheel_dir.join(name)),
+        _ => Err(DependencyError::new(&format!(
+            "The build backend didn't produce a wheel. Build log:\n{}",
+            log
+        ))),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn build_system() {
+        let dir = env::temp_dir().join("pypackage_pep517_test");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            BuildSystem::from_source(&dir).unwrap(),
+            BuildSystem::default()
+        );
+
+        fs::write(
+            dir.join("pyproject.toml"),
+            r#"[build-system]
+requires = ["flit_core >=2,<4"]
+build-backend = "flit_core.buildapi"
+backend-path = ["."]
+
+[tool.black]
+line-length = 100
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            BuildSystem::from_source(&dir).unwrap(),
+            BuildSystem {
+                requires: vec!["flit_core >=2,<4".into()],
+                build_backend: Some("flit_core.buildapi".into()),
+                backend_path: Some(vec![".".into()]),
+            }
+        );
+
+        // Requirements without a backend use setuptools' legacy backend.
+        fs::write(
+            dir.join("pyproject.toml"),
+            "[build-system]\nrequires = [\"setuptools\", \"wheel\", \"cython\"]\n",
+        )
+        .unwrap();
+        let bs = BuildSystem::from_source(&dir).unwrap();
+        assert_eq!(bs.requires.len(), 3);
+        assert_eq!(bs.build_backend, BuildSystem::default().build_backend);
+
+        fs::write(
+            dir.join("pyproject.toml"),
+            "[tool.black]\nline-length = 100\n",
+        )
+        .unwrap();
+        assert_eq!(
+            BuildSystem::from_source(&dir).unwrap(),
+            BuildSystem::default()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1221 @@
+//! A conflict-driven dependency resolver, modelled after
+//! [PubGrub](https://github.com/dart-lang/pub/blob/master/doc/solver.md). It picks one version
+//! per package, backtracking when it finds a conflict, and records each conflict as an
+//! incompatibility so it never explores the same dead end twice.
+
+use crate::{
+    dep_types::{Constraint, DependencyError, Req, ReqType, Version},
+    util,
+};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+/// The virtual package representing the project being resolved. Not a valid PyPi name.
+pub const ROOT: &str = "<root>";
+
+/// Supplies the resolver with package data; from PyPi for real use, and in-memory for tests.
+pub trait DependencyProvider {
+    /// All versions available for a package, in any order.
+    fn versions(&mut self, name: &str) -> Result<Vec<Version>, DependencyError>;
+
+    /// The requirements of a package version, with environment markers already evaluated
+    /// using the extras passed.
+    fn dependencies(
+        &mut self,
+        name: &str,
+        extras: &[String],
+        version: &Version,
+    ) -> Result<Vec<Req>, DependencyError>;
+
+    /// Pick the version to try next from `candidates`, which is sorted newest-first.
+    /// By default, choose the newest final release, falling back to pre-releases if there are none.
+    fn choose_version(&mut self, _name: &str, candidates: &[Version]) -> Option<Version> {
+        candidates
+            .iter()
+            .find(|v| !v.is_prerelease())
+            .or_else(|| candidates.first())
+            .cloned()
+    }
+}
+
+/// A statement about a package's version. Positive terms require the package be installed
+/// with one of `versions`. Negative ones require it either not be installed, or be installed
+/// with one of `versions`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Term {
+    pub positive: bool,
+    pub versions: BTreeSet<Version>,
+}
+
+impl Term {
+    pub fn new(positive: bool, versions: BTreeSet<Version>) -> Self {
+        Self { positive, versions }
+    }
+
+    /// A term that places no restriction on the package.
+    fn any(universe: &BTreeSet<Version>) -> Self {
+        Self::new(false, universe.clone())
+    }
+
+    fn is_any(&self, universe: &BTreeSet<Version>) -> bool {
+        !self.positive && self.versions == *universe
+    }
+
+    fn negate(&self, universe: &BTreeSet<Version>) -> Self {
+        Self::new(
+            !self.positive,
+            universe.difference(&self.versions).cloned().collect(),
+        )
+    }
+
+    fn intersect(&self, other: &Self) -> Self {
+        Self::new(
+            self.positive || other.positive,
+            self.versions
+                .intersection(&other.versions)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    fn union(&self, other: &Self) -> Self {
+        Self::new(
+            self.positive && other.positive,
+            self.versions.union(&other.versions).cloned().collect(),
+        )
+    }
+
+    /// Is everything this term allows also allowed by `other`?
+    fn subset_of(&self, other: &Self) -> bool {
+        self.versions.is_subset(&other.versions) && (self.positive || !other.positive)
+    }
+
+    /// Is there nothing allowed by both terms?
+    fn disjoint(&self, other: &Self) -> bool {
+        self.versions.is_disjoint(&other.versions) && (self.positive || other.positive)
+    }
+}
+
+/// Why an incompatibility exists.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cause {
+    /// The project itself must be selected.
+    Root,
+    /// No versions of the package match the term.
+    NoVersions,
+    /// A package version depends on another package, with these constraints.
+    Dependency(Vec<Constraint>),
+    /// Derived during conflict resolution from two other incompatibilities, by index.
+    Derived(usize, usize),
+}
+
+/// A set of terms that can't all be true at once.
+#[derive(Clone, Debug)]
+pub struct Incompatibility {
+    pub terms: Vec<(String, Term)>,
+    pub cause: Cause,
+}
+
+impl Incompatibility {
+    fn term(&self, package: &str) -> Option<&Term> {
+        self.terms
+            .iter()
+            .find(|(p, _)| p == package)
+            .map(|(_, t)| t)
+    }
+}
+
+/// Details on why resolution failed: every incompatibility we learned, and the one which
+/// proved there's no solution. Following each incompatibility's causes from `root` gives a
+/// derivation tree, where each leaf is a fact like a dependency or a missing version.
+#[derive(Clone, Debug)]
+pub struct Conflict {
+    pub incompats: Vec<Incompatibility>,
+    pub root: usize,
+    /// The versions available for each package, for describing terms as ranges.
+    pub universes: HashMap<String, BTreeSet<Version>>,
+    pub names: HashMap<String, String>,
+}
+
+impl Conflict {
+    fn name(&self, package: &str) -> String {
+        if package == ROOT {
+            return "your project".into();
+        }
+        let (base, extras) = split_key(package);
+        let name = self.names.get(base).map_or(base, |n| n.as_str());
+        if extras.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}[{}]", name, extras.join(","))
+        }
+    }
+
+    /// Describe a set of versions as ranges over those available, eg `foo >=1.2, <=1.4`.
+    fn describe_versions(&self, package: &str, versions: &BTreeSet<Version>) -> String {
+        let universe = &self.universes[package];
+        let name = self.name(package);
+        if package == ROOT || versions == universe {
+            return name;
+        }
+
+        let all: Vec<&Version> = universe.iter().collect();
+        let mut runs: Vec<(usize, usize)> = vec![];
+        for (i, v) in all.iter().enumerate() {
+            if !versions.contains(v) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some((_, end)) if *end + 1 == i => *end = i,
+                _ => runs.push((i, i)),
+            }
+        }
+        if runs.is_empty() {
+            return format!("{} (no versions)", name);
+        }
+
+        let constr =
+            |type_, i: usize| Constraint::new(type_, all[i].clone()).to_string(false, true);
+        let ranges: Vec<String> = runs
+            .into_iter()
+            .map(|(start, end)| {
+                if start == end {
+                    constr(ReqType::Exact, start)
+                } else if start == 0 {
+                    constr(ReqType::Lte, end)
+                } else if end == all.len() - 1 {
+                    constr(ReqType::Gte, start)
+                } else {
+                    format!(
+                        "{}, {}",
+                        constr(ReqType::Gte, start),
+                        constr(ReqType::Lte, end)
+                    )
+                }
+            })
+            .collect();
+        format!("{} {}", name, ranges.join(" or "))
+    }
+
+    /// Describe what a term requires to be true. Negative terms are described by what
+    /// they allow if the package is installed.
+    fn describe_term(&self, package: &str, term: &Term) -> String {
+        if term.positive {
+            self.describe_versions(package, &term.versions)
+        } else {
+            let universe = &self.universes[package];
+            self.describe_versions(package, &term.negate(universe).versions)
+        }
+    }
+
+    /// A sentence fragment describing an incompatibility.
+    fn describe(&self, id: usize) -> String {
+        let incompat = &self.incompats[id];
+        let terms = &incompat.terms;
+        match &incompat.cause {
+            Cause::Root => "your project is required".into(),
+            Cause::NoVersions => {
+                let (package, term) = &terms[0];
+                if term.versions.is_empty() {
+                    format!("no versions of {} are available", self.name(package))
+                } else {
+                    format!(
+                        "no versions of {} can be used",
+                        self.describe_versions(package, &term.versions)
+                    )
+                }
+            }
+            Cause::Dependency(constraints) => {
+                let (package, term) = &terms[0];
+                let (dep, dep_term) = &terms[1];
+                let depender = match term.versions.iter().next() {
+                    Some(v) if package != ROOT => {
+                        format!("{} {}", self.name(package), v.to_string2())
+                    }
+                    _ => self.name(package),
+                };
+                let constrs: Vec<String> = constraints
+                    .iter()
+                    .map(|c| c.to_string(false, true))
+                    .collect();
+                let mut result = format!("{} depends on {}", depender, self.name(dep));
+                if !constrs.is_empty() {
+                    result.push_str(&format!(" {}", constrs.join(", ")));
+                }
+                if dep_term.is_any(&self.universes[dep]) {
+                    result.push_str(" (no versions match)");
+                }
+                result
+            }
+            Cause::Derived(_, _) => {
+                let positive: Vec<&(String, Term)> =
+                    terms.iter().filter(|(_, t)| t.positive).collect();
+                let negative: Vec<&(String, Term)> =
+                    terms.iter().filter(|(_, t)| !t.positive).collect();
+                let desc = |items: &[&(String, Term)]| -> Vec<String> {
+                    items
+                        .iter()
+                        .map(|(p, t)| self.describe_term(p, t))
+                        .collect()
+                };
+
+                match (positive.len(), negative.len()) {
+                    (0, 0) => "version solving failed".into(),
+                    (1, 0) if positive[0].0 == ROOT => "version solving failed".into(),
+                    (1, 0) => format!("{} is forbidden", desc(&positive)[0]),
+                    (0, 1) => format!("{} is required", desc(&negative)[0]),
+                    (1, 1) => format!("{} requires {}", desc(&positive)[0], desc(&negative)[0]),
+                    (2, 0) => format!(
+                        "{} is incompatible with {}",
+                        desc(&positive)[0],
+                        desc(&positive)[1]
+                    ),
+                    (_, 0) => format!("{} are incompatible", desc(&positive).join(", ")),
+                    (0, _) => format!("one of {} is required", desc(&negative).join(" or ")),
+                    (_, _) => format!(
+                        "if {} then {}",
+                        desc(&positive).join(" and "),
+                        desc(&negative).join(" or ")
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Explain why there's no solution, as numbered lines of reasoning ending in the conclusion.
+    /// Based on PubGrub's error reporting.
+    pub fn explain(&self) -> String {
+        let mut reporter = Reporter {
+            conflict: self,
+            refs: HashMap::new(),
+            line_numbers: HashMap::new(),
+            lines: vec![],
+        };
+        reporter.count_refs(self.root);
+
+        match self.incompats[self.root].cause {
+            Cause::Derived(_, _) => reporter.build(self.root),
+            _ => reporter
+                .lines
+                .push(format!("{}.", capitalize(&self.describe(self.root)))),
+        }
+        reporter.lines.join("\n")
+    }
+
+    /// The packages (excluding the project itself) whose requirements couldn't be satisfied.
+    pub fn packages(&self) -> Vec<String> {
+        let mut result = vec![];
+        let mut stack = vec![self.root];
+        let mut seen = vec![];
+        while let Some(id) = stack.pop() {
+            if seen.contains(&id) {
+                continue;
+            }
+            seen.push(id);
+            let incompat = &self.incompats[id];
+            if let Cause::Derived(a, b) = incompat.cause {
+                stack.push(a);
+                stack.push(b);
+            }
+            for (package, _) in incompat.terms.iter() {
+                let (base, _) = split_key(package);
+                let name = self.names.get(base).map_or(base, |n| n.as_str()).to_owned();
+                if package != ROOT && !result.contains(&name) {
+                    result.push(name);
+                }
+            }
+        }
+        result.sort();
+        result
+    }
+}
+
+#[derive(Debug)]
+pub enum ResolveError {
+    /// There's no set of versions satisfying all requirements.
+    NoSolution(Box<Conflict>),
+    /// We couldn't get package data.
+    Provider(DependencyError),
+}
+
+impl From<DependencyError> for ResolveError {
+    fn from(err: DependencyError) -> Self {
+        ResolveError::Provider(err)
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::NoSolution(conflict) => write!(f, "{}", conflict.explain()),
+            ResolveError::Provider(e) => write!(f, "{}", e.details),
+        }
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Writes a derivation tree out as sentences. Incompatibilities referenced more than once
+/// get a line number, so later lines can refer back to them instead of repeating the reasoning.
+struct Reporter<'a> {
+    conflict: &'a Conflict,
+    refs: HashMap<usize, usize>,
+    line_numbers: HashMap<usize, usize>,
+    lines: Vec<String>,
+}
+
+impl<'a> Reporter<'a> {
+    fn causes(&self, id: usize) -> Option<(usize, usize)> {
+        match self.conflict.incompats[id].cause {
+            Cause::Derived(a, b) => Some((a, b)),
+            _ => None,
+        }
+    }
+
+    fn is_derived(&self, id: usize) -> bool {
+        self.causes(id).is_some()
+    }
+
+    fn count_refs(&mut self, id: usize) {
+        if let Some((a, b)) = self.causes(id) {
+            for cause in [a, b].iter() {
+                let count = self.refs.entry(*cause).or_insert(0);
+                *count += 1;
+                if *count == 1 {
+                    self.count_refs(*cause);
+                }
+            }
+        }
+    }
+
+    fn describe(&self, id: usize) -> String {
+        self.conflict.describe(id)
+    }
+
+    /// A description, with its line number if it has one.
+    fn describe_ref(&self, id: usize) -> String {
+        match self.line_numbers.get(&id) {
+            Some(n) => format!("{} ({})", self.describe(id), n),
+            None => self.describe(id),
+        }
+    }
+
+    fn add_line(&mut self, line: String) {
+        self.lines.push(line);
+    }
+
+    /// Number the last line written, which concludes `id`.
+    fn number_last_line(&mut self, id: usize) {
+        let n = self.line_numbers.len() + 1;
+        self.line_numbers.insert(id, n);
+        if let Some(line) = self.lines.last_mut() {
+            line.push_str(&format!(" ({})", n));
+        }
+    }
+
+    fn build(&mut self, id: usize) {
+        self.build_helper(id);
+        if self.refs.get(&id).cloned().unwrap_or(0) > 1 {
+            self.number_last_line(id);
+        }
+    }
+
+    fn build_helper(&mut self, id: usize) {
+        let (c1, c2) = self
+            .causes(id)
+            .expect("Building an explanation from an external fact");
+        match (self.is_derived(c1), self.is_derived(c2)) {
+            (false, false) => self.add_line(format!(
+                "Because {} and {}, {}.",
+                self.describe(c1),
+                self.describe(c2),
+                self.describe(id)
+            )),
+            (true, false) => self.build_one_each(c1, c2, id),
+            (false, true) => self.build_one_each(c2, c1, id),
+            (true, true) => {
+                match (
+                    self.line_numbers.contains_key(&c1),
+                    self.line_numbers.contains_key(&c2),
+                ) {
+                    (true, true) => self.add_line(format!(
+                        "Because {} and {}, {}.",
+                        self.describe_ref(c1),
+                        self.describe_ref(c2),
+                        self.describe(id)
+                    )),
+                    (true, false) => {
+                        self.build(c2);
+                        self.add_line(format!(
+                            "And because {}, {}.",
+                            self.describe_ref(c1),
+                            self.describe(id)
+                        ));
+                    }
+                    (false, true) => {
+                        self.build(c1);
+                        self.add_line(format!(
+                            "And because {}, {}.",
+                            self.describe_ref(c2),
+                            self.describe(id)
+                        ));
+                    }
+                    (false, false) => {
+                        self.build(c1);
+                        if !self.line_numbers.contains_key(&c1) {
+                            self.number_last_line(c1);
+                        }
+                        self.add_line(String::new());
+                        self.build(c2);
+                        self.add_line(format!(
+                            "And because {}, {}.",
+                            self.describe_ref(c1),
+                            self.describe(id)
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    fn build_one_each(&mut self, derived: usize, external: usize, id: usize) {
+        if self.line_numbers.contains_key(&derived) {
+            self.add_line(format!(
+                "Because {} and {}, {}.",
+                self.describe(external),
+                self.describe_ref(derived),
+                self.describe(id)
+            ));
+            return;
+        }
+
+        // If the derived cause came from one derived and one external fact, fold the
+        // external one into this line, to avoid a trivial line of its own.
+        let (p1, p2) = self.causes(derived).unwrap();
+        let prior = match (self.is_derived(p1), self.is_derived(p2)) {
+            (true, false) if !self.line_numbers.contains_key(&p1) => Some((p1, p2)),
+            (false, true) if !self.line_numbers.contains_key(&p2) => Some((p2, p1)),
+            _ => None,
+        };
+        match prior {
+            Some((prior_derived, prior_external)) => {
+                self.build(prior_derived);
+                self.add_line(format!(
+                    "And because {} and {}, {}.",
+                    self.describe(prior_external),
+                    self.describe(external),
+                    self.describe(id)
+                ));
+            }
+            None => {
+                self.build(derived);
+                self.add_line(format!(
+                    "And because {}, {}.",
+                    self.describe(external),
+                    self.describe(id)
+                ));
+            }
+        }
+    }
+}
+
+/// A package version picked by the resolver.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Resolved {
+    pub name: String,
+    pub version: Version,
+    /// Names of the packages this one depends on, as listed in its requirements.
+    pub deps: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Assignment {
+    package: String,
+    term: Term,
+    decision_level: usize,
+    /// The incompatibility this was derived from; `None` for decisions.
+    cause: Option<usize>,
+}
+
+enum Relation {
+    Satisfied,
+    Contradicted,
+    AlmostSatisfied(String),
+    Inconclusive,
+}
+
+/// Packages are keyed by standardized name plus any extras, eg `ipython[qtconsole]`.
+/// A package with extras is a virtual package, which depends on the same version of the plain
+/// package, plus the requirements its extras enable.
+fn package_key(name: &str, extras: &[String]) -> String {
+    let name = util::standardize_name(name);
+    if extras.is_empty() {
+        return name;
+    }
+    let mut extras: Vec<String> = extras.iter().map(|e| util::standardize_name(e)).collect();
+    extras.sort();
+    extras.dedup();
+    format!("{}[{}]", name, extras.join(","))
+}
+
+/// The package key without extras, and the extras.
+fn split_key(key: &str) -> (&str, Vec<String>) {
+    match key.find('[') {
+        Some(i) => (
+            &key[..i],
+            key[i + 1..key.len() - 1]
+                .split(',')
+                .map(|e| e.to_owned())
+                .collect(),
+        ),
+        None => (key, vec![]),
+    }
+}
+
+struct Solver<'a, P: DependencyProvider> {
+    provider: &'a mut P,
+    incompats: Vec<Incompatibility>,
+    by_package: HashMap<String, Vec<usize>>,
+    assignments: Vec<Assignment>,
+    decision_level: usize,
+    universes: HashMap<String, BTreeSet<Version>>,
+    /// Display names, as first seen in a requirement.
+    names: HashMap<String, String>,
+    deps: HashMap<(String, Version), Vec<(String, Req)>>,
+}
+
+impl<'a, P: DependencyProvider> Solver<'a, P> {
+    fn universe(&mut self, package: &str) -> Result<&BTreeSet<Version>, DependencyError> {
+        if !self.universes.contains_key(package) {
+            let (base, _) = split_key(package);
+            let versions = if package == ROOT {
+                vec![Version::new(0, 0, 0)]
+            } else {
+                let name = self.names.get(base).cloned().unwrap_or_else(|| base.into());
+                self.provider.versions(&name)?
+            };
+            self.universes
+                .insert(package.to_owned(), versions.into_iter().collect());
+        }
+        Ok(&self.universes[package])
+    }
+
+    fn add_incompat(&mut self, incompat: Incompatibility) -> usize {
+        let id = self.incompats.len();
+        self.incompats.push(incompat);
+        self.index_incompat(id);
+        id
+    }
+
+    fn index_incompat(&mut self, id: usize) {
+        for (package, _) in self.incompats[id].terms.iter() {
+            self.by_package
+                .entry(package.clone())
+                .or_insert_with(Vec::new)
+                .push(id);
+        }
+    }
+
+    /// Everything the partial solution says about a package.
+    fn solution_term(&self, package: &str) -> Term {
+        let mut result = Term::any(&self.universes[package]);
+        for a in self.assignments.iter().filter(|a| a.package == package) {
+            result = result.intersect(&a.term);
+        }
+        result
+    }
+
+    fn decision(&self, package: &str) -> Option<&Version> {
+        self.assignments
+            .iter()
+            .find(|a| a.package == package && a.cause.is_none())
+            .and_then(|a| a.term.versions.iter().next())
+    }
+
+    fn relation(&self, id: usize) -> Relation {
+        let mut unsatisfied = None;
+        for (package, term) in self.incompats[id].terms.iter() {
+            let solution = self.solution_term(package);
+            if solution.subset_of(term) {
+                continue;
+            }
+            if solution.disjoint(term) {
+                return Relation::Contradicted;
+            }
+            if unsatisfied.is_some() {
+                return Relation::Inconclusive;
+            }
+            unsatisfied = Some(package.clone());
+        }
+        match unsatisfied {
+            Some(package) => Relation::AlmostSatisfied(package),
+            None => Relation::Satisfied,
+        }
+    }
+
+    /// Add the negation of an almost-satisfied incompatibility's remaining term.
+    fn derive(&mut self, package: &str, id: usize) {
+        let term = self.incompats[id]
+            .term(package)
+            .expect("Missing term when deriving")
+            .negate(&self.universes[package]);
+        self.assignments.push(Assignment {
+            package: package.to_owned(),
+            term,
+            decision_level: self.decision_level,
+            cause: Some(id),
+        });
+    }
+
+    fn propagate(&mut self, package: String) -> Result<(), ResolveError> {
+        let mut changed = vec![package];
+        while let Some(package) = changed.pop() {
+            let ids = self.by_package.get(&package).cloned().unwrap_or_default();
+            for id in ids.into_iter().rev() {
+                match self.relation(id) {
+                    Relation::Satisfied => {
+                        let root_cause = self.resolve_conflict(id)?;
+                        // After backtracking, the root cause is almost satisfied.
+                        match self.relation(root_cause) {
+                            Relation::AlmostSatisfied(p) => {
+                                self.derive(&p, root_cause);
+                                changed.clear();
+                                changed.push(p);
+                            }
+                            _ => panic!("Conflict resolution produced an unusable incompatibility"),
+                        }
+                        break;
+                    }
+                    Relation::AlmostSatisfied(p) => {
+                        self.derive(&p, id);
+                        changed.push(p);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the assignment that caused the incompatibility to be satisfied, and the
+    /// decision level to backtrack to.
+    fn find_satisfier(&self, id: usize) -> (usize, usize) {
+        let terms = &self.incompats[id].terms;
+        let satisfied = |accum: &HashMap<&str, Term>| {
+            terms
+                .iter()
+                .all(|(p, t)| accum.get(p.as_str()).map_or(false, |a| a.subset_of(t)))
+        };
+        let start = || -> HashMap<&str, Term> {
+            terms
+                .iter()
+                .map(|(p, _)| (p.as_str(), Term::any(&self.universes[p])))
+                .collect()
+        };
+
+        let mut accum = start();
+        let mut satisfier = None;
+        for (i, a) in self.assignments.iter().enumerate() {
+            if let Some(t) = accum.get_mut(a.package.as_str()) {
+                *t = t.intersect(&a.term);
+                if satisfied(&accum) {
+                    satisfier = Some(i);
+                    break;
+                }
+            }
+        }
+        let satisfier = satisfier.expect("Satisfied incompatibility without a satisfier");
+
+        let sat = &self.assignments[satisfier];
+        let mut accum = start();
+        accum.insert(sat.package.as_str(), sat.term.clone());
+        let mut previous_level = 1;
+        if !satisfied(&accum) {
+            for a in self.assignments[..satisfier].iter() {
+                if let Some(t) = accum.get_mut(a.package.as_str()) {
+                    *t = t.intersect(&a.term);
+                    if satisfied(&accum) {
+                        previous_level = a.decision_level;
+                        break;
+                    }
+                }
+            }
+        }
+        (satisfier, previous_level)
+    }
+
+    fn is_terminal(&self, id: usize) -> bool {
+        let terms = &self.incompats[id].terms;
+        terms.is_empty() || (terms.len() == 1 && terms[0].0 == ROOT && terms[0].1.positive)
+    }
+
+    fn backtrack(&mut self, level: usize) {
+        self.assignments.retain(|a| a.decision_level <= level);
+        self.decision_level = level;
+    }
+
+    /// Learn from a conflict, and backtrack to where it no longer applies. Returns the id
+    /// of the incompatibility that's the root cause.
+    fn resolve_conflict(&mut self, id: usize) -> Result<usize, ResolveError> {
+        let mut current = id;
+        loop {
+            if self.is_terminal(current) {
+                return Err(ResolveError::NoSolution(Box::new(Conflict {
+                    incompats: self.incompats.clone(),
+                    root: current,
+                    universes: self.universes.clone(),
+                    names: self.names.clone(),
+                })));
+            }
+
+            let (satisfier, previous_level) = self.find_satisfier(current);
+            let sat = self.assignments[satisfier].clone();
+
+            if sat.cause.is_none() || previous_level < sat.decision_level {
+                self.backtrack(previous_level);
+                if current != id {
+                    self.index_incompat(current);
+                }
+                return Ok(current);
+            }
+
+            // Resolve the current incompatibility with the satisfier's cause, to find one
+            // that's satisfied earlier.
+            let cause = sat.cause.unwrap();
+            let mut terms: Vec<(String, Term)> = self.incompats[current].terms.clone();
+            for (package, term) in self.incompats[cause].terms.iter() {
+                match terms.iter_mut().find(|(p, _)| p == package) {
+                    Some((_, existing)) => {
+                        *existing = if *package == sat.package {
+                            existing.union(term)
+                        } else {
+                            existing.intersect(term)
+                        }
+                    }
+                    None => terms.push((package.clone(), term.clone())),
+                }
+            }
+            // Terms that allow anything can't contribute to a conflict.
+            let universes = &self.universes;
+            terms.retain(|(p, t)| !t.is_any(&universes[p]));
+
+            self.incompats.push(Incompatibility {
+                terms,
+                cause: Cause::Derived(current, cause),
+            });
+            current = self.incompats.len() - 1;
+        }
+    }
+
+    /// The next package to decide: one we've derived must be installed, but haven't picked a
+    /// version for. Prefer those with the fewest options, to find conflicts early.
+    fn next_package(&self) -> Option<(String, Term)> {
+        let mut packages: Vec<&str> = vec![];
+        for a in self.assignments.iter() {
+            if a.term.positive && !packages.contains(&a.package.as_str()) {
+                packages.push(&a.package);
+            }
+        }
+        packages
+            .into_iter()
+            .filter(|p| self.decision(p).is_none())
+            .map(|p| (p.to_owned(), self.solution_term(p)))
+            .min_by(|(p1, t1), (p2, t2)| {
+                t1.versions
+                    .len()
+                    .cmp(&t2.versions.len())
+                    .then_with(|| p1.cmp(p2))
+            })
+    }
+
+    /// Requirements of a package version, keyed. Packages with extras depend on their
+    /// plain counterpart at the same version.
+    fn dependencies(
+        &mut self,
+        package: &str,
+        version: &Version,
+    ) -> Result<Vec<(String, Req)>, DependencyError> {
+        let cache_key = (package.to_owned(), version.clone());
+        if let Some(deps) = self.deps.get(&cache_key) {
+            return Ok(deps.clone());
+        }
+
+        let (base, extras) = split_key(package);
+        let name = self.names.get(base).cloned().unwrap_or_else(|| base.into());
+        let mut reqs = vec![];
+        if !extras.is_empty() {
+            reqs.push(Req::new(
+                name.clone(),
+                vec![Constraint::new(ReqType::Exact, version.clone())],
+            ));
+        }
+        reqs.append(&mut self.provider.dependencies(&name, &extras, version)?);
+
+        let mut result = vec![];
+        for req in reqs.into_iter() {
+            let key = package_key(
+                &req.name,
+                req.install_with_extras.as_ref().unwrap_or(&vec![]),
+            );
+            if key == package {
+                continue;
+            }
+            let (dep_base, _) = split_key(&key);
+            if !self.names.contains_key(dep_base) {
+                self.names.insert(dep_base.to_owned(), req.name.clone());
+            }
+            result.push((key, req));
+        }
+        self.deps.insert(cache_key, result.clone());
+        Ok(result)
+    }
+
+    /// Pick a version for the package, and add incompatibilities for its dependencies.
+    fn decide(&mut self, package: &str, term: &Term) -> Result<(), ResolveError> {
+        let candidates: Vec<Version> = term.versions.iter().rev().cloned().collect();
+        let (base, _) = split_key(package);
+        let name = self.names.get(base).cloned().unwrap_or_else(|| base.into());
+
+        let version = if package == ROOT {
+            candidates.first().cloned()
+        } else {
+            self.provider.choose_version(&name, &candidates)
+        };
+        let version = match version {
+            Some(v) => v,
+            None => {
+                self.add_incompat(Incompatibility {
+                    terms: vec![(package.to_owned(), term.clone())],
+                    cause: Cause::NoVersions,
+                });
+                return Ok(());
+            }
+        };
+
+        let mut conflicts = false;
+        for (key, req) in self.dependencies(package, &version)? {
+            let universe = self.universe(&key)?.clone();
+            let allowed: BTreeSet<Version> = universe
+                .iter()
+                .filter(|v| req.constraints.iter().all(|c| c.is_compatible(v)))
+                .cloned()
+                .collect();
+            let dep_term = Term::new(true, allowed).negate(&universe);
+
+            if self.solution_term(&key).subset_of(&dep_term) {
+                conflicts = true;
+            }
+            self.add_incompat(Incompatibility {
+                terms: vec![
+                    (
+                        package.to_owned(),
+                        Term::new(true, vec![version.clone()].into_iter().collect()),
+                    ),
+                    (key, dep_term),
+                ],
+                cause: Cause::Dependency(req.constraints.clone()),
+            });
+        }
+
+        // If a dependency is already ruled out, propagation will rule out this version instead.
+        if !conflicts {
+            self.decision_level += 1;
+            self.assignments.push(Assignment {
+                package: package.to_owned(),
+                term: Term::new(true, vec![version].into_iter().collect()),
+                decision_level: self.decision_level,
+                cause: None,
+            });
+        }
+        Ok(())
+    }
+
+    fn solve(&mut self) -> Result<Vec<Resolved>, ResolveError> {
+        self.universe(ROOT)?;
+        self.add_incompat(Incompatibility {
+            terms: vec![(ROOT.to_owned(), Term::new(false, BTreeSet::new()))],
+            cause: Cause::Root,
+        });
+
+        let mut next = ROOT.to_owned();
+        loop {
+            self.propagate(next)?;
+            match self.next_package() {
+                Some((package, term)) => {
+                    self.decide(&package, &term)?;
+                    next = package;
+                }
+                None => break,
+            }
+        }
+
+        // Collect decisions, folding packages with extras into their plain counterparts.
+        let mut result: Vec<Resolved> = vec![];
+        for a in self.assignments.iter().filter(|a| a.cause.is_none()) {
+            if a.package == ROOT {
+                continue;
+            }
+            let version = a.term.versions.iter().next().unwrap().clone();
+            let (base, _) = split_key(&a.package);
+            let deps: Vec<String> = self.deps[&(a.package.clone(), version.clone())]
+                .iter()
+                .filter(|(key, _)| split_key(key).0 != base)
+                .map(|(_, req)| req.name.clone())
+                .collect();
+
+            match result
+                .iter_mut()
+                .find(|r| util::compare_names(&r.name, base))
+            {
+                Some(existing) => {
+                    for dep in deps {
+                        if !existing.deps.iter().any(|d| util::compare_names(d, &dep)) {
+                            existing.deps.push(dep);
+                        }
+                    }
+                }
+                None => result.push(Resolved {
+                    name: self.names.get(base).cloned().unwrap_or_else(|| base.into()),
+                    version,
+                    deps,
+                }),
+            }
+        }
+        result.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
+        Ok(result)
+    }
+}
+
+/// Find a single version of each package that satisfies `reqs`, and the requirements
+/// of each package picked, recursively.
+pub fn resolve<P: DependencyProvider>(
+    reqs: &[Req],
+    provider: &mut P,
+) -> Result<Vec<Resolved>, ResolveError> {
+    let mut solver = Solver {
+        provider,
+        incompats: vec![],
+        by_package: HashMap::new(),
+        assignments: vec![],
+        decision_level: 0,
+        universes: HashMap::new(),
+        names: HashMap::new(),
+        deps: HashMap::new(),
+    };
+
+    // The project's requirements are the root package's dependencies.
+    let root_deps = reqs
+        .iter()
+        .map(|req| {
+            let key = package_key(
+                &req.name,
+                req.install_with_extras.as_ref().unwrap_or(&vec![]),
+            );
+            let (base, _) = split_key(&key);
+            solver
+                .names
+                .entry(base.to_owned())
+                .or_insert_with(|| req.name.clone());
+            (key, req.clone())
+        })
+        .collect();
+    solver
+        .deps
+        .insert((ROOT.to_owned(), Version::new(0, 0, 0)), root_deps);
+
+    solver.solve()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    /// Package name, version, and its requirements in PEP 508 format.
+    struct MemoryProvider {
+        packages: Vec<(&'static str, &'static str, Vec<&'static str>)>,
+    }
+
+    impl DependencyProvider for MemoryProvider {
+        fn versions(&mut self, name: &str) -> Result<Vec<Version>, DependencyError> {
+            Ok(self
+                .packages
+                .iter()
+                .filter(|(n, _, _)| util::compare_names(n, name))
+                .map(|(_, v, _)| Version::from_str(v).unwrap())
+                .collect())
+        }
+
+        fn dependencies(
+            &mut self,
+            name: &str,
+            extras: &[String],
+            version: &Version,
+        ) -> Result<Vec<Req>, DependencyError> {
+            let env = crate::markers::Environment::new(crate::Os::Linux, &Version::new(3, 7, 0))
+                .with_extras(extras);
+            let (_, _, reqs) = self
+                .packages
+                .iter()
+                .find(|(n, v, _)| {
+                    util::compare_names(n, name) && Version::from_str(v).unwrap() == *version
+                })
+                .unwrap();
+            Ok(reqs
+                .iter()
+                .map(|r| Req::from_str(r, true).unwrap())
+                .filter(|r| r.marker.as_ref().map_or(true, |m| m.evaluate(&env)))
+                .collect())
+        }
+    }
+
+    fn solve(
+        packages: Vec<(&'static str, &'static str, Vec<&'static str>)>,
+        reqs: &[&str],
+    ) -> Result<Vec<(String, String)>, ResolveError> {
+        let reqs: Vec<Req> = reqs
+            .iter()
+            .map(|r| Req::from_str(r, true).unwrap())
+            .collect();
+        let mut provider = MemoryProvider { packages };
+        Ok(resolve(&reqs, &mut provider)?
+            .into_iter()
+            .map(|r| (r.name, r.version.to_string2()))
+            .collect())
+    }
+
+    fn pairs(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn no_conflicts() {
+        let packages = vec![
+            ("a", "1.0.0", vec!["b (>=1.0)"]),
+            ("a", "2.0.0", vec!["b (>=2.0)"]),
+            ("b", "1.0.0", vec![]),
+            ("b", "2.0.0", vec![]),
+            ("b", "2.1.0rc1", vec![]),
+        ];
+        assert_eq!(
+            solve(packages, &["a"]).unwrap(),
+            pairs(&[("a", "2.0.0"), ("b", "2.0.0")])
+        );
+    }
+
+    #[test]
+    fn avoids_conflict_by_backtracking() {
+        // The newest `foo` requires a `bar` that the project rules out; use an older `foo`.
+        let packages = vec![
+            ("foo", "1.0.0", vec!["bar (<2)"]),
+            ("foo", "2.0.0", vec!["bar (>=2)"]),
+            ("bar", "1.0.0", vec![]),
+            ("bar", "2.0.0", vec![]),
+        ];
+        assert_eq!(
+            solve(packages, &["foo", "bar (<2)"]).unwrap(),
+            pairs(&[("bar", "1.0.0"), ("foo", "1.0.0")])
+        );
+    }
+
+    #[test]
+    fn conflict_in_transitive_deps() {
+        // From the PubGrub docs: `foo 1.1` needs `bar 2`, which needs `baz 3`, incompatible with
+        // the project's `baz`; settle on `foo 1.0`.
+        let packages = vec![
+            ("foo", "1.0.0", vec![]),
+            ("foo", "1.1.0", vec!["bar (>=2.0)"]),
+            ("bar", "1.0.0", vec![]),
+            ("bar", "2.0.0", vec!["baz (>=3.0)"]),
+            ("baz", "1.0.0", vec![]),
+            ("baz", "3.0.0", vec![]),
+        ];
+        assert_eq!(
+            solve(packages, &["foo (>=1.0)", "baz (<2)"]).unwrap(),
+            pairs(&[("baz", "1.0.0"), ("foo", "1.0.0")])
+        );
+    }
+
+    #[test]
+    fn extras_and_markers() {
+        let packages = vec![
+            (
+                "requests",
+                "2.22.0",
+                vec![
+                    "idna (>=2.5)",
+                    "PySocks (>=1.5.6) ; extra == 'socks'",
+                    "win-inet-pton ; sys_platform == 'win32'",
+                ],
+            ),
+            ("idna", "2.8", vec![]),
+            ("PySocks", "1.7.1", vec![]),
+            ("win-inet-pton", "1.1.0", vec![]),
+        ];
+        let result = solve(packages, &["requests[socks]"]).unwrap();
+        assert_eq!(
+            result,
+            pairs(&[
+                ("idna", "2.8"),
+                ("PySocks", "1.7.1"),
+                ("requests", "2.22.0")
+            ])
+        );
+    }
+
+    #[test]
+    fn unsolvable() {
+        let packages = vec![
+            ("a", "1.0.0", vec!["c (<2)"]),
+            ("b", "1.0.0", vec!["c (>=2)"]),
+            ("c", "1.0.0", vec![]),
+            ("c", "2.0.0", vec![]),
+        ];
+        match solve(packages, &["a", "b"]) {
+            Err(ResolveError::NoSolution(conflict)) => {
+                assert_eq!(conflict.packages(), vec!["a", "b", "c"])
+            }
+            _ => panic!("Expected no solution"),
+        }
+    }
+
+    #[test]
+    fn explain_conflict() {
+        // From the PubGrub docs' linear error reporting example.
+        let packages = vec![
+            ("foo", "1.0.0", vec!["bar (>=2.0,<3)"]),
+            ("bar", "2.0.0", vec!["baz (>=3.0,<4)"]),
+            ("baz", "1.0.0", vec![]),
+            ("baz", "3.0.0", vec![]),
+        ];
+        match solve(packages, &["foo (>=1.0,<2)", "baz (>=1.0,<2)"]) {
+            Err(ResolveError::NoSolution(conflict)) => assert_eq!(
+                conflict.explain(),
+                "Because foo 1.0.0 depends on bar >=2.0, <3 and bar 2.0.0 depends on \
+                 baz >=3.0, <4, foo requires baz ==3.0.0.\n\
+                 And because your project depends on foo >=1.0, <2 and your project depends \
+                 on baz >=1.0, <2, version solving failed."
+            ),
+            _ => panic!("Expected no solution"),
+        }
+
+        let packages = vec![("x", "1.0.0", vec!["y (>=3)"]), ("y", "1.0.0", vec![])];
+        match solve(packages, &["x"]) {
+            Err(ResolveError::NoSolution(conflict)) => assert_eq!(
+                conflict.explain(),
+                "Because x 1.0.0 depends on y >=3 (no versions match) and your project \
+                 depends on x, version solving failed."
+            ),
+            _ => panic!("Expected no solution"),
+        }
+    }
+}
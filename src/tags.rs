@@ -0,0 +1,420 @@
+//! Wheel compatibility tags, which describe the interpreters and platforms a wheel supports.
+//! https://www.python.org/dev/peps/pep-0425/
+//! https://packaging.python.org/specifications/platform-compatibility-tags/
+
+use crate::{dep_types::Version, Os};
+
+use regex::Regex;
+use std::{env, fmt, process::Command};
+
+/// A single tag, eg `cp37-cp37m-manylinux1_x86_64`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Tag {
+    pub python: String,
+    pub abi: String,
+    pub platform: String,
+}
+
+impl Tag {
+    pub fn new(python: &str, abi: &str, platform: &str) -> Self {
+        Self {
+            python: python.to_owned(),
+            abi: abi.to_owned(),
+            platform: platform.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}-{}", self.python, self.abi, self.platform)
+    }
+}
+
+/// The C library a Linux system uses, and its major and minor version.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Libc {
+    Glibc(u32, u32),
+    Musl(u32, u32),
+}
+
+/// The system we're installing wheels for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Platform {
+    pub os: Os,
+    /// As used in platform tags, eg `x86_64`, `aarch64`, or `i686`.
+    pub arch: String,
+    /// Linux only.
+    pub libc: Option<Libc>,
+    /// Mac only, eg `(10, 15)`.
+    pub macos_version: Option<(u32, u32)>,
+}
+
+impl Platform {
+    /// Find the details of the machine we're running on.
+    pub fn detect(os: Os) -> Self {
+        let arch = match (os, env::consts::ARCH) {
+            (Os::Linux32, _) | (Os::Windows32, _) | (_, "x86") => "i686",
+            (Os::Mac, "aarch64") => "arm64",
+            (_, "arm") => "armv7l",
+            (_, a) => a,
+        };
+
+        let libc = match os {
+            Os::Linux | Os::Linux32 => Some(detect_libc().unwrap_or(Libc::Glibc(2, 17))),
+            _ => None,
+        };
+        let macos_version = match os {
+            Os::Mac => Some(detect_macos_version().unwrap_or((10, 9))),
+            _ => None,
+        };
+
+        Self {
+            os,
+            arch: arch.to_owned(),
+            libc,
+            macos_version,
+        }
+    }
+}
+
+/// Find the libc version from `ldd`, which both glibc and musl provide.
+fn detect_libc() -> Option<Libc> {
+    let output = Command::new("ldd").arg("--version").output().ok()?;
+    // musl's `ldd` prints to stderr.
+    let text = String::from_utf8_lossy(&output.stdout).into_owned()
+        + &String::from_utf8_lossy(&output.stderr);
+
+    if text.contains("musl") {
+        let caps = Regex::new(r"Version (\d+)\.(\d+)")
+            .unwrap()
+            .captures(&text)?;
+        return Some(Libc::Musl(
+            caps.get(1)?.as_str().parse().ok()?,
+            caps.get(2)?.as_str().parse().ok()?,
+        ));
+    }
+    // eg `ldd (Ubuntu GLIBC 2.27-3ubuntu1) 2.27`
+    let first_line = text.lines().next()?;
+    let caps = Regex::new(r"(\d+)\.(\d+)\s*$")
+        .unwrap()
+        .captures(first_line)?;
+    Some(Libc::Glibc(
+        caps.get(1)?.as_str().parse().ok()?,
+        caps.get(2)?.as_str().parse().ok()?,
+    ))
+}
+
+fn detect_macos_version() -> Option<(u32, u32)> {
+    let output = Command::new("sw_vers")
+        .arg("-productVersion")
+        .output()
+        .ok()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let mut parts = text.trim().split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().and_then(|m| m.parse().ok()).unwrap_or(0);
+    Some((major, minor))
+}
+
+/// Parse the tags from a wheel filename. Each part may be a set, eg
+/// `PyQt5-5.13.0-5.13.0-cp35.cp36.cp37.cp38-none-win32.whl` supports 4 Python versions.
+pub fn wheel_tags(filename: &str) -> Option<Vec<Tag>> {
+    if !filename.ends_with(".whl") {
+        return None;
+    }
+    let parts: Vec<&str> = filename.trim_end_matches(".whl").split('-').collect();
+    if parts.len() != 5 && parts.len() != 6 {
+        return None;
+    }
+    let (pythons, abis, platforms) = (
+        parts[parts.len() - 3],
+        parts[parts.len() - 2],
+        parts[parts.len() - 1],
+    );
+
+    let mut result = vec![];
+    for python in pythons.split('.') {
+        for abi in abis.split('.') {
+            for platform in platforms.split('.') {
+                result.push(Tag::new(python, abi, platform));
+            }
+        }
+    }
+    Some(result)
+}
+
+/// The oldest glibc each architecture has manylinux wheels for.
+fn min_glibc_minor(arch: &str) -> u32 {
+    match arch {
+        "x86_64" | "i686" => 5,
+        _ => 17,
+    }
+}
+
+/// The tags from before PEP 600, which are aliases of newer ones: (glibc minor version, tag, archs)
+const LEGACY_MANYLINUX: [(u32, &str, &[&str]); 3] = [
+    (
+        17,
+        "manylinux2014",
+        &[
+            "x86_64", "i686", "aarch64", "armv7l", "ppc64", "ppc64le", "s390x",
+        ],
+    ),
+    (12, "manylinux2010", &["x86_64", "i686"]),
+    (5, "manylinux1", &["x86_64", "i686"]),
+];
+
+/// Platform tags we support, most specific first.
+fn platform_tags(platform: &Platform) -> Vec<String> {
+    let arch = &platform.arch;
+    let mut result = vec![];
+
+    match platform.os {
+        Os::Linux | Os::Linux32 => {
+            match platform.libc {
+                Some(Libc::Glibc(2, minor)) => {
+                    for m in (min_glibc_minor(arch)..=minor).rev() {
+                        result.push(format!("manylinux_2_{}_{}", m, arch));
+                        for (legacy_minor, tag, archs) in LEGACY_MANYLINUX.iter() {
+                            if *legacy_minor == m && archs.contains(&arch.as_str()) {
+                                result.push(format!("{}_{}", tag, arch));
+                            }
+                        }
+                    }
+                }
+                Some(Libc::Musl(1, minor)) => {
+                    for m in (0..=minor).rev() {
+                        result.push(format!("musllinux_1_{}_{}", m, arch));
+                    }
+                }
+                _ => (),
+            }
+            result.push(format!("linux_{}", arch));
+        }
+        Os::Mac => {
+            let formats: &[&str] = match arch.as_ref() {
+                "arm64" => &["arm64", "universal2"],
+                _ => &[
+                    "x86_64",
+                    "intel",
+                    "fat64",
+                    "fat32",
+                    "universal2",
+                    "universal",
+                ],
+            };
+            let (major, minor) = platform.macos_version.unwrap_or((10, 9));
+
+            let mut versions = vec![];
+            if major >= 11 {
+                // From macOS 11, only the major version is relevant.
+                for ma in (11..=major).rev() {
+                    versions.push((ma, 0));
+                }
+                // Intel Macs can use wheels for 10.x, as can universal2 wheels on Apple Silicon.
+                for mi in (4..=16).rev() {
+                    versions.push((10, mi));
+                }
+            } else {
+                for mi in (0..=minor).rev() {
+                    versions.push((10, mi));
+                }
+            }
+
+            for (ma, mi) in versions.into_iter() {
+                for format in formats.iter() {
+                    // Apple Silicon can't run 10.x wheels built only for Intel.
+                    if ma == 10 && arch == "arm64" && *format != "universal2" {
+                        continue;
+                    }
+                    result.push(format!("macosx_{}_{}_{}", ma, mi, format));
+                }
+            }
+        }
+        Os::Windows => result.push(if arch == "aarch64" {
+            "win_arm64".to_owned()
+        } else {
+            "win_amd64".to_owned()
+        }),
+        Os::Windows32 => result.push("win32".to_owned()),
+        Os::Any => (),
+    }
+    result
+}
+
+/// The tags of wheels we can install for a CPython version and platform, most preferred first.
+/// This follows the order `pip` uses: Wheels built for this interpreter and ABI, then the stable
+/// ABI, then pure-Python wheels, with platform-specific ones before `any`.
+pub fn supported_tags(py_vers: &Version, platform: &Platform) -> Vec<Tag> {
+    let (major, minor) = (py_vers.major(), py_vers.minor());
+    let interpreter = format!("cp{}{}", major, minor);
+    let platforms = platform_tags(platform);
+
+    // Python 3.8 dropped the `m` (pymalloc) ABI flag, and Python 3.3 the `u` one.
+    let abi = match (major, minor) {
+        (2, _) if platform.os == Os::Linux || platform.os == Os::Linux32 => {
+            format!("{}mu", interpreter)
+        }
+        (3, m) if m >= 8 => interpreter.clone(),
+        _ => format!("{}m", interpreter),
+    };
+
+    let mut result = vec![];
+    let mut abis = vec![abi];
+    if major == 3 && minor >= 2 {
+        abis.push("abi3".to_owned());
+    }
+    abis.push("none".to_owned());
+    for abi in abis.iter() {
+        for p in platforms.iter() {
+            result.push(Tag::new(&interpreter, abi, p));
+        }
+    }
+    // The stable ABI is forward-compatible.
+    if major == 3 {
+        for m in (2..minor).rev() {
+            for p in platforms.iter() {
+                result.push(Tag::new(&format!("cp3{}", m), "abi3", p));
+            }
+        }
+    }
+
+    // eg `py37`, `py3`, `py36`... `py30`
+    let mut pythons = vec![format!("py{}{}", major, minor), format!("py{}", major)];
+    for m in (0..minor).rev() {
+        pythons.push(format!("py{}{}", major, m));
+    }
+    for python in pythons.iter() {
+        for p in platforms.iter() {
+            result.push(Tag::new(python, "none", p));
+        }
+    }
+    result.push(Tag::new(&interpreter, "none", "any"));
+    for python in pythons.iter() {
+        result.push(Tag::new(python, "none", "any"));
+    }
+    result
+}
+
+/// How well a wheel suits us; lower is better. `None` if we can't install it.
+pub fn rank(filename: &str, supported: &[Tag]) -> Option<usize> {
+    let tags = wheel_tags(filename)?;
+    supported.iter().position(|s| tags.contains(s))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn linux(libc: Libc) -> Platform {
+        Platform {
+            os: Os::Linux,
+            arch: "x86_64".into(),
+            libc: Some(libc),
+            macos_version: None,
+        }
+    }
+
+    #[test]
+    fn parse_wheel_tags() {
+        assert_eq!(
+            wheel_tags("PyQt5-5.13.0-5.13.0-cp35.cp36-none-win32.whl"),
+            Some(vec![
+                Tag::new("cp35", "none", "win32"),
+                Tag::new("cp36", "none", "win32")
+            ])
+        );
+        assert_eq!(
+            wheel_tags("numpy-1.17.2-cp37-cp37m-manylinux2014_x86_64.manylinux_2_17_x86_64.whl")
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(wheel_tags("numpy-1.17.2.tar.gz"), None);
+        assert_eq!(wheel_tags("numpy-1.17.2-cp37.whl"), None);
+    }
+
+    #[test]
+    fn supported_tag_order() {
+        let tags = supported_tags(&Version::new_short(3, 7), &linux(Libc::Glibc(2, 17)));
+        let strs: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+
+        assert_eq!(strs[0], "cp37-cp37m-manylinux_2_17_x86_64");
+        assert_eq!(strs[1], "cp37-cp37m-manylinux2014_x86_64");
+        assert_eq!(strs.last().unwrap(), "py30-none-any");
+        let pos = |t: &str| strs.iter().position(|s| s == t).unwrap();
+        assert!(pos("cp37-cp37m-manylinux1_x86_64") < pos("cp37-cp37m-linux_x86_64"));
+        assert!(pos("cp37-cp37m-linux_x86_64") < pos("cp37-abi3-manylinux_2_17_x86_64"));
+        assert!(pos("cp37-abi3-manylinux_2_17_x86_64") < pos("cp36-abi3-manylinux1_x86_64"));
+        assert!(pos("cp36-abi3-manylinux1_x86_64") < pos("py3-none-manylinux1_x86_64"));
+        assert!(pos("py3-none-linux_x86_64") < pos("cp37-none-any"));
+        assert!(pos("cp37-none-any") < pos("py3-none-any"));
+        assert!(!strs.contains(&"cp37-cp37m-manylinux_2_24_x86_64".to_owned()));
+        assert!(!strs.contains(&"cp38-cp38-manylinux1_x86_64".to_owned()));
+
+        let py38 = supported_tags(&Version::new_short(3, 8), &linux(Libc::Glibc(2, 17)));
+        assert_eq!(py38[0].to_string(), "cp38-cp38-manylinux_2_17_x86_64");
+
+        let musl = supported_tags(&Version::new_short(3, 9), &linux(Libc::Musl(1, 1)));
+        assert_eq!(musl[0].to_string(), "cp39-cp39-musllinux_1_1_x86_64");
+        assert_eq!(musl[1].to_string(), "cp39-cp39-musllinux_1_0_x86_64");
+        assert_eq!(musl[2].to_string(), "cp39-cp39-linux_x86_64");
+    }
+
+    #[test]
+    fn mac_and_windows_tags() {
+        let mac = |arch: &str, version| Platform {
+            os: Os::Mac,
+            arch: arch.into(),
+            libc: None,
+            macos_version: Some(version),
+        };
+        let py = Version::new_short(3, 9);
+
+        let intel = supported_tags(&py, &mac("x86_64", (10, 15)));
+        assert_eq!(intel[0].to_string(), "cp39-cp39-macosx_10_15_x86_64");
+        assert!(intel.contains(&Tag::new("cp39", "cp39", "macosx_10_9_universal2")));
+        assert!(!intel.contains(&Tag::new("cp39", "cp39", "macosx_11_0_x86_64")));
+
+        let arm = supported_tags(&py, &mac("arm64", (12, 3)));
+        assert_eq!(arm[0].to_string(), "cp39-cp39-macosx_12_0_arm64");
+        assert!(arm.contains(&Tag::new("cp39", "cp39", "macosx_11_0_arm64")));
+        assert!(arm.contains(&Tag::new("cp39", "cp39", "macosx_10_9_universal2")));
+        assert!(!arm.contains(&Tag::new("cp39", "cp39", "macosx_10_9_x86_64")));
+
+        let win = Platform {
+            os: Os::Windows,
+            arch: "x86_64".into(),
+            libc: None,
+            macos_version: None,
+        };
+        let tags = supported_tags(&py, &win);
+        assert_eq!(tags[0].to_string(), "cp39-cp39-win_amd64");
+        assert!(!tags.iter().any(|t| t.platform == "win32"));
+    }
+
+    #[test]
+    fn rank_wheels() {
+        let supported = supported_tags(&Version::new_short(3, 7), &linux(Libc::Glibc(2, 28)));
+        let mut wheels = vec![
+            "numpy-1.17.2-cp37-cp37m-win_amd64.whl",
+            "numpy-1.17.2-py3-none-any.whl",
+            "numpy-1.17.2-cp37-cp37m-manylinux1_x86_64.whl",
+            "numpy-1.17.2-cp37-cp37m-manylinux_2_24_x86_64.whl",
+            "numpy-1.17.2-cp38-cp38-manylinux1_x86_64.whl",
+            "numpy-1.17.2-cp37-cp37m-manylinux_2_34_x86_64.whl",
+            "numpy-1.17.2-cp37-cp37m-manylinux2014_aarch64.whl",
+        ];
+        wheels.retain(|w| rank(w, &supported).is_some());
+        wheels.sort_by_key(|w| rank(w, &supported));
+        assert_eq!(
+            wheels,
+            vec![
+                "numpy-1.17.2-cp37-cp37m-manylinux_2_24_x86_64.whl",
+                "numpy-1.17.2-cp37-cp37m-manylinux1_x86_64.whl",
+                "numpy-1.17.2-py3-none-any.whl",
+            ]
+        );
+    }
+}
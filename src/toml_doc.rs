@@ -0,0 +1,792 @@
+//! Format-preserving edits to TOML documents, for updating `pyproject.toml`. We find the
+//! position of each table, `key = value` item, and array element in the text, and only change
+//! the text we're editing, so comments, ordering, and whitespace stay as they were.
+
+use crate::dep_types::DependencyError;
+
+use std::{fmt, ops::Range};
+
+/// A `key = value` line.
+#[derive(Clone, Debug)]
+struct Item {
+    key: Vec<String>,
+    /// From the start of its line, to after its newline.
+    line: Range<usize>,
+    value: Range<usize>,
+}
+
+/// A `[table]` header, and the items under it. The root table has an empty name.
+#[derive(Clone, Debug)]
+struct Table {
+    name: Vec<String>,
+    /// The header's line; empty for the root table.
+    header: Range<usize>,
+    items: Vec<Item>,
+}
+
+impl Table {
+    /// The end of the table's last item, or of its header.
+    fn end(&self) -> usize {
+        self.items
+            .last()
+            .map(|i| i.line.end)
+            .unwrap_or(self.header.end)
+    }
+}
+
+/// A `key = value` pair in an inline table, with its value's position.
+type InlineItem = (Vec<String>, Range<usize>);
+
+struct Scanner<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(text: &'a str, pos: usize) -> Self {
+        Self {
+            text: text.as_bytes(),
+            pos,
+        }
+    }
+
+    fn err(&self, msg: &str) -> DependencyError {
+        let line = self.text[..self.pos.min(self.text.len())]
+            .iter()
+            .filter(|c| **c == b'\n')
+            .count();
+        DependencyError::new(&format!(
+            "Problem parsing `pyproject.toml` on line {}: {}",
+            line + 1,
+            msg
+        ))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).cloned()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        self.text[self.pos..].starts_with(s.as_bytes())
+    }
+
+    fn skip_spaces(&mut self) {
+        while let Some(b' ') | Some(b'\t') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some(b'#') {
+            while let Some(c) = self.peek() {
+                if c == b'\n' {
+                    break;
+                }
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// Skip whitespace, newlines, and comments, as allowed between array elements.
+    fn skip_all(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some(b'\n') | Some(b'\r') => self.pos += 1,
+                _ => return,
+            }
+        }
+    }
+
+    /// Skip trailing spaces and a comment, and the newline that ends the line.
+    fn finish_line(&mut self) -> Result<(), DependencyError> {
+        self.skip_spaces();
+        self.skip_comment();
+        if self.peek() == Some(b'\r') {
+            self.pos += 1;
+        }
+        match self.peek() {
+            Some(b'\n') => {
+                self.pos += 1;
+                Ok(())
+            }
+            None => Ok(()),
+            _ => Err(self.err("Expected a new line")),
+        }
+    }
+
+    /// A quoted string's contents, eg in a key. We don't need to unescape anything other than
+    /// quotes and backslashes for the keys we look up.
+    fn string(&mut self) -> Result<String, DependencyError> {
+        let start = self.pos;
+        self.scan_value()?;
+        let raw = String::from_utf8_lossy(&self.text[start + 1..self.pos - 1]).into_owned();
+        Ok(if self.text[start] == b'"' {
+            raw.replace("\\\"", "\"").replace("\\\\", "\\")
+        } else {
+            raw
+        })
+    }
+
+    /// A key, eg `name`, `"my.plugins"`, or `tool.pypackage`, split into its parts.
+    fn key(&mut self) -> Result<Vec<String>, DependencyError> {
+        let mut parts = vec![];
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                Some(b'"') | Some(b'\'') => parts.push(self.string()?),
+                _ => {
+                    let start = self.pos;
+                    while let Some(c) = self.peek() {
+                        if c.is_ascii_alphanumeric() || c == b'_' || c == b'-' {
+                            self.pos += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    if start == self.pos {
+                        return Err(self.err("Expected a key"));
+                    }
+                    parts.push(String::from_utf8_lossy(&self.text[start..self.pos]).into_owned());
+                }
+            }
+            self.skip_spaces();
+            if self.peek() == Some(b'.') {
+                self.pos += 1;
+            } else {
+                return Ok(parts);
+            }
+        }
+    }
+
+    /// Move past a value of any type.
+    fn scan_value(&mut self) -> Result<(), DependencyError> {
+        match self.peek() {
+            Some(b'"') | Some(b'\'') => {
+                let quote = self.text[self.pos];
+                let multiline = if quote == b'"' {
+                    self.starts_with("\"\"\"")
+                } else {
+                    self.starts_with("'''")
+                };
+                self.pos += if multiline { 3 } else { 1 };
+                loop {
+                    match self.peek() {
+                        None => return Err(self.err("Unterminated string")),
+                        Some(b'\n') if !multiline => {
+                            return Err(self.err("Unterminated string"));
+                        }
+                        // Literal strings don't have escapes.
+                        Some(b'\\') if quote == b'"' => self.pos += 2,
+                        Some(c) if c == quote => {
+                            if !multiline {
+                                self.pos += 1;
+                                return Ok(());
+                            }
+                            let closing = if quote == b'"' { "\"\"\"" } else { "'''" };
+                            if self.starts_with(closing) {
+                                self.pos += 3;
+                                // Up to two quotes may come right before the closing ones.
+                                while self.peek() == Some(quote) {
+                                    self.pos += 1;
+                                }
+                                return Ok(());
+                            }
+                            self.pos += 1;
+                        }
+                        _ => self.pos += 1,
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.array_elements()?;
+                Ok(())
+            }
+            Some(b'{') => {
+                self.inline_items()?;
+                Ok(())
+            }
+            _ => {
+                // Numbers, booleans, and dates.
+                let start = self.pos;
+                while let Some(c) = self.peek() {
+                    match c {
+                        b' ' | b'\t' | b'\r' | b'\n' | b',' | b']' | b'}' | b'#' => break,
+                        _ => self.pos += 1,
+                    }
+                }
+                if start == self.pos {
+                    return Err(self.err("Expected a value"));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Move past an array, returning the positions of its elements.
+    fn array_elements(&mut self) -> Result<Vec<Range<usize>>, DependencyError> {
+        let mut result = vec![];
+        self.pos += 1;
+        loop {
+            self.skip_all();
+            match self.peek() {
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(result);
+                }
+                None => return Err(self.err("Unterminated array")),
+                _ => (),
+            }
+            let start = self.pos;
+            self.scan_value()?;
+            result.push(start..self.pos);
+            self.skip_all();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => (),
+                _ => return Err(self.err("Expected `,` or `]` in an array")),
+            }
+        }
+    }
+
+    /// Move past an inline table, returning the keys and value positions of its items.
+    fn inline_items(&mut self) -> Result<Vec<InlineItem>, DependencyError> {
+        let mut result = vec![];
+        self.pos += 1;
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(result);
+                }
+                None | Some(b'\n') => return Err(self.err("Unterminated inline table")),
+                _ => (),
+            }
+            let key = self.key()?;
+            if self.peek() != Some(b'=') {
+                return Err(self.err("Expected `=`"));
+            }
+            self.pos += 1;
+            self.skip_spaces();
+            let start = self.pos;
+            self.scan_value()?;
+            result.push((key, start..self.pos));
+            self.skip_spaces();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => (),
+                _ => return Err(self.err("Expected `,` or `}` in an inline table")),
+            }
+        }
+    }
+
+    fn line_start(&self, pos: usize) -> usize {
+        self.text[..pos]
+            .iter()
+            .rposition(|c| *c == b'\n')
+            .map(|i| i + 1)
+            .unwrap_or(0)
+    }
+
+    fn tables(&mut self) -> Result<Vec<Table>, DependencyError> {
+        let mut result = vec![Table {
+            name: vec![],
+            header: 0..0,
+            items: vec![],
+        }];
+        loop {
+            self.skip_all();
+            if self.peek().is_none() {
+                return Ok(result);
+            }
+            let line_start = self.line_start(self.pos);
+
+            if self.peek() == Some(b'[') {
+                // Arrays of tables, eg `[[tool.poetry.source]]`, are kept as separate tables.
+                let brackets = if self.starts_with("[[") { 2 } else { 1 };
+                self.pos += brackets;
+                let name = self.key()?;
+                for _ in 0..brackets {
+                    if self.peek() != Some(b']') {
+                        return Err(self.err("Expected `]`"));
+                    }
+                    self.pos += 1;
+                }
+                self.finish_line()?;
+                result.push(Table {
+                    name,
+                    header: line_start..self.pos,
+                    items: vec![],
+                });
+                continue;
+            }
+
+            let key = self.key()?;
+            if self.peek() != Some(b'=') {
+                return Err(self.err("Expected `=`"));
+            }
+            self.pos += 1;
+            self.skip_spaces();
+            let value_start = self.pos;
+            self.scan_value()?;
+            let value = value_start..self.pos;
+            self.finish_line()?;
+            result.last_mut().unwrap().items.push(Item {
+                key,
+                line: line_start..self.pos,
+                value,
+            });
+        }
+    }
+}
+
+/// Split a dotted name, eg `tool.pypackage.dependencies`.
+fn split_name(name: &str) -> Vec<String> {
+    if name.is_empty() {
+        return vec![];
+    }
+    name.split('.').map(str::to_owned).collect()
+}
+
+/// A key as written in TOML, quoted if it needs to be.
+fn format_key(key: &str) -> String {
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare {
+        key.to_owned()
+    } else {
+        toml::Value::String(key.to_owned()).to_string()
+    }
+}
+
+/// A TOML document, which we can edit without changing its formatting.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Document {
+    text: String,
+}
+
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Document {
+    pub fn parse(text: &str) -> Result<Self, DependencyError> {
+        let result = Self {
+            text: text.to_owned(),
+        };
+        result.tables()?;
+        Ok(result)
+    }
+
+    fn tables(&self) -> Result<Vec<Table>, DependencyError> {
+        Scanner::new(&self.text, 0).tables()
+    }
+
+    fn table(&self, name: &str) -> Option<Table> {
+        let name = split_name(name);
+        self.tables().ok()?.into_iter().find(|t| t.name == name)
+    }
+
+    fn item(&self, table: &str, key: &str) -> Option<Item> {
+        self.table(table)?
+            .items
+            .into_iter()
+            .find(|i| i.key.len() == 1 && i.key[0] == key)
+    }
+
+    fn replace(&mut self, range: Range<usize>, new: &str) {
+        self.text.replace_range(range, new);
+    }
+
+    pub fn has_table(&self, name: &str) -> bool {
+        self.table(name).is_some()
+    }
+
+    /// The keys in a table, in order.
+    pub fn keys(&self, table: &str) -> Vec<String> {
+        match self.table(table) {
+            Some(t) => t
+                .items
+                .into_iter()
+                .filter(|i| i.key.len() == 1)
+                .map(|i| i.key[0].clone())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Set a key to a value, given as TOML text. Adds the key after the table's last item if
+    /// it's not there, and adds the table at the end of the document if it's not there.
+    pub fn set(&mut self, table: &str, key: &str, value: &str) {
+        if let Some(item) = self.item(table, key) {
+            self.replace(item.value, value);
+            return;
+        }
+
+        let line = format!("{} = {}\n", format_key(key), value);
+        match self.table(table) {
+            Some(t) => {
+                let end = t.end();
+                // Match the indentation of the item before.
+                let indent: String = match t.items.last() {
+                    Some(i) => self.text[i.line.clone()]
+                        .chars()
+                        .take_while(|c| *c == ' ' || *c == '\t')
+                        .collect(),
+                    None => String::new(),
+                };
+                let newline = if end > 0 && !self.text[..end].ends_with('\n') {
+                    "\n"
+                } else {
+                    ""
+                };
+                self.replace(end..end, &format!("{}{}{}", newline, indent, line));
+            }
+            None => {
+                if !self.text.is_empty() && !self.text.ends_with('\n') {
+                    self.text.push('\n');
+                }
+                if !self.text.trim().is_empty() {
+                    self.text.push('\n');
+                }
+                let header = split_name(table)
+                    .iter()
+                    .map(|p| format_key(p))
+                    .collect::<Vec<String>>()
+                    .join(".");
+                self.text.push_str(&format!("[{}]\n{}", header, line));
+            }
+        }
+    }
+
+    /// Remove a key, and a sub-table with its name if there is one, eg
+    /// `[tool.poetry.dependencies.numpy]`. Returns true if anything was removed.
+    pub fn remove(&mut self, table: &str, key: &str) -> bool {
+        let mut removed = false;
+        if let Some(item) = self.item(table, key) {
+            self.replace(item.line, "");
+            removed = true;
+        }
+
+        let mut sub_name = split_name(table);
+        sub_name.push(key.to_owned());
+        if let Ok(tables) = self.tables() {
+            // Remove from the end, so earlier positions stay valid.
+            for t in tables.iter().rev() {
+                if t.name.starts_with(&sub_name) {
+                    self.replace(t.header.start..t.end(), "");
+                    removed = true;
+                }
+            }
+        }
+        removed
+    }
+
+    fn elements(&self, table: &str, key: &str) -> Option<(Item, Vec<Range<usize>>)> {
+        let item = self.item(table, key)?;
+        if !self.text[item.value.clone()].starts_with('[') {
+            return None;
+        }
+        let elements = Scanner::new(&self.text, item.value.start)
+            .array_elements()
+            .ok()?;
+        Some((item, elements))
+    }
+
+    /// The TOML text of each element in an array.
+    pub fn array_items(&self, table: &str, key: &str) -> Option<Vec<String>> {
+        self.elements(table, key).map(|(_, elements)| {
+            elements
+                .into_iter()
+                .map(|e| self.text[e].to_owned())
+                .collect()
+        })
+    }
+
+    /// Add an element, given as TOML text, to the end of an array. If the array's elements are
+    /// on separate lines, the new one gets its own line. Adds the key if it's not there.
+    pub fn array_push(&mut self, table: &str, key: &str, value: &str) {
+        let (item, elements) = match self.elements(table, key) {
+            Some(e) => e,
+            None => {
+                self.set(table, key, &format!("[\n    {},\n]", value));
+                return;
+            }
+        };
+        let array = item.value.clone();
+        let closing = array.end - 1;
+
+        let last = match elements.last() {
+            Some(l) => l.clone(),
+            None => {
+                let open = array.start + 1;
+                if self.text[array.clone()].contains('\n') {
+                    let line_end = open + self.text[open..].find('\n').unwrap_or(0) + 1;
+                    self.replace(line_end..line_end, &format!("    {},\n", value));
+                } else {
+                    self.replace(open..open, value);
+                }
+                return;
+            }
+        };
+
+        if !self.text[last.end..closing].contains('\n') {
+            // eg `["a", "b"]`, or a closing bracket on the same line as the last element.
+            self.replace(last.end..last.end, &format!(", {}", value));
+            return;
+        }
+        let indent: String = {
+            let line_start = Scanner::new(&self.text, 0).line_start(last.start);
+            self.text[line_start..last.start]
+                .chars()
+                .take_while(|c| *c == ' ' || *c == '\t')
+                .collect()
+        };
+        let mut scanner = Scanner::new(&self.text, last.end);
+        scanner.skip_spaces();
+        let has_comma = scanner.peek() == Some(b',');
+        if has_comma {
+            scanner.pos += 1;
+        }
+        scanner.skip_spaces();
+        scanner.skip_comment();
+        let line_end = scanner.pos;
+
+        self.replace(line_end..line_end, &format!("\n{}{},", indent, value));
+        if !has_comma {
+            self.replace(last.end..last.end, ",");
+        }
+    }
+
+    /// Replace an array element with a value, given as TOML text.
+    pub fn array_replace(&mut self, table: &str, key: &str, index: usize, value: &str) {
+        if let Some((_, elements)) = self.elements(table, key) {
+            if let Some(e) = elements.get(index) {
+                self.replace(e.clone(), value);
+            }
+        }
+    }
+
+    /// Remove an element from an array. If it's on its own line, remove the line, including
+    /// any comment on it.
+    pub fn array_remove(&mut self, table: &str, key: &str, index: usize) {
+        let (_, elements) = match self.elements(table, key) {
+            Some(e) => e,
+            None => return,
+        };
+        let element = match elements.get(index) {
+            Some(e) => e.clone(),
+            None => return,
+        };
+
+        let line_start = Scanner::new(&self.text, 0).line_start(element.start);
+        let own_line_start = self.text[line_start..element.start].trim().is_empty();
+        let mut scanner = Scanner::new(&self.text, element.end);
+        scanner.skip_spaces();
+        let comma_end = if scanner.peek() == Some(b',') {
+            scanner.pos += 1;
+            Some(scanner.pos)
+        } else {
+            None
+        };
+        scanner.skip_spaces();
+        scanner.skip_comment();
+
+        if own_line_start && scanner.peek() == Some(b'\n') {
+            // Remove comment lines directly above it too, since they're likely about it.
+            let mut start = line_start;
+            while start > 0 {
+                let prev = Scanner::new(&self.text, 0).line_start(start - 1);
+                if !self.text[prev..start].trim_start().starts_with('#') {
+                    break;
+                }
+                start = prev;
+            }
+            self.replace(start..scanner.pos + 1, "");
+        } else if let Some(end) = comma_end {
+            // eg `["a", "b"]`: Remove `"a", `.
+            let mut scanner = Scanner::new(&self.text, end);
+            scanner.skip_spaces();
+            self.replace(element.start..scanner.pos, "");
+        } else if index > 0 {
+            // The last element: Remove the comma before it.
+            self.replace(elements[index - 1].end..element.end, "");
+        } else {
+            self.replace(element, "");
+        }
+    }
+
+    /// Set a key in an inline table value, eg the `version` in
+    /// `ipython = {version = "^7.7.0", extras = ["qtconsole"]}`. Returns false if the value
+    /// isn't an inline table.
+    pub fn set_inline(&mut self, table: &str, key: &str, inline_key: &str, value: &str) -> bool {
+        let item = match self.item(table, key) {
+            Some(i) => i,
+            None => return false,
+        };
+        if !self.text[item.value.clone()].starts_with('{') {
+            return false;
+        }
+        let entries = match Scanner::new(&self.text, item.value.start).inline_items() {
+            Ok(e) => e,
+            Err(_) => return false,
+        };
+
+        match entries
+            .iter()
+            .find(|(k, _)| k.len() == 1 && k[0] == inline_key)
+        {
+            Some((_, range)) => self.replace(range.clone(), value),
+            None => {
+                let entry = format!("{} = {}", format_key(inline_key), value);
+                match entries.last() {
+                    Some((_, range)) => {
+                        self.replace(range.end..range.end, &format!(", {}", entry));
+                    }
+                    None => {
+                        let open = item.value.start + 1;
+                        self.replace(open..item.value.end - 1, &entry);
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    const PYPROJECT: &str = r#"# Project settings.
+[project]
+name = "my-pkg"  # The name on PyPi.
+dependencies = [
+    "numpy>=1.16.4",  # For arrays.
+    # Pinned, since 2.x is broken.
+    "requests==2.22.0",
+    'pywin32 >= 1.0; sys_platform == "win32"'
+]
+keywords = ["a", "b"]
+
+[tool.pypackage.dependencies]
+saturn = "^0.3.1" # comment
+"ipython" = { version = "^7.7.0", extras = ["qtconsole"] }
+multi = """a
+[not.a.table]
+"""
+
+[tool.pypackage.dependencies.matplotlib]
+version = "3.1.1"
+
+[tool.black]
+line-length = 100
+"#;
+
+    #[test]
+    fn read() {
+        let doc = Document::parse(PYPROJECT).unwrap();
+        assert_eq!(doc.to_string(), PYPROJECT);
+        assert!(doc.has_table("project"));
+        assert!(!doc.has_table("not.a.table"));
+        assert_eq!(
+            doc.keys("tool.pypackage.dependencies"),
+            vec!["saturn", "ipython", "multi"]
+        );
+        assert_eq!(
+            doc.array_items("project", "dependencies").unwrap(),
+            vec![
+                "\"numpy>=1.16.4\"",
+                "\"requests==2.22.0\"",
+                "'pywin32 >= 1.0; sys_platform == \"win32\"'"
+            ]
+        );
+
+        assert!(Document::parse("[project\nname = 1\n").is_err());
+        assert!(Document::parse("a = [1, 2\n").is_err());
+        assert!(Document::parse("a = \"b\" c\n").is_err());
+    }
+
+    #[test]
+    fn edit_tables() {
+        let mut doc = Document::parse(PYPROJECT).unwrap();
+        let deps = "tool.pypackage.dependencies";
+        doc.set(deps, "saturn", "\"^0.4.0\"");
+        doc.set(deps, "scipy", "\"^1.3.1\"");
+        assert!(doc.set_inline(deps, "ipython", "version", "\"^7.8.0\""));
+        assert!(!doc.set_inline(deps, "saturn", "version", "\"^7.8.0\""));
+        assert!(doc.remove(deps, "matplotlib"));
+        assert!(!doc.remove(deps, "missing"));
+        assert!(doc.to_string().contains(
+            r#"[tool.pypackage.dependencies]
+saturn = "^0.4.0" # comment
+"ipython" = { version = "^7.8.0", extras = ["qtconsole"] }
+multi = """a
+[not.a.table]
+"""
+scipy = "^1.3.1"
+
+
+[tool.black]"#
+        ));
+
+        doc.remove(deps, "ipython");
+        doc.set("tool.poetry.dependencies", "python", "\"^3.7\"");
+        assert!(doc
+            .to_string()
+            .starts_with("# Project settings.\n[project]\n"));
+        assert!(doc
+            .to_string()
+            .ends_with("line-length = 100\n\n[tool.poetry.dependencies]\npython = \"^3.7\"\n"));
+        assert_eq!(doc.keys(deps), vec!["saturn", "multi", "scipy"]);
+        assert!(Document::parse(&doc.to_string()).is_ok());
+        assert!(toml::from_str::<toml::Value>(&doc.to_string()).is_ok());
+    }
+
+    #[test]
+    fn edit_arrays() {
+        let mut doc = Document::parse(PYPROJECT).unwrap();
+        doc.array_push("project", "dependencies", "\"scipy\"");
+        doc.array_replace("project", "dependencies", 0, "\"numpy>=1.17\"");
+        doc.array_remove("project", "dependencies", 1);
+        doc.array_push("project", "keywords", "\"c\"");
+        doc.array_remove("project", "keywords", 0);
+        assert!(doc.to_string().starts_with(
+            r#"# Project settings.
+[project]
+name = "my-pkg"  # The name on PyPi.
+dependencies = [
+    "numpy>=1.17",  # For arrays.
+    'pywin32 >= 1.0; sys_platform == "win32"',
+    "scipy",
+]
+keywords = ["b", "c"]
+"#
+        ));
+
+        // Removing the last element on a line with the closing bracket.
+        let mut doc = Document::parse("a = [\n  1,\n  2]\nb = []\nc = [\n]\n").unwrap();
+        doc.array_remove("", "a", 1);
+        doc.array_push("", "a", "3");
+        doc.array_push("", "b", "4");
+        doc.array_push("", "c", "5");
+        doc.array_push("", "d", "6");
+        assert_eq!(
+            doc.to_string(),
+            "a = [\n  1, 3]\nb = [4]\nc = [\n    5,\n]\nd = [\n    6,\n]\n"
+        );
+
+        // Comments directly above an element go with it.
+        let mut doc =
+            Document::parse("a = [\n  # One.\n\n  # Two.\n  # 2.0 breaks.\n  2,\n  3,\n]\n")
+                .unwrap();
+        doc.array_remove("", "a", 0);
+        assert_eq!(doc.to_string(), "a = [\n  # One.\n\n  3,\n]\n");
+    }
+}
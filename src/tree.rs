@@ -0,0 +1,576 @@
+//! The locked dependency graph: Display it as a tree, export it for graph tools, or explain
+//! why a package is installed.
+
+use crate::{
+    dep_resolution,
+    dep_types::{self, Constraint, LockPackage, Req, ReqType, Version, MAX_VER},
+    index::PackageIndex,
+    util,
+};
+
+use serde::Serialize;
+use std::{collections::HashSet, str::FromStr};
+
+/// The node our own requirements come from.
+pub const ROOT: &str = "pyproject.toml";
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Node {
+    /// The standardized name; what edges refer to.
+    pub id: String,
+    pub name: String,
+    /// `None` for the project itself.
+    pub version: Option<String>,
+}
+
+/// A package requiring another, or the project requiring a package.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+    /// The requirement, eg `>=2.5, <3`, or `*` if it allows any version.
+    pub constraint: String,
+    #[serde(skip)]
+    pub constraints: Vec<Constraint>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub marker: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+fn constraint_text(constraints: &[Constraint]) -> String {
+    if constraints.is_empty() {
+        return "*".to_owned();
+    }
+    constraints
+        .iter()
+        .map(|c| c.to_string(false, false))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+impl Graph {
+    /// Build the graph from the lock file. `reqs` are the project's requirements, including
+    /// groups and extras. We find the requirement behind each edge from the package's metadata.
+    pub fn new(index: &dyn PackageIndex, reqs: &[Req], lock_packs: &[LockPackage]) -> Self {
+        Self::from_parts(reqs, lock_packs, |lp| {
+            let version = Version::from_str(&lp.version).unwrap_or_default();
+            dep_resolution::get_requirements_or_warn(index, &lp.name, &version)
+        })
+    }
+
+    fn from_parts<F>(reqs: &[Req], lock_packs: &[LockPackage], mut requirements: F) -> Self
+    where
+        F: FnMut(&LockPackage) -> Vec<Req>,
+    {
+        let mut result = Self::default();
+        result.nodes.push(Node {
+            id: ROOT.to_owned(),
+            name: ROOT.to_owned(),
+            version: None,
+        });
+        for lp in lock_packs.iter() {
+            result.nodes.push(Node {
+                id: util::standardize_name(&lp.name),
+                name: lp.name.clone(),
+                version: Some(lp.version.clone()),
+            });
+        }
+
+        for req in reqs.iter() {
+            let to = util::standardize_name(&req.name);
+            if result.node(&to).is_none() {
+                continue; // eg not needed on any platform we lock for.
+            }
+            let edge = Edge {
+                from: ROOT.to_owned(),
+                to,
+                constraint: constraint_text(&req.constraints),
+                constraints: req.constraints.clone(),
+                marker: req.marker.as_ref().map(|m| m.to_string()),
+            };
+            // eg a group that repeats a main requirement.
+            if !result.edges.contains(&edge) {
+                result.edges.push(edge);
+            }
+        }
+
+        for lp in lock_packs.iter() {
+            let deps = lp.dependencies.clone().unwrap_or_default();
+            if deps.is_empty() {
+                continue;
+            }
+            let package_reqs = requirements(lp);
+            for dep in deps.iter() {
+                let (name, _, marker) = crate::parse_lock_dep(dep);
+                let constraints = package_reqs
+                    .iter()
+                    .find(|r| util::compare_names(&r.name, &name))
+                    .map(|r| r.constraints.clone());
+                result.edges.push(Edge {
+                    from: util::standardize_name(&lp.name),
+                    to: util::standardize_name(&name),
+                    // We couldn't find the requirement, eg if its metadata isn't available.
+                    constraint: constraints
+                        .as_ref()
+                        .map(|c| constraint_text(c))
+                        .unwrap_or_default(),
+                    constraints: constraints.unwrap_or_default(),
+                    marker: marker.map(|m| m.to_string()),
+                });
+            }
+        }
+        result
+    }
+
+    pub fn node(&self, id: &str) -> Option<&Node> {
+        self.nodes.iter().find(|n| n.id == id)
+    }
+
+    fn label(&self, id: &str) -> String {
+        match self.node(id) {
+            Some(Node {
+                name,
+                version: Some(version),
+                ..
+            }) => format!("{} {}", name, version),
+            Some(node) => node.name.clone(),
+            None => id.to_owned(),
+        }
+    }
+
+    /// The edges out of a node, or into it if `invert` is true, sorted by the other node.
+    pub fn next(&self, id: &str, invert: bool) -> Vec<&Edge> {
+        let mut result: Vec<&Edge> = self
+            .edges
+            .iter()
+            .filter(|e| if invert { e.to == id } else { e.from == id })
+            .collect();
+        result.sort_by_key(|e| if invert { &e.from } else { &e.to });
+        result
+    }
+
+    /// Render the tree below `start` as text, down to `max_depth` levels. If `invert` is true,
+    /// we show what requires each package instead. Return the edges shown too. Packages
+    /// shown more than once are expanded the first time, and marked with `(*)` after that.
+    pub fn render(
+        &self,
+        start: &str,
+        invert: bool,
+        max_depth: Option<usize>,
+    ) -> (Vec<String>, Vec<&Edge>) {
+        let mut lines = vec![];
+        let mut shown = vec![];
+        if start != ROOT || invert {
+            lines.push(self.label(start));
+        }
+        let mut seen = HashSet::new();
+        seen.insert(start.to_owned());
+        self.render_below(
+            start, invert, "", 1, max_depth, &mut seen, &mut lines, &mut shown,
+        );
+        (lines, shown)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn render_below<'a>(
+        &'a self,
+        id: &str,
+        invert: bool,
+        prefix: &str,
+        depth: usize,
+        max_depth: Option<usize>,
+        seen: &mut HashSet<String>,
+        lines: &mut Vec<String>,
+        shown: &mut Vec<&'a Edge>,
+    ) {
+        if let Some(max) = max_depth {
+            if depth > max {
+                return;
+            }
+        }
+        let edges = self.next(id, invert);
+        for (i, edge) in edges.iter().enumerate() {
+            shown.push(edge);
+            let last = i == edges.len() - 1;
+            let child = if invert { &edge.from } else { &edge.to };
+
+            let mut line = format!(
+                "{}{}{}",
+                prefix,
+                if last { "└── " } else { "├── " },
+                self.label(child)
+            );
+            // Leave out `*`, so it's not confused with the mark for repeated packages.
+            let mut requirement = if edge.constraint == "*" {
+                String::new()
+            } else {
+                edge.constraint.clone()
+            };
+            if let Some(marker) = &edge.marker {
+                if !requirement.is_empty() {
+                    requirement.push_str("; ");
+                }
+                requirement.push_str(marker);
+            }
+            if !requirement.is_empty() {
+                line.push_str(&format!(" ({})", requirement));
+            }
+            let repeated = seen.contains(child) && !self.next(child, invert).is_empty();
+            if repeated {
+                line.push_str(" (*)");
+            }
+            lines.push(line);
+
+            // Only mark packages seen once we expand them, so one first reached at `max_depth`
+            // is still expanded where it's shallower.
+            let expand = max_depth.map(|max| depth < max).unwrap_or(true);
+            if !repeated && expand {
+                seen.insert(child.clone());
+                let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+                self.render_below(
+                    child,
+                    invert,
+                    &child_prefix,
+                    depth + 1,
+                    max_depth,
+                    seen,
+                    lines,
+                    shown,
+                );
+            }
+        }
+    }
+
+    /// Only the nodes and edges in `edges`, and `start`.
+    pub fn subgraph(&self, start: &str, edges: &[&Edge]) -> Self {
+        let ids: HashSet<&str> = edges
+            .iter()
+            .flat_map(|e| vec![e.from.as_str(), e.to.as_str()])
+            .chain(vec![start])
+            .collect();
+        let mut result_edges: Vec<Edge> = vec![];
+        for edge in edges.iter() {
+            if !result_edges.contains(edge) {
+                result_edges.push((*edge).clone());
+            }
+        }
+        Self {
+            nodes: self
+                .nodes
+                .iter()
+                .filter(|n| ids.contains(n.id.as_str()))
+                .cloned()
+                .collect(),
+            edges: result_edges,
+        }
+    }
+
+    /// The graph in [Graphviz](https://graphviz.org/) format.
+    pub fn to_dot(&self) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut result = "digraph dependencies {\n".to_owned();
+        for node in self.nodes.iter() {
+            result.push_str(&format!(
+                "    {} [label={}];\n",
+                quote(&node.id),
+                quote(&self.label(&node.id))
+            ));
+        }
+        for edge in self.edges.iter() {
+            let mut label = edge.constraint.clone();
+            if let Some(marker) = &edge.marker {
+                label.push_str(&format!("; {}", marker));
+            }
+            result.push_str(&format!(
+                "    {} -> {} [label={}];\n",
+                quote(&edge.from),
+                quote(&edge.to),
+                quote(&label)
+            ));
+        }
+        result.push_str("}\n");
+        result
+    }
+}
+
+/// Describe a range from `intersection_many`, eg `>=1.21.1, <1.26`. Its bounds are inclusive,
+/// and `<1.26` is represented with a maximum of `1.25.999999`.
+fn range_text(range: &(Version, Version)) -> String {
+    let (min, max) = range;
+    if min == max {
+        return format!("=={}", min.to_string2());
+    }
+    let mut parts = vec![];
+    if *min != Version::new(0, 0, 0) {
+        parts.push(format!(">={}", min.to_string2()));
+    }
+    if max.major() < MAX_VER {
+        parts.push(if max.minor() == MAX_VER && max.patch() == MAX_VER {
+            format!("<{}", max.major() + 1)
+        } else if max.patch() == MAX_VER {
+            format!("<{}.{}", max.major(), max.minor() + 1)
+        } else {
+            format!("<={}", max.to_string2())
+        });
+    }
+    if parts.is_empty() {
+        "any version".to_owned()
+    } else {
+        parts.join(", ")
+    }
+}
+
+impl Graph {
+    /// Every chain of requirements from `pyproject.toml` to a package, without cycles.
+    pub fn paths(&self, id: &str) -> Vec<Vec<&Edge>> {
+        let mut result = vec![];
+        self.paths_to(id, &mut vec![], &mut result);
+        for path in result.iter_mut() {
+            path.reverse();
+        }
+        result.sort_by_key(|path| path.iter().map(|e| e.to.clone()).collect::<Vec<String>>());
+        result
+    }
+
+    fn paths_to<'a>(&'a self, id: &str, path: &mut Vec<&'a Edge>, result: &mut Vec<Vec<&'a Edge>>) {
+        for edge in self.next(id, true) {
+            if path.iter().any(|e| e.to == edge.from) || edge.to == edge.from {
+                continue; // A cycle.
+            }
+            path.push(edge);
+            if edge.from == ROOT {
+                result.push(path.clone());
+            } else {
+                self.paths_to(&edge.from, path, result);
+            }
+            path.pop();
+        }
+    }
+
+    /// Explain why a package is locked at its version: Each chain of requirements leading to
+    /// it, and the range of versions they allow together.
+    pub fn explain(&self, id: &str) -> Vec<String> {
+        let label = self.label(id);
+        let paths = self.paths(id);
+        if paths.is_empty() {
+            return vec![format!("Nothing requires {}", label)];
+        }
+
+        let mut lines = vec![format!("{} is required by:", label)];
+        let mut direct: Vec<&Edge> = vec![];
+        for path in paths.iter() {
+            let mut line = ROOT.to_owned();
+            for (i, edge) in path.iter().enumerate() {
+                // We show the locked version of intermediate packages, since it determines
+                // their requirements.
+                let node = if i == path.len() - 1 {
+                    self.node(&edge.to)
+                        .map(|n| n.name.clone())
+                        .unwrap_or_default()
+                } else {
+                    self.label(&edge.to)
+                };
+                let mut requirement = edge.constraint.clone();
+                if let Some(marker) = &edge.marker {
+                    requirement.push_str(&format!("; {}", marker));
+                }
+                line.push_str(&format!(" -> {} ({})", node, requirement));
+            }
+            lines.push(format!("  {}", line));
+            let last = path[path.len() - 1];
+            if !direct.contains(&last) {
+                direct.push(last);
+            }
+        }
+
+        let constraints: Vec<Constraint> = direct
+            .iter()
+            .flat_map(|e| e.constraints.iter().cloned())
+            .collect();
+        let name = self.node(id).map(|n| n.name.clone()).unwrap_or_default();
+        let ranges = dep_types::intersection_many(&constraints);
+        if ranges.is_empty() {
+            lines.push(format!("No version of {} meets all of these", name));
+        } else {
+            let mut allowed = ranges
+                .iter()
+                .map(range_text)
+                .collect::<Vec<String>>()
+                .join(" or ");
+            // `intersection_many` doesn't account for exclusions.
+            for c in constraints.iter().filter(|c| c.type_ == ReqType::Ne) {
+                allowed.push_str(&format!(", !={}", c.version.to_string2()));
+            }
+            lines.push(format!("Together, these allow {} {}", name, allowed));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::dep_types::{tests::lock_pack, ReqType};
+
+    pub fn graph() -> Graph {
+        let packs = vec![
+            lock_pack("requests", "2.22.0", &["urllib3", "idna"]),
+            lock_pack("urllib3", "1.25.7", &[]),
+            lock_pack("idna", "2.8", &[]),
+            lock_pack("botocore", "1.13.0", &["urllib3"]),
+        ];
+        let gte = |name: &str, major, minor| {
+            Req::new(
+                name.into(),
+                vec![Constraint::new(ReqType::Gte, Version::new(major, minor, 0))],
+            )
+        };
+        let mut reqs = vec![gte("Requests", 2, 20), gte("botocore", 1, 0)];
+        reqs[1].marker = crate::markers::Marker::from_str("python_version >= \"3.6\"").ok();
+
+        Graph::from_parts(&reqs, &packs, |lp| match lp.name.as_ref() {
+            "requests" => vec![
+                Req::new(
+                    "urllib3".into(),
+                    vec![
+                        Constraint::new(ReqType::Gte, Version::new(1, 21, 1)),
+                        Constraint::new(ReqType::Lt, Version::new(1, 26, 0)),
+                    ],
+                ),
+                Req::new("idna".into(), vec![]),
+            ],
+            "botocore" => vec![gte("urllib3", 1, 20)],
+            _ => vec![],
+        })
+    }
+
+    #[test]
+    fn render_tree() {
+        let graph = graph();
+        let (lines, shown) = graph.render(ROOT, false, None);
+        assert_eq!(
+            lines,
+            vec![
+                "├── botocore 1.13.0 (>=1.0.0; python_version >= \"3.6\")",
+                "│   └── urllib3 1.25.7 (>=1.20.0)",
+                "└── requests 2.22.0 (>=2.20.0)",
+                "    ├── idna 2.8",
+                "    └── urllib3 1.25.7 (>=1.21.1, <1.26.0)",
+            ]
+        );
+        assert_eq!(shown.len(), 5);
+
+        let (lines, _) = graph.render(ROOT, false, Some(1));
+        assert_eq!(lines.len(), 2);
+
+        let (lines, shown) = graph.render("urllib3", true, None);
+        assert_eq!(
+            lines,
+            vec![
+                "urllib3 1.25.7",
+                "├── botocore 1.13.0 (>=1.20.0)",
+                "│   └── pyproject.toml (>=1.0.0; python_version >= \"3.6\")",
+                "└── requests 2.22.0 (>=1.21.1, <1.26.0)",
+                "    └── pyproject.toml (>=2.20.0)",
+            ]
+        );
+        let sub = graph.subgraph("urllib3", &shown);
+        assert_eq!(sub.nodes.len(), 4);
+        assert!(sub.node("idna").is_none());
+    }
+
+    #[test]
+    fn render_depth() {
+        // `requests` is both a requirement of the project, and of `awscli`.
+        let packs = vec![
+            lock_pack("awscli", "1.16.0", &["requests"]),
+            lock_pack("requests", "2.22.0", &["idna"]),
+            lock_pack("idna", "2.8", &[]),
+        ];
+        let reqs = vec![
+            Req::new("awscli".into(), vec![]),
+            Req::new("requests".into(), vec![]),
+        ];
+        let graph = Graph::from_parts(&reqs, &packs, |lp| match lp.name.as_ref() {
+            "awscli" => vec![Req::new("requests".into(), vec![])],
+            "requests" => vec![Req::new("idna".into(), vec![])],
+            _ => vec![],
+        });
+
+        let (lines, _) = graph.render(ROOT, false, Some(2));
+        assert_eq!(
+            lines,
+            vec![
+                "├── awscli 1.16.0",
+                "│   └── requests 2.22.0",
+                "└── requests 2.22.0",
+                "    └── idna 2.8",
+            ]
+        );
+        let (lines, _) = graph.render(ROOT, false, None);
+        assert_eq!(
+            lines,
+            vec![
+                "├── awscli 1.16.0",
+                "│   └── requests 2.22.0",
+                "│       └── idna 2.8",
+                "└── requests 2.22.0 (*)",
+            ]
+        );
+    }
+
+    #[test]
+    fn export() {
+        let graph = graph();
+        let (_, shown) = graph.render("idna", true, None);
+        let sub = graph.subgraph("idna", &shown);
+        assert_eq!(
+            sub.to_dot(),
+            "digraph dependencies {
+    \"pyproject.toml\" [label=\"pyproject.toml\"];
+    \"requests\" [label=\"requests 2.22.0\"];
+    \"idna\" [label=\"idna 2.8\"];
+    \"requests\" -> \"idna\" [label=\"*\"];
+    \"pyproject.toml\" -> \"requests\" [label=\">=2.20.0\"];
+}
+"
+        );
+        assert_eq!(
+            serde_json::to_string(&sub.edges[0]).unwrap(),
+            r#"{"from":"requests","to":"idna","constraint":"*"}"#
+        );
+    }
+
+    #[test]
+    fn explain_package() {
+        let graph = graph();
+        assert_eq!(
+            graph.explain("urllib3"),
+            vec![
+                "urllib3 1.25.7 is required by:",
+                "  pyproject.toml -> botocore 1.13.0 (>=1.0.0; python_version >= \"3.6\") -> urllib3 (>=1.20.0)",
+                "  pyproject.toml -> requests 2.22.0 (>=2.20.0) -> urllib3 (>=1.21.1, <1.26.0)",
+                "Together, these allow urllib3 >=1.21.1, <1.26",
+            ]
+        );
+        assert_eq!(
+            graph.explain("idna")[2],
+            "Together, these allow idna any version"
+        );
+        assert_eq!(graph.paths("requests").len(), 1);
+    }
+
+    #[test]
+    fn ranges() {
+        let v = |major, minor, patch| Version::new(major, minor, patch);
+        assert_eq!(range_text(&(v(1, 2, 0), v(1, 2, 0))), "==1.2.0");
+        assert_eq!(range_text(&(v(1, 2, 0), v(MAX_VER, 0, 0))), ">=1.2.0");
+        assert_eq!(range_text(&(v(0, 0, 0), v(2, MAX_VER, MAX_VER))), "<3");
+        assert_eq!(range_text(&(v(0, 0, 0), v(2, 4, 1))), "<=2.4.1");
+    }
+}
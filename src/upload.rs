@@ -0,0 +1,576 @@
+//! Publishing with the legacy upload API, which PyPI and most other indexes support.
+//! https://warehouse.readthedocs.io/api-reference/legacy/#upload-api
+
+use crate::{
+    build, cache,
+    dep_types::{DependencyError, Version},
+    metadata, util,
+};
+
+use blake2::{
+    digest::{Input, VariableOutput},
+    VarBlake2b,
+};
+use crossterm::Color;
+use data_encoding::HEXLOWER;
+use regex::Regex;
+use ring::digest;
+use std::{collections::HashMap, env, fs, path::PathBuf, str::FromStr};
+
+pub const PYPI_URL: &str = "https://upload.pypi.org/legacy/";
+pub const TEST_PYPI_URL: &str = "https://test.pypi.org/legacy/";
+
+/// Content types PyPI can render a description as.
+const DESCRIPTION_TYPES: [&str; 3] = ["text/plain", "text/x-rst", "text/markdown"];
+
+/// Where to upload to, and the credentials `~/.pypirc` lists for it, if any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Repository {
+    pub name: String,
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Parse a `.pypirc` file, which is in INI format, into its sections, eg
+/// `[testpypi]\nrepository = https://test.pypi.org/legacy/\nusername = __token__`.
+fn parse_pypirc(text: &str) -> HashMap<String, HashMap<String, String>> {
+    let section_re = Regex::new(r"^\[(.+)\]$").unwrap();
+    let mut result: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut section: Option<String> = None;
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+        // Indented lines continue a multi-line value, like `index-servers`, which we don't use.
+        if trimmed.is_empty()
+            || trimmed.starts_with('#')
+            || trimmed.starts_with(';')
+            || line.starts_with(' ')
+            || line.starts_with('\t')
+        {
+            continue;
+        }
+        if let Some(caps) = section_re.captures(trimmed) {
+            let name = caps.get(1).unwrap().as_str().trim().to_owned();
+            result.entry(name.clone()).or_default();
+            section = Some(name);
+            continue;
+        }
+        if let (Some(s), Some(i)) = (&section, trimmed.find(&['=', ':'][..])) {
+            // eg `repository: https://...`; the url's own colon comes after the separator.
+            let (key, value) = (&trimmed[..i], &trimmed[i + 1..]);
+            if let Some(entries) = result.get_mut(s) {
+                entries.insert(key.trim().to_lowercase(), value.trim().to_owned());
+            }
+        }
+    }
+    result
+}
+
+fn pypirc_path() -> Option<PathBuf> {
+    env::var("HOME")
+        .or_else(|_| env::var("USERPROFILE"))
+        .ok()
+        .map(|h| PathBuf::from(h).join(".pypirc"))
+}
+
+/// Find a repository from its name or upload url. Names are looked up in
+/// `[tool.pypackage.repositories]`, then `~/.pypirc`, then are `pypi` or `testpypi`.
+pub fn find_repository(
+    name_or_url: &str,
+    aliases: &HashMap<String, String>,
+    pypirc: &HashMap<String, HashMap<String, String>>,
+) -> Result<Repository, DependencyError> {
+    let builtin = |name: &str| match name {
+        "pypi" => Some(PYPI_URL.to_owned()),
+        "testpypi" => Some(TEST_PYPI_URL.to_owned()),
+        _ => None,
+    };
+
+    let (name, url) = if name_or_url.contains("://") {
+        // Use credentials from `.pypirc` if it has an entry for this url.
+        let name = pypirc
+            .iter()
+            .find(|(_, s)| {
+                s.get("repository").map(|r| r.trim_end_matches('/'))
+                    == Some(name_or_url.trim_end_matches('/'))
+            })
+            .map(|(n, _)| n.clone())
+            .unwrap_or_else(|| name_or_url.to_owned());
+        (name, name_or_url.to_owned())
+    } else {
+        let url = aliases
+            .get(name_or_url)
+            .cloned()
+            .or_else(|| {
+                pypirc
+                    .get(name_or_url)
+                    .and_then(|s| s.get("repository").cloned())
+            })
+            .or_else(|| builtin(name_or_url))
+            .ok_or_else(|| {
+                DependencyError::new(&format!(
+                    "Can't find a repository named {}. Add its upload url to \
+                     `[tool.pypackage.repositories]` in `pyproject.toml`, or to `~/.pypirc`.",
+                    name_or_url
+                ))
+            })?;
+        (name_or_url.to_owned(), url)
+    };
+
+    let section = pypirc.get(&name);
+    Ok(Repository {
+        name,
+        url,
+        username: section.and_then(|s| s.get("username").cloned()),
+        password: section.and_then(|s| s.get("password").cloned()),
+    })
+}
+
+/// The environment variable holding an API token for a repository, eg
+/// `PYPACKAGE_TOKEN_TESTPYPI`.
+fn token_var(repo_name: &str) -> String {
+    let suffix: String = repo_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("PYPACKAGE_TOKEN_{}", suffix)
+}
+
+/// The username and password to upload with. A token in `PYPACKAGE_TOKEN_{REPOSITORY}` or
+/// `PYPACKAGE_TOKEN` takes precedence over credentials from `~/.pypirc`.
+fn credentials(repo: &Repository) -> Option<(String, String)> {
+    let token = env::var(token_var(&repo.name))
+        .or_else(|_| env::var("PYPACKAGE_TOKEN"))
+        .ok()
+        .filter(|t| !t.is_empty());
+    if let Some(t) = token {
+        return Some(("__token__".to_owned(), t));
+    }
+    match (&repo.username, &repo.password) {
+        (Some(u), Some(p)) => Some((u.clone(), p.clone())),
+        // Tokens don't need a username.
+        (None, Some(p)) => Some(("__token__".to_owned(), p.clone())),
+        _ => None,
+    }
+}
+
+/// The form field a metadata header is uploaded as, eg `home_page` for `Home-page`.
+fn field_name(header: &str) -> String {
+    let name = header.replace('-', "_");
+    match name.as_ref() {
+        "classifier" => "classifiers".to_owned(),
+        "project_url" => "project_urls".to_owned(),
+        _ => name,
+    }
+}
+
+/// The hex-encoded BLAKE2b digest, with a 256-bit output, that Warehouse checks uploads against.
+fn blake2_256(data: &[u8]) -> String {
+    let mut hasher = VarBlake2b::new(32).expect("Invalid BLAKE2b output size");
+    hasher.input(data);
+    HEXLOWER.encode(&hasher.vec_result())
+}
+
+/// The form fields to upload a distribution with: Its metadata, type, and digests.
+pub fn upload_fields(filename: &str, data: &[u8], metadata_text: &str) -> Vec<(String, String)> {
+    let (filetype, pyversion) = if filename.ends_with(".whl") {
+        // The Python tag, eg `py3` or `cp37`.
+        let parts: Vec<&str> = filename.trim_end_matches(".whl").split('-').collect();
+        let python = if parts.len() >= 5 {
+            parts[parts.len() - 3]
+        } else {
+            ""
+        };
+        ("bdist_wheel", python)
+    } else {
+        ("sdist", "source")
+    };
+
+    let mut result: Vec<(String, String)> = vec![
+        (":action".into(), "file_upload".into()),
+        ("protocol_version".into(), "1".into()),
+        ("filetype".into(), filetype.into()),
+        ("pyversion".into(), pyversion.into()),
+        (
+            "sha256_digest".into(),
+            HEXLOWER.encode(digest::digest(&digest::SHA256, data).as_ref()),
+        ),
+        ("blake2_256_digest".into(), blake2_256(data)),
+    ];
+
+    let (headers, body) = metadata::parse_fields(metadata_text);
+    for (header, value) in headers.into_iter() {
+        result.push((field_name(&header), value));
+    }
+    if !body.trim().is_empty() {
+        result.push(("description".into(), body));
+    }
+    result
+}
+
+/// Check a distribution's metadata before uploading, so we can report all the problems at
+/// once, instead of the index rejecting them one at a time.
+pub fn validate(filename: &str, fields: &[(String, String)]) -> Vec<String> {
+    let get = |name: &str| {
+        fields
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    };
+    let mut problems = vec![];
+
+    match get("metadata_version") {
+        Some(v) if ["1.0", "1.1", "1.2", "2.0", "2.1", "2.2"].contains(&v) => (),
+        Some(v) => problems.push(format!("Unsupported metadata version: {}", v)),
+        None => problems.push("Missing `Metadata-Version`".to_owned()),
+    }
+
+    let name = get("name").unwrap_or("");
+    let name_re = Regex::new(r"(?i)^([a-z0-9]|[a-z0-9][a-z0-9._-]*[a-z0-9])$").unwrap();
+    if !name_re.is_match(name) {
+        problems.push(format!("Invalid name: {:?}", name));
+    }
+
+    let version = get("version").unwrap_or("");
+    if Version::from_str(version).is_err() {
+        problems.push(format!("Invalid version: {:?}", version));
+    }
+
+    // eg `my_pkg-0.1.0.tar.gz`, or `my_pkg-0.1.0-py3-none-any.whl`.
+    let prefix = format!("{}-{}", build::dist_name(name), version);
+    let matches_name = filename.to_lowercase() == format!("{}.tar.gz", prefix).to_lowercase()
+        || (filename.ends_with(".whl")
+            && filename
+                .to_lowercase()
+                .starts_with(&format!("{}-", prefix).to_lowercase()));
+    if !matches_name {
+        problems.push(format!(
+            "The filename doesn't match the name and version in its metadata: {} {}",
+            name, version
+        ));
+    }
+    if filename.ends_with(".whl") && crate::tags::wheel_tags(filename).is_none() {
+        problems.push("Invalid wheel filename".to_owned());
+    }
+
+    if let Some(summary) = get("summary") {
+        if summary.contains('\n') || summary.len() > 512 {
+            problems.push("The summary must be a single line, of up to 512 characters".into());
+        }
+    }
+    if let Some(content_type) = get("description_content_type") {
+        let main_type = content_type.split(';').next().unwrap_or("").trim();
+        if !DESCRIPTION_TYPES.contains(&main_type) {
+            problems.push(format!(
+                "Unsupported description content type: {}. Use one of {}",
+                content_type,
+                DESCRIPTION_TYPES.join(", ")
+            ));
+        }
+    }
+    problems
+}
+
+/// Read a built distribution's metadata.
+fn read_metadata(filename: &str, data: &[u8]) -> Option<String> {
+    if filename.ends_with(".whl") {
+        metadata::metadata_from_wheel(data)
+    } else {
+        metadata::metadata_from_sdist(data, filename)
+    }
+}
+
+fn upload(
+    repo: &Repository,
+    username: &str,
+    password: &str,
+    filename: &str,
+    data: Vec<u8>,
+    fields: Vec<(String, String)>,
+) -> Result<(), DependencyError> {
+    let mut form = reqwest::multipart::Form::new();
+    for (name, value) in fields.into_iter() {
+        form = form.text(name, value);
+    }
+    let content = reqwest::multipart::Part::bytes(data)
+        .file_name(filename.to_owned())
+        .mime_str("application/octet-stream")
+        .expect("Problem setting the upload's content type");
+    form = form.part("content", content);
+
+    // A redirect would turn the upload into a `GET`, so report it instead of following it.
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::RedirectPolicy::none())
+        .build()
+        .map_err(|e| DependencyError::new(&format!("Problem setting up the upload: {}", e)))?;
+    let mut resp = client
+        .post(&repo.url)
+        .basic_auth(username, Some(password))
+        .multipart(form)
+        .send()
+        .map_err(|e| DependencyError::new(&format!("Problem uploading {}: {}", filename, e)))?;
+
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    if status.is_redirection() {
+        let location = resp
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .unwrap_or("another url");
+        return Err(DependencyError::new(&format!(
+            "{} redirected to {}. Is the repository url right? It should be the upload url, \
+             eg {}",
+            repo.url, location, PYPI_URL
+        )));
+    }
+
+    let body = resp.text().unwrap_or_default();
+    let reason = match status.as_u16() {
+        401 | 403 => "Check your username and password, or token.".to_owned(),
+        _ => body.lines().take(5).collect::<Vec<&str>>().join("\n"),
+    };
+    Err(DependencyError::new(&format!(
+        "Problem uploading {}: {}\n{}",
+        filename, status, reason
+    )))
+}
+
+/// Upload the current version's distributions from `dist`. `repository` is a name or upload
+/// url; if not specified, we use `package_url`, then PyPI. With `dry_run`, only check the
+/// distributions, and show what we'd upload.
+pub(crate) fn publish(cfg: &crate::Config, repository: Option<String>, dry_run: bool) {
+    let (name, version) = match (&cfg.name, &cfg.version) {
+        (Some(n), Some(v)) => (n, v),
+        _ => {
+            util::abort("A name and version are required in `pyproject.toml` to publish");
+            unreachable!()
+        }
+    };
+    if cache::is_offline() && !dry_run {
+        util::abort("Can't publish in offline mode");
+    }
+
+    let dist_dir = env::current_dir()
+        .expect("Problem finding current dir")
+        .join("dist");
+    let prefix = format!("{}-{}", build::dist_name(name), version.to_string2());
+    let mut filenames: Vec<String> = fs::read_dir(&dist_dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .filter(|f| {
+                    *f == format!("{}.tar.gz", prefix)
+                        || (f.starts_with(&format!("{}-", prefix)) && f.ends_with(".whl"))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    filenames.sort();
+    if filenames.is_empty() {
+        util::abort(&format!(
+            "Can't find distributions for {} {} in `dist`. Run `pypackage package` first.",
+            name,
+            version.to_string2()
+        ));
+    }
+
+    let pypirc = pypirc_path()
+        .and_then(|p| fs::read_to_string(p).ok())
+        .map(|t| parse_pypirc(&t))
+        .unwrap_or_default();
+    let target = repository
+        .or_else(|| cfg.package_url.clone())
+        .unwrap_or_else(|| "pypi".to_owned());
+    let repo = match find_repository(&target, &cfg.repositories, &pypirc) {
+        Ok(r) => r,
+        Err(e) => {
+            util::abort(&e.details);
+            unreachable!()
+        }
+    };
+    let creds = credentials(&repo);
+
+    // Check everything before uploading anything.
+    let mut uploads = vec![];
+    let mut problems = vec![];
+    for filename in filenames.iter() {
+        let data = fs::read(dist_dir.join(filename)).expect("Problem reading a distribution");
+        let fields = match read_metadata(filename, &data) {
+            Some(text) => upload_fields(filename, &data, &text),
+            None => {
+                problems.push(format!("{}: Can't find its metadata", filename));
+                continue;
+            }
+        };
+        for problem in validate(filename, &fields) {
+            problems.push(format!("{}: {}", filename, problem));
+        }
+        uploads.push((filename, data, fields));
+    }
+    if !problems.is_empty() {
+        util::abort(&format!(
+            "Problems with the distributions:\n{}",
+            problems.join("\n")
+        ));
+    }
+
+    if dry_run {
+        println!("Would upload to {} ({}):", repo.name, repo.url);
+        for (filename, data, fields) in uploads.iter() {
+            let sha256 = fields
+                .iter()
+                .find(|(k, _)| k == "sha256_digest")
+                .map(|(_, v)| v.as_str())
+                .unwrap_or("");
+            println!("{} ({} bytes, sha256 {})", filename, data.len(), sha256);
+        }
+        match &creds {
+            Some((user, _)) => println!("Authenticating as {}", user),
+            None => println!(
+                "No credentials found: Set `{}` or `PYPACKAGE_TOKEN`, or add them to `~/.pypirc`",
+                token_var(&repo.name)
+            ),
+        }
+        util::print_color("The distributions are valid", Color::Green);
+        return;
+    }
+
+    let (username, password) = match creds {
+        Some(c) => c,
+        None => {
+            util::abort(&format!(
+                "Can't find credentials for {}. Set an API token in `{}` or `PYPACKAGE_TOKEN`, \
+                 or add a username and password to `~/.pypirc`.",
+                repo.name,
+                token_var(&repo.name)
+            ));
+            unreachable!()
+        }
+    };
+
+    for (filename, data, fields) in uploads.into_iter() {
+        println!("Uploading {} to {}...", filename, repo.url);
+        if let Err(e) = upload(&repo, &username, &password, filename, data, fields) {
+            util::abort(&e.details);
+        }
+    }
+    util::print_color("Publish complete", Color::Green);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn repositories() {
+        let pypirc = parse_pypirc(
+            "[distutils]\nindex-servers =\n    pypi\n    private\n\n[pypi]\nusername = __token__\n\
+             password = pypi-abc\n\n[private]\nrepository: https://pypi.example.com/legacy/\n\
+             username = me\npassword = secret\n",
+        );
+        assert_eq!(pypirc.len(), 3);
+        assert_eq!(
+            pypirc["private"]["repository"],
+            "https://pypi.example.com/legacy/"
+        );
+
+        let mut aliases = HashMap::new();
+        aliases.insert("local".to_owned(), "http://localhost:8080/".to_owned());
+
+        let pypi = find_repository("pypi", &aliases, &pypirc).unwrap();
+        assert_eq!(pypi.url, PYPI_URL);
+        assert_eq!(
+            credentials(&pypi),
+            Some(("__token__".into(), "pypi-abc".into()))
+        );
+
+        let private = find_repository("private", &aliases, &pypirc).unwrap();
+        assert_eq!(private.url, "https://pypi.example.com/legacy/");
+        assert_eq!(private.username, Some("me".into()));
+        // Urls listed in `.pypirc` use its credentials.
+        assert_eq!(
+            find_repository("https://pypi.example.com/legacy", &aliases, &pypirc)
+                .unwrap()
+                .password,
+            Some("secret".into())
+        );
+
+        let local = find_repository("local", &aliases, &pypirc).unwrap();
+        assert_eq!(local.url, "http://localhost:8080/");
+        assert_eq!(credentials(&local), None);
+        assert_eq!(
+            find_repository("testpypi", &aliases, &pypirc).unwrap().url,
+            TEST_PYPI_URL
+        );
+        assert!(find_repository("missing", &aliases, &pypirc).is_err());
+
+        assert_eq!(token_var("test-pypi"), "PYPACKAGE_TOKEN_TEST_PYPI");
+        env::set_var("PYPACKAGE_TOKEN_PYPACKAGE_UPLOAD_TEST", "pypi-xyz");
+        let repo = Repository {
+            name: "pypackage-upload-test".into(),
+            url: "http://localhost:8080/".into(),
+            username: Some("me".into()),
+            password: Some("secret".into()),
+        };
+        assert_eq!(
+            credentials(&repo),
+            Some(("__token__".into(), "pypi-xyz".into()))
+        );
+    }
+
+    #[test]
+    fn fields_and_validation() {
+        let md = "Metadata-Version: 2.1\nName: My-Pkg\nVersion: 0.1.0\nSummary: A test\n\
+                  Home-page: https://example.com\nClassifier: A :: B\nClassifier: C :: D\n\
+                  Requires-Dist: numpy>=1.16.4\nDescription-Content-Type: text/markdown\n\n\
+                  # My package\n";
+        let fields = upload_fields("my_pkg-0.1.0-py3-none-any.whl", b"data", md);
+        let get = |name: &str| -> Vec<&str> {
+            fields
+                .iter()
+                .filter(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+                .collect()
+        };
+        assert_eq!(get("filetype"), vec!["bdist_wheel"]);
+        assert_eq!(get("pyversion"), vec!["py3"]);
+        assert_eq!(get("metadata_version"), vec!["2.1"]);
+        assert_eq!(get("home_page"), vec!["https://example.com"]);
+        assert_eq!(get("classifiers"), vec!["A :: B", "C :: D"]);
+        assert_eq!(get("requires_dist"), vec!["numpy>=1.16.4"]);
+        assert_eq!(get("description"), vec!["# My package"]);
+        assert_eq!(
+            get("sha256_digest"),
+            vec!["3a6eb0790f39ac87c94f3856b2dd2c5d110e6811602261a9a923d3bb23adc8b7"]
+        );
+        assert_eq!(
+            get("blake2_256_digest"),
+            vec!["a035872d6af8639ede962dfe7536b0c150b590f3234a922fb7064cd11971b58e"]
+        );
+        assert!(validate("my_pkg-0.1.0-py3-none-any.whl", &fields).is_empty());
+
+        let sdist = upload_fields("my_pkg-0.1.0.tar.gz", b"data", md);
+        assert!(sdist.contains(&("pyversion".into(), "source".into())));
+        assert!(validate("my_pkg-0.1.0.tar.gz", &sdist).is_empty());
+        assert_eq!(validate("my_pkg-0.2.0.tar.gz", &sdist).len(), 1);
+
+        let bad = upload_fields(
+            "bad.whl",
+            b"data",
+            "Metadata-Version: 9.9\nName: -bad\nVersion: x\n\
+             Description-Content-Type: text/html\n",
+        );
+        assert_eq!(validate("bad.whl", &bad).len(), 6);
+    }
+}
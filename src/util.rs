@@ -2,6 +2,7 @@ use crate::{
     dep_resolution,
     dep_types::{Constraint, Req, ReqType, Version},
     files,
+    index::PackageIndex,
 };
 use crossterm::{Color, Colored};
 use regex::Regex;
@@ -19,6 +20,16 @@ pub fn print_color(message: &str, color: Color) {
     );
 }
 
+/// Like `print_color`, but to stderr, for warnings that shouldn't mix with output, eg JSON.
+pub fn eprint_color(message: &str, color: Color) {
+    eprintln!(
+        "{}{}{}",
+        Colored::Fg(color),
+        message,
+        Colored::Fg(Color::Reset)
+    );
+}
+
 /// Used when the program should exit from a condition that may arise normally from program use,
 /// like incorrect info in config files, problems with dependencies, or internet connection problems.
 /// We use `expect`, `panic!` etc for problems that indicate a bug in this program.
@@ -193,6 +204,33 @@ pub fn find_installed(lib_path: &PathBuf) -> Vec<(String, Version, Vec<String>)>
     result
 }
 
+/// Find the `dist-info` folder of an installed package. Its name uses the version as released,
+/// eg `1.0` vs `1.0.0`, and the package name's capitalization may differ from ours, so
+/// compare parsed values instead of building the path directly.
+pub fn find_dist_info(name: &str, version: &Version, lib_path: &PathBuf) -> Option<PathBuf> {
+    let re_dist = Regex::new(r"^(.*?)-(.*?)\.dist-info$").unwrap();
+
+    for entry in lib_path.read_dir().ok()? {
+        if let Ok(entry) = entry {
+            let folder_name = entry.file_name();
+            let folder_name = match folder_name.to_str() {
+                Some(f) => f,
+                None => continue,
+            };
+            if let Some(caps) = re_dist.captures(folder_name) {
+                let vers_matches = match Version::from_str(caps.get(2).unwrap().as_str()) {
+                    Ok(v) => v == *version,
+                    Err(_) => false,
+                };
+                if vers_matches && compare_names(caps.get(1).unwrap().as_str(), name) {
+                    return Some(entry.path());
+                }
+            }
+        }
+    }
+    None
+}
+
 /// Find console scripts installed, by browsing the (custom) bin folder
 pub fn find_console_scripts(bin_path: &PathBuf) -> Vec<String> {
     let mut result = vec![];
@@ -211,7 +249,12 @@ pub fn find_console_scripts(bin_path: &PathBuf) -> Vec<String> {
 }
 
 /// Handle reqs added via the CLI
-pub fn merge_reqs(added: &[String], cfg: &crate::Config, cfg_filename: &str) -> Vec<Req> {
+pub fn merge_reqs(
+    added: &[String],
+    cfg: &crate::Config,
+    cfg_filename: &str,
+    index: &dyn PackageIndex,
+) -> Vec<Req> {
     let mut added_reqs = vec![];
     for p in added.iter() {
         match Req::from_str(&p, false) {
@@ -247,7 +290,7 @@ pub fn merge_reqs(added: &[String], cfg: &crate::Config, cfg_filename: &str) ->
     // version.
     for added_req in added_reqs_unique.iter_mut() {
         if added_req.constraints.is_empty() {
-            let (_, vers, _) = dep_resolution::get_version_info(&added_req.name)
+            let (_, vers, _) = dep_resolution::get_version_info(index, &added_req.name)
                 .expect("Problem getting latest version of the package you added.");
             added_req.constraints.push(Constraint::new(
                 ReqType::Caret,
@@ -276,7 +319,7 @@ pub fn merge_reqs(added: &[String], cfg: &crate::Config, cfg_filename: &str) ->
     }
 
     if !added_reqs_unique.is_empty() {
-        files::add_reqs_to_cfg(cfg_filename, &added_reqs_unique);
+        files::add_reqs_to_cfg(cfg_filename, &added_reqs_unique, index);
     }
 
     result.append(&mut added_reqs_unique);